@@ -3,7 +3,9 @@ use axum::response::sse::Event;
 use std::convert::Infallible;
 use typify::import_types;
 
-use arroyo_rpc::types::{ConnectionSchema, ConnectionType, Format, TestSourceMessage};
+use arroyo_rpc::types::{
+    ConnectionSchema, ConnectionType, Format, SchemaDefinition, TestSourceMessage,
+};
 use arroyo_rpc::OperatorConfig;
 use serde::{Deserialize, Serialize};
 
@@ -72,29 +74,62 @@ impl Connector for FileSystemConnector {
         id: Option<i64>,
         name: &str,
         config: Self::ProfileT,
-        table: Self::TableT,
+        mut table: Self::TableT,
         schema: Option<&ConnectionSchema>,
     ) -> anyhow::Result<crate::Connection> {
         let is_local = match &table.write_target {
-            Destination::FolderUri { path } => path.starts_with("file:/"),
-            Destination::S3Bucket { .. } => false,
+            Destination::FolderUri {
+                path,
+                scheme_override,
+            } => match scheme_override {
+                Some(scheme) => scheme == "file",
+                None => path.starts_with("file:/"),
+            },
+            Destination::S3Bucket { .. } | Destination::GcsBucket { .. } => false,
             Destination::LocalFilesystem { .. } => true,
         };
+        if let Some(format) = &table.format {
+            if format.is_empty()
+                || !format
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!(
+                    "'{}' is not a valid format name; names may only contain letters, digits, '_' and '-'",
+                    format
+                );
+            }
+            if is_local {
+                bail!("registered formats are not supported for local filesystem targets");
+            }
+            let builtin = match &table.format_settings {
+                Some(FormatSettings::Parquet { .. }) => "parquet",
+                Some(FormatSettings::Json { .. }) => "json",
+                None => bail!("have to have some format settings"),
+            };
+            if matches!(format.as_str(), "json" | "parquet") && format != builtin {
+                bail!(
+                    "format '{}' does not match the {} format settings",
+                    format,
+                    builtin
+                );
+            }
+        }
         let (description, operator) = match (&table.format_settings, is_local) {
             (Some(FormatSettings::Parquet { .. }), true) => (
                 "LocalFileSystem<Parquet>".to_string(),
                 "connectors::filesystem::LocalParquetFileSystemSink::<#in_k, #in_t, #in_tRecordBatchBuilder>"
             ),
             (Some(FormatSettings::Parquet { .. }), false) => (
-                "FileSystem<Parquet>".to_string(),
+                format!("FileSystem<{}>", table.format.as_deref().unwrap_or("Parquet")),
                 "connectors::filesystem::ParquetFileSystemSink::<#in_k, #in_t, #in_tRecordBatchBuilder>"
             ),
-            (Some(FormatSettings::Json {  }), true) => (
+            (Some(FormatSettings::Json { .. }), true) => (
                 "LocalFileSystem<JSON>".to_string(),
                 "connectors::filesystem::LocalJsonFileSystemSink::<#in_k, #in_t>"
             ),
-            (Some(FormatSettings::Json {  }), false) => (
-                "FileSystem<JSON>".to_string(),
+            (Some(FormatSettings::Json { .. }), false) => (
+                format!("FileSystem<{}>", table.format.as_deref().unwrap_or("JSON")),
                 "connectors::filesystem::JsonFileSystemSink::<#in_k, #in_t>"
             ),
             (None, _) => bail!("have to have some format settings"),
@@ -110,6 +145,64 @@ impl Connector for FileSystemConnector {
             .map(|t| t.to_owned())
             .ok_or_else(|| anyhow!("'format' must be set for FileSystem connection"))?;
 
+        if let Some(FormatSettings::Parquet {
+            bloom_filter_columns,
+            columns,
+            dictionary_column_overrides,
+            ..
+        }) = &table.format_settings
+        {
+            for column in bloom_filter_columns {
+                if !schema
+                    .fields
+                    .iter()
+                    .any(|field| &field.field_name == column)
+                {
+                    bail!("bloom filter column '{}' is not in the schema", column);
+                }
+            }
+            for column in columns {
+                if !schema
+                    .fields
+                    .iter()
+                    .any(|field| &field.field_name == column)
+                {
+                    bail!("column '{}' is not in the schema", column);
+                }
+            }
+            for column in dictionary_column_overrides.keys() {
+                if !schema
+                    .fields
+                    .iter()
+                    .any(|field| &field.field_name == column)
+                {
+                    bail!(
+                        "dictionary override column '{}' is not in the schema",
+                        column
+                    );
+                }
+            }
+        }
+
+        if let Some(FormatSettings::Json {
+            validate_against_schema: Some(true),
+            json_schema,
+            ..
+        }) = &mut table.format_settings
+        {
+            if json_schema.is_none() {
+                let Some(SchemaDefinition::JsonSchema(definition)) = &schema.definition else {
+                    bail!("validate_against_schema requires a JSON schema definition");
+                };
+                *json_schema = Some(definition.clone());
+            }
+            let definition =
+                serde_json::from_str::<serde_json::Value>(json_schema.as_ref().unwrap())
+                    .map_err(|e| anyhow!("invalid JSON schema for validation: {}", e))?;
+            jsonschema::JSONSchema::compile(&definition)
+                .map_err(|e| anyhow!("invalid JSON schema for validation: {}", e))?;
+        }
+
         let config = OperatorConfig {
             connection: serde_json::to_value(config).unwrap(),
             table: serde_json::to_value(table).unwrap(),
@@ -135,7 +228,10 @@ impl Connector for FileSystemConnector {
         schema: Option<&ConnectionSchema>,
     ) -> anyhow::Result<crate::Connection> {
         let write_target = if let Some(path) = opts.remove("path") {
-            Destination::FolderUri { path }
+            Destination::FolderUri {
+                path,
+                scheme_override: opts.remove("scheme_override"),
+            }
         } else if let (Some(s3_bucket), Some(s3_directory), Some(aws_region)) = (
             opts.remove("s3_bucket"),
             opts.remove("s3_directory"),
@@ -145,23 +241,140 @@ impl Connector for FileSystemConnector {
                 s3_bucket,
                 s3_directory,
                 aws_region,
+                role_arn: opts.remove("role_arn"),
+                external_id: opts.remove("external_id"),
+            }
+        } else if let (Some(gcs_bucket), Some(gcs_directory)) =
+            (opts.remove("gcs_bucket"), opts.remove("gcs_directory"))
+        {
+            Destination::GcsBucket {
+                gcs_bucket,
+                gcs_directory,
+                endpoint: opts.remove("gcs_endpoint"),
             }
         } else {
-            bail!("Target for filesystem connector incorrectly specified. Should be a URI path or a triple of s3_bucket, s3_directory, and aws_region");
+            bail!("Target for filesystem connector incorrectly specified. Should be a URI path, a triple of s3_bucket, s3_directory, and aws_region, or a pair of gcs_bucket and gcs_directory");
         };
 
         let inactivity_rollover_seconds = pull_option_to_i64("inactivity_rollover_seconds", opts)?;
         let max_parts = pull_option_to_i64("max_parts", opts)?;
+        if let Some(max_parts) = max_parts {
+            if !(1..=10_000).contains(&max_parts) {
+                bail!("max_parts must be between 1 and 10000, not {}", max_parts);
+            }
+        }
+        let max_records = pull_option_to_i64("max_records", opts)?;
         let rollover_seconds = pull_option_to_i64("rollover_seconds", opts)?;
+        let finalize_on_watermark_seconds =
+            pull_option_to_i64("finalize_on_watermark_seconds", opts)?;
         let target_file_size = pull_option_to_i64("target_file_size", opts)?;
         let target_part_size = pull_option_to_i64("target_part_size", opts)?;
+        let max_concurrent_parts = pull_option_to_i64("max_concurrent_parts", opts)?;
+        let max_inflight_uploads = pull_option_to_i64("max_inflight_uploads", opts)?;
+        let file_index_width = pull_option_to_i64("file_index_width", opts)?;
+        let subtask_index_width = pull_option_to_i64("subtask_index_width", opts)?;
+        let parquet_max_buffer_bytes = pull_option_to_i64("parquet_max_buffer_bytes", opts)?;
+        let idle_flush_seconds = pull_option_to_i64("idle_flush_seconds", opts)?;
+        let compaction_candidate_threshold =
+            pull_option_to_i64("compaction_candidate_threshold", opts)?;
+        let fsync_on_close = opts
+            .remove("fsync_on_close")
+            .map(|value| {
+                value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} is not a valid fsync_on_close argument", value))
+            })
+            .transpose()?;
+        let write_checksum_sidecar = opts
+            .remove("write_checksum_sidecar")
+            .map(|value| {
+                value.parse::<bool>().map_err(|_| {
+                    anyhow!("{} is not a valid write_checksum_sidecar argument", value)
+                })
+            })
+            .transpose()?;
+        let roll_on_checkpoint = opts
+            .remove("roll_on_checkpoint")
+            .map(|value| {
+                value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} is not a valid roll_on_checkpoint argument", value))
+            })
+            .transpose()?;
+        let subtask_subdirectory = opts
+            .remove("subtask_subdirectory")
+            .map(|value| {
+                value
+                    .parse::<bool>()
+                    .map_err(|_| anyhow!("{} is not a valid subtask_subdirectory argument", value))
+            })
+            .transpose()?;
+        let tmp_dir = opts.remove("tmp_dir");
+        let file_mode = opts
+            .remove("file_mode")
+            .map(|value| {
+                u32::from_str_radix(value.trim_start_matches("0o"), 8)
+                    .map_err(|_| anyhow!("{} is not a valid octal file_mode argument", value))
+            })
+            .transpose()?;
+        let key_prefix = opts.remove("key_prefix");
+        let include_host_in_filename = opts
+            .remove("include_host_in_filename")
+            .map(|value| {
+                value.parse::<bool>().map_err(|_| {
+                    anyhow!("{} is not a valid include_host_in_filename argument", value)
+                })
+            })
+            .transpose()?;
+        let restart_expired_uploads = opts
+            .remove("restart_expired_uploads")
+            .map(|value| {
+                value.parse::<bool>().map_err(|_| {
+                    anyhow!("{} is not a valid restart_expired_uploads argument", value)
+                })
+            })
+            .transpose()?;
+        let on_part_limit = opts
+            .remove("on_part_limit")
+            .map(|value| {
+                OnPartLimit::try_from(&value)
+                    .map_err(|_| anyhow!("{} is not a valid on_part_limit argument", value))
+            })
+            .transpose()?;
+        let run_subdirectory = opts
+            .remove("run_subdirectory")
+            .map(|value| {
+                RunSubdirectory::try_from(&value)
+                    .map_err(|_| anyhow!("{} is not a valid run_subdirectory argument", value))
+            })
+            .transpose()?;
 
         let file_settings = Some(FileSettings {
             inactivity_rollover_seconds,
             max_parts,
+            on_part_limit,
+            max_records,
             rollover_seconds,
+            finalize_on_watermark_seconds,
             target_file_size,
             target_part_size,
+            max_concurrent_parts,
+            max_inflight_uploads,
+            file_index_width,
+            subtask_index_width,
+            parquet_max_buffer_bytes,
+            fsync_on_close,
+            write_checksum_sidecar,
+            run_subdirectory,
+            roll_on_checkpoint,
+            idle_flush_seconds,
+            subtask_subdirectory,
+            tmp_dir,
+            key_prefix,
+            compaction_candidate_threshold,
+            file_mode,
+            include_host_in_filename,
+            restart_expired_uploads,
         });
         let format_settings = match schema
             .ok_or(anyhow!("require schema"))?
@@ -180,13 +393,172 @@ impl Connector for FileSystemConnector {
                     .transpose()?;
                 let row_batch_size = pull_option_to_i64("parquet_row_batch_size", opts)?;
                 let row_group_size = pull_option_to_i64("parquet_row_group_size", opts)?;
+                let bloom_filter_columns = opts
+                    .remove("parquet_bloom_filter_columns")
+                    .map(|columns| {
+                        columns
+                            .split(',')
+                            .map(|column| column.trim().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let columns = opts
+                    .remove("parquet_columns")
+                    .map(|columns| {
+                        columns
+                            .split(',')
+                            .map(|column| column.trim().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let dictionary_enabled = opts
+                    .remove("parquet_dictionary_enabled")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!(
+                                "{} is not a valid parquet_dictionary_enabled argument",
+                                value
+                            )
+                        })
+                    })
+                    .transpose()?;
+                // comma-separated column:bool pairs, e.g. `user_id:false,country:true`
+                let dictionary_column_overrides = opts
+                    .remove("parquet_dictionary_columns")
+                    .map(|overrides| {
+                        overrides
+                            .split(',')
+                            .map(|column_override| {
+                                let (column, enabled) =
+                                    column_override.trim().split_once(':').ok_or_else(|| {
+                                        anyhow!(
+                                            "{} is not a valid parquet_dictionary_columns entry",
+                                            column_override
+                                        )
+                                    })?;
+                                let enabled = enabled.trim().parse::<bool>().map_err(|_| {
+                                    anyhow!(
+                                        "{} is not a valid parquet_dictionary_columns entry",
+                                        column_override
+                                    )
+                                })?;
+                                Ok((column.trim().to_string(), enabled))
+                            })
+                            .collect::<Result<_>>()
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                // comma-separated key:value pairs, e.g. `schema_version:2,team:growth`
+                let key_value_metadata = opts
+                    .remove("parquet_key_value_metadata")
+                    .map(|pairs| {
+                        pairs
+                            .split(',')
+                            .map(|pair| {
+                                let (key, value) =
+                                    pair.trim().split_once(':').ok_or_else(|| {
+                                        anyhow!(
+                                            "{} is not a valid parquet_key_value_metadata entry",
+                                            pair
+                                        )
+                                    })?;
+                                Ok((key.trim().to_string(), value.trim().to_string()))
+                            })
+                            .collect::<Result<_>>()
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                let statistics = opts
+                    .remove("parquet_statistics")
+                    .map(|value| {
+                        ParquetStatistics::try_from(&value).map_err(|_err| {
+                            anyhow!("{} is not a valid parquet_statistics argument", value)
+                        })
+                    })
+                    .transpose()?;
+                let write_page_index = opts
+                    .remove("parquet_write_page_index")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!("{} is not a valid parquet_write_page_index argument", value)
+                        })
+                    })
+                    .transpose()?;
+                if write_page_index == Some(true)
+                    && matches!(
+                        statistics,
+                        Some(ParquetStatistics::None | ParquetStatistics::Chunk)
+                    )
+                {
+                    bail!("parquet_write_page_index requires parquet_statistics to be 'page'");
+                }
                 Some(FormatSettings::Parquet {
                     compression,
                     row_batch_size,
                     row_group_size,
+                    bloom_filter_columns,
+                    statistics,
+                    columns,
+                    dictionary_enabled,
+                    dictionary_column_overrides,
+                    key_value_metadata,
+                    write_page_index,
+                })
+            }
+            Format::Json(..) => {
+                let validate_against_schema = opts
+                    .remove("json_validate_against_schema")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!(
+                                "{} is not a valid json_validate_against_schema argument",
+                                value
+                            )
+                        })
+                    })
+                    .transpose()?;
+                let dedupe_consecutive = opts
+                    .remove("json_dedupe_consecutive")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!("{} is not a valid json_dedupe_consecutive argument", value)
+                        })
+                    })
+                    .transpose()?;
+                let trailing_newline = opts
+                    .remove("json_trailing_newline")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!("{} is not a valid json_trailing_newline argument", value)
+                        })
+                    })
+                    .transpose()?;
+                let redact_fields = opts
+                    .remove("json_redact_fields")
+                    .map(|fields| {
+                        fields
+                            .split(',')
+                            .map(|field| field.trim().to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let redact_with_hash = opts
+                    .remove("json_redact_with_hash")
+                    .map(|value| {
+                        value.parse::<bool>().map_err(|_| {
+                            anyhow!("{} is not a valid json_redact_with_hash argument", value)
+                        })
+                    })
+                    .transpose()?;
+                Some(FormatSettings::Json {
+                    validate_against_schema,
+                    json_schema: None,
+                    dedupe_consecutive,
+                    trailing_newline,
+                    redact_fields,
+                    redact_with_hash,
                 })
             }
-            Format::Json(..) => Some(FormatSettings::Json {}),
             other => bail!("Unsupported format: {:?}", other),
         };
 
@@ -198,6 +570,7 @@ impl Connector for FileSystemConnector {
                 write_target,
                 file_settings,
                 format_settings,
+                format: None,
             },
             schema,
         )
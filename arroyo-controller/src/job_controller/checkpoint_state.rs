@@ -7,22 +7,378 @@ use arroyo_rpc::grpc;
 use arroyo_rpc::grpc::api::OperatorCheckpointDetail;
 use arroyo_rpc::grpc::{
     api, backend_data, BackendData, CheckpointMetadata, OperatorCheckpointMetadata,
-    TableDescriptor, TableWriteBehavior, TaskCheckpointCompletedReq, TaskCheckpointEventReq,
+    SubtaskCheckpointMetadata, TableDescriptor, TableWriteBehavior, TaskCheckpointCompletedReq,
+    TaskCheckpointEventReq,
 };
 use arroyo_rpc::public_ids::{generate_id, IdTypes};
 use arroyo_state::{BackingStore, StateBackend};
-use arroyo_types::to_micros;
-use deadpool_postgres::Pool;
+use arroyo_types::{to_micros, RESTORE_COMPATIBILITY_ENV};
+use deadpool_postgres::{Pool, PoolError};
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::time::SystemTime;
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use time::OffsetDateTime;
+use tokio_postgres::error::SqlState;
 use tracing::{debug, info, warn};
 
+/// Version of Arroyo recorded in checkpoints written by this controller
+pub const ARROYO_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Attempts made at a checkpoint database write before giving up
+const DB_WRITE_ATTEMPTS: usize = 5;
+const DB_RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DB_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Whether a failed database operation may succeed if retried: connection failures, pool
+/// timeouts, and transient server errors are; anything else (e.g., a constraint violation)
+/// would fail the same way again
+fn is_retryable_db_error(err: &anyhow::Error) -> bool {
+    fn postgres_retryable(err: &tokio_postgres::Error) -> bool {
+        match err.code() {
+            Some(code) => {
+                // class 08 is connection exceptions
+                code.code().starts_with("08")
+                    || [
+                        SqlState::T_R_SERIALIZATION_FAILURE,
+                        SqlState::T_R_DEADLOCK_DETECTED,
+                        SqlState::ADMIN_SHUTDOWN,
+                        SqlState::CANNOT_CONNECT_NOW,
+                    ]
+                    .contains(code)
+            }
+            // errors without a SQLSTATE didn't come from the server; only retry those caused
+            // by the connection
+            None => {
+                err.is_closed()
+                    || std::error::Error::source(err)
+                        .map_or(false, |source| source.is::<std::io::Error>())
+            }
+        }
+    }
+
+    if let Some(err) = err.downcast_ref::<PoolError>() {
+        return match err {
+            PoolError::Timeout(_) => true,
+            PoolError::Backend(err) => postgres_retryable(err),
+            _ => false,
+        };
+    }
+    if let Some(err) = err.downcast_ref::<tokio_postgres::Error>() {
+        return postgres_retryable(err);
+    }
+    err.is::<std::io::Error>()
+}
+
+/// Runs `operation`, retrying retryable database errors with exponential backoff so that a
+/// transient blip in the connection to Postgres doesn't fail the checkpoint
+pub(crate) async fn with_db_retries<T, F, Fut>(
+    description: &str,
+    mut operation: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut backoff = DB_RETRY_INITIAL_BACKOFF;
+    for attempt in 1.. {
+        match operation().await {
+            Err(err) if attempt < DB_WRITE_ATTEMPTS && is_retryable_db_error(&err) => {
+                warn!(
+                    message = "database write failed; retrying",
+                    operation = description,
+                    attempt,
+                    error = format!("{:?}", err)
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(DB_RETRY_MAX_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+/// How closely a checkpoint must match the program that's restoring from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompatibilityPolicy {
+    /// The checkpoint must have been written by the same program (including parallelism) on
+    /// the same version of Arroyo
+    Strict,
+    /// The checkpoint must have been written by the same major and minor version of Arroyo;
+    /// the program may have changed, e.g. by being rescaled
+    SameArroyoVersion,
+    /// Any checkpoint may be restored, but differences in the Arroyo version or program are
+    /// logged as warnings
+    #[default]
+    Warn,
+    /// Any checkpoint may be restored
+    Any,
+}
+
+impl FromStr for CompatibilityPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "same_version" => Ok(Self::SameArroyoVersion),
+            "warn" => Ok(Self::Warn),
+            "any" => Ok(Self::Any),
+            _ => bail!(
+                "invalid restore compatibility policy '{}'; expected strict, same_version, warn, or any",
+                s
+            ),
+        }
+    }
+}
+
+impl CompatibilityPolicy {
+    /// The policy set by `RESTORE_COMPATIBILITY`, or the default if it's unset or invalid
+    pub fn from_env() -> Self {
+        match std::env::var(RESTORE_COMPATIBILITY_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!("{:?}; using the default policy", e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// Checks that the checkpoint described by `metadata` can be restored into the program with
+/// `program_hash` under `policy`. Checkpoints written before versions were recorded can only
+/// be checked against the program, so the strict policy rejects them.
+pub fn check_restore_compatibility(
+    metadata: &CheckpointMetadata,
+    program_hash: &str,
+    policy: CompatibilityPolicy,
+) -> anyhow::Result<()> {
+    if policy == CompatibilityPolicy::Any {
+        return Ok(());
+    }
+
+    let major_minor = |version: &str| version.split('.').take(2).collect::<Vec<_>>();
+    let version_mismatch = if metadata.arroyo_version.is_empty() {
+        if policy == CompatibilityPolicy::Strict {
+            bail!(
+                "checkpoint has no version information, so it can't be checked against Arroyo {}",
+                ARROYO_VERSION
+            );
+        }
+        warn!(
+            message = "checkpoint has no version information; skipping version check",
+            job_id = metadata.job_id,
+            epoch = metadata.epoch
+        );
+        None
+    } else {
+        let compatible = if policy == CompatibilityPolicy::Strict {
+            metadata.arroyo_version == ARROYO_VERSION
+        } else {
+            major_minor(&metadata.arroyo_version) == major_minor(ARROYO_VERSION)
+        };
+        (!compatible).then(|| {
+            format!(
+                "checkpoint was written by Arroyo {}, which is not compatible with Arroyo {}",
+                metadata.arroyo_version, ARROYO_VERSION
+            )
+        })
+    };
+    let program_mismatch =
+        (!metadata.program_hash.is_empty() && metadata.program_hash != program_hash).then(|| {
+            format!(
+                "checkpoint was written by program {}, but the current program is {}",
+                metadata.program_hash, program_hash
+            )
+        });
+
+    match policy {
+        CompatibilityPolicy::Strict => {
+            if let Some(mismatch) = version_mismatch.or(program_mismatch) {
+                bail!(mismatch);
+            }
+        }
+        CompatibilityPolicy::SameArroyoVersion => {
+            if let Some(mismatch) = version_mismatch {
+                bail!(mismatch);
+            }
+            if let Some(mismatch) = program_mismatch {
+                info!(
+                    message = "restoring checkpoint written by a different program",
+                    job_id = metadata.job_id,
+                    epoch = metadata.epoch,
+                    reason = mismatch
+                );
+            }
+        }
+        CompatibilityPolicy::Warn => {
+            for mismatch in [version_mismatch, program_mismatch].into_iter().flatten() {
+                warn!(
+                    message = "restoring checkpoint that may not be compatible",
+                    job_id = metadata.job_id,
+                    epoch = metadata.epoch,
+                    reason = mismatch
+                );
+            }
+        }
+        CompatibilityPolicy::Any => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Maximum number of epochs whose artifacts are removed in a single call to the backing store
+const PRUNE_BATCH_EPOCHS: u32 = 8;
+
+/// The backing store operations used by [`prune_checkpoints`]
+#[async_trait::async_trait]
+pub trait CheckpointPruner {
+    /// Deletes the checkpoint metadata and operator state for epochs in
+    /// `old_min_epoch..new_min_epoch`, leaving the job's checkpoint metadata unchanged
+    async fn delete_epochs(
+        metadata: &CheckpointMetadata,
+        old_min_epoch: u32,
+        new_min_epoch: u32,
+    ) -> anyhow::Result<()>;
+
+    async fn write_checkpoint_metadata(metadata: CheckpointMetadata);
+}
+
+#[async_trait::async_trait]
+impl CheckpointPruner for StateBackend {
+    async fn delete_epochs(
+        metadata: &CheckpointMetadata,
+        old_min_epoch: u32,
+        new_min_epoch: u32,
+    ) -> anyhow::Result<()> {
+        StateBackend::delete_checkpoint_epochs(metadata, old_min_epoch, new_min_epoch).await
+    }
+
+    async fn write_checkpoint_metadata(metadata: CheckpointMetadata) {
+        <StateBackend as BackingStore>::write_checkpoint_metadata(metadata).await
+    }
+}
+
+/// Removes the checkpoint metadata and operator state for epochs in `old_min_epoch..new_min_epoch`
+/// from the backing store `S`, working through the range in batches of [`PRUNE_BATCH_EPOCHS`] so
+/// that a large backlog doesn't have to be deleted in one go. Epochs at or above `new_min_epoch`
+/// are never touched. The checkpoint metadata is rewritten with the new `min_epoch` once, after
+/// every batch has been deleted.
+pub async fn prune_checkpoints<S: CheckpointPruner>(
+    mut metadata: CheckpointMetadata,
+    old_min_epoch: u32,
+    new_min_epoch: u32,
+) -> anyhow::Result<()> {
+    let mut batch_start = old_min_epoch;
+    while batch_start < new_min_epoch {
+        let batch_end = (batch_start + PRUNE_BATCH_EPOCHS).min(new_min_epoch);
+        debug!(
+            message = "Pruning checkpoint epochs",
+            job_id = metadata.job_id,
+            batch_start,
+            batch_end
+        );
+        S::delete_epochs(&metadata, batch_start, batch_end).await?;
+        batch_start = batch_end;
+    }
+
+    metadata.min_epoch = new_min_epoch;
+    S::write_checkpoint_metadata(metadata).await;
+    Ok(())
+}
+
+/// The minimum watermark across an operator's subtasks. Subtasks that were idle when they
+/// checkpointed (or had no watermark yet) don't hold back the rest, so they're excluded unless
+/// every subtask is idle, in which case the minimum over all of them is used.
+fn min_active_watermark<'a>(
+    subtasks: impl Iterator<Item = &'a SubtaskCheckpointMetadata> + Clone,
+) -> Option<u64> {
+    subtasks
+        .clone()
+        .filter(|s| !s.watermark_idle)
+        .filter_map(|s| s.watermark)
+        .min()
+        .or_else(|| subtasks.map(|s| s.watermark).min().flatten())
+}
+
+/// Records a subtask's checkpoint event in the details shown in the web UI
+pub(crate) fn record_checkpoint_event(
+    operator_details: &mut HashMap<String, OperatorCheckpointDetail>,
+    c: &TaskCheckpointEventReq,
+) {
+    operator_details
+        .entry(c.operator_id.clone())
+        .or_insert_with(|| OperatorCheckpointDetail {
+            operator_id: c.operator_id.clone(),
+            start_time: c.time,
+            finish_time: None,
+            has_state: false,
+            tasks: HashMap::new(),
+        })
+        .tasks
+        .entry(c.subtask_index)
+        .or_insert_with(|| api::TaskCheckpointDetail {
+            subtask_index: c.subtask_index,
+            start_time: c.time,
+            finish_time: None,
+            bytes: None,
+            events: vec![],
+        })
+        .events
+        .push(api::TaskCheckpointEvent {
+            time: c.time,
+            event_type: match c.event_type() {
+                grpc::TaskCheckpointEventType::StartedAlignment => {
+                    api::TaskCheckpointEventType::AlignmentStarted
+                }
+                grpc::TaskCheckpointEventType::StartedCheckpointing => {
+                    api::TaskCheckpointEventType::CheckpointStarted
+                }
+                grpc::TaskCheckpointEventType::FinishedOperatorSetup => {
+                    api::TaskCheckpointEventType::CheckpointOperatorFinished
+                }
+                grpc::TaskCheckpointEventType::FinishedSync => {
+                    api::TaskCheckpointEventType::CheckpointSyncFinished
+                }
+                grpc::TaskCheckpointEventType::FinishedCommit => {
+                    api::TaskCheckpointEventType::CheckpointPreCommit
+                }
+            } as i32,
+        });
+}
+
+/// A view of an in-progress checkpoint, built from the controller's in-memory state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointStatusSnapshot {
+    pub checkpoint_id: i64,
+    pub epoch: u32,
+    pub start_time: SystemTime,
+    /// Whether every operator has finished checkpointing
+    pub done: bool,
+    pub operators: BTreeMap<String, OperatorCheckpointStatus>,
+}
+
+/// The progress of one operator within a [`CheckpointStatusSnapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorCheckpointStatus {
+    /// Subtasks that have finished their checkpoint
+    pub subtasks_reported: usize,
+    pub subtasks_expected: usize,
+    /// Bytes written by the subtasks that have reported so far
+    pub bytes: u64,
+    /// Whether all of the operator's subtasks have reported and its metadata has been written
+    pub completed: bool,
+    /// Whether any of the operator's subtasks have writes to commit once the checkpoint is done
+    pub committing: bool,
+}
+
 pub struct CheckpointState {
     job_id: String,
     checkpoint_id: i64,
     epoch: u32,
     min_epoch: u32,
+    program_hash: String,
     pub start_time: SystemTime,
     tasks_per_operator: HashMap<String, usize>,
     tasks: HashMap<String, BTreeMap<u32, SubtaskState>>,
@@ -40,6 +396,7 @@ impl CheckpointState {
         checkpoint_id: i64,
         epoch: u32,
         min_epoch: u32,
+        program_hash: String,
         tasks_per_operator: HashMap<String, usize>,
     ) -> Self {
         Self {
@@ -47,6 +404,7 @@ impl CheckpointState {
             checkpoint_id,
             epoch,
             min_epoch,
+            program_hash,
             start_time: SystemTime::now(),
             tasks_per_operator,
             tasks: HashMap::new(),
@@ -89,6 +447,7 @@ impl CheckpointState {
             checkpoint_id,
             epoch,
             min_epoch,
+            program.get_hash(),
             program.tasks_per_operator(),
         ))
     }
@@ -96,54 +455,20 @@ impl CheckpointState {
     pub fn checkpoint_event(&mut self, c: TaskCheckpointEventReq) -> anyhow::Result<()> {
         debug!(message = "Checkpoint event", checkpoint_id = self.checkpoint_id, event_type = ?c.event_type(), subtask_index = c.subtask_index, operator_id = ?c.operator_id);
 
+        record_checkpoint_event(&mut self.operator_details, &c);
+
         if grpc::TaskCheckpointEventType::FinishedCommit == c.event_type() {
-            bail!(
-                "shouldn't receive finished commit {:?} while checkpointing",
-                c
+            // commits are tracked by the committing state once the checkpoint is done, so a
+            // commit reported before then has nothing to update
+            warn!(
+                message = "Received finished commit while checkpointing",
+                checkpoint_id = self.checkpoint_id,
+                operator_id = c.operator_id,
+                subtask_index = c.subtask_index
             );
+            return Ok(());
         }
 
-        // This is all for the UI
-        self.operator_details
-            .entry(c.operator_id.clone())
-            .or_insert_with(|| OperatorCheckpointDetail {
-                operator_id: c.operator_id.clone(),
-                start_time: c.time,
-                finish_time: None,
-                has_state: false,
-                tasks: HashMap::new(),
-            })
-            .tasks
-            .entry(c.subtask_index)
-            .or_insert_with(|| api::TaskCheckpointDetail {
-                subtask_index: c.subtask_index,
-                start_time: c.time,
-                finish_time: None,
-                bytes: None,
-                events: vec![],
-            })
-            .events
-            .push(api::TaskCheckpointEvent {
-                time: c.time,
-                event_type: match c.event_type() {
-                    grpc::TaskCheckpointEventType::StartedAlignment => {
-                        api::TaskCheckpointEventType::AlignmentStarted
-                    }
-                    grpc::TaskCheckpointEventType::StartedCheckpointing => {
-                        api::TaskCheckpointEventType::CheckpointStarted
-                    }
-                    grpc::TaskCheckpointEventType::FinishedOperatorSetup => {
-                        api::TaskCheckpointEventType::CheckpointOperatorFinished
-                    }
-                    grpc::TaskCheckpointEventType::FinishedSync => {
-                        api::TaskCheckpointEventType::CheckpointSyncFinished
-                    }
-                    grpc::TaskCheckpointEventType::FinishedCommit => {
-                        api::TaskCheckpointEventType::CheckpointPreCommit
-                    }
-                } as i32,
-            });
-
         // this is for the actual checkpoint management
         self.tasks
             .entry(c.operator_id.clone())
@@ -250,11 +575,8 @@ impl CheckpointState {
             .max()
             .unwrap();
 
-        let min_watermark = subtasks
-            .values()
-            .map(|s| s.metadata.as_ref().unwrap().watermark)
-            .min()
-            .unwrap();
+        let min_watermark =
+            min_active_watermark(subtasks.values().map(|s| s.metadata.as_ref().unwrap()));
 
         let max_watermark = subtasks
             .values()
@@ -321,25 +643,56 @@ impl CheckpointState {
         self.completed_operators.len() == self.tasks_per_operator.len()
     }
 
+    /// The progress of each operator in the checkpoint so far
+    pub fn status_snapshot(&self) -> CheckpointStatusSnapshot {
+        let operators = self
+            .tasks_per_operator
+            .iter()
+            .map(|(operator_id, subtasks_expected)| {
+                let subtasks_reported = self.tasks.get(operator_id).map_or(0, |subtasks| {
+                    subtasks.values().filter(|subtask| subtask.done()).count()
+                });
+                let bytes = self.operator_details.get(operator_id).map_or(0, |details| {
+                    details.tasks.values().filter_map(|task| task.bytes).sum()
+                });
+                let committing = self
+                    .subtasks_to_commit
+                    .iter()
+                    .any(|(committing_operator, _)| committing_operator == operator_id);
+                (
+                    operator_id.clone(),
+                    OperatorCheckpointStatus {
+                        subtasks_reported,
+                        subtasks_expected: *subtasks_expected,
+                        bytes,
+                        completed: self.completed_operators.contains(operator_id),
+                        committing,
+                    },
+                )
+            })
+            .collect();
+
+        CheckpointStatusSnapshot {
+            checkpoint_id: self.checkpoint_id,
+            epoch: self.epoch,
+            start_time: self.start_time,
+            done: self.done(),
+            operators,
+        }
+    }
+
     pub fn committing_state(&self) -> CommittingState {
-        CommittingState::new(self.checkpoint_id, self.subtasks_to_commit.clone())
+        CommittingState::new(
+            self.checkpoint_id,
+            self.subtasks_to_commit.clone(),
+            self.operator_details.clone(),
+        )
     }
 
     /// Syncs the operator details to the database so the API/UI can see them
     pub async fn update_db(&self, pool: &Pool) -> anyhow::Result<()> {
-        let c = pool.get().await?;
-
-        controller_queries::update_checkpoint()
-            .bind(
-                &c,
-                &serde_json::to_value(&self.operator_details).unwrap(),
-                &None,
-                &crate::types::public::CheckpointState::inprogress,
-                &self.checkpoint_id,
-            )
-            .await?;
-
-        Ok(())
+        self.update_checkpoint_in_db(pool, crate::types::public::CheckpointState::inprogress)
+            .await
     }
 
     pub async fn save_state(&self) -> anyhow::Result<()> {
@@ -351,6 +704,8 @@ impl CheckpointState {
             finish_time: to_micros(finish_time),
             min_epoch: self.min_epoch,
             operator_ids: self.completed_operators.iter().cloned().collect(),
+            arroyo_version: ARROYO_VERSION.to_string(),
+            program_hash: self.program_hash.clone(),
         })
         .await;
         Ok(())
@@ -361,18 +716,371 @@ impl CheckpointState {
         pool: &Pool,
         db_checkpoint_state: crate::types::public::CheckpointState,
     ) -> anyhow::Result<()> {
-        let c = pool.get().await?;
-        let operator_state = serde_json::to_value(&self.operator_details).unwrap();
-        controller_queries::update_checkpoint()
-            .bind(
-                &c,
-                &operator_state,
-                &None,
-                &db_checkpoint_state,
-                &self.checkpoint_id,
-            )
-            .await?;
+        let operator_state = &serde_json::to_value(&self.operator_details).unwrap();
+        let db_checkpoint_state = &db_checkpoint_state;
+        let checkpoint_id = &self.checkpoint_id;
+        with_db_retries("update checkpoint", || async move {
+            let c = pool.get().await?;
+            controller_queries::update_checkpoint()
+                .bind(
+                    &c,
+                    operator_state,
+                    &None,
+                    db_checkpoint_state,
+                    checkpoint_id,
+                )
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod test {
+    use super::{
+        check_restore_compatibility, min_active_watermark, prune_checkpoints, with_db_retries,
+        CheckpointPruner, CheckpointState, CompatibilityPolicy, OperatorCheckpointStatus,
+        ARROYO_VERSION, DB_WRITE_ATTEMPTS,
+    };
+    use arroyo_rpc::grpc::{
+        api, CheckpointMetadata, SubtaskCheckpointMetadata, TableDescriptor, TableWriteBehavior,
+        TaskCheckpointCompletedReq, TaskCheckpointEventReq, TaskCheckpointEventType,
+    };
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    lazy_static::lazy_static! {
+        static ref DELETED_EPOCHS: Mutex<Vec<u32>> = Mutex::new(vec![]);
+        static ref WRITTEN_MIN_EPOCHS: Mutex<Vec<u32>> = Mutex::new(vec![]);
+    }
+
+    /// A pruner that only records which epochs it deleted and the metadata it wrote
+    struct RecordingPruner;
+
+    #[async_trait]
+    impl CheckpointPruner for RecordingPruner {
+        async fn delete_epochs(
+            _: &CheckpointMetadata,
+            old_min_epoch: u32,
+            new_min_epoch: u32,
+        ) -> anyhow::Result<()> {
+            DELETED_EPOCHS
+                .lock()
+                .unwrap()
+                .extend(old_min_epoch..new_min_epoch);
+            Ok(())
+        }
+
+        async fn write_checkpoint_metadata(metadata: CheckpointMetadata) {
+            WRITTEN_MIN_EPOCHS.lock().unwrap().push(metadata.min_epoch);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prune_only_removes_epochs_below_min() {
+        let metadata = CheckpointMetadata {
+            job_id: "job".to_string(),
+            epoch: 30,
+            start_time: 0,
+            finish_time: 0,
+            min_epoch: 3,
+            operator_ids: vec!["op".to_string()],
+            arroyo_version: String::new(),
+            program_hash: String::new(),
+        };
+
+        prune_checkpoints::<RecordingPruner>(metadata, 3, 26)
+            .await
+            .unwrap();
+
+        let deleted = DELETED_EPOCHS.lock().unwrap().clone();
+        assert_eq!(deleted, (3..26).collect::<Vec<_>>());
+
+        // the metadata is only rewritten once all of the batches have been deleted
+        let written = WRITTEN_MIN_EPOCHS.lock().unwrap().clone();
+        assert_eq!(written, vec![26]);
+    }
+
+    #[tokio::test]
+    async fn test_db_writes_retry_connection_errors() {
+        // the first attempt fails as if the connection to the pool had been dropped
+        let attempts = &AtomicUsize::new(0);
+        let committed = with_db_retries("test update", || async move {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "connection reset by peer",
+                )
+                .into());
+            }
+            Ok("committed")
+        })
+        .await
+        .unwrap();
+        assert_eq!(committed, "committed");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // other errors, like constraint violations, fail immediately
+        let attempts = &AtomicUsize::new(0);
+        let result: anyhow::Result<()> = with_db_retries("test update", || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!(
+                "duplicate key value violates unique constraint"
+            ))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // and retryable errors are given up on eventually
+        let attempts = &AtomicUsize::new(0);
+        let result: anyhow::Result<()> = with_db_retries("test update", || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused").into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), DB_WRITE_ATTEMPTS);
+    }
+
+    #[test]
+    fn test_finished_commit_while_checkpointing() {
+        let mut state = CheckpointState::new(
+            "job".to_string(),
+            1,
+            1,
+            1,
+            "hash".to_string(),
+            HashMap::from([("sink".to_string(), 1)]),
+        );
+
+        let mut event = TaskCheckpointEventReq {
+            worker_id: 1,
+            time: 100,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            subtask_index: 0,
+            epoch: 1,
+            event_type: 0,
+        };
+        event.set_event_type(TaskCheckpointEventType::FinishedCommit);
+
+        // recorded for the UI rather than failing the checkpoint
+        state.checkpoint_event(event).unwrap();
+        let events = &state.operator_details["sink"].tasks[&0].events;
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].event_type,
+            api::TaskCheckpointEventType::CheckpointPreCommit as i32
+        );
+        assert!(!state.tasks.contains_key("sink"));
+    }
+
+    #[tokio::test]
+    async fn test_status_snapshot() {
+        let mut state = CheckpointState::new(
+            "job".to_string(),
+            1,
+            3,
+            1,
+            "hash".to_string(),
+            HashMap::from([("source".to_string(), 2), ("sink".to_string(), 1)]),
+        );
+
+        for (operator_id, subtask_index) in [("source", 0), ("source", 1), ("sink", 0)] {
+            let mut event = TaskCheckpointEventReq {
+                worker_id: 1,
+                time: 100,
+                job_id: "job".to_string(),
+                operator_id: operator_id.to_string(),
+                subtask_index,
+                epoch: 3,
+                event_type: 0,
+            };
+            event.set_event_type(TaskCheckpointEventType::StartedCheckpointing);
+            state.checkpoint_event(event).unwrap();
+        }
+
+        // one of the source's subtasks has finished, with writes to commit
+        state
+            .checkpoint_finished(TaskCheckpointCompletedReq {
+                worker_id: 1,
+                time: 200,
+                job_id: "job".to_string(),
+                operator_id: "source".to_string(),
+                epoch: 3,
+                metadata: Some(SubtaskCheckpointMetadata {
+                    subtask_index: 1,
+                    start_time: 100,
+                    finish_time: 200,
+                    has_state: true,
+                    bytes: 1_024,
+                    tables: vec![TableDescriptor {
+                        write_behavior: TableWriteBehavior::CommitWrites as i32,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                needs_commit: true,
+            })
+            .await
+            .unwrap();
+
+        let snapshot = state.status_snapshot();
+        assert_eq!(snapshot.checkpoint_id, 1);
+        assert_eq!(snapshot.epoch, 3);
+        assert!(!snapshot.done);
+        assert_eq!(
+            snapshot.operators["source"],
+            OperatorCheckpointStatus {
+                subtasks_reported: 1,
+                subtasks_expected: 2,
+                bytes: 1_024,
+                completed: false,
+                committing: true,
+            }
+        );
+        assert_eq!(
+            snapshot.operators["sink"],
+            OperatorCheckpointStatus {
+                subtasks_reported: 0,
+                subtasks_expected: 1,
+                bytes: 0,
+                completed: false,
+                committing: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_restore_compatibility() {
+        let metadata = CheckpointMetadata {
+            job_id: "job".to_string(),
+            epoch: 5,
+            start_time: 0,
+            finish_time: 0,
+            min_epoch: 1,
+            operator_ids: vec!["op".to_string()],
+            arroyo_version: ARROYO_VERSION.to_string(),
+            program_hash: "abc".to_string(),
+        };
+
+        assert!(check_restore_compatibility(&metadata, "abc", CompatibilityPolicy::Strict).is_ok());
+        assert!(
+            check_restore_compatibility(&metadata, "def", CompatibilityPolicy::Strict).is_err()
+        );
+        assert!(check_restore_compatibility(
+            &metadata,
+            "def",
+            CompatibilityPolicy::SameArroyoVersion
+        )
+        .is_ok());
+
+        let old_version = CheckpointMetadata {
+            arroyo_version: "0.0.1".to_string(),
+            ..metadata.clone()
+        };
+        assert!(check_restore_compatibility(
+            &old_version,
+            "abc",
+            CompatibilityPolicy::SameArroyoVersion
+        )
+        .is_err());
+        assert!(check_restore_compatibility(&old_version, "def", CompatibilityPolicy::Any).is_ok());
+
+        // the default only warns
+        assert!(
+            check_restore_compatibility(&old_version, "def", CompatibilityPolicy::Warn).is_ok()
+        );
+        assert_eq!(CompatibilityPolicy::default(), CompatibilityPolicy::Warn);
+
+        // checkpoints written before versions were recorded can't be checked strictly, but the
+        // program is still compared if it was recorded
+        let unversioned = CheckpointMetadata {
+            arroyo_version: String::new(),
+            ..metadata
+        };
+        assert!(
+            check_restore_compatibility(&unversioned, "abc", CompatibilityPolicy::Strict).is_err()
+        );
+        assert!(check_restore_compatibility(
+            &unversioned,
+            "abc",
+            CompatibilityPolicy::SameArroyoVersion
+        )
+        .is_ok());
+        assert!(
+            check_restore_compatibility(&unversioned, "def", CompatibilityPolicy::Warn).is_ok()
+        );
+        let unversioned = CheckpointMetadata {
+            program_hash: String::new(),
+            ..unversioned
+        };
+        assert!(check_restore_compatibility(
+            &unversioned,
+            "def",
+            CompatibilityPolicy::SameArroyoVersion
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_compatibility_policy() {
+        assert_eq!(
+            "strict".parse::<CompatibilityPolicy>().unwrap(),
+            CompatibilityPolicy::Strict
+        );
+        assert_eq!(
+            "Same_Version".parse::<CompatibilityPolicy>().unwrap(),
+            CompatibilityPolicy::SameArroyoVersion
+        );
+        assert_eq!(
+            "warn".parse::<CompatibilityPolicy>().unwrap(),
+            CompatibilityPolicy::Warn
+        );
+        assert_eq!(
+            "any".parse::<CompatibilityPolicy>().unwrap(),
+            CompatibilityPolicy::Any
+        );
+        assert!("sometimes".parse::<CompatibilityPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_min_watermark_ignores_idle_subtasks() {
+        let subtask = |subtask_index, watermark, watermark_idle| SubtaskCheckpointMetadata {
+            subtask_index,
+            watermark,
+            watermark_idle,
+            ..Default::default()
+        };
+
+        // the idle subtask's watermark is stale, so only the active one counts
+        let idle = subtask(0, Some(1_000), true);
+        let active = subtask(1, Some(5_000), false);
+        assert_eq!(
+            min_active_watermark([&idle, &active].into_iter()),
+            Some(5_000)
+        );
+
+        // a subtask that has never had a watermark doesn't count either
+        let never_active = subtask(2, None, false);
+        assert_eq!(
+            min_active_watermark([&never_active, &active].into_iter()),
+            Some(5_000)
+        );
+
+        // if every subtask is idle, fall back to the minimum over all of them
+        assert_eq!(
+            min_active_watermark([&idle, &never_active].into_iter()),
+            None
+        );
+        let also_idle = subtask(1, Some(3_000), true);
+        assert_eq!(
+            min_active_watermark([&idle, &also_idle].into_iter()),
+            Some(1_000)
+        );
     }
 }
@@ -1,38 +1,95 @@
+use crate::job_controller::checkpoint_state::{record_checkpoint_event, with_db_retries};
 use crate::queries::controller_queries;
+use crate::types::public::CheckpointState as DbCheckpointState;
+use arroyo_rpc::grpc::api::OperatorCheckpointDetail;
+use arroyo_rpc::grpc::{TaskCheckpointEventReq, TaskCheckpointEventType};
 use deadpool_postgres::Pool;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
+use time::OffsetDateTime;
+use tracing::warn;
 
 pub struct CommittingState {
     checkpoint_id: i64,
     subtasks_to_commit: HashSet<(String, u32)>,
+    // the checkpoint's details for the web ui, which commit events are added to; not known
+    // for commits restored from the database
+    operator_details: Option<HashMap<String, OperatorCheckpointDetail>>,
 }
 
 impl CommittingState {
-    pub fn new(checkpoint_id: i64, subtasks_to_commit: HashSet<(String, u32)>) -> Self {
+    pub fn new(
+        checkpoint_id: i64,
+        subtasks_to_commit: HashSet<(String, u32)>,
+        operator_details: HashMap<String, OperatorCheckpointDetail>,
+    ) -> Self {
         Self {
             checkpoint_id,
             subtasks_to_commit,
+            operator_details: Some(operator_details),
         }
     }
+
     pub fn subtask_committed(&mut self, operator_id: String, subtask_index: u32) {
         self.subtasks_to_commit
             .remove(&(operator_id, subtask_index));
     }
 
+    /// Handles a checkpoint event received while committing, which should only be a subtask
+    /// reporting that it finished its commit
+    pub fn checkpoint_event(&mut self, c: TaskCheckpointEventReq) {
+        if c.event_type() != TaskCheckpointEventType::FinishedCommit {
+            warn!(
+                message = "Unexpected checkpoint event while committing",
+                checkpoint_id = self.checkpoint_id,
+                event_type = ?c.event_type(),
+                operator_id = c.operator_id,
+                subtask_index = c.subtask_index
+            );
+            return;
+        }
+
+        if let Some(operator_details) = &mut self.operator_details {
+            record_checkpoint_event(operator_details, &c);
+        }
+        self.subtask_committed(c.operator_id, c.subtask_index);
+    }
+
     pub fn done(&self) -> bool {
         self.subtasks_to_commit.is_empty()
     }
 
     pub async fn finish(self, pool: &Pool) -> anyhow::Result<()> {
         let finish_time = SystemTime::now();
+        let operator_details = &self
+            .operator_details
+            .as_ref()
+            .map(|operator_details| serde_json::to_value(operator_details).unwrap());
+        let checkpoint_id = &self.checkpoint_id;
 
-        let c = pool.get().await?;
-        controller_queries::commit_checkpoint()
-            .bind(&c, &finish_time.into(), &self.checkpoint_id)
-            .await?;
-
-        Ok(())
+        with_db_retries("commit checkpoint", || async move {
+            let c = pool.get().await?;
+            match operator_details {
+                Some(operator_details) => {
+                    controller_queries::update_checkpoint()
+                        .bind(
+                            &c,
+                            operator_details,
+                            &Some(OffsetDateTime::from(finish_time)),
+                            &DbCheckpointState::ready,
+                            checkpoint_id,
+                        )
+                        .await?;
+                }
+                None => {
+                    controller_queries::commit_checkpoint()
+                        .bind(&c, &finish_time.into(), checkpoint_id)
+                        .await?;
+                }
+            }
+            Ok(())
+        })
+        .await
     }
 }
 
@@ -41,6 +98,64 @@ impl From<(i64, HashSet<(String, u32)>)> for CommittingState {
         Self {
             checkpoint_id,
             subtasks_to_commit,
+            operator_details: None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::CommittingState;
+    use arroyo_rpc::grpc::api;
+    use arroyo_rpc::grpc::{TaskCheckpointEventReq, TaskCheckpointEventType};
+    use std::collections::{HashMap, HashSet};
+
+    fn event(event_type: TaskCheckpointEventType, subtask_index: u32) -> TaskCheckpointEventReq {
+        let mut event = TaskCheckpointEventReq {
+            worker_id: 1,
+            time: 100,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            subtask_index,
+            epoch: 1,
+            event_type: 0,
+        };
+        event.set_event_type(event_type);
+        event
+    }
+
+    #[test]
+    fn test_finished_commit_while_committing() {
+        let mut state = CommittingState::new(
+            1,
+            HashSet::from([("sink".to_string(), 0), ("sink".to_string(), 1)]),
+            HashMap::new(),
+        );
+
+        state.checkpoint_event(event(TaskCheckpointEventType::FinishedCommit, 0));
+        assert!(!state.done());
+
+        // other events don't count as commits
+        state.checkpoint_event(event(TaskCheckpointEventType::FinishedSync, 1));
+        assert!(!state.done());
+
+        state.checkpoint_event(event(TaskCheckpointEventType::FinishedCommit, 1));
+        assert!(state.done());
+
+        let details = &state.operator_details.as_ref().unwrap()["sink"];
+        for subtask_index in [0, 1] {
+            let events = &details.tasks[&subtask_index].events;
+            assert_eq!(events.len(), 1);
+            assert_eq!(
+                events[0].event_type,
+                api::TaskCheckpointEventType::CheckpointPreCommit as i32
+            );
+        }
+
+        // restored commits don't have the details, but still track the subtasks
+        let mut restored = CommittingState::from((1, HashSet::from([("sink".to_string(), 0)])));
+        restored.checkpoint_event(event(TaskCheckpointEventType::FinishedCommit, 0));
+        assert!(restored.done());
+        assert!(restored.operator_details.is_none());
+    }
+}
@@ -21,7 +21,7 @@ use tokio::{sync::mpsc::Receiver, task::JoinHandle};
 use tonic::{transport::Channel, Request};
 use tracing::{error, info, warn};
 
-use crate::job_controller::checkpoint_state::CheckpointState;
+use crate::job_controller::checkpoint_state::{prune_checkpoints, CheckpointState};
 use crate::job_controller::comitting_state::CommittingState;
 use crate::types::public::CheckpointState as DbCheckpointState;
 use crate::{queries::controller_queries, JobConfig, JobMessage, RunningMessage};
@@ -121,13 +121,13 @@ impl RunningJobModel {
                                 checkpoint_state.update_db(pool).await?
                             }
                             CheckpointingOrCommittingState::Committing(committing_state) => {
-                                if matches!(c.event_type(), TaskCheckpointEventType::FinishedCommit)
-                                {
-                                    committing_state
-                                        .subtask_committed(c.operator_id.clone(), c.subtask_index);
+                                let committed = matches!(
+                                    c.event_type(),
+                                    TaskCheckpointEventType::FinishedCommit
+                                );
+                                committing_state.checkpoint_event(c);
+                                if committed {
                                     self.compact_state().await?;
-                                } else {
-                                    warn!("unexpected checkpoint event type {:?}", c.event_type())
                                 }
                             }
                         };
@@ -709,7 +709,7 @@ impl JobController {
                 .bind(&c, &job_id, &(min_epoch as i32), &(new_min as i32))
                 .await?;
 
-            StateBackend::cleanup_checkpoint(checkpoint, min_epoch, new_min).await?;
+            prune_checkpoints::<StateBackend>(checkpoint, min_epoch, new_min).await?;
 
             controller_queries::mark_checkpoints_compacted()
                 .bind(&c, &job_id, &(new_min as i32))
@@ -17,6 +17,7 @@ use anyhow::anyhow;
 use arroyo_state::{parquet::get_storage_env_vars, BackingStore, StateBackend};
 
 use crate::{
+    job_controller::checkpoint_state::{check_restore_compatibility, CompatibilityPolicy},
     job_controller::JobController,
     queries::controller_queries,
     states::{compiling::Compiling, stop_if_desired_non_running},
@@ -362,6 +363,13 @@ impl State for Scheduling {
                     )
                 })?;
 
+            check_restore_compatibility(
+                &metadata,
+                &ctx.program.get_hash(),
+                CompatibilityPolicy::from_env(),
+            )
+            .map_err(|e| fatal("Failed to restore job; checkpoint is not compatible.", e))?;
+
             if let Err(e) = StateBackend::prepare_checkpoint_load(&metadata).await {
                 return Err(ctx.retryable(self, "failed to prepare checkpoint for loading", e, 10));
             }
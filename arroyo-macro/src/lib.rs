@@ -720,7 +720,8 @@ fn impl_stream_node_type(
             crate::process_fn::ProcessFnUtils::send_checkpoint_event(checkpoint_barrier, ctx, arroyo_rpc::grpc::TaskCheckpointEventType::FinishedOperatorSetup).await;
 
             let watermark = ctx.watermarks.last_present_watermark();
-            ctx.state.checkpoint(checkpoint_barrier, watermark).await;
+            let watermark_idle = ctx.watermarks.watermark() == Some(arroyo_types::Watermark::Idle);
+            ctx.state.checkpoint(checkpoint_barrier, watermark, watermark_idle).await;
 
             crate::process_fn::ProcessFnUtils::send_checkpoint_event(checkpoint_barrier, ctx, arroyo_rpc::grpc::TaskCheckpointEventType::FinishedSync).await;
 
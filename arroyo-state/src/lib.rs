@@ -123,6 +123,7 @@ pub trait BackingStore {
         &mut self,
         barrier: CheckpointBarrier,
         watermark: Option<SystemTime>,
+        watermark_idle: bool,
     ) -> u32;
 
     async fn get_data_tuples<K: Key, V: Data>(&self, table: char) -> Vec<DataTuple<K, V>>;
@@ -336,8 +337,15 @@ impl<S: BackingStore> StateStore<S> {
         KeyedState::new(table, &mut self.backend, cache)
     }
 
-    pub async fn checkpoint(&mut self, barrier: CheckpointBarrier, watermark: Option<SystemTime>) {
-        self.backend.checkpoint(barrier, watermark).await;
+    pub async fn checkpoint(
+        &mut self,
+        barrier: CheckpointBarrier,
+        watermark: Option<SystemTime>,
+        watermark_idle: bool,
+    ) {
+        self.backend
+            .checkpoint(barrier, watermark, watermark_idle)
+            .await;
     }
 
     pub async fn load_compacted(&mut self, compaction: CompactionResult) {
@@ -455,6 +463,7 @@ mod test {
                     then_stop: false,
                 },
                 Some(SystemTime::UNIX_EPOCH),
+                false,
             )
             .await;
         // wait until we get confirmation on the queue
@@ -486,6 +495,8 @@ mod test {
             start_time: 0,
             finish_time: 0,
             operator_ids: vec![operator_id.to_string()],
+            arroyo_version: String::new(),
+            program_hash: String::new(),
         };
 
         ParquetBackend::write_checkpoint_metadata(checkpoint_metadata.clone()).await;
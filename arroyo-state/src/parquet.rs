@@ -9,10 +9,11 @@ use arroyo_rpc::grpc::{
     SubtaskCheckpointMetadata, TableDeleteBehavior, TableDescriptor, TableType,
 };
 use arroyo_rpc::{grpc, CheckpointCompleted, CompactionResult, ControlResp};
-use arroyo_storage::StorageProvider;
+use arroyo_storage::{StorageOptions, StorageProvider};
 use arroyo_types::{
     from_micros, range_for_server, to_micros, CheckpointBarrier, Data, Key, TaskInfo,
-    CHECKPOINT_URL_ENV, S3_ENDPOINT_ENV, S3_REGION_ENV,
+    CHECKPOINT_DELETE_BATCH_SIZE_ENV, CHECKPOINT_OBJECT_TAGS_ENV, CHECKPOINT_URL_ENV,
+    S3_ENDPOINT_ENV, S3_REGION_ENV,
 };
 use bincode::config;
 use bytes::Bytes;
@@ -29,19 +30,64 @@ use std::ops::RangeInclusive;
 use std::time::SystemTime;
 use tokio::sync::mpsc::{self, channel, Receiver, Sender};
 use tokio::sync::oneshot;
-use tokio::sync::Mutex;
 use tracing::{debug, info, warn};
 
 pub const FULL_KEY_RANGE: RangeInclusive<u64> = 0..=u64::MAX;
 pub const GENERATIONS_TO_COMPACT: u32 = 1; // only compact generation 0 files
 
 async fn get_storage_provider() -> anyhow::Result<StorageProvider> {
+    get_storage_provider_with_tags(checkpoint_object_tags()?).await
+}
+
+/// A storage provider that also tags the objects it writes with the task's job and operator
+async fn get_task_storage_provider(task_info: &TaskInfo) -> anyhow::Result<StorageProvider> {
+    let mut tags = checkpoint_object_tags()?;
+    tags.push(("job-id".to_string(), task_info.job_id.clone()));
+    tags.push(("operator-id".to_string(), task_info.operator_id.clone()));
+    get_storage_provider_with_tags(tags).await
+}
+
+/// The tags configured for checkpoint objects in `$CHECKPOINT_OBJECT_TAGS`
+fn checkpoint_object_tags() -> anyhow::Result<Vec<(String, String)>> {
+    let Ok(tags) = env::var(CHECKPOINT_OBJECT_TAGS_ENV) else {
+        return Ok(vec![]);
+    };
+    tags.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            tag.split_once('=')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .with_context(|| {
+                    format!(
+                        "invalid tag '{}' in {}; expected key=value",
+                        tag, CHECKPOINT_OBJECT_TAGS_ENV
+                    )
+                })
+        })
+        .collect()
+}
+
+async fn get_storage_provider_with_tags(
+    tags: Vec<(String, String)>,
+) -> anyhow::Result<StorageProvider> {
     // TODO: this should be encoded in the config so that the controller doesn't need
     // to be synchronized with the workers
     let storage_url =
         env::var(CHECKPOINT_URL_ENV).unwrap_or_else(|_| "file:///tmp/arroyo".to_string());
 
-    StorageProvider::for_url(&storage_url)
+    let mut options = StorageOptions::default().with_s3_object_tags(tags);
+    if let Ok(batch_size) = env::var(CHECKPOINT_DELETE_BATCH_SIZE_ENV) {
+        let batch_size = batch_size.trim().parse::<usize>().with_context(|| {
+            format!(
+                "invalid {} '{}'; expected a number of keys",
+                CHECKPOINT_DELETE_BATCH_SIZE_ENV, batch_size
+            )
+        })?;
+        options = options.with_delete_batch_size(batch_size);
+    }
+
+    StorageProvider::for_url_with_options(&storage_url, options)
         .await
         .context(format!(
             "failed to construct checkpoint backend for URL {}",
@@ -150,7 +196,7 @@ impl BackingStore for ParquetBackend {
         tables: Vec<TableDescriptor>,
         tx: Sender<ControlResp>,
     ) -> Self {
-        let storage = get_storage_provider().await.unwrap();
+        let storage = get_task_storage_provider(task_info).await.unwrap();
         Self {
             epoch: 1,
             min_epoch: 1,
@@ -219,7 +265,7 @@ impl BackingStore for ParquetBackend {
 
         let writer_current_files = current_files.clone();
 
-        let storage = get_storage_provider().await.unwrap();
+        let storage = get_task_storage_provider(task_info).await.unwrap();
 
         Self {
             epoch: metadata.epoch + 1,
@@ -247,54 +293,7 @@ impl BackingStore for ParquetBackend {
         old_min_epoch: u32,
         min_epoch: u32,
     ) -> Result<()> {
-        info!(
-            message = "Cleaning checkpoint",
-            min_epoch,
-            job_id = metadata.job_id
-        );
-
-        let mut futures: FuturesUnordered<_> = metadata
-            .operator_ids
-            .iter()
-            .map(|operator_id| {
-                Self::cleanup_operator(
-                    metadata.job_id.clone(),
-                    operator_id.clone(),
-                    old_min_epoch,
-                    min_epoch,
-                )
-            })
-            .collect();
-
-        let storage_client = Mutex::new(get_storage_provider().await?);
-
-        // wait for all of the futures to complete
-        while let Some(result) = futures.next().await {
-            let operator_id = result?;
-
-            for epoch_to_remove in old_min_epoch..min_epoch {
-                let path = metadata_path(&operator_path(
-                    &metadata.job_id,
-                    epoch_to_remove,
-                    &operator_id,
-                ));
-                storage_client.lock().await.delete_if_present(path).await?;
-            }
-            debug!(
-                message = "Finished cleaning operator",
-                job_id = metadata.job_id,
-                operator_id,
-                min_epoch
-            );
-        }
-
-        for epoch_to_remove in old_min_epoch..min_epoch {
-            storage_client
-                .lock()
-                .await
-                .delete_if_present(metadata_path(&base_path(&metadata.job_id, epoch_to_remove)))
-                .await?;
-        }
+        Self::delete_checkpoint_epochs(&metadata, old_min_epoch, min_epoch).await?;
         metadata.min_epoch = min_epoch;
         Self::write_checkpoint_metadata(metadata).await;
         Ok(())
@@ -304,10 +303,17 @@ impl BackingStore for ParquetBackend {
         &mut self,
         barrier: CheckpointBarrier,
         watermark: Option<SystemTime>,
+        watermark_idle: bool,
     ) -> u32 {
         assert_eq!(barrier.epoch, self.epoch);
         self.writer
-            .checkpoint(self.epoch, barrier.timestamp, watermark, barrier.then_stop)
+            .checkpoint(
+                self.epoch,
+                barrier.timestamp,
+                watermark,
+                watermark_idle,
+                barrier.then_stop,
+            )
             .await;
         self.epoch += 1;
         self.min_epoch = barrier.min_epoch;
@@ -598,7 +604,7 @@ impl ParquetBackend {
                         task.clone(),
                         generation,
                         generation_files,
-                        get_storage_provider().await?,
+                        get_task_storage_provider(&task).await?,
                         epoch,
                         state_store.table_descriptors.get(&table_char).unwrap(),
                     )
@@ -625,6 +631,67 @@ impl ParquetBackend {
         }
     }
 
+    /// Delete the operator state and metadata for epochs in `old_min_epoch..min_epoch`, without
+    /// updating the checkpoint metadata's `min_epoch`
+    pub async fn delete_checkpoint_epochs(
+        metadata: &CheckpointMetadata,
+        old_min_epoch: u32,
+        min_epoch: u32,
+    ) -> Result<()> {
+        info!(
+            message = "Cleaning checkpoint",
+            min_epoch,
+            job_id = metadata.job_id
+        );
+
+        let mut futures: FuturesUnordered<_> = metadata
+            .operator_ids
+            .iter()
+            .map(|operator_id| {
+                Self::cleanup_operator(
+                    metadata.job_id.clone(),
+                    operator_id.clone(),
+                    old_min_epoch,
+                    min_epoch,
+                )
+            })
+            .collect();
+
+        let storage_client = get_storage_provider().await?;
+
+        let mut metadata_paths = vec![];
+        // wait for all of the futures to complete
+        while let Some(result) = futures.next().await {
+            let operator_id = result?;
+
+            for epoch_to_remove in old_min_epoch..min_epoch {
+                metadata_paths.push(metadata_path(&operator_path(
+                    &metadata.job_id,
+                    epoch_to_remove,
+                    &operator_id,
+                )));
+            }
+            debug!(
+                message = "Finished cleaning operator",
+                job_id = metadata.job_id,
+                operator_id,
+                min_epoch
+            );
+        }
+        storage_client.delete_many(metadata_paths).await?;
+
+        storage_client
+            .delete_many(
+                (old_min_epoch..min_epoch)
+                    .map(|epoch_to_remove| {
+                        metadata_path(&base_path(&metadata.job_id, epoch_to_remove))
+                    })
+                    .collect(),
+            )
+            .await?;
+        Ok(())
+    }
+
     /// Delete files no longer referenced by the new min epoch
     pub async fn cleanup_operator(
         job_id: String,
@@ -648,6 +715,7 @@ impl ParquetBackend {
                 .collect();
 
         let mut deleted_paths = HashSet::new();
+        let mut files_to_delete = vec![];
         let storage_client = get_storage_provider().await?;
 
         for epoch_to_remove in old_min_epoch..new_min_epoch {
@@ -666,10 +734,12 @@ impl ParquetBackend {
                 let file = parquet_store.file.clone();
                 if !paths_to_keep.contains(&file) && !deleted_paths.contains(&file) {
                     deleted_paths.insert(file.clone());
-                    storage_client.delete_if_present(file).await?;
+                    files_to_delete.push(file);
                 }
             }
         }
+        // superseded files are removed with bulk deletes rather than one request per file
+        storage_client.delete_many(files_to_delete).await?;
 
         Ok(operator_id)
     }
@@ -961,6 +1031,7 @@ impl ParquetWriter {
         epoch: u32,
         time: SystemTime,
         watermark: Option<SystemTime>,
+        watermark_idle: bool,
         then_stop: bool,
     ) {
         self.sender
@@ -968,6 +1039,7 @@ impl ParquetWriter {
                 epoch,
                 time,
                 watermark,
+                watermark_idle,
                 then_stop,
             }))
             .await
@@ -1002,6 +1074,7 @@ struct ParquetCheckpoint {
     epoch: u32,
     time: SystemTime,
     watermark: Option<SystemTime>,
+    watermark_idle: bool,
     then_stop: bool,
 }
 
@@ -1339,6 +1412,7 @@ impl ParquetFlusher {
                 has_state: !checkpoint_backend_data.is_empty(),
                 tables: self.table_descriptors.values().cloned().collect(),
                 watermark: cp.watermark.map(to_micros),
+                watermark_idle: cp.watermark_idle,
                 backend_data: checkpoint_backend_data,
                 bytes: bytes as u64,
             };
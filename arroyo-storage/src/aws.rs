@@ -1,14 +1,21 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use object_store::{aws::AwsCredential, CredentialProvider};
 use rusoto_core::credential::{
-    AutoRefreshingProvider, ChainProvider, ProfileProvider, ProvideAwsCredentials,
+    AutoRefreshingProvider, AwsCredentials, ChainProvider, CredentialsError, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::{HttpClient, Region};
+use rusoto_sts::{
+    AssumeRoleRequest, AssumeRoleWithWebIdentityRequest, NewAwsCredsForStsCreds, Sts, StsClient,
 };
 
 use crate::StorageError;
 
 pub struct ArroyoCredentialProvider {
-    provider: AutoRefreshingProvider<ChainProvider>,
+    provider: Box<dyn ProvideAwsCredentials + Send + Sync>,
 }
 
 impl std::fmt::Debug for ArroyoCredentialProvider {
@@ -19,11 +26,20 @@ impl std::fmt::Debug for ArroyoCredentialProvider {
 
 impl ArroyoCredentialProvider {
     pub fn try_new() -> Result<Self, StorageError> {
-        let inner: AutoRefreshingProvider<ChainProvider> =
-            AutoRefreshingProvider::new(ChainProvider::new())
-                .map_err(|e| StorageError::CredentialsError(e.to_string()))?;
+        // on EKS with IRSA, assume the service account's role directly rather than relying on
+        // the chain to find the projected token
+        let provider: Box<dyn ProvideAwsCredentials + Send + Sync> =
+            if let Some(web_identity) = WebIdentityProvider::from_env() {
+                Box::new(AutoRefreshingProvider::new(web_identity)?)
+            } else if let Some(profile_chain) = ProfileChainProvider::from_env()? {
+                // the default chain only reads static credentials from profiles, so roles
+                // configured with source_profile are assumed explicitly
+                Box::new(AutoRefreshingProvider::new(profile_chain)?)
+            } else {
+                Box::new(AutoRefreshingProvider::new(ChainProvider::new())?)
+            };
 
-        Ok(Self { provider: inner })
+        Ok(Self { provider })
     }
 
     pub async fn default_region(&self) -> Option<String> {
@@ -53,3 +69,580 @@ impl CredentialProvider for ArroyoCredentialProvider {
         }))
     }
 }
+
+// lets rusoto clients share the provider, and its cached credentials, with object_store
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for ArroyoCredentialProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.provider.credentials().await
+    }
+}
+
+/// Provides credentials by assuming `AWS_ROLE_ARN` with the token in
+/// `AWS_WEB_IDENTITY_TOKEN_FILE` through STS's `AssumeRoleWithWebIdentity`, which is how
+/// IAM roles for service accounts (IRSA) work on EKS. The token file is re-read on every
+/// request, as Kubernetes rotates it. Wrap in an [`AutoRefreshingProvider`] to cache the
+/// credentials until they expire.
+///
+/// This makes the same call as [`rusoto_sts::WebIdentityProvider`], but that always uses the
+/// default region's STS endpoint, so it can't honor `AWS_ENDPOINT_URL_STS`.
+pub struct WebIdentityProvider {
+    token_file: PathBuf,
+    role_arn: String,
+    session_name: String,
+    sts_region: Region,
+}
+
+impl WebIdentityProvider {
+    pub fn new(
+        token_file: impl Into<PathBuf>,
+        role_arn: impl Into<String>,
+        session_name: impl Into<String>,
+        sts_region: Region,
+    ) -> Self {
+        Self {
+            token_file: token_file.into(),
+            role_arn: role_arn.into(),
+            session_name: session_name.into(),
+            sts_region,
+        }
+    }
+
+    /// Returns a provider if both `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN` are set
+    pub fn from_env() -> Option<Self> {
+        let token_file = std::env::var("AWS_WEB_IDENTITY_TOKEN_FILE").ok()?;
+        let role_arn = std::env::var("AWS_ROLE_ARN").ok()?;
+        let session_name = std::env::var("AWS_ROLE_SESSION_NAME")
+            .unwrap_or_else(|_| format!("arroyo-{}", uuid::Uuid::new_v4()));
+
+        // the region from AWS_DEFAULT_REGION or AWS_REGION, or us-east-1
+        let sts_region = match std::env::var("AWS_ENDPOINT_URL_STS") {
+            Ok(endpoint) => Region::Custom {
+                name: Region::default().name().to_string(),
+                endpoint,
+            },
+            Err(_) => Region::default(),
+        };
+
+        Some(Self::new(token_file, role_arn, session_name, sts_region))
+    }
+}
+
+/// A role assumed as one link of a profile chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChainedRole {
+    role_arn: String,
+    external_id: Option<String>,
+    session_name: String,
+}
+
+/// Provides credentials for a profile in `~/.aws/config` that assumes a role with
+/// `role_arn` and `source_profile`, following the chain of source profiles down to one with
+/// static credentials and then assuming each role in turn through STS. Wrap in an
+/// [`AutoRefreshingProvider`] to cache the credentials until they expire.
+pub struct ProfileChainProvider {
+    base: StaticProvider,
+    // in the order they're assumed, ending with the requested profile's role
+    roles: Vec<ChainedRole>,
+    sts_region: Region,
+}
+
+impl ProfileChainProvider {
+    /// Returns a provider if `AWS_PROFILE` names a profile that assumes a role
+    pub fn from_env() -> Result<Option<Self>, CredentialsError> {
+        let Ok(profile) = std::env::var("AWS_PROFILE") else {
+            return Ok(None);
+        };
+        let home = std::env::var("HOME").unwrap_or_default();
+        let config_file = std::env::var("AWS_CONFIG_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| Path::new(&home).join(".aws/config"));
+        let credentials_file = std::env::var("AWS_SHARED_CREDENTIALS_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| Path::new(&home).join(".aws/credentials"));
+
+        Self::from_files(&profile, &config_file, &credentials_file, None)
+    }
+
+    /// Resolves `profile` from the given files, returning None if it doesn't assume a role.
+    /// STS is called in `sts_region` if set, or else the profile's region.
+    pub fn from_files(
+        profile: &str,
+        config_file: &Path,
+        credentials_file: &Path,
+        sts_region: Option<Region>,
+    ) -> Result<Option<Self>, CredentialsError> {
+        let config = read_profiles(config_file, true)?;
+        let credentials = read_profiles(credentials_file, false)?;
+
+        let Some(settings) = config.get(profile) else {
+            return Ok(None);
+        };
+        if !settings.contains_key("role_arn") {
+            return Ok(None);
+        }
+
+        let sts_region = match sts_region {
+            Some(region) => region,
+            None => settings
+                .get("region")
+                .cloned()
+                .or_else(|| std::env::var("AWS_REGION").ok())
+                .and_then(|region| region.parse().ok())
+                .unwrap_or(Region::UsEast1),
+        };
+
+        let mut roles = vec![];
+        let mut visited = HashSet::new();
+        let mut current = profile.to_string();
+        let base = loop {
+            if !visited.insert(current.clone()) {
+                return Err(CredentialsError::new(format!(
+                    "profile '{}' has a cycle in its source_profile chain",
+                    profile
+                )));
+            }
+            let Some((settings, role_arn)) = config
+                .get(&current)
+                .and_then(|settings| Some((settings, settings.get("role_arn")?)))
+            else {
+                break static_credentials(&current, &config, &credentials)?;
+            };
+
+            roles.push(ChainedRole {
+                role_arn: role_arn.clone(),
+                external_id: settings.get("external_id").cloned(),
+                session_name: settings
+                    .get("role_session_name")
+                    .cloned()
+                    .unwrap_or_else(|| format!("arroyo-{}", uuid::Uuid::new_v4())),
+            });
+
+            let Some(source_profile) = settings.get("source_profile") else {
+                return Err(CredentialsError::new(format!(
+                    "profile '{}' sets role_arn without source_profile, which is not supported",
+                    current
+                )));
+            };
+            if source_profile == &current {
+                // a profile can hold the static credentials used to assume its own role
+                break static_credentials(&current, &config, &credentials)?;
+            }
+            current = source_profile.clone();
+        };
+        roles.reverse();
+
+        Ok(Some(Self {
+            base,
+            roles,
+            sts_region,
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for ProfileChainProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut credentials = self.base.credentials().await?;
+        for role in &self.roles {
+            let sts = StsClient::new_with(
+                HttpClient::new().map_err(|e| {
+                    CredentialsError::new(format!("failed to create STS client: {}", e))
+                })?,
+                StaticProvider::new(
+                    credentials.aws_access_key_id().to_string(),
+                    credentials.aws_secret_access_key().to_string(),
+                    credentials.token().clone(),
+                    None,
+                ),
+                self.sts_region.clone(),
+            );
+            let response = sts
+                .assume_role(AssumeRoleRequest {
+                    role_arn: role.role_arn.clone(),
+                    role_session_name: role.session_name.clone(),
+                    external_id: role.external_id.clone(),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| {
+                    CredentialsError::new(format!("failed to assume role {}: {}", role.role_arn, e))
+                })?;
+            let sts_credentials = response.credentials.ok_or_else(|| {
+                CredentialsError::new(format!(
+                    "no credentials returned when assuming role {}",
+                    role.role_arn
+                ))
+            })?;
+            credentials = AwsCredentials::new_for_credentials(sts_credentials).map_err(|e| {
+                CredentialsError::new(format!(
+                    "invalid credentials returned when assuming role {}: {}",
+                    role.role_arn, e
+                ))
+            })?;
+        }
+        Ok(credentials)
+    }
+}
+
+/// The static credentials for a profile, from the credentials file or the config file
+fn static_credentials(
+    profile: &str,
+    config: &HashMap<String, HashMap<String, String>>,
+    credentials: &HashMap<String, HashMap<String, String>>,
+) -> Result<StaticProvider, CredentialsError> {
+    [credentials.get(profile), config.get(profile)]
+        .into_iter()
+        .flatten()
+        .find_map(|settings| {
+            Some(StaticProvider::new(
+                settings.get("aws_access_key_id")?.clone(),
+                settings.get("aws_secret_access_key")?.clone(),
+                settings.get("aws_session_token").cloned(),
+                None,
+            ))
+        })
+        .ok_or_else(|| {
+            CredentialsError::new(format!(
+                "source profile '{}' has no static credentials",
+                profile
+            ))
+        })
+}
+
+/// Parses the profiles in an AWS config or credentials file. In config files, profiles other
+/// than `default` are in `[profile name]` sections. A missing file has no profiles.
+fn read_profiles(
+    path: &Path,
+    is_config: bool,
+) -> Result<HashMap<String, HashMap<String, String>>, CredentialsError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => {
+            return Err(CredentialsError::new(format!(
+                "failed to read {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    let mut profiles: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let section = section.trim();
+            current = match (is_config, section.strip_prefix("profile ")) {
+                (true, Some(name)) => Some(name.trim().to_string()),
+                (true, None) if section == "default" => Some(section.to_string()),
+                // other sections of the config file (like sso-session) aren't profiles
+                (true, None) => None,
+                (false, _) => Some(section.to_string()),
+            };
+            if let Some(name) = &current {
+                profiles.entry(name.clone()).or_default();
+            }
+            continue;
+        }
+        if let (Some(name), Some((key, value))) = (&current, line.split_once('=')) {
+            profiles
+                .get_mut(name)
+                .unwrap()
+                .insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    Ok(profiles)
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for WebIdentityProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let token = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .map_err(|e| {
+                CredentialsError::new(format!(
+                    "failed to read web identity token from {}: {}",
+                    self.token_file.display(),
+                    e
+                ))
+            })?;
+
+        // the call is authenticated by the token, so it isn't signed with real credentials
+        let sts = StsClient::new_with(
+            HttpClient::new().map_err(|e| {
+                CredentialsError::new(format!("failed to create STS client: {}", e))
+            })?,
+            StaticProvider::new_minimal(String::new(), String::new()),
+            self.sts_region.clone(),
+        );
+        let response = sts
+            .assume_role_with_web_identity(AssumeRoleWithWebIdentityRequest {
+                role_arn: self.role_arn.clone(),
+                role_session_name: self.session_name.clone(),
+                web_identity_token: token.trim().to_string(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| {
+                CredentialsError::new(format!(
+                    "failed to assume role {} with web identity: {}",
+                    self.role_arn, e
+                ))
+            })?;
+        let sts_credentials = response.credentials.ok_or_else(|| {
+            CredentialsError::new(format!(
+                "no credentials returned when assuming role {} with web identity",
+                self.role_arn
+            ))
+        })?;
+        AwsCredentials::new_for_credentials(sts_credentials).map_err(|e| {
+            CredentialsError::new(format!(
+                "invalid credentials returned when assuming role {}: {}",
+                self.role_arn, e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::CredentialProvider;
+    use rusoto_core::credential::{
+        AutoRefreshingProvider, CredentialsError, ProvideAwsCredentials,
+    };
+    use rusoto_core::Region;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{ArroyoCredentialProvider, ChainedRole, ProfileChainProvider, WebIdentityProvider};
+    use crate::StorageError;
+
+    const STS_RESPONSE: &str = r#"<AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithWebIdentityResult>
+    <SubjectFromWebIdentityToken>system:serviceaccount:arroyo:worker</SubjectFromWebIdentityToken>
+    <Credentials>
+      <SessionToken>assumed-session-token</SessionToken>
+      <SecretAccessKey>assumed-secret-key</SecretAccessKey>
+      <Expiration>2099-01-01T00:00:00Z</Expiration>
+      <AccessKeyId>ASIAASSUMED</AccessKeyId>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#;
+
+    /// Serves a single STS request, returning the request that was received
+    async fn mock_sts(listener: TcpListener) -> String {
+        respond(&listener, STS_RESPONSE).await
+    }
+
+    /// Accepts one request and responds with `response`, returning the request
+    async fn respond(listener: &TcpListener, response: &str) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let mut buf = [0; 4096];
+        // read until we have the headers and the full form body
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let content_length = headers
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if body.len() >= content_length {
+                    break;
+                }
+            }
+        }
+
+        socket
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/xml\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_web_identity_credentials() {
+        let token_file = "/tmp/arroyo-testing/web-identity-token";
+        tokio::fs::create_dir_all("/tmp/arroyo-testing")
+            .await
+            .unwrap();
+        tokio::fs::write(token_file, "fake-token\n").await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sts_endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let sts = tokio::spawn(mock_sts(listener));
+
+        let provider = ArroyoCredentialProvider {
+            provider: Box::new(
+                AutoRefreshingProvider::new(WebIdentityProvider::new(
+                    token_file,
+                    "arn:aws:iam::123456789012:role/arroyo",
+                    "test-session",
+                    Region::Custom {
+                        name: "us-east-1".to_string(),
+                        endpoint: sts_endpoint,
+                    },
+                ))
+                .unwrap(),
+            ),
+        };
+
+        let credential = provider.get_credential().await.unwrap();
+        assert_eq!(credential.key_id, "ASIAASSUMED");
+        assert_eq!(credential.secret_key, "assumed-secret-key");
+        assert_eq!(credential.token.as_deref(), Some("assumed-session-token"));
+
+        let request = sts.await.unwrap();
+        assert!(request.starts_with("POST / "));
+        assert!(request.contains("Action=AssumeRoleWithWebIdentity"));
+        assert!(request.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Farroyo"));
+        assert!(request.contains("WebIdentityToken=fake-token"));
+    }
+
+    #[test]
+    fn test_credentials_error_source() {
+        let err: StorageError = CredentialsError::new("profile 'missing' not found").into();
+        assert_eq!(
+            err.to_string(),
+            "failed to load credentials: profile 'missing' not found"
+        );
+
+        let source = std::error::Error::source(&err).expect("credentials error has a source");
+        let source = source
+            .downcast_ref::<CredentialsError>()
+            .expect("source is the underlying CredentialsError");
+        assert_eq!(source.message, "profile 'missing' not found");
+    }
+
+    fn assume_role_response(key_id: &str) -> String {
+        format!(
+            r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleResult>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::123456789012:assumed-role/role/session</Arn>
+      <AssumedRoleId>AROAEXAMPLE:session</AssumedRoleId>
+    </AssumedRoleUser>
+    <Credentials>
+      <AccessKeyId>{}</AccessKeyId>
+      <SecretAccessKey>{}-secret</SecretAccessKey>
+      <SessionToken>{}-token</SessionToken>
+      <Expiration>2099-01-01T00:00:00Z</Expiration>
+    </Credentials>
+  </AssumeRoleResult>
+</AssumeRoleResponse>"#,
+            key_id, key_id, key_id
+        )
+    }
+
+    #[tokio::test]
+    async fn test_profile_chain_credentials() {
+        let dir = format!("/tmp/arroyo-testing/profile-chain-{}", uuid::Uuid::new_v4());
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let config_file = format!("{}/config", dir);
+        let credentials_file = format!("{}/credentials", dir);
+        tokio::fs::write(
+            &config_file,
+            r#"[default]
+region = us-west-2
+
+[profile base]
+region = us-west-2
+
+[profile intermediate]
+role_arn = arn:aws:iam::123456789012:role/intermediate
+source_profile = base
+
+[profile deploy]
+role_arn = arn:aws:iam::210987654321:role/deploy
+source_profile = intermediate
+external_id = shared-secret
+role_session_name = arroyo-deploy
+"#,
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            &credentials_file,
+            "[base]\naws_access_key_id = AKIDBASE\naws_secret_access_key = base-secret\n",
+        )
+        .await
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let sts = tokio::spawn(async move {
+            let first = respond(&listener, &assume_role_response("ASIAFIRST")).await;
+            let second = respond(&listener, &assume_role_response("ASIASECOND")).await;
+            (first, second)
+        });
+
+        let provider = ProfileChainProvider::from_files(
+            "deploy",
+            config_file.as_ref(),
+            credentials_file.as_ref(),
+            Some(Region::Custom {
+                name: "us-east-1".to_string(),
+                endpoint,
+            }),
+        )
+        .unwrap()
+        .expect("deploy assumes a role");
+        assert_eq!(
+            provider.roles,
+            vec![
+                ChainedRole {
+                    role_arn: "arn:aws:iam::123456789012:role/intermediate".to_string(),
+                    external_id: None,
+                    session_name: provider.roles[0].session_name.clone(),
+                },
+                ChainedRole {
+                    role_arn: "arn:aws:iam::210987654321:role/deploy".to_string(),
+                    external_id: Some("shared-secret".to_string()),
+                    session_name: "arroyo-deploy".to_string(),
+                },
+            ]
+        );
+
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials.aws_access_key_id(), "ASIASECOND");
+        assert_eq!(credentials.aws_secret_access_key(), "ASIASECOND-secret");
+        assert_eq!(credentials.token().as_deref(), Some("ASIASECOND-token"));
+        assert!(credentials.expires_at().is_some());
+
+        // each role is assumed with the credentials from the previous link of the chain
+        let (first, second) = sts.await.unwrap();
+        assert!(first.contains("Action=AssumeRole"));
+        assert!(first.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fintermediate"));
+        assert!(first.contains("Credential=AKIDBASE/"));
+        assert!(second.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A210987654321%3Arole%2Fdeploy"));
+        assert!(second.contains("ExternalId=shared-secret"));
+        assert!(second.contains("Credential=ASIAFIRST/"));
+
+        // profiles with static credentials are left to the default chain
+        assert!(ProfileChainProvider::from_files(
+            "base",
+            config_file.as_ref(),
+            credentials_file.as_ref(),
+            None,
+        )
+        .unwrap()
+        .is_none());
+    }
+}
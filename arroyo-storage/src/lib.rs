@@ -1,26 +1,68 @@
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::{
-    collections::HashMap,
+    collections::{BinaryHeap, HashMap},
+    ops::Range,
+    pin::Pin,
     sync::{Arc, OnceLock},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
-use arroyo_types::{S3_ENDPOINT_ENV, S3_REGION_ENV};
+use arroyo_types::{
+    S3_ENDPOINT_ENV, S3_REGION_ENV, S3_SSE_ENV, STORAGE_CONNECT_TIMEOUT_ENV,
+    STORAGE_REQUEST_TIMEOUT_ENV,
+};
+use async_trait::async_trait;
 use aws::ArroyoCredentialProvider;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use bytes::Bytes;
-use object_store::gcp::GoogleCloudStorageBuilder;
+use chrono::{DateTime, Utc};
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, FuturesUnordered},
+    FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use md5::Md5;
+use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+use object_store::gcp::{GoogleCloudStorageBuilder, GoogleConfigKey};
 use object_store::path::Path;
-use object_store::{aws::AmazonS3Builder, local::LocalFileSystem, ObjectStore};
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder, AmazonS3ConfigKey},
+    local::LocalFileSystem,
+    signer::Signer,
+    ClientConfigKey, ClientOptions, GetOptions, GetResult, ListResult, MultipartId, ObjectMeta,
+    ObjectStore,
+};
 use regex::{Captures, Regex};
+use reqwest::Method;
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, S3Client, UploadPartRequest,
+    S3,
+};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
 
 mod aws;
 
+pub use aws::WebIdentityProvider;
+
 #[derive(Clone)]
 pub struct StorageProvider {
     config: BackendConfig,
     object_store: Arc<dyn ObjectStore>,
     canonical_url: String,
+    read_your_writes: Option<Duration>,
+    retry_classifier: Option<RetryClassifier>,
+    retry: Option<RetryConfig>,
+    delete_batch_size: Option<usize>,
+    // set for backends that can presign URLs
+    signer: Option<Arc<dyn Signer>>,
 }
 
 #[derive(Error, Debug)]
@@ -37,10 +79,874 @@ pub enum StorageError {
     #[error("object store error: {0:?}")]
     ObjectStore(#[from] object_store::Error),
 
-    #[error("failed to load credentials: {0}")]
-    CredentialsError(String),
+    #[error("failed to load credentials: {source}")]
+    CredentialsError {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("bucket {0} does not exist")]
+    BucketNotFound(String),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("invalid GCS predefined ACL '{0}'")]
+    InvalidPredefinedAcl(String),
+
+    #[error("failed to write {key} in batch: {source}")]
+    BatchPutFailed {
+        key: String,
+        source: Box<StorageError>,
+    },
+
+    #[error("presigned URLs can be valid for at most {max:?}, not {requested:?}")]
+    PresignedUrlExpiryTooLong { requested: Duration, max: Duration },
+
+    #[error("failed to delete {key} in batch: {source}")]
+    BatchDeleteFailed {
+        key: String,
+        source: Box<StorageError>,
+    },
+
+    #[error("precondition failed for {key}: {reason}")]
+    PreconditionFailed { key: String, reason: String },
+
+    #[error("gave up after {attempts} attempts: {source:?}")]
+    RetriesExhausted {
+        attempts: usize,
+        source: object_store::Error,
+    },
+}
+
+impl From<rusoto_core::credential::CredentialsError> for StorageError {
+    fn from(e: rusoto_core::credential::CredentialsError) -> Self {
+        StorageError::CredentialsError {
+            source: Box::new(e),
+        }
+    }
+}
+
+// SigV4 presigned URLs are valid for at most 7 days
+const MAX_PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// S3's limits on object tags
+const MAX_S3_OBJECT_TAGS: usize = 10;
+const MAX_S3_TAG_KEY_LENGTH: usize = 128;
+const MAX_S3_TAG_VALUE_LENGTH: usize = 256;
+
+/// The headers sent with every S3 request for the storage class and object tags; S3 ignores
+/// them on requests that don't create objects
+fn s3_default_headers(
+    options: &StorageOptions,
+) -> Result<reqwest::header::HeaderMap, StorageError> {
+    let mut headers = reqwest::header::HeaderMap::new();
+
+    if let Some(storage_class) = &options.s3_storage_class {
+        headers.insert(
+            "x-amz-storage-class",
+            reqwest::header::HeaderValue::from_str(storage_class).map_err(|_| {
+                StorageError::PathError(format!("invalid S3 storage class '{}'", storage_class))
+            })?,
+        );
+    }
+
+    if !options.s3_object_tags.is_empty() {
+        headers.insert(
+            "x-amz-tagging",
+            reqwest::header::HeaderValue::from_str(&s3_tagging(&options.s3_object_tags)?)
+                .expect("tags are percent-encoded"),
+        );
+    }
+
+    Ok(headers)
+}
+
+/// Builds the clients for an S3 bucket, returning the object store to use and the plain client
+/// for signing URLs. With encryption, the object store is an [`EncryptedWriteStore`], which
+/// makes multipart uploads and copies through the given rusoto client so that each request
+/// carries only the encryption headers S3 accepts on it.
+fn s3_stores(
+    builder: AmazonS3Builder,
+    options: &StorageOptions,
+    encryption: Option<(&S3Encryption, S3Client)>,
+) -> Result<(Arc<dyn ObjectStore>, Arc<AmazonS3>), StorageError> {
+    let build = |headers: reqwest::header::HeaderMap| -> Result<AmazonS3, StorageError> {
+        let mut builder = builder.clone();
+        if !headers.is_empty() {
+            builder =
+                builder.with_client_options(ClientOptions::new().with_default_headers(headers));
+        }
+
+        // applied after the client options above, which would otherwise replace them
+        for (key, value) in options.client_timeouts()? {
+            builder = builder.with_config(AmazonS3ConfigKey::Client(key), value);
+        }
+        Ok(builder.with_retry(options.retry_config()).build()?)
+    };
+
+    let headers = s3_default_headers(options)?;
+    let s3 = Arc::new(build(headers.clone())?);
+    let object_store: Arc<dyn ObjectStore> = match encryption {
+        None => s3.clone(),
+        Some((encryption, uploads)) => {
+            let mut write_headers = headers;
+            write_headers.extend(encryption.headers()?);
+            let writes = Arc::new(build(write_headers)?);
+            let reads: Arc<dyn ObjectStore> = if encryption.applies_to_reads() {
+                writes.clone()
+            } else {
+                s3.clone()
+            };
+            let uploads = S3Uploads {
+                client: uploads,
+                bucket: builder
+                    .get_config_value(&AmazonS3ConfigKey::Bucket)
+                    .unwrap_or_default(),
+                encryption: encryption.clone(),
+                storage_class: options.s3_storage_class.clone(),
+                tagging: if options.s3_object_tags.is_empty() {
+                    None
+                } else {
+                    Some(s3_tagging(&options.s3_object_tags)?)
+                },
+            };
+            Arc::new(EncryptedWriteStore::new(reads, writes, uploads))
+        }
+    };
+
+    Ok((object_store, s3))
+}
+
+/// The rusoto client for S3 requests that need headers object_store can't send: it only has
+/// default headers, which go on every request, but S3 rejects the SSE-S3 and SSE-KMS headers
+/// on `UploadPart` and `CompleteMultipartUpload`, and copies of SSE-C objects need the key
+/// for the source as well as the destination. Requests are made path-style to the bucket's
+/// regional (or custom) endpoint.
+fn s3_uploads_client(
+    config: &S3Config,
+    credentials: Arc<ArroyoCredentialProvider>,
+) -> Result<S3Client, StorageError> {
+    let name = config
+        .region
+        .clone()
+        .unwrap_or_else(|| "us-east-1".to_string());
+    let endpoint = config
+        .endpoint
+        .clone()
+        .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", name));
+    let dispatcher = HttpClient::new().map_err(|e| {
+        StorageError::PathError(format!("failed to create S3 client for uploads: {}", e))
+    })?;
+    Ok(S3Client::new_with(
+        dispatcher,
+        credentials,
+        Region::Custom { name, endpoint },
+    ))
+}
+
+/// The part size of multipart uploads made by [`EncryptedWriteStore`], as in object_store
+const ENCRYPTED_PART_SIZE: usize = 10 * 1024 * 1024;
+
+/// How many parts of an encrypted multipart upload are uploaded at once
+const ENCRYPTED_PART_CONCURRENCY: usize = 8;
+
+fn rusoto_error<E: std::error::Error + Send + Sync + 'static>(
+    e: RusotoError<E>,
+) -> object_store::Error {
+    object_store::Error::Generic {
+        store: "S3",
+        source: Box::new(e),
+    }
+}
+
+/// Makes the writes of [`EncryptedWriteStore`] that object_store can't, with the encryption
+/// settings on exactly the requests that take them
+struct S3Uploads {
+    client: S3Client,
+    bucket: String,
+    encryption: S3Encryption,
+    storage_class: Option<String>,
+    tagging: Option<String>,
+}
+
+impl std::fmt::Debug for S3Uploads {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Uploads")
+            .field("bucket", &self.bucket)
+            .field("encryption", &self.encryption)
+            .finish()
+    }
+}
+
+impl S3Uploads {
+    async fn create(&self, key: &str) -> object_store::Result<String> {
+        let (server_side_encryption, ssekms_key_id) = self.encryption.managed();
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.encryption.customer_key();
+        let output = self
+            .client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                server_side_encryption,
+                ssekms_key_id,
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                storage_class: self.storage_class.clone(),
+                tagging: self.tagging.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(rusoto_error)?;
+        output
+            .upload_id
+            .ok_or_else(|| object_store::Error::Generic {
+                store: "S3",
+                source: format!("no upload id in response to creating upload for {}", key).into(),
+            })
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        data: Vec<u8>,
+    ) -> object_store::Result<CompletedPart> {
+        // unlike the SSE-S3 and SSE-KMS settings, the SSE-C key is needed for every part
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.encryption.customer_key();
+        let output = self
+            .client
+            .upload_part(UploadPartRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                part_number,
+                content_length: Some(data.len() as i64),
+                body: Some(data.into()),
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                ..Default::default()
+            })
+            .await
+            .map_err(rusoto_error)?;
+        Ok(CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> object_store::Result<()> {
+        self.client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                ..Default::default()
+            })
+            .await
+            .map_err(rusoto_error)?;
+        Ok(())
+    }
+
+    async fn abort(&self, key: &str, upload_id: &str) -> object_store::Result<()> {
+        self.client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_string(),
+                upload_id: upload_id.to_string(),
+                ..Default::default()
+            })
+            .await
+            .map_err(rusoto_error)?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> object_store::Result<()> {
+        let (server_side_encryption, ssekms_key_id) = self.encryption.managed();
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.encryption.customer_key();
+        self.client
+            .copy_object(CopyObjectRequest {
+                bucket: self.bucket.clone(),
+                key: to.to_string(),
+                copy_source: format!("{}/{}", self.bucket, uri_encode(from, false)),
+                server_side_encryption,
+                ssekms_key_id,
+                // an SSE-C source can only be read with its key
+                copy_source_sse_customer_algorithm: sse_customer_algorithm.clone(),
+                copy_source_sse_customer_key: sse_customer_key.clone(),
+                copy_source_sse_customer_key_md5: sse_customer_key_md5.clone(),
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                storage_class: self.storage_class.clone(),
+                ..Default::default()
+            })
+            .await
+            .map_err(rusoto_error)?;
+        Ok(())
+    }
+}
+
+type PartFuture = BoxFuture<'static, std::io::Result<CompletedPart>>;
+
+/// The writer for a multipart upload made through [`S3Uploads`], which uploads each
+/// [`ENCRYPTED_PART_SIZE`] bytes as a part and completes the upload on shutdown
+struct EncryptedUpload {
+    uploads: Arc<S3Uploads>,
+    key: String,
+    upload_id: String,
+    buffer: Vec<u8>,
+    next_part: i64,
+    in_flight: FuturesUnordered<PartFuture>,
+    parts: Vec<CompletedPart>,
+    completing: Option<BoxFuture<'static, std::io::Result<()>>>,
+}
+
+impl EncryptedUpload {
+    fn upload_buffer(&mut self) {
+        let data = std::mem::take(&mut self.buffer);
+        let part_number = self.next_part;
+        self.next_part += 1;
+
+        let uploads = self.uploads.clone();
+        let key = self.key.clone();
+        let upload_id = self.upload_id.clone();
+        self.in_flight.push(Box::pin(async move {
+            uploads
+                .upload_part(&key, &upload_id, part_number, data)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        }));
+    }
+
+    /// Waits until at most `max` parts are still being uploaded
+    fn poll_parts(&mut self, cx: &mut Context<'_>, max: usize) -> Poll<std::io::Result<()>> {
+        while self.in_flight.len() > max {
+            match ready!(self.in_flight.poll_next_unpin(cx)) {
+                Some(part) => self.parts.push(part?),
+                None => break,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for EncryptedUpload {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_parts(cx, ENCRYPTED_PART_CONCURRENCY - 1))?;
+        let n = buf.len().min(ENCRYPTED_PART_SIZE - this.buffer.len());
+        this.buffer.extend_from_slice(&buf[..n]);
+        if this.buffer.len() == ENCRYPTED_PART_SIZE {
+            this.upload_buffer();
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // the buffer is only uploaded once it's a full part, as S3 requires of all but the last
+        self.get_mut().poll_parts(cx, 0)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.completing.is_none() {
+            // S3 needs at least one part, even if it's empty
+            if !this.buffer.is_empty() || this.next_part == 1 {
+                this.upload_buffer();
+            }
+            ready!(this.poll_parts(cx, 0))?;
+
+            let mut parts = std::mem::take(&mut this.parts);
+            parts.sort_by_key(|part| part.part_number);
+            let uploads = this.uploads.clone();
+            let key = this.key.clone();
+            let upload_id = this.upload_id.clone();
+            this.completing = Some(Box::pin(async move {
+                uploads
+                    .complete(&key, &upload_id, parts)
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            }));
+        }
+        this.completing
+            .as_mut()
+            .expect("completion was just started")
+            .poll_unpin(cx)
+    }
+}
+
+/// Sends writes (which S3 encrypts based on their headers) and reads to different clients, for
+/// encryption headers that S3 only accepts on some requests. Single-request writes go
+/// through `writes`, which sends the encryption headers; multipart uploads and copies go
+/// through `uploads`; and everything else through `reads`, which sends the SSE-C key but
+/// not the SSE-S3 or SSE-KMS headers, which S3 rejects on reads.
+#[derive(Debug)]
+struct EncryptedWriteStore {
+    reads: Arc<dyn ObjectStore>,
+    writes: Arc<dyn ObjectStore>,
+    uploads: Arc<S3Uploads>,
+}
+
+impl EncryptedWriteStore {
+    fn new(reads: Arc<dyn ObjectStore>, writes: Arc<dyn ObjectStore>, uploads: S3Uploads) -> Self {
+        Self {
+            reads,
+            writes,
+            uploads: Arc::new(uploads),
+        }
+    }
+}
+
+impl std::fmt::Display for EncryptedWriteStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptedWriteStore({})", self.writes)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for EncryptedWriteStore {
+    async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
+        self.writes.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &Path,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        let key = location.as_ref().to_string();
+        let upload_id = self.uploads.create(&key).await?;
+        let writer = EncryptedUpload {
+            uploads: self.uploads.clone(),
+            key,
+            upload_id: upload_id.clone(),
+            buffer: Vec::with_capacity(ENCRYPTED_PART_SIZE),
+            next_part: 1,
+            in_flight: FuturesUnordered::new(),
+            parts: vec![],
+            completing: None,
+        };
+        Ok((upload_id, Box::new(writer)))
+    }
+
+    async fn abort_multipart(
+        &self,
+        location: &Path,
+        multipart_id: &MultipartId,
+    ) -> object_store::Result<()> {
+        self.uploads.abort(location.as_ref(), multipart_id).await
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        self.reads.get_opts(location, options).await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<usize>) -> object_store::Result<Bytes> {
+        self.reads.get_range(location, range).await
+    }
+
+    async fn get_ranges(
+        &self,
+        location: &Path,
+        ranges: &[Range<usize>],
+    ) -> object_store::Result<Vec<Bytes>> {
+        self.reads.get_ranges(location, ranges).await
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        self.reads.head(location).await
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.reads.delete(location).await
+    }
+
+    fn delete_stream<'a>(
+        &'a self,
+        locations: BoxStream<'a, object_store::Result<Path>>,
+    ) -> BoxStream<'a, object_store::Result<Path>> {
+        self.reads.delete_stream(locations)
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&Path>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        self.reads.list(prefix).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.reads.list_with_delimiter(prefix).await
+    }
+
+    // copies create objects, so they need the encryption settings too
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.uploads.copy(from.as_ref(), to.as_ref()).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        // S3 can't copy conditionally, so this is an error either way
+        self.writes.copy_if_not_exists(from, to).await
+    }
+}
+
+/// Percent-encodes `s` as S3 expects in URIs, leaving slashes unless `encode_slash`
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Encodes tags as the query string expected by S3's `x-amz-tagging` header
+fn s3_tagging(tags: &[(String, String)]) -> Result<String, StorageError> {
+    if tags.len() > MAX_S3_OBJECT_TAGS {
+        return Err(StorageError::PathError(format!(
+            "at most {} S3 object tags may be set, but {} were configured",
+            MAX_S3_OBJECT_TAGS,
+            tags.len()
+        )));
+    }
+
+    tags.iter()
+        .map(|(key, value)| {
+            if key.is_empty() || key.len() > MAX_S3_TAG_KEY_LENGTH {
+                return Err(StorageError::PathError(format!(
+                    "invalid S3 object tag key '{}'; keys must be 1 to {} characters",
+                    key, MAX_S3_TAG_KEY_LENGTH
+                )));
+            }
+            if value.len() > MAX_S3_TAG_VALUE_LENGTH {
+                return Err(StorageError::PathError(format!(
+                    "invalid value for S3 object tag '{}'; values may be at most {} characters",
+                    key, MAX_S3_TAG_VALUE_LENGTH
+                )));
+            }
+            Ok(format!(
+                "{}={}",
+                uri_encode(key, true),
+                uri_encode(value, true)
+            ))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|tags| tags.join("&"))
+}
+
+/// Decides whether an error from the object store should be retried
+pub type RetryClassifier = Arc<dyn Fn(&object_store::Error) -> bool + Send + Sync>;
+
+/// How [`StorageProvider`] retries transient errors (timeouts, connection errors, 429s and 5xx
+/// responses) that are still failing after object_store's own retries. Each retry waits
+/// for a random time up to a backoff that starts at `initial_backoff` and doubles with each
+/// attempt, up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The time to wait before retry number `retry` (starting from 0), with full jitter
+    fn backoff(&self, retry: usize) -> Duration {
+        let max = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.min(31) as u32))
+            .min(self.max_backoff);
+        max.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Whether an error from object_store is worth retrying: timeouts, connection errors,
+/// throttling, and server errors, identified from the HTTP or IO errors in its chain of
+/// causes. Missing objects, auth failures, and other client errors aren't.
+fn is_transient(e: &object_store::Error) -> bool {
+    let object_store::Error::Generic { source, .. } = e else {
+        return false;
+    };
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(source.as_ref());
+    while let Some(e) = cause {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() || e.is_connect() {
+                return true;
+            }
+            if let Some(status) = e.status() {
+                return status.is_server_error()
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+            }
+        }
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        cause = e.source();
+    }
+    false
+}
+
+/// Options controlling how a [`StorageProvider`] is constructed.
+#[derive(Clone, Default)]
+pub struct StorageOptions {
+    /// Probe the bucket when constructing the provider, failing with
+    /// [`StorageError::BucketNotFound`] if it does not exist. For local storage, the
+    /// directory must already exist and will not be created.
+    pub validate_on_construct: bool,
+    /// Permission bits to set on the directory created for local storage, e.g. `0o700`.
+    /// Ignored on non-Unix platforms.
+    pub dir_mode: Option<u32>,
+    /// Predefined ACL (e.g. `publicRead`) applied to objects written to GCS. Must be one of
+    /// the values accepted by GCS's `predefinedAcl` parameter.
+    pub gcs_predefined_acl: Option<String>,
+    /// Timeout for each request to S3, GCS, or Azure. Defaults to `$STORAGE_REQUEST_TIMEOUT`
+    /// seconds, or 30 seconds if that is not set.
+    pub request_timeout: Option<Duration>,
+    /// Timeout for connecting to S3, GCS, or Azure. Defaults to `$STORAGE_CONNECT_TIMEOUT`
+    /// seconds, or 5 seconds if that is not set.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum number of times a failed request to S3, GCS, or Azure is retried, defaulting to
+    /// object_store's default of 10.
+    pub max_retries: Option<usize>,
+    /// Maximum total time spent retrying a request to S3, GCS, or Azure, defaulting to
+    /// object_store's default of 3 minutes.
+    pub retry_timeout: Option<Duration>,
+    /// Whether to address S3 buckets path-style (`https://endpoint/bucket/key`) rather than
+    /// virtual-hosted style (`https://bucket.endpoint/key`). By default, path-style is used
+    /// except with transfer acceleration, which only supports virtual-hosted style.
+    pub s3_path_style: Option<bool>,
+    /// Storage class (e.g. `STANDARD_IA` or `INTELLIGENT_TIERING`) for objects written to S3.
+    /// Defaults to the bucket's default storage class.
+    pub s3_storage_class: Option<String>,
+    /// Tags (e.g. `env=prod`) applied to objects written to S3, for lifecycle policies and
+    /// cost allocation. S3 allows at most 10 tags per object. Ignored for other backends.
+    pub s3_object_tags: Vec<(String, String)>,
+    /// For eventually-consistent stores (like older MinIO or Ceph releases), retry gets that
+    /// fail with `NotFound`, backing off as set by `retry`, for up to this long, so that an
+    /// object can be read right after it's written. Off by default.
+    pub read_your_writes: Option<Duration>,
+    /// Decides which errors that object_store returns (after its own retries) are retried,
+    /// e.g. a store's particular throttling responses, in place of the default check for
+    /// transient errors. Retries back off as set by `retry`, or its defaults if that isn't
+    /// set. `NotFound` errors within the `read_your_writes` window are retried either way.
+    pub retry_classifier: Option<RetryClassifier>,
+    /// Retries transient errors from reads and writes with jittered exponential backoff, failing
+    /// with [`StorageError::RetriesExhausted`] once `max_retries` have been made. Off by
+    /// default. Also sets the backoff for `retry_classifier` and `read_your_writes` retries.
+    pub retry: Option<RetryConfig>,
+    /// Maximum number of keys [`StorageProvider::delete_many`] passes to each of the object
+    /// store's bulk deletes. By default all of the keys go to a single bulk delete, which
+    /// S3 splits into requests of 1,000 keys.
+    pub delete_batch_size: Option<usize>,
+    /// Server-side encryption for objects written to S3, overriding `$ARROYO_S3_SSE`. Ignored
+    /// for other backends.
+    pub s3_encryption: Option<S3Encryption>,
+}
+
+impl std::fmt::Debug for StorageOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageOptions")
+            .field("validate_on_construct", &self.validate_on_construct)
+            .field("dir_mode", &self.dir_mode)
+            .field("gcs_predefined_acl", &self.gcs_predefined_acl)
+            .field("request_timeout", &self.request_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("max_retries", &self.max_retries)
+            .field("retry_timeout", &self.retry_timeout)
+            .field("s3_path_style", &self.s3_path_style)
+            .field("s3_storage_class", &self.s3_storage_class)
+            .field("s3_object_tags", &self.s3_object_tags)
+            .field("read_your_writes", &self.read_your_writes)
+            .field(
+                "retry_classifier",
+                &self.retry_classifier.as_ref().map(|_| "<fn>"),
+            )
+            .field("retry", &self.retry)
+            .field("delete_batch_size", &self.delete_batch_size)
+            .field("s3_encryption", &self.s3_encryption)
+            .finish()
+    }
+}
+
+impl StorageOptions {
+    pub fn with_dir_mode(mut self, mode: u32) -> Self {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    pub fn with_gcs_predefined_acl(mut self, acl: impl Into<String>) -> Self {
+        self.gcs_predefined_acl = Some(acl.into());
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    pub fn with_retry_timeout(mut self, timeout: Duration) -> Self {
+        self.retry_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_s3_path_style(mut self, path_style: bool) -> Self {
+        self.s3_path_style = Some(path_style);
+        self
+    }
+
+    pub fn with_s3_storage_class(mut self, storage_class: impl Into<String>) -> Self {
+        self.s3_storage_class = Some(storage_class.into());
+        self
+    }
+
+    pub fn with_s3_encryption(mut self, encryption: S3Encryption) -> Self {
+        self.s3_encryption = Some(encryption);
+        self
+    }
+
+    pub fn with_s3_object_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.s3_object_tags.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_s3_object_tags(mut self, tags: Vec<(String, String)>) -> Self {
+        self.s3_object_tags.extend(tags);
+        self
+    }
+
+    pub fn with_read_your_writes(mut self, window: Duration) -> Self {
+        self.read_your_writes = Some(window);
+        self
+    }
+
+    pub fn with_retry_classifier(
+        mut self,
+        classifier: impl Fn(&object_store::Error) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.retry_classifier = Some(Arc::new(classifier));
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn with_delete_batch_size(mut self, batch_size: usize) -> Self {
+        self.delete_batch_size = Some(batch_size.max(1));
+        self
+    }
+
+    /// The retry config for the object store builders, based on object_store's defaults
+    fn retry_config(&self) -> object_store::RetryConfig {
+        let mut retry_config = object_store::RetryConfig::default();
+        if let Some(max_retries) = self.max_retries {
+            retry_config.max_retries = max_retries;
+        }
+        if let Some(retry_timeout) = self.retry_timeout {
+            retry_config.retry_timeout = retry_timeout;
+        }
+        retry_config
+    }
+
+    /// The client timeouts to configure on the object store builders, as config values
+    fn client_timeouts(&self) -> Result<[(ClientConfigKey, String); 2], StorageError> {
+        fn resolve(
+            timeout: Option<Duration>,
+            env: &str,
+            default: Duration,
+        ) -> Result<String, StorageError> {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => match std::env::var(env) {
+                    Ok(seconds) => Duration::from_secs(seconds.parse().map_err(|_| {
+                        StorageError::PathError(format!(
+                            "invalid value for {}: '{}' is not a number of seconds",
+                            env, seconds
+                        ))
+                    })?),
+                    Err(_) => default,
+                },
+            };
+            Ok(format!("{}ms", timeout.as_millis()))
+        }
+
+        Ok([
+            (
+                ClientConfigKey::Timeout,
+                resolve(
+                    self.request_timeout,
+                    STORAGE_REQUEST_TIMEOUT_ENV,
+                    DEFAULT_REQUEST_TIMEOUT,
+                )?,
+            ),
+            (
+                ClientConfigKey::ConnectTimeout,
+                resolve(
+                    self.connect_timeout,
+                    STORAGE_CONNECT_TIMEOUT_ENV,
+                    DEFAULT_CONNECT_TIMEOUT,
+                )?,
+            ),
+        ])
+    }
 }
 
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 // https://s3.us-west-2.amazonaws.com/DOC-EXAMPLE-BUCKET1/puppy.jpg
 const S3_PATH: &str =
     r"^https://s3\.(?P<region>[\w\-]+)\.amazonaws\.com/(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
@@ -49,8 +955,16 @@ const S3_VIRTUAL: &str =
     r"^https://(?P<bucket>[a-z0-9\-\.]+)\.s3\.(?P<region>[\w\-]+)\.amazonaws\.com(/(?P<key>.+))?$";
 // S3://mybucket/puppy.jpg
 const S3_URL: &str = r"^[sS]3[aA]?://(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
-// unofficial, but convenient -- s3::https://my-endpoint.com:1234/mybucket/puppy.jpg
-const S3_ENDPOINT_URL: &str = r"^[sS]3[aA]?::(?<protocol>https?)://(?P<endpoint>[^:/]+):(?<port>\d+)/(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
+// https://DOC-EXAMPLE-BUCKET1.s3-accelerate.amazonaws.com/puppy.png
+const S3_ACCELERATE: &str = r"^https://(?P<bucket>[a-z0-9\-\.]+)\.(?P<accelerate>s3-accelerate)\.amazonaws\.com(/(?P<key>.+))?$";
+// unofficial, but convenient -- s3::https://my-endpoint.com:1234/mybucket/puppy.jpg; the port
+// may be omitted, e.g. s3::http://minio.internal/mybucket/puppy.jpg
+const S3_ENDPOINT_URL: &str = r"^[sS]3[aA]?::(?<protocol>https?)://(?P<endpoint>[^:/]+)(:(?<port>\d+))?/(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
+// Cloudflare R2's S3-compatible API -- https://ACCOUNT_ID.r2.cloudflarestorage.com/BUCKET/KEY
+const R2_HTTPS: &str = r"^https://(?P<account>[a-zA-Z0-9]+)\.r2\.cloudflarestorage\.com/(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
+// r2://ACCOUNT_ID/BUCKET/KEY
+const R2_URL: &str =
+    r"^[rR]2://(?P<account>[a-zA-Z0-9]+)/(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
 
 // file:///my/path/directory
 const FILE_URI: &str = r"^file://(?P<path>.*)$";
@@ -67,10 +981,38 @@ const GCS_PATH: &str =
     r"^https://storage\.googleapis\.com/(?P<bucket>[a-z\d\-_\.]+)(/(?P<key>.+))?$";
 const GCS_URL: &str = r"^[gG][sS]://(?P<bucket>[a-z0-9\-\.]+)(/(?P<key>.+))?$";
 
+// https://ACCOUNT.blob.core.windows.net/CONTAINER/BLOB_NAME
+const AZURE_HTTPS: &str = r"^https://(?P<account>[a-z0-9]+)\.blob\.core\.windows\.net/(?P<container>[a-z0-9\-]+)(/(?P<key>.+))?$";
+// az://CONTAINER/BLOB_NAME, with the account from $AZURE_STORAGE_ACCOUNT
+const AZURE_URL: &str = r"^[aA][zZ]://(?P<container>[a-z0-9\-]+)(/(?P<key>.+))?$";
+
+const AZURE_STORAGE_ACCOUNT_ENV: &str = "AZURE_STORAGE_ACCOUNT";
+const AZURE_STORAGE_ACCESS_KEY_ENV: &str = "AZURE_STORAGE_ACCESS_KEY";
+
+/// Whether a local path starts with a Windows drive letter, like `/C:/data` (as parsed from
+/// `file:///C:/data`) or `C:/data`
+fn is_windows_drive_path(path: &str) -> bool {
+    let path = path.strip_prefix('/').unwrap_or(path).as_bytes();
+    path.len() >= 3
+        && path[0].is_ascii_alphabetic()
+        && path[1] == b':'
+        && (path[2] == b'/' || path[2] == b'\\')
+}
+
+/// The `file://` URL for a local path, which needs an extra slash for Windows drive paths
+fn local_url(path: &str) -> String {
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        format!("file:///{}", path)
+    }
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Copy)]
 enum Backend {
     S3,
     GCS,
+    Azure,
     Local,
 }
 
@@ -84,7 +1026,10 @@ fn matchers() -> &'static HashMap<Backend, Vec<Regex>> {
             vec![
                 Regex::new(S3_PATH).unwrap(),
                 Regex::new(S3_VIRTUAL).unwrap(),
+                Regex::new(S3_ACCELERATE).unwrap(),
                 Regex::new(S3_ENDPOINT_URL).unwrap(),
+                Regex::new(R2_HTTPS).unwrap(),
+                Regex::new(R2_URL).unwrap(),
                 Regex::new(S3_URL).unwrap(),
             ],
         );
@@ -98,6 +1043,14 @@ fn matchers() -> &'static HashMap<Backend, Vec<Regex>> {
             ],
         );
 
+        m.insert(
+            Backend::Azure,
+            vec![
+                Regex::new(AZURE_HTTPS).unwrap(),
+                Regex::new(AZURE_URL).unwrap(),
+            ],
+        );
+
         m.insert(
             Backend::Local,
             vec![
@@ -117,43 +1070,236 @@ pub struct S3Config {
     region: Option<String>,
     bucket: String,
     key: Option<String>,
+    accelerate: bool,
+    encryption: Option<S3Encryption>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct GCSConfig {
-    bucket: String,
-    key: Option<String>,
+impl S3Config {
+    /// The S3 Transfer Acceleration endpoint for this bucket, which must be addressed
+    /// virtual-hosted style
+    pub fn accelerate_endpoint(&self) -> String {
+        format!("https://{}.s3-accelerate.amazonaws.com", self.bucket)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct LocalConfig {
-    path: String,
-    key: Option<String>,
+/// Server-side encryption for objects written to S3. Set with
+/// [`StorageOptions::with_s3_encryption`] or `$ARROYO_S3_SSE`, which takes `sse-s3`,
+/// `sse-kms:{key_id}`, or `sse-c:{base64_key}`.
+#[derive(Clone, PartialEq, Eq)]
+pub enum S3Encryption {
+    /// SSE-S3, with keys managed by S3
+    Sse,
+    /// SSE-KMS, with the given KMS key ID or ARN
+    SseKms { key_id: String },
+    /// SSE-C, with a base64-encoded 256-bit key that must also be sent to read the objects
+    SseC { key: String },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum BackendConfig {
-    S3(S3Config),
-    GCS(GCSConfig),
-    Local(LocalConfig),
+impl std::fmt::Debug for S3Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sse => write!(f, "Sse"),
+            Self::SseKms { key_id } => f.debug_struct("SseKms").field("key_id", key_id).finish(),
+            Self::SseC { .. } => f.debug_struct("SseC").field("key", &"<redacted>").finish(),
+        }
+    }
 }
 
-impl BackendConfig {
-    pub fn parse_url(url: &str, with_key: bool) -> Result<Self, StorageError> {
-        for (k, v) in matchers() {
-            if let Some(matches) = v.iter().filter_map(|r| r.captures(url)).next() {
-                return match k {
-                    Backend::S3 => Self::parse_s3(matches),
-                    Backend::GCS => Self::parse_gcs(matches),
-                    Backend::Local => Self::parse_local(matches, with_key),
-                };
-            }
-        }
+impl FromStr for S3Encryption {
+    type Err = StorageError;
 
-        return Err(StorageError::InvalidUrl);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "sse-s3" => Ok(Self::Sse),
+            Some(("sse-kms", key_id)) if !key_id.is_empty() => Ok(Self::SseKms {
+                key_id: key_id.to_string(),
+            }),
+            Some(("sse-c", key)) if !key.is_empty() => Ok(Self::SseC {
+                key: key.to_string(),
+            }),
+            _ => Err(StorageError::PathError(format!(
+                "invalid S3 encryption '{}'; expected sse-s3, sse-kms:{{key_id}}, or sse-c:{{base64_key}}",
+                s
+            ))),
+        }
+    }
+}
+
+impl S3Encryption {
+    /// The headers that request this encryption when an object is written
+    pub fn headers(&self) -> Result<reqwest::header::HeaderMap, StorageError> {
+        use reqwest::header::HeaderValue;
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        match self {
+            Self::Sse => {
+                headers.insert(
+                    "x-amz-server-side-encryption",
+                    HeaderValue::from_static("AES256"),
+                );
+            }
+            Self::SseKms { key_id } => {
+                headers.insert(
+                    "x-amz-server-side-encryption",
+                    HeaderValue::from_static("aws:kms"),
+                );
+                headers.insert(
+                    "x-amz-server-side-encryption-aws-kms-key-id",
+                    HeaderValue::from_str(key_id).map_err(|_| {
+                        StorageError::PathError(format!("invalid KMS key id '{}'", key_id))
+                    })?,
+                );
+            }
+            Self::SseC { key } => {
+                let invalid_key = || {
+                    StorageError::PathError(
+                        "SSE-C keys must be 256 bits, base64-encoded".to_string(),
+                    )
+                };
+                let decoded = BASE64.decode(key).map_err(|_| invalid_key())?;
+                if decoded.len() != 32 {
+                    return Err(invalid_key());
+                }
+                headers.insert(
+                    "x-amz-server-side-encryption-customer-algorithm",
+                    HeaderValue::from_static("AES256"),
+                );
+                headers.insert(
+                    "x-amz-server-side-encryption-customer-key",
+                    HeaderValue::from_str(key).map_err(|_| invalid_key())?,
+                );
+                headers.insert(
+                    "x-amz-server-side-encryption-customer-key-md5",
+                    HeaderValue::from_str(&BASE64.encode(Md5::digest(&decoded)))
+                        .expect("base64 is a valid header value"),
+                );
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Whether the headers must also be sent when reading objects. S3 rejects reads that
+    /// carry the SSE-S3 and SSE-KMS headers, but requires the key for SSE-C objects.
+    fn applies_to_reads(&self) -> bool {
+        matches!(self, Self::SseC { .. })
+    }
+
+    /// The `x-amz-server-side-encryption` and KMS key ID settings, which S3 takes only on
+    /// requests that create objects
+    fn managed(&self) -> (Option<String>, Option<String>) {
+        match self {
+            Self::Sse => (Some("AES256".to_string()), None),
+            Self::SseKms { key_id } => (Some("aws:kms".to_string()), Some(key_id.clone())),
+            Self::SseC { .. } => (None, None),
+        }
+    }
+
+    /// The SSE-C algorithm, key, and key MD5, which S3 needs on every request for the
+    /// object's data. The key is validated by [`S3Encryption::headers`] when the store is
+    /// built.
+    fn customer_key(&self) -> (Option<String>, Option<String>, Option<String>) {
+        match self {
+            Self::SseC { key } => {
+                let md5 = BASE64
+                    .decode(key)
+                    .map(|decoded| BASE64.encode(Md5::digest(decoded)))
+                    .ok();
+                (Some("AES256".to_string()), Some(key.clone()), md5)
+            }
+            Self::Sse | Self::SseKms { .. } => (None, None, None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GCSConfig {
+    bucket: String,
+    key: Option<String>,
+    predefined_acl: Option<String>,
+}
+
+impl GCSConfig {
+    /// Maps a GCS predefined ACL name to the value of the `x-goog-acl` header that applies it,
+    /// which is how the ACL is sent on uploads.
+    pub fn acl_header_value(predefined_acl: &str) -> Result<&'static str, StorageError> {
+        Ok(match predefined_acl {
+            "authenticatedRead" => "authenticated-read",
+            "bucketOwnerFullControl" => "bucket-owner-full-control",
+            "bucketOwnerRead" => "bucket-owner-read",
+            "private" => "private",
+            "projectPrivate" => "project-private",
+            "publicRead" => "public-read",
+            _ => {
+                return Err(StorageError::InvalidPredefinedAcl(
+                    predefined_acl.to_string(),
+                ))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AzureConfig {
+    /// The storage account, from the URL or `$AZURE_STORAGE_ACCOUNT`
+    account: Option<String>,
+    container: String,
+    key: Option<String>,
+}
+
+impl AzureConfig {
+    /// The blob endpoint for this container, if the account is known
+    fn endpoint(&self) -> Option<String> {
+        self.account.as_ref().map(|account| {
+            format!(
+                "https://{}.blob.core.windows.net/{}",
+                account, self.container
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalConfig {
+    path: String,
+    key: Option<String>,
+    /// Whether to create the directory if it doesn't exist; set to false with `?create=false`
+    create: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendConfig {
+    S3(S3Config),
+    GCS(GCSConfig),
+    Azure(AzureConfig),
+    Local(LocalConfig),
+}
+
+impl BackendConfig {
+    pub fn parse_url(url: &str, with_key: bool) -> Result<Self, StorageError> {
+        for (k, v) in matchers() {
+            // S3 and local URLs may carry options in the query string, e.g. `?accelerate=true`
+            let (target, query) = match k {
+                Backend::S3 | Backend::Local => url
+                    .split_once('?')
+                    .map(|(url, query)| (url, Some(query)))
+                    .unwrap_or((url, None)),
+                _ => (url, None),
+            };
+
+            if let Some(matches) = v.iter().filter_map(|r| r.captures(target)).next() {
+                return match k {
+                    Backend::S3 => Self::parse_s3(matches, query),
+                    Backend::GCS => Self::parse_gcs(matches),
+                    Backend::Azure => Self::parse_azure(matches),
+                    Backend::Local => Self::parse_local(matches, query, with_key),
+                };
+            }
+        }
+
+        return Err(StorageError::InvalidUrl);
     }
 
-    fn parse_s3(matches: Captures) -> Result<Self, StorageError> {
+    fn parse_s3(matches: Captures, query: Option<&str>) -> Result<Self, StorageError> {
         // fill in env vars
         let bucket = matches
             .name("bucket")
@@ -165,6 +1311,8 @@ impl BackendConfig {
             std::env::var("AWS_DEFAULT_REGION").ok(),
             std::env::var(S3_REGION_ENV).ok(),
             matches.name("region").map(|m| m.as_str().to_string()),
+            // R2 only accepts "auto" (or its aliases) as the region
+            matches.name("account").map(|_| "auto".to_string()),
         ]);
 
         let endpoint = last([
@@ -173,32 +1321,68 @@ impl BackendConfig {
             matches
                 .name("endpoint")
                 .map(|endpoint| -> Result<String, StorageError> {
+                    let protocol = if let Some(protocol) = matches.name("protocol") {
+                        protocol.as_str().to_string()
+                    } else {
+                        "https".to_string()
+                    };
+
                     let port = if let Some(port) = matches.name("port") {
                         u16::from_str(port.as_str()).map_err(|_| {
                             StorageError::PathError(format!("invalid port: {}", port.as_str()))
                         })?
+                    } else if protocol == "http" {
+                        80
                     } else {
                         443
                     };
 
-                    let protocol = if let Some(protocol) = matches.name("protocol") {
-                        protocol.as_str().to_string()
-                    } else {
-                        "https".to_string()
-                    };
-
                     Ok(format!("{}://{}:{}", protocol, endpoint.as_str(), port))
                 })
                 .transpose()?,
+            // R2 buckets are addressed path-style under the account's endpoint, which
+            // `construct_s3` does for all custom endpoints
+            matches.name("account").map(|account| {
+                format!("https://{}.r2.cloudflarestorage.com:443", account.as_str())
+            }),
         ]);
 
         let key = matches.name("key").map(|m| m.as_str().to_string());
 
+        let mut region = region;
+        let mut accelerate = matches.name("accelerate").is_some();
+        for option in query.into_iter().flat_map(|q| q.split('&')) {
+            match option.split_once('=') {
+                Some(("accelerate", value)) => {
+                    accelerate = bool::from_str(value).map_err(|_| {
+                        StorageError::PathError(format!("invalid value for accelerate: {}", value))
+                    })?;
+                }
+                // for endpoint URLs, which have nowhere else to put the region
+                Some(("region", value)) => {
+                    region = Some(value.to_string());
+                }
+                _ => {
+                    return Err(StorageError::PathError(format!(
+                        "unsupported S3 URL option: {}",
+                        option
+                    )));
+                }
+            }
+        }
+
+        let encryption = std::env::var(S3_SSE_ENV)
+            .ok()
+            .map(|value| value.parse())
+            .transpose()?;
+
         Ok(BackendConfig::S3(S3Config {
             endpoint,
             region,
             bucket,
             key,
+            accelerate,
+            encryption,
         }))
     }
 
@@ -211,16 +1395,48 @@ impl BackendConfig {
 
         let key = matches.name("key").map(|r| r.as_str().to_string());
 
-        Ok(BackendConfig::GCS(GCSConfig { bucket, key }))
+        Ok(BackendConfig::GCS(GCSConfig {
+            bucket,
+            key,
+            predefined_acl: None,
+        }))
+    }
+
+    fn parse_azure(matches: Captures) -> Result<Self, StorageError> {
+        let account = last([
+            std::env::var(AZURE_STORAGE_ACCOUNT_ENV).ok(),
+            matches.name("account").map(|m| m.as_str().to_string()),
+        ]);
+
+        let container = matches
+            .name("container")
+            .expect("container should always be available")
+            .as_str()
+            .to_string();
+
+        let key = matches.name("key").map(|r| r.as_str().to_string());
+
+        Ok(BackendConfig::Azure(AzureConfig {
+            account,
+            container,
+            key,
+        }))
     }
 
-    fn parse_local(matches: Captures, with_key: bool) -> Result<Self, StorageError> {
+    fn parse_local(
+        matches: Captures,
+        query: Option<&str>,
+        with_key: bool,
+    ) -> Result<Self, StorageError> {
         let path = matches
             .name("path")
             .expect("path regex must contain a path group")
             .as_str();
 
-        let mut path = if !path.starts_with("/") {
+        let mut path = if cfg!(windows) && is_windows_drive_path(path) {
+            // file:///C:/data has a path of /C:/data, which isn't valid on Windows
+            PathBuf::from(path.trim_start_matches('/'))
+        } else if !path.starts_with("/") {
             PathBuf::from(format!("/{}", path))
         } else {
             PathBuf::from(path)
@@ -234,62 +1450,461 @@ impl BackendConfig {
             None
         };
 
+        let mut create = true;
+        for option in query.into_iter().flat_map(|q| q.split('&')) {
+            match option.split_once('=') {
+                Some(("create", value)) => {
+                    create = bool::from_str(value).map_err(|_| {
+                        StorageError::PathError(format!("invalid value for create: {}", value))
+                    })?;
+                }
+                _ => {
+                    return Err(StorageError::PathError(format!(
+                        "unsupported local URL option: {}",
+                        option
+                    )));
+                }
+            }
+        }
+
         Ok(BackendConfig::Local(LocalConfig {
             path: path.to_str().unwrap().to_string(),
             key,
+            create,
         }))
     }
+
+    /// The key in the URL this config was parsed from, if any
+    pub fn key(&self) -> Option<&String> {
+        match self {
+            BackendConfig::S3(s3) => s3.key.as_ref(),
+            BackendConfig::GCS(gcs) => gcs.key.as_ref(),
+            BackendConfig::Azure(azure) => azure.key.as_ref(),
+            BackendConfig::Local(local) => local.key.as_ref(),
+        }
+    }
+
+    /// Whether the backend supports uploading an object in parts. S3, GCS, and Azure upload
+    /// the parts natively. Local storage doesn't: its writers stage the object in a single
+    /// temporary file, so callers that upload parts concurrently or checkpoint them (like the
+    /// filesystem sink) should use single puts. There's no in-memory backend to report on.
+    pub fn supports_multipart(&self) -> bool {
+        match self {
+            BackendConfig::S3(_) | BackendConfig::GCS(_) | BackendConfig::Azure(_) => true,
+            BackendConfig::Local(_) => false,
+        }
+    }
+
+    /// Whether [`StorageProvider::signed_url`] can sign URLs for the backend. Only S3 (and
+    /// S3-compatible stores) can: the version of object_store we use has no signer for GCS
+    /// or Azure, and local files can't be served over HTTP.
+    pub fn supports_signed_urls(&self) -> bool {
+        match self {
+            BackendConfig::S3(_) => true,
+            BackendConfig::GCS(_) | BackendConfig::Azure(_) | BackendConfig::Local(_) => false,
+        }
+    }
+
+    /// Serializes this config to a URL that [`BackendConfig::parse_url`] parses back to the
+    /// same config (with `with_key` set if the config has a key, for local paths), without
+    /// constructing a client. Settings that come from [`StorageOptions`] or the environment
+    /// rather than the URL, like the GCS predefined ACL or S3 encryption, aren't included.
+    pub fn to_url(&self) -> String {
+        match self {
+            BackendConfig::S3(config) => {
+                let mut options = vec![];
+                let base = match (&config.endpoint, &config.region) {
+                    (Some(endpoint), region) => {
+                        if let Some(region) = region {
+                            options.push(format!("region={}", region));
+                        }
+                        format!("s3::{}/{}", endpoint, config.bucket)
+                    }
+                    (None, Some(region)) => {
+                        format!("https://s3.{}.amazonaws.com/{}", region, config.bucket)
+                    }
+                    (None, None) if config.accelerate => config.accelerate_endpoint(),
+                    (None, None) => format!("s3://{}", config.bucket),
+                };
+                if config.accelerate && (config.endpoint.is_some() || config.region.is_some()) {
+                    options.push("accelerate=true".to_string());
+                }
+                with_query(with_key(base, &config.key), &options)
+            }
+            BackendConfig::GCS(config) => with_key(format!("gs://{}", config.bucket), &config.key),
+            BackendConfig::Azure(config) => with_key(
+                config
+                    .endpoint()
+                    .unwrap_or_else(|| format!("az://{}", config.container)),
+                &config.key,
+            ),
+            BackendConfig::Local(config) => {
+                let mut options = vec![];
+                if !config.create {
+                    options.push("create=false".to_string());
+                }
+                with_query(with_key(local_url(&config.path), &config.key), &options)
+            }
+        }
+    }
+}
+
+fn with_key(base: String, key: &Option<String>) -> String {
+    match key {
+        Some(key) => format!("{}/{}", base.trim_end_matches('/'), key),
+        None => base,
+    }
+}
+
+fn with_query(url: String, options: &[String]) -> String {
+    if options.is_empty() {
+        url
+    } else {
+        format!("{}?{}", url, options.join("&"))
+    }
+}
+
+fn is_bucket_not_found(e: &object_store::Error) -> bool {
+    match e {
+        object_store::Error::NotFound { .. } => true,
+        // list errors aren't mapped to NotFound by object_store, so we look for the
+        // provider-specific error in the response
+        object_store::Error::Generic { source, .. } => {
+            let message = format!("{:?}", source);
+            message.contains("NoSuchBucket")
+                || message.contains("bucket does not exist")
+                || message.contains("ContainerNotFound")
+        }
+        _ => false,
+    }
+}
+
+const CREATE_DIR_ATTEMPTS: usize = 3;
+
+/// Creates a directory with `create`, retrying a few times with a short backoff since
+/// NFS-backed paths can fail transiently (e.g., with EAGAIN or stale file handles)
+async fn create_dir_with_retry<F, Fut>(path: &str, mut create: F) -> Result<(), StorageError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        match create(path.to_string()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < CREATE_DIR_ATTEMPTS => {
+                tracing::warn!(
+                    "failed to create directory {} (attempt {}/{}): {:?}",
+                    path,
+                    attempt,
+                    CREATE_DIR_ATTEMPTS,
+                    e
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(50 * attempt as u64)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(StorageError::PathError(format!(
+                    "failed to create directory {} after {} attempts: {:?}",
+                    path, CREATE_DIR_ATTEMPTS, e
+                )));
+            }
+        }
+    }
 }
 
 fn last<I: Sized, const COUNT: usize>(opts: [Option<I>; COUNT]) -> Option<I> {
     opts.into_iter().flatten().last()
 }
 
+/// Preconditions for [`StorageProvider::get_conditional`], mirroring the HTTP `If-None-Match`
+/// and `If-Modified-Since` headers
+#[derive(Debug, Clone, Default)]
+pub struct GetConditions {
+    /// Skip the read if the object's etag matches
+    pub if_none_match: Option<String>,
+    /// Skip the read if the object hasn't been modified since this time
+    pub if_modified_since: Option<DateTime<Utc>>,
+}
+
+impl GetConditions {
+    /// Whether an object with this metadata passes the conditions, for backends that can't
+    /// evaluate them server-side
+    fn is_modified(&self, meta: &ObjectMeta) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            return !if_none_match
+                .split(',')
+                .map(|etag| etag.trim())
+                .any(|etag| etag == "*" || Some(etag) == meta.e_tag.as_deref());
+        }
+        match self.if_modified_since {
+            Some(since) => meta.last_modified > since,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GetResponse {
+    Modified(Bytes, ObjectMeta),
+    NotModified,
+}
+
+/// The writer returned by [`StorageProvider::put_multipart`], which aborts its upload if
+/// dropped before it's been shut down
+struct MultipartUpload {
+    object_store: Arc<dyn ObjectStore>,
+    path: Path,
+    multipart_id: MultipartId,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    completed: bool,
+}
+
+impl AsyncWrite for MultipartUpload {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let result = ready!(Pin::new(&mut self.writer).poll_shutdown(cx));
+        self.completed = result.is_ok();
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for MultipartUpload {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+
+        let object_store = self.object_store.clone();
+        let path = self.path.clone();
+        let multipart_id = self.multipart_id.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = object_store.abort_multipart(&path, &multipart_id).await {
+                        tracing::warn!(
+                            "failed to abort multipart upload {} to {}: {}",
+                            multipart_id,
+                            path,
+                            e
+                        );
+                    }
+                });
+            }
+            Err(_) => tracing::warn!(
+                "not aborting multipart upload {} to {} dropped outside of a runtime",
+                multipart_id,
+                path
+            ),
+        }
+    }
+}
+
+/// Reads up to `part_size` bytes from `reader`, returning fewer only at the end of the input
+async fn read_part<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    part_size: usize,
+) -> std::io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(part_size);
+    reader.take(part_size as u64).read_to_end(&mut part).await?;
+    Ok(part)
+}
+
+/// Appended to [`StorageError::PreconditionFailed`] reasons when the condition was checked
+/// with a separate request before writing, rather than atomically by the store
+const NON_ATOMIC_CAVEAT: &str =
+    " (checked before writing rather than atomically, so a concurrent writer may still have been overwritten)";
+
+/// Orders objects by location, for keeping the first keys of an unordered listing
+struct ByLocation(ObjectMeta);
+
+impl PartialEq for ByLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.location == other.0.location
+    }
+}
+
+impl Eq for ByLocation {}
+
+impl PartialOrd for ByLocation {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByLocation {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.location.as_ref().cmp(other.0.location.as_ref())
+    }
+}
+
+/// `object` with its location made relative to `base`, if it's under it
+fn relative_to(base: &Path, mut object: ObjectMeta) -> ObjectMeta {
+    let relative: Option<Path> = object
+        .location
+        .prefix_match(base)
+        .map(|parts| parts.collect());
+    if let Some(relative) = relative {
+        object.location = relative;
+    }
+    object
+}
+
+/// The prefix under which [`StorageProvider::put_dedup`] stores its content hash index
+const DEDUP_INDEX_PREFIX: &str = "_dedup";
+
+/// The outcome of [`StorageProvider::put_dedup`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DedupPut {
+    /// The bytes were written; holds the canonical URL of the new object
+    Uploaded(String),
+    /// An object with the same content already existed, so nothing was written; holds its
+    /// canonical URL
+    Existing(String),
+}
+
+/// The metadata of a local object from the file opened to read it, so that it matches the
+/// bytes that are read even if the object is replaced in the meantime
+fn local_file_meta(
+    location: &Path,
+    file: &std::fs::File,
+    local_path: &std::path::Path,
+) -> Result<ObjectMeta, StorageError> {
+    let metadata = file.metadata().map_err(|e| {
+        StorageError::PathError(format!(
+            "failed to read metadata of {}: {:?}",
+            local_path.display(),
+            e
+        ))
+    })?;
+    let last_modified = metadata.modified().map_err(|e| {
+        StorageError::PathError(format!(
+            "failed to read modification time of {}: {:?}",
+            local_path.display(),
+            e
+        ))
+    })?;
+    let mut meta = ObjectMeta {
+        location: location.clone(),
+        last_modified: DateTime::<Utc>::from(last_modified),
+        size: metadata.len() as usize,
+        e_tag: None,
+    };
+    meta.e_tag = Some(fallback_etag(&meta));
+    Ok(meta)
+}
+
+/// The etag reported for objects whose backend doesn't supply one, built from the size and
+/// modification time like an HTTP server's weak etag
+fn fallback_etag(meta: &ObjectMeta) -> String {
+    format!(
+        "{:x}-{:x}",
+        meta.last_modified.timestamp_micros(),
+        meta.size
+    )
+}
+
 impl StorageProvider {
     pub async fn for_url(url: &str) -> Result<Self, StorageError> {
+        Self::for_url_with_options(url, StorageOptions::default()).await
+    }
+
+    pub async fn for_url_with_options(
+        url: &str,
+        options: StorageOptions,
+    ) -> Result<Self, StorageError> {
         let config: BackendConfig = BackendConfig::parse_url(url, false)?;
 
-        match config {
-            BackendConfig::S3(config) => Self::construct_s3(config).await,
-            BackendConfig::GCS(config) => Self::construct_gcs(config),
-            BackendConfig::Local(config) => Self::construct_local(config).await,
-        }
+        Self::construct(config, &options).await
     }
 
     pub async fn get_url(url: &str) -> Result<Bytes, StorageError> {
+        Self::get_url_with_options(url, StorageOptions::default()).await
+    }
+
+    pub async fn get_url_with_options(
+        url: &str,
+        options: StorageOptions,
+    ) -> Result<Bytes, StorageError> {
         let config: BackendConfig = BackendConfig::parse_url(url, true)?;
 
-        let provider = match config {
-            BackendConfig::S3(config) => Self::construct_s3(config).await,
-            BackendConfig::GCS(config) => Self::construct_gcs(config),
-            BackendConfig::Local(config) => Self::construct_local(config).await,
-        }?;
+        let provider = Self::construct(config, &options).await?;
 
-        let path = match &provider.config {
-            BackendConfig::S3(s3) => s3.key.as_ref(),
-            BackendConfig::GCS(gcs) => gcs.key.as_ref(),
-            BackendConfig::Local(local) => local.key.as_ref(),
-        }
-        .ok_or_else(|| StorageError::NoKeyInUrl)?;
+        let key = provider
+            .config
+            .key()
+            .ok_or_else(|| StorageError::NoKeyInUrl)?;
 
-        let result = provider.get(path).await;
+        // keys in URLs are already percent-encoded (see `object_url`), so we parse them
+        // rather than re-encoding
+        let path = Path::parse(key)
+            .map_err(|e| StorageError::PathError(format!("invalid key {}: {:?}", key, e)))?;
 
-        result
+        provider.get_path(&path).await
+    }
+
+    async fn construct(
+        config: BackendConfig,
+        options: &StorageOptions,
+    ) -> Result<Self, StorageError> {
+        match config {
+            BackendConfig::S3(config) => Self::construct_s3(config, options).await,
+            BackendConfig::GCS(config) => Self::construct_gcs(config, options).await,
+            BackendConfig::Azure(config) => Self::construct_azure(config, options).await,
+            BackendConfig::Local(config) => Self::construct_local(config, options).await,
+        }
     }
 
-    async fn construct_s3(mut config: S3Config) -> Result<Self, StorageError> {
+    async fn construct_s3(
+        mut config: S3Config,
+        options: &StorageOptions,
+    ) -> Result<Self, StorageError> {
         let credentials = Arc::new(ArroyoCredentialProvider::try_new()?);
 
         let mut builder = AmazonS3Builder::from_env()
             .with_bucket_name(&config.bucket)
             .with_credentials(credentials.clone());
 
-        let default_region = credentials.default_region().await;
-        config.region = config.region.or(default_region);
+        // resolve the region and endpoint the same way the builder will, so that `config()`
+        // reflects what is actually used; the URL and Arroyo's env vars take precedence over
+        // the standard AWS env vars, which take precedence over the profile
+        if config.region.is_none() {
+            config.region = match builder.get_config_value(&AmazonS3ConfigKey::Region) {
+                Some(region) => Some(region),
+                None => credentials.default_region().await,
+            };
+        }
         if let Some(region) = &config.region {
             builder = builder.with_region(region);
         }
 
+        if config.endpoint.is_none() {
+            config.endpoint = builder.get_config_value(&AmazonS3ConfigKey::Endpoint);
+        }
+
+        if config.accelerate {
+            if let Some(endpoint) = &config.endpoint {
+                return Err(StorageError::PathError(format!(
+                    "transfer acceleration cannot be used with a custom endpoint ({})",
+                    endpoint
+                )));
+            }
+            builder = builder
+                .with_endpoint(config.accelerate_endpoint())
+                .with_virtual_hosted_style_request(true);
+        }
+
         if let Some(endpoint) = &config.endpoint {
             builder = builder
                 .with_endpoint(endpoint)
@@ -297,7 +1912,21 @@ impl StorageProvider {
                 .with_allow_http(true);
         }
 
+        if let Some(path_style) = options.s3_path_style {
+            if path_style && config.accelerate {
+                return Err(StorageError::PathError(
+                    "transfer acceleration cannot be used with path-style requests".to_string(),
+                ));
+            }
+            builder = builder.with_virtual_hosted_style_request(!path_style);
+        }
+
+        if let Some(encryption) = &options.s3_encryption {
+            config.encryption = Some(encryption.clone());
+        }
+
         let canonical_url = match (&config.region, &config.endpoint) {
+            _ if config.accelerate => config.accelerate_endpoint(),
             (_, Some(endpoint)) => {
                 format!("s3::{}/{}", endpoint, config.bucket)
             }
@@ -309,59 +1938,458 @@ impl StorageProvider {
             }
         };
 
-        Ok(Self {
+        let bucket = config.bucket.clone();
+        let encryption = match &config.encryption {
+            Some(encryption) => Some((encryption, s3_uploads_client(&config, credentials)?)),
+            None => None,
+        };
+        let (object_store, s3) = s3_stores(builder, options, encryption)?;
+        let provider = Self {
             config: BackendConfig::S3(config),
-            object_store: Arc::new(builder.build().map_err(|e| Into::<StorageError>::into(e))?),
+            object_store,
             canonical_url,
-        })
+            read_your_writes: options.read_your_writes,
+            retry_classifier: options.retry_classifier.clone(),
+            retry: options.retry,
+            delete_batch_size: options.delete_batch_size,
+            signer: Some(s3),
+        };
+
+        if options.validate_on_construct {
+            provider.validate_bucket(&bucket).await?;
+        }
+
+        Ok(provider)
     }
 
-    fn construct_gcs(config: GCSConfig) -> Result<Self, StorageError> {
-        let gcs = GoogleCloudStorageBuilder::from_env()
-            .with_bucket_name(&config.bucket)
-            .build()?;
+    async fn construct_gcs(
+        mut config: GCSConfig,
+        options: &StorageOptions,
+    ) -> Result<Self, StorageError> {
+        let mut builder = GoogleCloudStorageBuilder::from_env().with_bucket_name(&config.bucket);
+
+        if let Some(acl) = &options.gcs_predefined_acl {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(
+                "x-goog-acl",
+                reqwest::header::HeaderValue::from_static(GCSConfig::acl_header_value(acl)?),
+            );
+            builder =
+                builder.with_client_options(ClientOptions::new().with_default_headers(headers));
+            config.predefined_acl = Some(acl.clone());
+        }
+
+        // applied after the client options above, which would otherwise replace them
+        for (key, value) in options.client_timeouts()? {
+            builder = builder.with_config(GoogleConfigKey::Client(key), value);
+        }
+        builder = builder.with_retry(options.retry_config());
+
+        let gcs = builder.build()?;
 
         let canonical_url = format!("https://{}.storage.googleapis.com", config.bucket);
 
-        Ok(Self {
+        let bucket = config.bucket.clone();
+        let provider = Self {
             config: BackendConfig::GCS(config),
             object_store: Arc::new(gcs),
             canonical_url,
-        })
+            read_your_writes: options.read_your_writes,
+            retry_classifier: options.retry_classifier.clone(),
+            retry: options.retry,
+            delete_batch_size: options.delete_batch_size,
+            // object_store can't yet sign GCS URLs
+            signer: None,
+        };
+
+        if options.validate_on_construct {
+            provider.validate_bucket(&bucket).await?;
+        }
+
+        Ok(provider)
     }
 
-    async fn construct_local(config: LocalConfig) -> Result<Self, StorageError> {
-        tokio::fs::create_dir_all(&config.path).await.map_err(|e| {
-            StorageError::PathError(format!(
-                "failed to create directory {}: {:?}",
-                config.path, e
-            ))
-        })?;
+    async fn construct_azure(
+        config: AzureConfig,
+        options: &StorageOptions,
+    ) -> Result<Self, StorageError> {
+        let (Some(account), Some(canonical_url)) = (config.account.clone(), config.endpoint())
+        else {
+            return Err(StorageError::PathError(format!(
+                "no storage account for Azure container {}; use an https://{{account}}.blob.core.windows.net URL or set {}",
+                config.container, AZURE_STORAGE_ACCOUNT_ENV
+            )));
+        };
+
+        let mut builder = MicrosoftAzureBuilder::from_env()
+            .with_account(account)
+            .with_container_name(&config.container);
+        if let Ok(access_key) = std::env::var(AZURE_STORAGE_ACCESS_KEY_ENV) {
+            builder = builder.with_access_key(access_key);
+        }
+
+        for (key, value) in options.client_timeouts()? {
+            builder = builder.with_config(AzureConfigKey::Client(key), value);
+        }
+        builder = builder.with_retry(options.retry_config());
+
+        let azure = builder.build()?;
+
+        let container = config.container.clone();
+        let provider = Self {
+            config: BackendConfig::Azure(config),
+            object_store: Arc::new(azure),
+            canonical_url,
+            read_your_writes: options.read_your_writes,
+            retry_classifier: options.retry_classifier.clone(),
+            retry: options.retry,
+            delete_batch_size: options.delete_batch_size,
+            signer: None,
+        };
+
+        if options.validate_on_construct {
+            provider.validate_bucket(&container).await?;
+        }
+
+        Ok(provider)
+    }
+
+    async fn construct_local(
+        config: LocalConfig,
+        options: &StorageOptions,
+    ) -> Result<Self, StorageError> {
+        if options.validate_on_construct {
+            if !tokio::fs::try_exists(&config.path).await.unwrap_or(false) {
+                return Err(StorageError::BucketNotFound(config.path));
+            }
+        } else if !config.create {
+            let is_dir = tokio::fs::metadata(&config.path)
+                .await
+                .map(|metadata| metadata.is_dir())
+                .unwrap_or(false);
+            if !is_dir {
+                return Err(StorageError::PathError(format!(
+                    "directory {} does not exist",
+                    config.path
+                )));
+            }
+        } else {
+            create_dir_with_retry(&config.path, tokio::fs::create_dir_all).await?;
+
+            #[cfg(unix)]
+            if let Some(mode) = options.dir_mode {
+                use std::os::unix::fs::PermissionsExt;
+                tokio::fs::set_permissions(&config.path, std::fs::Permissions::from_mode(mode))
+                    .await
+                    .map_err(|e| {
+                        StorageError::PathError(format!(
+                            "failed to set permissions on directory {}: {:?}",
+                            config.path, e
+                        ))
+                    })?;
+            }
+        }
 
         let object_store = Arc::new(
             LocalFileSystem::new_with_prefix(&config.path)
                 .map_err(|e| Into::<StorageError>::into(e))?,
         );
 
-        let canonical_url = format!("file://{}", config.path);
+        let canonical_url = local_url(&config.path);
         Ok(Self {
             config: BackendConfig::Local(config),
             object_store,
             canonical_url,
+            read_your_writes: options.read_your_writes,
+            retry_classifier: options.retry_classifier.clone(),
+            retry: options.retry,
+            delete_batch_size: options.delete_batch_size,
+            signer: None,
         })
     }
 
-    pub async fn get<P: Into<String>>(&self, path: P) -> Result<Bytes, StorageError> {
-        let path: String = path.into();
-        let bytes = self
-            .object_store
-            .get(&path.into())
+    /// Makes a cheap list request against the bucket to check that it exists
+    async fn validate_bucket(&self, bucket: &str) -> Result<(), StorageError> {
+        match self.object_store.list_with_delimiter(None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_bucket_not_found(&e) => {
+                Err(StorageError::BucketNotFound(bucket.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn get<P: Into<String>>(&self, path: P) -> Result<Bytes, StorageError> {
+        let path: String = path.into();
+        self.get_path(&path.into()).await
+    }
+
+    /// Streams the object at `path`, so that large objects can be piped into a decompressor or
+    /// parser without holding the whole thing in memory
+    pub async fn get_stream<P: Into<String>>(
+        &self,
+        path: P,
+    ) -> Result<impl AsyncBufRead + Unpin + Send, StorageError> {
+        let path: String = path.into();
+        let stream = self
+            .get_result(&path.into())
+            .await?
+            .into_stream()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+        Ok(StreamReader::new(stream))
+    }
+
+    /// Reads the last `n` bytes of the object at `path` (or all of it, if it's smaller), e.g.
+    /// for a Parquet footer
+    pub async fn get_tail<P: Into<String>>(
+        &self,
+        path: P,
+        n: usize,
+    ) -> Result<Bytes, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+        let size = self.retrying(|| self.object_store.head(&path)).await?.size;
+        if size == 0 || n == 0 {
+            return Ok(Bytes::new());
+        }
+        let range = size.saturating_sub(n)..size;
+        self.retrying(|| self.object_store.get_range(&path, range.clone()))
             .await
-            .map_err(|e| Into::<StorageError>::into(e))?
-            .bytes()
+    }
+
+    /// Fetches the size, last-modified time, and etag of the object at `path` without reading
+    /// it. For local storage, the last-modified time is the file's mtime and the etag is
+    /// derived from it and the size, since the filesystem doesn't have one.
+    pub async fn head<P: Into<String>>(&self, path: P) -> Result<ObjectMeta, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+        let mut meta = self.retrying(|| self.object_store.head(&path)).await?;
+        meta.e_tag = Some(meta.e_tag.clone().unwrap_or_else(|| fallback_etag(&meta)));
+        Ok(meta)
+    }
+
+    /// Whether an object exists at `path`. Unlike reads, this isn't retried within the
+    /// `read_your_writes` window.
+    pub async fn exists<P: Into<String>>(&self, path: P) -> Result<bool, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+        match self.retrying_lookup(|| self.object_store.head(&path)).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the object at `path` unless it matches `conditions`, returning
+    /// [`GetResponse::NotModified`] in that case. The returned metadata always has an etag,
+    /// which can be passed back as `if_none_match` on the next read, and always describes
+    /// the returned bytes.
+    ///
+    /// The local filesystem reads the metadata from the file it opened. Other backends don't
+    /// return metadata with the object in the version of object_store we use, so the
+    /// conditions are checked against a head request and the object is then read with
+    /// `If-Match` set to its etag; if it changed in between, it's read again.
+    pub async fn get_conditional<P: Into<String>>(
+        &self,
+        path: P,
+        conditions: GetConditions,
+    ) -> Result<GetResponse, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+
+        if let BackendConfig::Local(_) = self.config {
+            return match self.get_result(&path).await? {
+                GetResult::File(file, local_path) => {
+                    let meta = local_file_meta(&path, &file, &local_path)?;
+                    if !conditions.is_modified(&meta) {
+                        return Ok(GetResponse::NotModified);
+                    }
+                    let bytes = GetResult::File(file, local_path).bytes().await?;
+                    Ok(GetResponse::Modified(bytes, meta))
+                }
+                GetResult::Stream(_) => Err(StorageError::ObjectStore(
+                    object_store::Error::NotImplemented,
+                )),
+            };
+        }
+
+        let max_retries = self.retry.unwrap_or_default().max_retries;
+        let mut retries = 0;
+        loop {
+            let mut meta = self.retrying(|| self.object_store.head(&path)).await?;
+            meta.e_tag = Some(meta.e_tag.clone().unwrap_or_else(|| fallback_etag(&meta)));
+            if !conditions.is_modified(&meta) {
+                return Ok(GetResponse::NotModified);
+            }
+
+            let options = || GetOptions {
+                if_match: meta.e_tag.clone(),
+                ..Default::default()
+            };
+            match self
+                .retrying(|| async {
+                    self.object_store
+                        .get_opts(&path, options())
+                        .await?
+                        .bytes()
+                        .await
+                })
+                .await
+            {
+                Ok(bytes) => return Ok(GetResponse::Modified(bytes, meta)),
+                // replaced since the head, so check the conditions against the new version
+                Err(StorageError::ObjectStore(e @ object_store::Error::Precondition { .. })) => {
+                    if retries >= max_retries {
+                        return Err(StorageError::RetriesExhausted {
+                            attempts: retries + 1,
+                            source: e,
+                        });
+                    }
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the bytes in `range` of the object at `path`. The local filesystem seeks to the
+    /// start of the range rather than reading the whole file.
+    pub async fn get_range<P: Into<String>>(
+        &self,
+        path: P,
+        range: Range<usize>,
+    ) -> Result<Bytes, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+        self.retrying(|| self.object_store.get_range(&path, range.clone()))
+            .await
+    }
+
+    /// Reads several ranges of the object at `path`, returned in the same order as `ranges`.
+    /// Nearby ranges are coalesced into a single request for remote backends, and the local
+    /// filesystem reads them all from one open file.
+    pub async fn get_ranges<P: Into<String>>(
+        &self,
+        path: P,
+        ranges: &[Range<usize>],
+    ) -> Result<Vec<Bytes>, StorageError> {
+        let path: String = path.into();
+        let path: Path = path.into();
+        self.retrying(|| self.object_store.get_ranges(&path, ranges))
+            .await
+    }
+
+    /// Returns a URL that can be used to GET the object at `path` without credentials until
+    /// `expires_in` has passed, for handing out links to objects without proxying their
+    /// contents. The URL is signed for the provider's configured region and endpoint.
+    ///
+    /// Only supported for S3, where URLs can be valid for up to 7 days. GCS, Azure, and local
+    /// storage are unsupported (see [`BackendConfig::supports_signed_urls`]), and callers
+    /// should check [`StorageProvider::supports_signed_urls`] before offering signed links
+    /// rather than relying on the [`object_store::Error::NotImplemented`] returned for them.
+    pub async fn signed_url<P: Into<String>>(
+        &self,
+        path: P,
+        expires_in: Duration,
+    ) -> Result<String, StorageError> {
+        let Some(signer) = &self.signer else {
+            return Err(object_store::Error::NotImplemented.into());
+        };
+        if expires_in > MAX_PRESIGNED_URL_EXPIRY {
+            return Err(StorageError::PresignedUrlExpiryTooLong {
+                requested: expires_in,
+                max: MAX_PRESIGNED_URL_EXPIRY,
+            });
+        }
+        let path: String = path.into();
+        let url = signer
+            .signed_url(Method::GET, &path.into(), expires_in)
             .await?;
+        Ok(url.to_string())
+    }
+
+    async fn get_path(&self, path: &Path) -> Result<Bytes, StorageError> {
+        Ok(self.get_result(path).await?.bytes().await?)
+    }
 
-        Ok(bytes)
+    /// Starts reading an object
+    async fn get_result(&self, path: &Path) -> Result<GetResult, StorageError> {
+        self.retrying(|| self.object_store.get(path)).await
+    }
+
+    /// Runs `op`, retrying failures with the backoff of the provider's [`RetryConfig`] (or the
+    /// default one if none is set). `NotFound` errors are retried until the `read_your_writes`
+    /// window has passed. Other errors are retried up to `max_retries` times if the retry
+    /// classifier accepts them, or, without a classifier, if they're transient and a
+    /// [`RetryConfig`] is set.
+    async fn retrying<T, F, Fut>(&self, op: F) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = object_store::Result<T>>,
+    {
+        self.retry_loop(op, true).await
+    }
+
+    /// Like [`StorageProvider::retrying`], but for requests where a missing object is an
+    /// expected answer (existence checks and deletes), so `NotFound` is returned straight away
+    async fn retrying_lookup<T, F, Fut>(&self, op: F) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = object_store::Result<T>>,
+    {
+        self.retry_loop(op, false).await
+    }
+
+    async fn retry_loop<T, F, Fut>(
+        &self,
+        mut op: F,
+        wait_for_missing: bool,
+    ) -> Result<T, StorageError>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = object_store::Result<T>>,
+    {
+        let retry = self.retry.unwrap_or_default();
+        let start = Instant::now();
+        // every retry backs off further, but only retries of errors other than missing objects
+        // within the read_your_writes window count towards max_retries
+        let mut attempts = 0;
+        let mut retries = 0;
+        loop {
+            let e = match op().await {
+                Ok(result) => return Ok(result),
+                Err(e) => e,
+            };
+            let missing = matches!(e, object_store::Error::NotFound { .. });
+            if missing && !wait_for_missing {
+                return Err(e.into());
+            }
+            let backoff = retry.backoff(attempts);
+
+            let not_yet_visible = missing
+                && self
+                    .read_your_writes
+                    .is_some_and(|window| start.elapsed() + backoff < window);
+            if !not_yet_visible {
+                let retryable = match &self.retry_classifier {
+                    Some(classifier) => classifier(&e),
+                    None => self.retry.is_some() && is_transient(&e),
+                };
+                if !retryable {
+                    return Err(e.into());
+                }
+                if retries >= retry.max_retries {
+                    return Err(StorageError::RetriesExhausted {
+                        attempts: retries + 1,
+                        source: e,
+                    });
+                }
+                retries += 1;
+            }
+
+            tokio::time::sleep(backoff).await;
+            attempts += 1;
+        }
     }
 
     pub async fn put<P: Into<String>>(
@@ -370,18 +2398,550 @@ impl StorageProvider {
         bytes: Vec<u8>,
     ) -> Result<String, StorageError> {
         let path: Path = path.into().into();
-        self.object_store.put(&path, bytes.into()).await?;
+        let bytes: Bytes = bytes.into();
+        self.retrying(|| self.object_store.put(&path, bytes.clone()))
+            .await?;
+
+        Ok(self.url_for_path(&path))
+    }
+
+    /// Writes each `(key, bytes)` pair, with at most `concurrency` puts in flight at once,
+    /// returning the canonical URLs in the same order as `items`. Stops at the first failure,
+    /// returning [`StorageError::BatchPutFailed`] with its key; objects already written are
+    /// left in place.
+    pub async fn put_batch(
+        &self,
+        items: Vec<(String, Vec<u8>)>,
+        concurrency: usize,
+    ) -> Result<Vec<String>, StorageError> {
+        futures::stream::iter(items)
+            .map(|(key, bytes)| async move {
+                self.put(key.clone(), bytes)
+                    .await
+                    .map_err(|e| StorageError::BatchPutFailed {
+                        key,
+                        source: Box::new(e),
+                    })
+            })
+            .buffered(concurrency.max(1))
+            .try_collect()
+            .await
+    }
+
+    /// Streams the contents of `reader` to `path`, reading `part_size` bytes at a time so
+    /// that the whole object never needs to be held in memory. Inputs that fit within a
+    /// single part are written with a single put. Returns the number of bytes written.
+    pub async fn put_reader<P: Into<String>, R: AsyncRead + Unpin>(
+        &self,
+        path: P,
+        reader: R,
+        part_size: usize,
+    ) -> Result<usize, StorageError> {
+        self.put_multipart_with_progress(path, reader, part_size, |_| {})
+            .await
+    }
+
+    /// Starts a multipart upload to `path`, returning a writer that bytes can be streamed to
+    /// without holding the whole object in memory. Shutting down the writer completes the
+    /// upload; if it's dropped first, for example after a write fails, the upload is aborted
+    /// in the background so that its parts aren't left behind.
+    pub async fn put_multipart<P: Into<String>>(
+        &self,
+        path: P,
+    ) -> Result<Box<dyn AsyncWrite + Unpin + Send>, StorageError> {
+        let path: Path = path.into().into();
+        let (multipart_id, writer) = self
+            .retrying(|| self.object_store.put_multipart(&path))
+            .await?;
+        Ok(Box::new(MultipartUpload {
+            object_store: self.object_store.clone(),
+            path,
+            multipart_id,
+            writer,
+            completed: false,
+        }))
+    }
+
+    /// Like [`StorageProvider::put_reader`], but calls `on_progress` with the total number of
+    /// bytes uploaded after each part completes, e.g. to drive a progress bar. The callback is
+    /// run on a separate task so that it can't hold up the upload, and has seen every part by
+    /// the time this returns. Returns the number of bytes written.
+    pub async fn put_multipart_with_progress<P, R, F>(
+        &self,
+        path: P,
+        mut reader: R,
+        part_size: usize,
+        on_progress: F,
+    ) -> Result<usize, StorageError>
+    where
+        P: Into<String>,
+        R: AsyncRead + Unpin,
+        F: Fn(u64) + Send + 'static,
+    {
+        let path: Path = path.into().into();
+
+        let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        let reporter = tokio::spawn(async move {
+            while let Some(total) = progress_rx.recv().await {
+                on_progress(total);
+            }
+        });
+
+        let result = self
+            .upload_with_progress(&path, &mut reader, part_size, &progress_tx)
+            .await;
+
+        drop(progress_tx);
+        // only fails if the callback panicked, which shouldn't fail the upload
+        let _ = reporter.await;
+
+        result
+    }
+
+    async fn upload_with_progress<R: AsyncRead + Unpin>(
+        &self,
+        path: &Path,
+        reader: &mut R,
+        part_size: usize,
+        progress: &tokio::sync::mpsc::UnboundedSender<u64>,
+    ) -> Result<usize, StorageError> {
+        let mut part = read_part(reader, part_size).await?;
+
+        if part.len() < part_size {
+            let size = part.len();
+            let part: Bytes = part.into();
+            self.retrying(|| self.object_store.put(path, part.clone()))
+                .await?;
+            let _ = progress.send(size as u64);
+            return Ok(size);
+        }
+
+        let (multipart_id, mut writer) = self
+            .retrying(|| self.object_store.put_multipart(path))
+            .await?;
+
+        let result: Result<usize, StorageError> = async {
+            let mut size = 0;
+            while !part.is_empty() {
+                writer.write_all(&part).await?;
+                size += part.len();
+
+                // flushing waits for the part to finish uploading, so read the next one while
+                // it does
+                let (flushed, next) = tokio::join!(writer.flush(), read_part(reader, part_size));
+                flushed?;
+                let _ = progress.send(size as u64);
+                part = next?;
+            }
+            writer.shutdown().await?;
+            Ok(size)
+        }
+        .await;
+
+        if result.is_err() {
+            // the upload's error is the one worth reporting, even if the abort fails too
+            if let Err(e) = self
+                .retrying_lookup(|| self.object_store.abort_multipart(path, &multipart_id))
+                .await
+            {
+                tracing::warn!(
+                    "failed to abort multipart upload {} to {}: {}",
+                    multipart_id,
+                    path,
+                    e
+                );
+            }
+        }
+
+        result
+    }
+
+    /// Writes `bytes` to `final_key` so that readers never see a partially-written object: the
+    /// object is written to a temporary key and then renamed into place. Stores that can't
+    /// rename fall back to a plain put, which is atomic on S3 and GCS but may expose partial
+    /// writes elsewhere. Returns the canonical URL of the object.
+    pub async fn put_atomic<P: Into<String>>(
+        &self,
+        final_key: P,
+        bytes: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let key: String = final_key.into();
+        let path: Path = key.clone().into();
+        let bytes: Bytes = bytes.into();
+
+        let tmp_path: Path = format!("{}.tmp.{}", key, Uuid::new_v4()).into();
+        self.retrying(|| self.object_store.put(&tmp_path, bytes.clone()))
+            .await?;
+
+        let result = self
+            .retrying(|| self.object_store.rename(&tmp_path, &path))
+            .await;
+        if result.is_err() {
+            self.delete_if_present(tmp_path.to_string()).await?;
+        }
+
+        match result {
+            Ok(_) => {}
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotImplemented | object_store::Error::NotSupported { .. },
+            )) => {
+                tracing::warn!(
+                    "object store for {} does not support renames; writing {} with a plain put, which may not be atomic",
+                    self.canonical_url,
+                    key
+                );
+                self.retrying(|| self.object_store.put(&path, bytes.clone()))
+                    .await?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(self.url_for_path(&path))
+    }
+
+    /// Writes `bytes` to `path` only if no object exists there yet, returning `false` without
+    /// writing if it does. On stores that support it the object is written to a temporary key
+    /// and then renamed into place without overwriting; S3 does not support this, so there we
+    /// check for the object before writing, which may race with concurrent writers.
+    pub async fn put_if_absent<P: Into<String>>(
+        &self,
+        path: P,
+        bytes: Vec<u8>,
+    ) -> Result<bool, StorageError> {
+        Ok(self.put_absent(path.into(), bytes.into()).await?.0)
+    }
+
+    /// Writes `bytes` to `path` if no object exists there yet, otherwise failing with
+    /// [`StorageError::PreconditionFailed`], as when another writer created it first. Like
+    /// [`StorageProvider::put_if_absent`], this falls back to checking for the object before
+    /// writing on stores that can't create objects atomically (including S3), which the error
+    /// notes since a concurrent writer may still have been overwritten.
+    pub async fn put_if_not_exists<P: Into<String>>(
+        &self,
+        path: P,
+        bytes: Vec<u8>,
+    ) -> Result<String, StorageError> {
+        let key: String = path.into();
+        let (written, atomic) = self.put_absent(key.clone(), bytes.into()).await?;
+        if !written {
+            return Err(StorageError::PreconditionFailed {
+                reason: format!(
+                    "an object already exists{}",
+                    if atomic { "" } else { NON_ATOMIC_CAVEAT }
+                ),
+                key,
+            });
+        }
+
+        Ok(self.url_for_path(&key.into()))
+    }
+
+    /// Overwrites the object at `path` with `bytes` only if its current etag (as returned by
+    /// [`StorageProvider::head`]) is `etag`, failing with [`StorageError::PreconditionFailed`]
+    /// if it has changed or no longer exists. This allows compare-and-swap updates of metadata
+    /// files, but the object store client used here can't make conditional writes, so the
+    /// etag is checked with a separate request before writing and a writer that changes the
+    /// object in between will be overwritten.
+    pub async fn put_if_match<P: Into<String>>(
+        &self,
+        path: P,
+        bytes: Vec<u8>,
+        etag: &str,
+    ) -> Result<String, StorageError> {
+        let key: String = path.into();
+        let path: Path = key.clone().into();
+
+        let current = match self.retrying_lookup(|| self.object_store.head(&path)).await {
+            Ok(meta) => meta.e_tag.clone().unwrap_or_else(|| fallback_etag(&meta)),
+            Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => {
+                return Err(StorageError::PreconditionFailed {
+                    key,
+                    reason: format!("the object no longer exists{}", NON_ATOMIC_CAVEAT),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+
+        if current != etag {
+            return Err(StorageError::PreconditionFailed {
+                key,
+                reason: format!(
+                    "expected etag {} but found {}{}",
+                    etag, current, NON_ATOMIC_CAVEAT
+                ),
+            });
+        }
+
+        let bytes: Bytes = bytes.into();
+        self.retrying(|| self.object_store.put(&path, bytes.clone()))
+            .await?;
+        Ok(self.url_for_path(&path))
+    }
+
+    /// Writes `bytes` to `key` if no object exists there, returning whether it was written and
+    /// whether that was checked atomically rather than with a separate request
+    async fn put_absent(&self, key: String, bytes: Bytes) -> Result<(bool, bool), StorageError> {
+        let path: Path = key.clone().into();
+
+        if !matches!(self.config, BackendConfig::S3(_)) {
+            let tmp_path: Path = format!("{}.tmp.{}", key, Uuid::new_v4()).into();
+            self.retrying(|| self.object_store.put(&tmp_path, bytes.clone()))
+                .await?;
+
+            let result = self
+                .retrying(|| self.object_store.rename_if_not_exists(&tmp_path, &path))
+                .await;
+            if result.is_err() {
+                self.delete_if_present(tmp_path.to_string()).await?;
+            }
+
+            match result {
+                Ok(_) => return Ok((true, true)),
+                Err(StorageError::ObjectStore(object_store::Error::AlreadyExists { .. })) => {
+                    return Ok((false, true))
+                }
+                Err(StorageError::ObjectStore(
+                    object_store::Error::NotImplemented | object_store::Error::NotSupported { .. },
+                )) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.retrying_lookup(|| self.object_store.head(&path)).await {
+            Ok(_) => Ok((false, false)),
+            Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => {
+                self.retrying(|| self.object_store.put(&path, bytes.clone()))
+                    .await?;
+                Ok((true, false))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `bytes` to `path` unless an object with the same content has already been
+    /// written through this method, in which case the upload is skipped and the URL of that
+    /// object is returned instead. Content is identified by its SHA-256 hash, which is
+    /// recorded in an index object under `_dedup/`, beneath the key in the provider's URL if
+    /// it has one, pointing at the key it was first written to.
+    ///
+    /// This is best-effort: the index is not updated atomically with the upload, so
+    /// concurrent calls with the same content may both upload it, and the last one's key
+    /// wins the index. If the indexed object has since been deleted or replaced by one of a
+    /// different size, the bytes are uploaded again and the index is updated; an indexed
+    /// object overwritten with different content of the same size is not detected.
+    pub async fn put_dedup<P: Into<String>>(
+        &self,
+        path: P,
+        bytes: Vec<u8>,
+    ) -> Result<DedupPut, StorageError> {
+        let key: String = path.into();
+        let hash = format!("{:x}", Sha256::digest(&bytes));
+        // the index lives under the provider's key, so providers for different keys in the
+        // same bucket don't share (and point into) each other's indexes
+        let (_, index_path) = self.qualified_prefix(format!("{}/{}", DEDUP_INDEX_PREFIX, hash))?;
+
+        // not retried within the read_your_writes window, since a missing index entry is the
+        // common case
+        let existing = match self
+            .retrying_lookup(|| async { self.object_store.get(&index_path).await?.bytes().await })
+            .await
+        {
+            Ok(bytes) => Some(String::from_utf8_lossy(&bytes).to_string()),
+            Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => None,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(existing) = existing {
+            let existing: Path = existing.into();
+            match self
+                .retrying_lookup(|| self.object_store.head(&existing))
+                .await
+            {
+                Ok(meta) if meta.size == bytes.len() => {
+                    return Ok(DedupPut::Existing(self.url_for_path(&existing)));
+                }
+                Ok(_) | Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let url = self.put(key.clone(), bytes).await?;
+        let index_bytes: Bytes = key.into_bytes().into();
+        self.retrying(|| self.object_store.put(&index_path, index_bytes.clone()))
+            .await?;
 
-        Ok(format!("{}/{}", self.canonical_url, path))
+        Ok(DedupPut::Uploaded(url))
     }
 
     pub async fn delete_if_present<P: Into<String>>(&self, path: P) -> Result<(), StorageError> {
-        let path = path.into();
-        return match self.object_store.delete(&path.into()).await {
-            Ok(_) => Ok(()),
-            Err(object_store::Error::NotFound { .. }) => Ok(()),
-            Err(e) => Err(e.into()),
+        let path: String = path.into();
+        let path: Path = path.into();
+        match self
+            .retrying_lookup(|| self.object_store.delete(&path))
+            .await
+        {
+            Ok(_) | Err(StorageError::ObjectStore(object_store::Error::NotFound { .. })) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes each of `paths` through the object store's bulk delete, which bounds how many
+    /// deletes are in flight, in batches of at most `delete_batch_size` keys. Keys that don't
+    /// exist are treated as already deleted. Returns [`StorageError::BatchDeleteFailed`] with
+    /// the key of the first other failure.
+    pub async fn delete_many(&self, paths: Vec<String>) -> Result<(), StorageError> {
+        let batch_size = self.delete_batch_size.unwrap_or(paths.len()).max(1);
+        for batch in paths.chunks(batch_size) {
+            let locations = futures::stream::iter(
+                batch
+                    .iter()
+                    .map(|path| Ok::<Path, object_store::Error>(path.as_str().into())),
+            )
+            .boxed();
+
+            // delete_stream yields one result per location, in the order they were given
+            let mut results = self.object_store.delete_stream(locations);
+            let mut keys = batch.iter();
+            while let Some(result) = results.next().await {
+                let key = keys
+                    .next()
+                    .expect("delete_stream returned more results than keys");
+                match result {
+                    Ok(_) | Err(object_store::Error::NotFound { .. }) => {}
+                    // retried on its own, so that transient failures go through the same retries
+                    // as other requests
+                    Err(_) => {
+                        self.delete_if_present(key.clone()).await.map_err(|e| {
+                            StorageError::BatchDeleteFailed {
+                                key: key.clone(),
+                                source: Box::new(e),
+                            }
+                        })?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists every object under `prefix`. The prefix is relative to the key in the provider's
+    /// URL, if it has one, and so are the locations of the returned objects, for every backend.
+    pub async fn list<P: Into<String>>(&self, prefix: P) -> Result<Vec<ObjectMeta>, StorageError> {
+        let (base, prefix) = self.qualified_prefix(prefix.into())?;
+        // a listing can fail part way through, so the whole listing is retried
+        let objects: Vec<ObjectMeta> = self
+            .retrying_lookup(|| async {
+                self.object_store
+                    .list(Some(&prefix))
+                    .await?
+                    .try_collect()
+                    .await
+            })
+            .await?;
+        Ok(objects
+            .into_iter()
+            .map(|object| relative_to(&base, object))
+            .collect())
+    }
+
+    /// Like [`StorageProvider::list`], but streams the objects as they're listed. Only starting
+    /// the listing is retried.
+    pub async fn list_stream<P: Into<String>>(
+        &self,
+        prefix: P,
+    ) -> Result<impl Stream<Item = Result<ObjectMeta, StorageError>> + Send + '_, StorageError>
+    {
+        let (base, prefix) = self.qualified_prefix(prefix.into())?;
+        let objects = self
+            .retrying_lookup(|| self.object_store.list(Some(&prefix)))
+            .await?;
+        Ok(
+            objects.map(move |object| -> Result<ObjectMeta, StorageError> {
+                Ok(relative_to(&base, object?))
+            }),
+        )
+    }
+
+    /// The key in the provider's URL (if any) and `prefix` under it, as paths in the store
+    fn qualified_prefix(&self, prefix: String) -> Result<(Path, Path), StorageError> {
+        let base = match self.config.key() {
+            // keys in URLs are already percent-encoded, as in `get_url_with_options`
+            Some(key) => Path::parse(key)
+                .map_err(|e| StorageError::PathError(format!("invalid key {}: {:?}", key, e)))?,
+            None => Path::default(),
+        };
+        let prefix: Path = base.parts().chain(Path::from(prefix).parts()).collect();
+        Ok((base, prefix))
+    }
+
+    /// Lists up to `limit` objects under `prefix` in key order, starting after `continuation`,
+    /// which is the token returned with the previous page. Returns the page and the token
+    /// for the next one, or None if this was the last page. As with
+    /// [`StorageProvider::list`], the prefix and locations are relative to the key in the
+    /// provider's URL, if it has one.
+    pub async fn page<P: Into<String>>(
+        &self,
+        prefix: P,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> Result<(Vec<ObjectMeta>, Option<String>), StorageError> {
+        let (base, prefix) = self.qualified_prefix(prefix.into())?;
+        // tokens are rendered locations, so they're already percent-encoded
+        let offset: Option<Path> = continuation
+            .as_deref()
+            .map(|continuation| {
+                Path::parse(continuation)
+                    .map(|token| base.parts().chain(token.parts()).collect())
+                    .map_err(|e| {
+                        StorageError::PathError(format!(
+                            "invalid continuation token {}: {:?}",
+                            continuation, e
+                        ))
+                    })
+            })
+            .transpose()?;
+        let ordered = !matches!(self.config, BackendConfig::Local(_));
+
+        let mut page = self
+            .retrying_lookup(|| async {
+                let mut objects = match &offset {
+                    Some(offset) => {
+                        self.object_store
+                            .list_with_offset(Some(&prefix), offset)
+                            .await?
+                    }
+                    None => self.object_store.list(Some(&prefix)).await?,
+                };
+
+                // S3, GCS, and Azure list in key order, so only the start of the listing is
+                // needed; the local filesystem doesn't, so we keep the first `limit + 1` keys
+                // it lists rather than collecting and sorting all of them
+                let mut first = BinaryHeap::new();
+                while let Some(object) = objects.next().await {
+                    first.push(ByLocation(object?));
+                    if first.len() > limit + 1 {
+                        first.pop();
+                    }
+                    if ordered && first.len() > limit {
+                        break;
+                    }
+                }
+                Ok(first
+                    .into_sorted_vec()
+                    .into_iter()
+                    .map(|ByLocation(object)| relative_to(&base, object))
+                    .collect::<Vec<_>>())
+            })
+            .await?;
+
+        // the extra object tells us whether there's another page
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|object| object.location.to_string())
+        } else {
+            None
         };
+        Ok((page, next))
     }
 
     /// Produces a URL representation of this path that can be read by other systems,
@@ -390,18 +2950,68 @@ impl StorageProvider {
         &self.canonical_url
     }
 
+    /// Produces a URL for the object at `key` that can be passed to [`StorageProvider::get_url`].
+    /// Leading, trailing, and repeated slashes in the key are ignored, and each segment of the
+    /// key is percent-encoded.
+    pub fn object_url(&self, key: &str) -> String {
+        self.url_for_path(&key.into())
+    }
+
+    fn url_for_path(&self, path: &Path) -> String {
+        format!("{}/{}", self.canonical_url.trim_end_matches('/'), path)
+    }
+
     pub fn config(&self) -> &BackendConfig {
         &self.config
     }
+
+    /// Whether this provider's backend supports multipart uploads; see
+    /// [`BackendConfig::supports_multipart`]
+    pub fn supports_multipart(&self) -> bool {
+        self.config.supports_multipart()
+    }
+
+    pub fn supports_signed_urls(&self) -> bool {
+        self.config.supports_signed_urls()
+    }
+
+    /// The underlying object store, for APIs that aren't wrapped by the provider. Paths are
+    /// relative to the bucket (or, for local storage, to the directory); any key in the
+    /// provider's URL isn't applied, so callers must add it to their paths themselves.
+    pub fn object_store(&self) -> Arc<dyn ObjectStore> {
+        self.object_store.clone()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
 
-    use arroyo_types::to_nanos;
+    use arroyo_types::{to_nanos, STORAGE_CONNECT_TIMEOUT_ENV};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::{stream::BoxStream, StreamExt};
+    use object_store::{
+        aws::{AmazonS3Builder, AmazonS3ConfigKey},
+        gcp::{GoogleCloudStorageBuilder, GoogleConfigKey},
+        local::LocalFileSystem,
+        path::Path,
+        ClientConfigKey, ClientOptions, GetOptions, GetResult, ListResult, MultipartId, ObjectMeta,
+        ObjectStore,
+    };
+    use rusoto_core::{credential::StaticProvider, HttpClient, Region};
+    use rusoto_s3::S3Client;
+    use sha2::{Digest, Sha256};
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
-    use crate::{matchers, BackendConfig, StorageProvider};
+    use crate::{
+        create_dir_with_retry, is_transient, matchers, s3_default_headers, s3_stores, AzureConfig,
+        BackendConfig, DedupPut, GCSConfig, GetConditions, GetResponse, RetryConfig, S3Encryption,
+        StorageError, StorageOptions, StorageProvider, DEDUP_INDEX_PREFIX,
+    };
 
     #[test]
     fn test_regex_compilation() {
@@ -417,6 +3027,8 @@ mod tests {
                 region: None,
                 bucket: "mybucket".to_string(),
                 key: Some("puppy.jpg".to_string()),
+                accelerate: false,
+                encryption: None,
             })
         );
 
@@ -431,6 +3043,8 @@ mod tests {
                 region: Some("us-west-2".to_string()),
                 bucket: "my-bucket1".to_string(),
                 key: Some("puppy.jpg".to_string()),
+                accelerate: false,
+                encryption: None,
             })
         );
 
@@ -442,6 +3056,8 @@ mod tests {
                 region: Some("us-east-1".to_string()),
                 bucket: "my-bucket".to_string(),
                 key: None,
+                accelerate: false,
+                encryption: None,
             })
         );
 
@@ -456,6 +3072,8 @@ mod tests {
                 region: Some("us-west-2".to_string()),
                 bucket: "my-bucket".to_string(),
                 key: Some("my/path/test.pdf".to_string()),
+                accelerate: false,
+                encryption: None,
             })
         );
 
@@ -470,43 +3088,1528 @@ mod tests {
                 region: None,
                 bucket: "my-bucket".to_string(),
                 key: Some("path/test.pdf".to_string()),
+                accelerate: false,
+                encryption: None,
             })
         );
-    }
 
-    #[test]
-    fn test_local_configs() {
         assert_eq!(
-            BackendConfig::parse_url("file:///my/path/directory", false).unwrap(),
-            BackendConfig::Local(crate::LocalConfig {
-                path: "/my/path/directory".to_string(),
-                key: None,
+            BackendConfig::parse_url("s3::http://minio.internal/my-bucket/path/test.pdf", false)
+                .unwrap(),
+            BackendConfig::S3(crate::S3Config {
+                endpoint: Some("http://minio.internal:80".to_string()),
+                region: None,
+                bucket: "my-bucket".to_string(),
+                key: Some("path/test.pdf".to_string()),
+                accelerate: false,
+                encryption: None,
             })
         );
 
         assert_eq!(
-            BackendConfig::parse_url("file:/my/path/directory", false).unwrap(),
-            BackendConfig::Local(crate::LocalConfig {
-                path: "/my/path/directory".to_string(),
+            BackendConfig::parse_url("s3::https://minio.internal/my-bucket", false).unwrap(),
+            BackendConfig::S3(crate::S3Config {
+                endpoint: Some("https://minio.internal:443".to_string()),
+                region: None,
+                bucket: "my-bucket".to_string(),
                 key: None,
+                accelerate: false,
+                encryption: None,
             })
         );
 
         assert_eq!(
-            BackendConfig::parse_url("/my/path/directory", false).unwrap(),
-            BackendConfig::Local(crate::LocalConfig {
-                path: "/my/path/directory".to_string(),
-                key: None,
-            })
-        );
+            BackendConfig::parse_url("s3://my-bucket/my/path?accelerate=true", false).unwrap(),
+            BackendConfig::S3(crate::S3Config {
+                endpoint: None,
+                region: None,
+                bucket: "my-bucket".to_string(),
+                key: Some("my/path".to_string()),
+                accelerate: true,
+                encryption: None,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url(
+                "https://my-bucket.s3-accelerate.amazonaws.com/my/path",
+                false
+            )
+            .unwrap(),
+            BackendConfig::S3(crate::S3Config {
+                endpoint: None,
+                region: None,
+                bucket: "my-bucket".to_string(),
+                key: Some("my/path".to_string()),
+                accelerate: true,
+                encryption: None,
+            })
+        );
+
+        assert!(BackendConfig::parse_url("s3://my-bucket?accelerate=yes", false).is_err());
+        assert!(BackendConfig::parse_url("s3://my-bucket?versioning=true", false).is_err());
+    }
+
+    #[test]
+    fn test_r2_configs() {
+        let r2 = |key: Option<&str>| {
+            BackendConfig::S3(crate::S3Config {
+                endpoint: Some("https://0123abcd.r2.cloudflarestorage.com:443".to_string()),
+                region: Some("auto".to_string()),
+                bucket: "my-bucket".to_string(),
+                key: key.map(|s| s.to_string()),
+                accelerate: false,
+                encryption: None,
+            })
+        };
+
+        for url in [
+            "https://0123abcd.r2.cloudflarestorage.com/my-bucket/path/test.pdf",
+            "r2://0123abcd/my-bucket/path/test.pdf",
+            "R2://0123abcd/my-bucket/path/test.pdf",
+        ] {
+            assert_eq!(
+                BackendConfig::parse_url(url, false).unwrap(),
+                r2(Some("path/test.pdf")),
+                "{}",
+                url
+            );
+        }
+
+        assert_eq!(
+            BackendConfig::parse_url("r2://0123abcd/my-bucket", false).unwrap(),
+            r2(None)
+        );
+        assert_eq!(
+            BackendConfig::parse_url("https://0123abcd.r2.cloudflarestorage.com/my-bucket", false)
+                .unwrap(),
+            r2(None)
+        );
+
+        // serialized as a plain endpoint URL, which parses back to the same config
+        let config = r2(Some("path/test.pdf"));
+        assert_eq!(
+            BackendConfig::parse_url(&config.to_url(), false).unwrap(),
+            config
+        );
+
+        assert!(BackendConfig::parse_url("r2://0123abcd", false).is_err());
+        assert!(BackendConfig::parse_url("r2://0123abcd/my-bucket?accelerate=yes", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_s3_accelerate() {
+        let storage = StorageProvider::for_url("s3://my-bucket/checkpoints?accelerate=true")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.canonical_url(),
+            "https://my-bucket.s3-accelerate.amazonaws.com"
+        );
+        assert_eq!(
+            storage.object_url("checkpoints/metadata"),
+            "https://my-bucket.s3-accelerate.amazonaws.com/checkpoints/metadata"
+        );
+
+        assert!(matches!(
+            StorageProvider::for_url(
+                "s3::https://my-custom-endpoint.com:1234/my-bucket/checkpoints?accelerate=true"
+            )
+            .await,
+            Err(StorageError::PathError(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_url_round_trips() {
+        let s3 = |endpoint: Option<&str>, region: Option<&str>, key: Option<&str>, accelerate| {
+            BackendConfig::S3(crate::S3Config {
+                endpoint: endpoint.map(|s| s.to_string()),
+                region: region.map(|s| s.to_string()),
+                bucket: "my-bucket".to_string(),
+                key: key.map(|s| s.to_string()),
+                accelerate,
+                encryption: None,
+            })
+        };
+        let local = |key: Option<&str>, create| {
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: key.map(|s| s.to_string()),
+                create,
+            })
+        };
+
+        let mut configs = vec![
+            BackendConfig::GCS(GCSConfig {
+                bucket: "my-bucket".to_string(),
+                key: None,
+                predefined_acl: None,
+            }),
+            BackendConfig::GCS(GCSConfig {
+                bucket: "my-bucket".to_string(),
+                key: Some("path/test.pdf".to_string()),
+                predefined_acl: None,
+            }),
+        ];
+        for endpoint in [
+            None,
+            Some("http://minio.internal:80"),
+            Some("https://minio:1234"),
+        ] {
+            for region in [None, Some("us-west-2")] {
+                for key in [None, Some("path/test.pdf")] {
+                    configs.push(s3(endpoint, region, key, false));
+                    if endpoint.is_none() {
+                        configs.push(s3(endpoint, region, key, true));
+                    }
+                }
+            }
+        }
+        for account in [None, Some("myaccount")] {
+            for key in [None, Some("path/test.pdf")] {
+                configs.push(BackendConfig::Azure(AzureConfig {
+                    account: account.map(|s| s.to_string()),
+                    container: "my-container".to_string(),
+                    key: key.map(|s| s.to_string()),
+                }));
+            }
+        }
+        for key in [None, Some("my-file.pdf")] {
+            for create in [true, false] {
+                configs.push(local(key, create));
+            }
+        }
+
+        for config in configs {
+            let with_key = match &config {
+                BackendConfig::S3(c) => c.key.is_some(),
+                BackendConfig::GCS(c) => c.key.is_some(),
+                BackendConfig::Azure(c) => c.key.is_some(),
+                BackendConfig::Local(c) => c.key.is_some(),
+            };
+            let url = config.to_url();
+            assert_eq!(
+                BackendConfig::parse_url(&url, with_key).unwrap(),
+                config,
+                "{} did not round-trip",
+                url
+            );
+        }
+
+        // parsing normalizes URLs, after which they round-trip
+        let config =
+            BackendConfig::parse_url("s3::http://minio.internal/my-bucket", false).unwrap();
+        assert_eq!(config.to_url(), "s3::http://minio.internal:80/my-bucket");
+        assert_eq!(
+            local(None, true).to_url(),
+            BackendConfig::parse_url("/my/path/directory", false)
+                .unwrap()
+                .to_url()
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_local_configs() {
+        assert_eq!(
+            BackendConfig::parse_url("file:///C:/data/out", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "C:/data/out".to_string(),
+                key: None,
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("file:///C:/data/out/my-file.pdf", true).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "C:/data/out".to_string(),
+                key: Some("my-file.pdf".to_string()),
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("file:C:/data/out", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "C:/data/out".to_string(),
+                key: None,
+                create: true,
+            })
+        );
+
+        let config = BackendConfig::parse_url("file:///C:/data/out", false).unwrap();
+        assert_eq!(config.to_url(), "file:///C:/data/out");
+    }
+
+    #[test]
+    fn test_local_configs() {
+        assert_eq!(
+            BackendConfig::parse_url("file:///my/path/directory", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: None,
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("file:/my/path/directory", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: None,
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("/my/path/directory", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: None,
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("/my/path/directory/my-file.pdf", true).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: Some("my-file.pdf".to_string()),
+                create: true,
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url("file:///my/path/directory?create=false", false).unwrap(),
+            BackendConfig::Local(crate::LocalConfig {
+                path: "/my/path/directory".to_string(),
+                key: None,
+                create: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_azure_configs() {
+        assert_eq!(
+            BackendConfig::parse_url(
+                "https://myaccount.blob.core.windows.net/my-container/path/test.pdf",
+                false
+            )
+            .unwrap(),
+            BackendConfig::Azure(AzureConfig {
+                account: Some("myaccount".to_string()),
+                container: "my-container".to_string(),
+                key: Some("path/test.pdf".to_string()),
+            })
+        );
+
+        assert_eq!(
+            BackendConfig::parse_url(
+                "https://myaccount.blob.core.windows.net/my-container",
+                false
+            )
+            .unwrap(),
+            BackendConfig::Azure(AzureConfig {
+                account: Some("myaccount".to_string()),
+                container: "my-container".to_string(),
+                key: None,
+            })
+        );
+
+        // the account comes from $AZURE_STORAGE_ACCOUNT, which isn't set in tests
+        assert_eq!(
+            BackendConfig::parse_url("az://my-container/path/test.pdf", false).unwrap(),
+            BackendConfig::Azure(AzureConfig {
+                account: None,
+                container: "my-container".to_string(),
+                key: Some("path/test.pdf".to_string()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_azure_construction() {
+        let storage =
+            StorageProvider::for_url("https://myaccount.blob.core.windows.net/my-container")
+                .await
+                .unwrap();
+        assert_eq!(
+            storage.canonical_url(),
+            "https://myaccount.blob.core.windows.net/my-container"
+        );
+        assert_eq!(
+            storage.object_url("checkpoints/metadata"),
+            "https://myaccount.blob.core.windows.net/my-container/checkpoints/metadata"
+        );
+
+        // without an account, there's nothing to connect to
+        assert!(matches!(
+            StorageProvider::for_url("az://my-container").await,
+            Err(StorageError::PathError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_supports_multipart() {
+        for url in [
+            "s3://mybucket/puppy.jpg",
+            "https://s3.us-west-2.amazonaws.com/my-bucket1/path/test.pdf",
+            "gs://mybucket/puppy.jpg",
+            "https://myaccount.blob.core.windows.net/my-container/path/test.pdf",
+        ] {
+            assert!(
+                BackendConfig::parse_url(url, false)
+                    .unwrap()
+                    .supports_multipart(),
+                "{}",
+                url
+            );
+        }
+
+        assert!(
+            !BackendConfig::parse_url("file:///tmp/arroyo-testing", false)
+                .unwrap()
+                .supports_multipart()
+        );
+
+        let dir = format!(
+            "/tmp/arroyo-testing/supports-multipart-{}",
+            to_nanos(SystemTime::now())
+        );
+        let storage = StorageProvider::for_url(&format!("file://{}", dir))
+            .await
+            .unwrap();
+        assert!(!storage.supports_multipart());
+
+        // put_multipart still works against local storage, through its staging file
+        let mut writer = storage.put_multipart("parts").await.unwrap();
+        writer.write_all(b"first, ").await.unwrap();
+        writer.write_all(b"second").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(storage.get("parts").await.unwrap(), &b"first, second"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_local_create() {
+        let path = format!("/tmp/arroyo-testing/create-{}", to_nanos(SystemTime::now()));
+
+        // without create=false, the directory is created
+        StorageProvider::for_url(&format!("file://{}", path))
+            .await
+            .unwrap();
+        assert!(tokio::fs::metadata(&path).await.unwrap().is_dir());
+    }
+
+    #[test]
+    fn test_client_timeouts() {
+        let options = StorageOptions::default().with_request_timeout(Duration::from_secs(10));
+
+        let mut s3 = AmazonS3Builder::new();
+        let mut gcs = GoogleCloudStorageBuilder::new();
+        for (key, value) in options.client_timeouts().unwrap() {
+            s3 = s3.with_config(AmazonS3ConfigKey::Client(key), value.clone());
+            gcs = gcs.with_config(GoogleConfigKey::Client(key), value);
+        }
+
+        assert_eq!(
+            s3.get_config_value(&AmazonS3ConfigKey::Client(ClientConfigKey::Timeout))
+                .as_deref(),
+            Some("10000ms")
+        );
+        assert_eq!(
+            gcs.get_config_value(&GoogleConfigKey::Client(ClientConfigKey::Timeout))
+                .as_deref(),
+            Some("10000ms")
+        );
+
+        // the connect timeout falls back to the default
+        if std::env::var(STORAGE_CONNECT_TIMEOUT_ENV).is_err() {
+            assert_eq!(
+                s3.get_config_value(&AmazonS3ConfigKey::Client(ClientConfigKey::ConnectTimeout))
+                    .as_deref(),
+                Some("5000ms")
+            );
+            assert_eq!(
+                gcs.get_config_value(&GoogleConfigKey::Client(ClientConfigKey::ConnectTimeout))
+                    .as_deref(),
+                Some("5000ms")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_for_url_with_options() {
+        let options = StorageOptions {
+            validate_on_construct: false,
+            dir_mode: Some(0o700),
+            gcs_predefined_acl: Some("publicRead".to_string()),
+            request_timeout: Some(Duration::from_secs(10)),
+            connect_timeout: Some(Duration::from_secs(2)),
+            max_retries: Some(3),
+            retry_timeout: Some(Duration::from_secs(30)),
+            s3_path_style: Some(true),
+            s3_storage_class: Some("STANDARD_IA".to_string()),
+            s3_object_tags: vec![("env".to_string(), "prod".to_string())],
+            read_your_writes: None,
+            retry_classifier: None,
+            retry: None,
+            delete_batch_size: None,
+            s3_encryption: None,
+        };
+
+        let s3 = StorageProvider::for_url_with_options(
+            "s3::http://localhost:9000/my-bucket",
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        let BackendConfig::S3(config) = s3.config() else {
+            panic!("expected an S3 config, got {:?}", s3.config());
+        };
+        assert_eq!(config.bucket, "my-bucket");
+
+        let path = format!(
+            "/tmp/arroyo-testing/options-{}",
+            to_nanos(SystemTime::now())
+        );
+        let local =
+            StorageProvider::for_url_with_options(&format!("file://{}", path), options.clone())
+                .await
+                .unwrap();
+        assert_eq!(local.canonical_url(), format!("file://{}", path));
+
+        // transfer acceleration requires virtual-hosted style requests
+        assert!(matches!(
+            StorageProvider::for_url_with_options("s3://my-bucket?accelerate=true", options).await,
+            Err(StorageError::PathError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_dir_retries() {
+        let path = format!("/tmp/arroyo-testing/retry-{}", to_nanos(SystemTime::now()));
+
+        // fails once with a transient error, then creates the directory
+        let attempts = AtomicUsize::new(0);
+        create_dir_with_retry(&path, |path| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    Err(std::io::Error::from(std::io::ErrorKind::WouldBlock))
+                } else {
+                    tokio::fs::create_dir_all(path).await
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(tokio::fs::metadata(&path).await.unwrap().is_dir());
+
+        // gives up after three attempts
+        let attempts = AtomicUsize::new(0);
+        let result = create_dir_with_retry(&path, |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async { Err(std::io::Error::from(std::io::ErrorKind::WouldBlock)) }
+        })
+        .await;
+        assert!(matches!(result, Err(StorageError::PathError(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_local_no_create() {
+        let missing = format!(
+            "/tmp/arroyo-testing/no-create-{}",
+            to_nanos(SystemTime::now())
+        );
+        assert!(matches!(
+            StorageProvider::for_url(&format!("file://{}?create=false", missing)).await,
+            Err(StorageError::PathError(_))
+        ));
+        assert!(!tokio::fs::try_exists(&missing).await.unwrap());
+
+        tokio::fs::create_dir_all("/tmp/arroyo-testing/no-create-existing")
+            .await
+            .unwrap();
+        assert!(StorageProvider::for_url(
+            "file:///tmp/arroyo-testing/no-create-existing?create=false"
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_object_url() {
+        let s3 = StorageProvider::for_url("s3://my-bucket/checkpoints")
+            .await
+            .unwrap();
+        let region = match s3.config() {
+            BackendConfig::S3(config) => config.region.clone(),
+            _ => unreachable!(),
+        };
+        let base = match region {
+            Some(region) => format!("https://s3.{}.amazonaws.com/my-bucket", region),
+            None => "https://s3.amazonaws.com/my-bucket".to_string(),
+        };
+
+        assert_eq!(
+            s3.object_url("a/b.parquet"),
+            format!("{}/a/b.parquet", base)
+        );
+        assert_eq!(
+            s3.object_url("/a/b.parquet"),
+            format!("{}/a/b.parquet", base)
+        );
+        assert_eq!(
+            s3.object_url("a/my file#1.json"),
+            format!("{}/a/my file%231.json", base)
+        );
+
+        let local = StorageProvider::for_url("file:///tmp/arroyo-testing/object-url/")
+            .await
+            .unwrap();
+        assert_eq!(
+            local.object_url("key.bin"),
+            "file:///tmp/arroyo-testing/object-url/key.bin"
+        );
+        assert_eq!(
+            local.object_url("/key.bin"),
+            "file:///tmp/arroyo-testing/object-url/key.bin"
+        );
+
+        local.put("my#file.bin", vec![1, 2, 3]).await.unwrap();
+        assert_eq!(
+            StorageProvider::get_url(&local.object_url("my#file.bin"))
+                .await
+                .unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolved_s3_config() {
+        std::env::set_var("AWS_REGION", "eu-west-3");
+        let storage = StorageProvider::for_url("s3://my-bucket/checkpoints").await;
+        std::env::remove_var("AWS_REGION");
+
+        let storage = storage.unwrap();
+        let BackendConfig::S3(config) = storage.config() else {
+            panic!("expected an S3 config");
+        };
+        assert_eq!(config.region.as_deref(), Some("eu-west-3"));
+        assert_eq!(
+            storage.canonical_url(),
+            "https://s3.eu-west-3.amazonaws.com/my-bucket"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_multipart() {
+        let dir = format!(
+            "/tmp/arroyo-testing/put-multipart-{}",
+            to_nanos(SystemTime::now())
+        );
+        let storage = StorageProvider::for_url(&format!("file://{}", dir))
+            .await
+            .unwrap();
+
+        let mut writer = storage.put_multipart("complete").await.unwrap();
+        writer.write_all(b"hello, ").await.unwrap();
+        writer.write_all(b"world").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(storage.get("complete").await.unwrap(), &b"hello, world"[..]);
+
+        // dropping the writer before shutting it down aborts the upload, removing its parts
+        let mut writer = storage.put_multipart("dropped").await.unwrap();
+        writer.write_all(b"partial").await.unwrap();
+        drop(writer);
+
+        let start = Instant::now();
+        loop {
+            let files: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            if files == ["complete"] {
+                break;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "upload wasn't aborted: {:?}",
+                files
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!storage.exists("dropped").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_put_reader() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/put-reader")
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..7 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let written = storage
+            .put_reader("large", std::io::Cursor::new(data.clone()), 3 * 1024 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(written, data.len());
+        assert_eq!(storage.get("large").await.unwrap(), data);
+
+        let written = storage
+            .put_reader(
+                "empty",
+                std::io::Cursor::new(Vec::<u8>::new()),
+                3 * 1024 * 1024,
+            )
+            .await
+            .unwrap();
+        assert_eq!(written, 0);
+        assert!(storage.get("empty").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_put_multipart_with_progress() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/put-progress")
+            .await
+            .unwrap();
+
+        const MB: usize = 1024 * 1024;
+        let data: Vec<u8> = (0..15 * MB).map(|i| (i % 251) as u8).collect();
+        let totals = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let reported = totals.clone();
+        let written = storage
+            .put_multipart_with_progress(
+                "large",
+                std::io::Cursor::new(data.clone()),
+                5 * MB,
+                move |total| reported.lock().unwrap().push(total),
+            )
+            .await
+            .unwrap();
+        assert_eq!(written, data.len());
+        assert_eq!(storage.get("large").await.unwrap(), data);
+
+        assert_eq!(
+            *totals.lock().unwrap(),
+            vec![5 * MB as u64, 10 * MB as u64, 15 * MB as u64]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_stream() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/get-stream")
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        storage.put("large", data.clone()).await.unwrap();
+
+        let mut read = vec![];
+        storage
+            .get_stream("large")
+            .await
+            .unwrap()
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+
+        assert!(matches!(
+            storage.get_stream("missing").await.err(),
+            Some(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_head_and_exists() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/head")
+            .await
+            .unwrap();
+        let key = format!("object-{}", to_nanos(SystemTime::now()));
+
+        assert!(!storage.exists(&key).await.unwrap());
+        assert!(matches!(
+            storage.head(&key).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+
+        let before = chrono::Utc::now() - chrono::Duration::seconds(5);
+        storage.put(&key, vec![0; 1234]).await.unwrap();
+        assert!(storage.exists(&key).await.unwrap());
+
+        let meta = storage.head(&key).await.unwrap();
+        assert_eq!(meta.location.to_string(), key);
+        assert_eq!(meta.size, 1234);
+        // from the file's mtime
+        assert!(meta.last_modified > before);
+        assert!(meta.e_tag.is_some());
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_dedup() {
+        let storage = StorageProvider::for_url(&format!(
+            "file:///tmp/arroyo-testing/dedup-{}",
+            to_nanos(SystemTime::now())
+        ))
+        .await
+        .unwrap();
+
+        let first = storage
+            .put_dedup("first", b"same bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(first, DedupPut::Uploaded(storage.object_url("first")));
+
+        // identical content skips the upload and points at the first object
+        let second = storage
+            .put_dedup("second", b"same bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(second, DedupPut::Existing(storage.object_url("first")));
+        assert!(!storage.exists("second").await.unwrap());
+
+        let other = storage
+            .put_dedup("other", b"other bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(other, DedupPut::Uploaded(storage.object_url("other")));
+
+        // once the indexed object is gone, the content is uploaded again
+        storage.delete_if_present("first").await.unwrap();
+        let third = storage
+            .put_dedup("third", b"same bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(third, DedupPut::Uploaded(storage.object_url("third")));
+        assert_eq!(storage.get("third").await.unwrap(), &b"same bytes"[..]);
+
+        // a provider with a key keeps its index beneath that key
+        let config =
+            BackendConfig::parse_url(&format!("{}/keyed", storage.canonical_url()), true).unwrap();
+        let keyed = StorageProvider::construct(config, &StorageOptions::default())
+            .await
+            .unwrap();
+        let uploaded = keyed
+            .put_dedup("keyed/first", b"same bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            uploaded,
+            DedupPut::Uploaded(keyed.object_url("keyed/first"))
+        );
+        let hash = format!("{:x}", Sha256::digest(b"same bytes"));
+        assert!(keyed
+            .exists(format!("keyed/{}/{}", DEDUP_INDEX_PREFIX, hash))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/get-range")
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        storage.put("object", data.clone()).await.unwrap();
+
+        assert_eq!(
+            storage.get_range("object", 100..228).await.unwrap(),
+            data[100..228]
+        );
+        assert!(storage
+            .get_range("object", 10..10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let ranges = storage
+            .get_ranges("object", &[900..1000, 0..4, 2..10])
+            .await
+            .unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0], data[900..1000]);
+        assert_eq!(ranges[1], data[0..4]);
+        assert_eq!(ranges[2], data[2..10]);
+
+        assert!(matches!(
+            storage.get_range("missing", 0..10).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_tail() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/get-tail")
+            .await
+            .unwrap();
+
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        storage.put("object", data.clone()).await.unwrap();
+
+        assert_eq!(storage.get_tail("object", 128).await.unwrap(), data[872..]);
+        // objects smaller than the tail are read in full
+        assert_eq!(storage.get_tail("object", 4096).await.unwrap(), data);
+        assert!(storage.get_tail("object", 0).await.unwrap().is_empty());
+
+        assert!(matches!(
+            storage.get_tail("missing", 128).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_page() {
+        let storage = StorageProvider::for_url(&format!(
+            "file:///tmp/arroyo-testing/page-{}",
+            to_nanos(SystemTime::now())
+        ))
+        .await
+        .unwrap();
+
+        for i in (0..5).rev() {
+            storage
+                .put(format!("keys/key-{}", i), vec![i as u8])
+                .await
+                .unwrap();
+        }
+        storage.put("other/key", vec![0]).await.unwrap();
+
+        let mut pages = vec![];
+        let mut continuation = None;
+        loop {
+            let (page, next) = storage.page("keys", continuation, 2).await.unwrap();
+            pages.push(
+                page.into_iter()
+                    .map(|object| object.location.filename().unwrap().to_string())
+                    .collect::<Vec<_>>(),
+            );
+            continuation = next;
+            if continuation.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(
+            pages,
+            vec![
+                vec!["key-0", "key-1"],
+                vec!["key-2", "key-3"],
+                vec!["key-4"]
+            ]
+        );
+
+        // with a key, prefixes, locations, and tokens are relative to it
+        let config =
+            BackendConfig::parse_url(&format!("{}/keys", storage.canonical_url()), true).unwrap();
+        let keyed = StorageProvider::construct(config, &StorageOptions::default())
+            .await
+            .unwrap();
+        let (page, next) = keyed.page("", None, 3).await.unwrap();
+        let locations: Vec<_> = page.iter().map(|o| o.location.to_string()).collect();
+        assert_eq!(locations, vec!["key-0", "key-1", "key-2"]);
+        assert_eq!(next.as_deref(), Some("key-2"));
+        let (page, next) = keyed.page("", next, 3).await.unwrap();
+        let locations: Vec<_> = page.iter().map(|o| o.location.to_string()).collect();
+        assert_eq!(locations, vec!["key-3", "key-4"]);
+        assert_eq!(next, None);
+    }
+
+    #[tokio::test]
+    async fn test_put_batch() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/put-batch")
+            .await
+            .unwrap();
+
+        let now = to_nanos(SystemTime::now());
+        let items: Vec<_> = ["manifest", "_SUCCESS", "marker"]
+            .iter()
+            .map(|name| (format!("{}/{}", now, name), name.as_bytes().to_vec()))
+            .collect();
+
+        let urls = storage.put_batch(items.clone(), 2).await.unwrap();
+        assert_eq!(
+            urls,
+            items
+                .iter()
+                .map(|(key, _)| format!("file:///tmp/arroyo-testing/put-batch/{}", key))
+                .collect::<Vec<_>>()
+        );
+
+        for (key, bytes) in items {
+            assert_eq!(storage.get(&key).await.unwrap(), bytes);
+            storage.delete_if_present(&key).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_many() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/delete-many")
+            .await
+            .unwrap();
+
+        let now = to_nanos(SystemTime::now());
+        let existing: Vec<_> = (0..20).map(|i| format!("{}/state-{}", now, i)).collect();
+        for key in &existing {
+            storage.put(key.clone(), b"data".to_vec()).await.unwrap();
+        }
+
+        let mut keys = existing.clone();
+        keys.push(format!("{}/missing-1", now));
+        keys.insert(5, format!("{}/missing-2", now));
+        storage.delete_many(keys).await.unwrap();
+
+        for key in existing {
+            assert!(matches!(
+                storage.get(&key).await,
+                Err(StorageError::ObjectStore(
+                    object_store::Error::NotFound { .. }
+                ))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_if_absent() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/put-if-absent")
+            .await
+            .unwrap();
+
+        let key = format!("committed/{}", to_nanos(SystemTime::now()));
+
+        assert!(storage.put_if_absent(&key, vec![1]).await.unwrap());
+        assert!(!storage.put_if_absent(&key, vec![2]).await.unwrap());
+        assert_eq!(storage.get(&key).await.unwrap(), vec![1]);
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conditional_puts() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/conditional-puts")
+            .await
+            .unwrap();
+
+        let key = format!("metadata/{}", to_nanos(SystemTime::now()));
+
+        // updates of missing objects fail
+        assert!(matches!(
+            storage.put_if_match(&key, vec![1], "etag").await,
+            Err(StorageError::PreconditionFailed { .. })
+        ));
+
+        storage.put_if_not_exists(&key, vec![1]).await.unwrap();
+        match storage.put_if_not_exists(&key, vec![2]).await {
+            Err(StorageError::PreconditionFailed {
+                key: failed,
+                reason,
+            }) => {
+                assert_eq!(failed, key);
+                // the local filesystem creates objects atomically
+                assert_eq!(reason, "an object already exists");
+            }
+            result => panic!("expected a failed precondition, got {:?}", result),
+        }
+        assert_eq!(storage.get(&key).await.unwrap(), vec![1]);
+
+        let etag = storage.head(&key).await.unwrap().e_tag.unwrap();
+        assert!(matches!(
+            storage.put_if_match(&key, vec![3], "stale").await,
+            Err(StorageError::PreconditionFailed { .. })
+        ));
+        assert_eq!(storage.get(&key).await.unwrap(), vec![1]);
+
+        storage.put_if_match(&key, vec![4, 4], &etag).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), vec![4, 4]);
+
+        // the etag changed with the write, so reusing it fails
+        assert!(matches!(
+            storage.put_if_match(&key, vec![5], &etag).await,
+            Err(StorageError::PreconditionFailed { .. })
+        ));
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_put_atomic() {
+        let dir = format!(
+            "/tmp/arroyo-testing/put-atomic-{}",
+            to_nanos(SystemTime::now())
+        );
+        let storage = StorageProvider::for_url(&format!("file://{}", dir))
+            .await
+            .unwrap();
+
+        storage
+            .put_atomic("data/object", b"first".to_vec())
+            .await
+            .unwrap();
+        let url = storage
+            .put_atomic("data/object", b"second".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(url, storage.object_url("data/object"));
+        assert_eq!(&storage.get("data/object").await.unwrap()[..], b"second");
+
+        // only the final key is left behind
+        let mut entries = std::fs::read_dir(format!("{}/data", dir))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, vec!["object".to_string()]);
+    }
+
+    /// Wraps the local filesystem, counting single and bulk deletes. The first `failing_puts`
+    /// puts fail as if the connection was reset.
+    #[derive(Debug)]
+    struct CountingStore {
+        inner: LocalFileSystem,
+        deletes: AtomicUsize,
+        delete_streams: AtomicUsize,
+        failing_puts: AtomicUsize,
+        failing_aborts: AtomicUsize,
+    }
+
+    impl CountingStore {
+        fn new(dir: &str) -> Self {
+            std::fs::create_dir_all(dir).unwrap();
+            Self {
+                inner: LocalFileSystem::new_with_prefix(dir).unwrap(),
+                deletes: AtomicUsize::new(0),
+                delete_streams: AtomicUsize::new(0),
+                failing_puts: AtomicUsize::new(0),
+                failing_aborts: AtomicUsize::new(0),
+            }
+        }
+
+        fn provider(self: &Arc<Self>, dir: &str) -> StorageProvider {
+            StorageProvider {
+                config: BackendConfig::parse_url(&format!("file://{}", dir), false).unwrap(),
+                object_store: self.clone(),
+                canonical_url: format!("file://{}", dir),
+                read_your_writes: None,
+                retry_classifier: None,
+                retry: None,
+                delete_batch_size: None,
+                signer: None,
+            }
+        }
+    }
 
-        assert_eq!(
-            BackendConfig::parse_url("/my/path/directory/my-file.pdf", true).unwrap(),
-            BackendConfig::Local(crate::LocalConfig {
-                path: "/my/path/directory".to_string(),
-                key: Some("my-file.pdf".to_string()),
+    impl std::fmt::Display for CountingStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "CountingStore")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for CountingStore {
+        async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
+            let failing = self
+                .failing_puts
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Err(object_store::Error::Generic {
+                    store: "S3",
+                    source: Box::new(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "connection reset by peer",
+                    )),
+                });
+            }
+            self.inner.put(location, bytes).await
+        }
+
+        async fn put_multipart(
+            &self,
+            location: &Path,
+        ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+            self.inner.put_multipart(location).await
+        }
+
+        async fn abort_multipart(
+            &self,
+            location: &Path,
+            multipart_id: &MultipartId,
+        ) -> object_store::Result<()> {
+            let failing = self
+                .failing_aborts
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Err(object_store::Error::Generic {
+                    store: "S3",
+                    source: "abort rejected".into(),
+                });
+            }
+            self.inner.abort_multipart(location, multipart_id).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &Path,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+            self.inner.head(location).await
+        }
+
+        async fn delete(&self, location: &Path) -> object_store::Result<()> {
+            self.deletes.fetch_add(1, Ordering::SeqCst);
+            self.inner.delete(location).await
+        }
+
+        fn delete_stream<'a>(
+            &'a self,
+            locations: BoxStream<'a, object_store::Result<Path>>,
+        ) -> BoxStream<'a, object_store::Result<Path>> {
+            self.delete_streams.fetch_add(1, Ordering::SeqCst);
+            self.inner.delete_stream(locations)
+        }
+
+        async fn list(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+            self.inner.list(prefix).await
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_many_batches() {
+        let dir = format!(
+            "/tmp/arroyo-testing/delete-batches-{}",
+            to_nanos(SystemTime::now())
+        );
+        let store = Arc::new(CountingStore::new(&dir));
+        let storage = StorageProvider {
+            delete_batch_size: Some(20),
+            ..store.provider(&dir)
+        };
+
+        let keys: Vec<_> = (0..50).map(|i| format!("superseded/file-{}", i)).collect();
+        for key in &keys {
+            storage.put(key.clone(), b"data".to_vec()).await.unwrap();
+        }
+        storage.delete_many(keys.clone()).await.unwrap();
+
+        // three bulk deletes of at most 20 keys, rather than 50 single deletes
+        assert_eq!(store.delete_streams.load(Ordering::SeqCst), 3);
+        assert_eq!(store.deletes.load(Ordering::SeqCst), 0);
+        assert!(storage.list("superseded").await.unwrap().is_empty());
+    }
+
+    /// A reader whose reads always fail
+    struct FailingReader;
+
+    impl tokio::io::AsyncRead for FailingReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "source read failed",
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_error_survives_failed_abort() {
+        let dir = format!(
+            "/tmp/arroyo-testing/failed-abort-{}",
+            to_nanos(SystemTime::now())
+        );
+        let store = Arc::new(CountingStore::new(&dir));
+        store.failing_aborts.store(1, Ordering::SeqCst);
+
+        // the first part is uploaded, then reading the second fails
+        let reader = std::io::Cursor::new(vec![0u8; 4]).chain(FailingReader);
+        let result = store
+            .provider(&dir)
+            .put_multipart_with_progress("object", reader, 4, |_| {})
+            .await;
+
+        // the abort's failure is only logged, so the read error is what's returned
+        match result {
+            Err(StorageError::IoError(e)) => assert_eq!(e.to_string(), "source read failed"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(store.failing_aborts.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transient_retries() {
+        let dir = format!(
+            "/tmp/arroyo-testing/transient-retries-{}",
+            to_nanos(SystemTime::now())
+        );
+        let store = Arc::new(CountingStore::new(&dir));
+        let storage = StorageProvider {
+            retry: Some(RetryConfig {
+                max_retries: 3,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(5),
+            }),
+            ..store.provider(&dir)
+        };
+
+        // transient errors are retried until the put succeeds
+        store.failing_puts.store(2, Ordering::SeqCst);
+        storage.put("object", b"data".to_vec()).await.unwrap();
+        assert_eq!(&storage.get("object").await.unwrap()[..], b"data");
+
+        store.failing_puts.store(10, Ordering::SeqCst);
+        assert!(matches!(
+            storage.put("object", b"data".to_vec()).await,
+            Err(StorageError::RetriesExhausted {
+                attempts: 4,
+                source: object_store::Error::Generic { .. }
             })
+        ));
+
+        // without a retry config, they fail straight away
+        store.failing_puts.store(1, Ordering::SeqCst);
+        assert!(matches!(
+            store.provider(&dir).put("object", b"data".to_vec()).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::Generic { .. }
+            ))
+        ));
+
+        // missing objects and auth failures aren't transient
+        assert!(matches!(
+            storage.get("missing").await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+        assert!(!is_transient(&object_store::Error::Generic {
+            store: "S3",
+            source: "403 Forbidden".into(),
+        }));
+        // errors are classified by type, not by what their messages happen to contain
+        assert!(!is_transient(&object_store::Error::Generic {
+            store: "S3",
+            source: "failed to parse logs/503/part-500.json: timed out waiting for a brace".into(),
+        }));
+        assert!(is_transient(&object_store::Error::Generic {
+            store: "S3",
+            source: Box::new(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "operation timed out",
+            )),
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_get_conditional() {
+        let storage = StorageProvider::for_url(&format!(
+            "file:///tmp/arroyo-testing/get-conditional-{}",
+            to_nanos(SystemTime::now())
+        ))
+        .await
+        .unwrap();
+        storage.put("object", b"first".to_vec()).await.unwrap();
+
+        let GetResponse::Modified(bytes, meta) = storage
+            .get_conditional("object", GetConditions::default())
+            .await
+            .unwrap()
+        else {
+            panic!("expected the object to be read without conditions");
+        };
+        assert_eq!(&bytes[..], b"first");
+        let etag = meta.e_tag.unwrap();
+
+        let conditions = GetConditions {
+            if_none_match: Some(etag.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            storage
+                .get_conditional("object", conditions.clone())
+                .await
+                .unwrap(),
+            GetResponse::NotModified
+        ));
+
+        // a changed object no longer matches the old etag
+        storage.put("object", b"second!".to_vec()).await.unwrap();
+        let GetResponse::Modified(bytes, meta) =
+            storage.get_conditional("object", conditions).await.unwrap()
+        else {
+            panic!("expected the changed object to be read");
+        };
+        assert_eq!(&bytes[..], b"second!");
+        // the metadata is that of the bytes that were read
+        assert_eq!(meta.size, bytes.len());
+        assert_eq!(meta.e_tag, storage.head("object").await.unwrap().e_tag,);
+        assert_ne!(meta.e_tag.unwrap(), etag);
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let dir = format!("/tmp/arroyo-testing/list-{}", to_nanos(SystemTime::now()));
+        let storage = StorageProvider::for_url(&format!("file://{}", dir))
+            .await
+            .unwrap();
+        for key in ["data/checkpoints/a", "data/checkpoints/b/c", "data/other"] {
+            storage.put(key, b"x".to_vec()).await.unwrap();
+        }
+
+        let mut locations: Vec<_> = storage
+            .list("data/checkpoints")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|object| object.location.to_string())
+            .collect();
+        locations.sort();
+        assert_eq!(
+            locations,
+            vec!["data/checkpoints/a", "data/checkpoints/b/c"]
+        );
+
+        // with a key, prefixes and locations are relative to it
+        let config = BackendConfig::parse_url(&format!("file://{}/data", dir), true).unwrap();
+        let keyed = StorageProvider::construct(config, &StorageOptions::default())
+            .await
+            .unwrap();
+        let mut locations: Vec<_> = keyed
+            .list_stream("checkpoints")
+            .await
+            .unwrap()
+            .map(|object| object.unwrap().location.to_string())
+            .collect()
+            .await;
+        locations.sort();
+        assert_eq!(locations, vec!["checkpoints/a", "checkpoints/b/c"]);
+    }
+
+    #[tokio::test]
+    async fn test_validate_on_construct() {
+        let options = StorageOptions {
+            validate_on_construct: true,
+            ..Default::default()
+        };
+
+        let missing = format!(
+            "file:///tmp/arroyo-testing/missing-{}",
+            to_nanos(SystemTime::now())
+        );
+        assert!(matches!(
+            StorageProvider::for_url_with_options(&missing, options.clone()).await,
+            Err(StorageError::BucketNotFound(_))
+        ));
+
+        tokio::fs::create_dir_all("/tmp/arroyo-testing/validated")
+            .await
+            .unwrap();
+        assert!(StorageProvider::for_url_with_options(
+            "file:///tmp/arroyo-testing/validated",
+            options
+        )
+        .await
+        .is_ok());
+    }
+
+    #[test]
+    fn test_gcs_predefined_acl() {
+        assert_eq!(
+            GCSConfig::acl_header_value("publicRead").unwrap(),
+            "public-read"
+        );
+        assert_eq!(
+            GCSConfig::acl_header_value("bucketOwnerFullControl").unwrap(),
+            "bucket-owner-full-control"
+        );
+        assert!(matches!(
+            GCSConfig::acl_header_value("public-read"),
+            Err(StorageError::InvalidPredefinedAcl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_gcs_predefined_acl_construction() {
+        let storage = StorageProvider::for_url_with_options(
+            "gs://my-bucket/checkpoints",
+            StorageOptions::default().with_gcs_predefined_acl("publicRead"),
+        )
+        .await
+        .unwrap();
+        let BackendConfig::GCS(config) = storage.config() else {
+            panic!("expected a GCS config");
+        };
+        assert_eq!(config.predefined_acl.as_deref(), Some("publicRead"));
+
+        assert!(matches!(
+            StorageProvider::for_url_with_options(
+                "gs://my-bucket/checkpoints",
+                StorageOptions::default().with_gcs_predefined_acl("everyone"),
+            )
+            .await,
+            Err(StorageError::InvalidPredefinedAcl(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_local_dir_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = format!(
+            "/tmp/arroyo-testing/dir-mode-{}",
+            to_nanos(SystemTime::now())
         );
+        StorageProvider::for_url_with_options(
+            &format!("file://{}", path),
+            StorageOptions::default().with_dir_mode(0o700),
+        )
+        .await
+        .unwrap();
+
+        let metadata = tokio::fs::metadata(&path).await.unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
     }
 
     #[tokio::test]
@@ -537,4 +4640,569 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn test_read_your_writes() {
+        let url = "file:///tmp/arroyo-testing/read-your-writes";
+        let key = format!("key-{}", to_nanos(SystemTime::now()));
+
+        // the object only appears after the first get has failed with NotFound
+        let storage = StorageProvider::for_url_with_options(
+            url,
+            StorageOptions::default().with_read_your_writes(Duration::from_secs(5)),
+        )
+        .await
+        .unwrap();
+        let writer = storage.clone();
+        let write_key = key.clone();
+        let put = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.put(write_key, b"hello".to_vec()).await.unwrap();
+        });
+        assert_eq!(&storage.get(&key).await.unwrap()[..], b"hello");
+        put.await.unwrap();
+
+        // without the option, a missing object fails straight away
+        let storage = StorageProvider::for_url(url).await.unwrap();
+        assert!(matches!(
+            storage.get(format!("{}-missing", key)).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry_classifier() {
+        let url = "file:///tmp/arroyo-testing/retry-classifier";
+        let key = format!("key-{}", to_nanos(SystemTime::now()));
+
+        // NotFound is normally fatal, but the classifier makes it retryable
+        let classified = Arc::new(AtomicUsize::new(0));
+        let counter = classified.clone();
+        let storage = StorageProvider::for_url_with_options(
+            url,
+            StorageOptions::default()
+                .with_retry(RetryConfig {
+                    max_retries: 50,
+                    initial_backoff: Duration::from_millis(10),
+                    max_backoff: Duration::from_millis(50),
+                })
+                .with_retry_classifier(move |e| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    matches!(e, object_store::Error::NotFound { .. })
+                }),
+        )
+        .await
+        .unwrap();
+        let writer = storage.clone();
+        let write_key = key.clone();
+        let put = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.put(write_key, b"hello".to_vec()).await.unwrap();
+        });
+        assert_eq!(&storage.get(&key).await.unwrap()[..], b"hello");
+        put.await.unwrap();
+        assert!(classified.load(Ordering::SeqCst) >= 1);
+
+        // errors the classifier rejects fail straight away
+        let storage = StorageProvider::for_url_with_options(
+            url,
+            StorageOptions::default().with_retry_classifier(|_| false),
+        )
+        .await
+        .unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            storage.get(format!("{}-missing", key)).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotFound { .. }
+            ))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // but the classifier doesn't replace the read_your_writes retries
+        let storage = StorageProvider::for_url_with_options(
+            url,
+            StorageOptions::default()
+                .with_read_your_writes(Duration::from_secs(5))
+                .with_retry_classifier(|_| false),
+        )
+        .await
+        .unwrap();
+        let late_key = format!("{}-late", key);
+        let writer = storage.clone();
+        let write_key = late_key.clone();
+        let put = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            writer.put(write_key, b"late".to_vec()).await.unwrap();
+        });
+        assert_eq!(&storage.get(&late_key).await.unwrap()[..], b"late");
+        put.await.unwrap();
+        storage.delete_if_present(&late_key).await.unwrap();
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_signed_url() {
+        // static credentials, so nothing is looked up while signing
+        let s3 = Arc::new(
+            AmazonS3Builder::new()
+                .with_bucket_name("my-bucket")
+                .with_region("us-west-2")
+                .with_access_key_id("AKIDEXAMPLE")
+                .with_secret_access_key("secret")
+                .build()
+                .unwrap(),
+        );
+        let provider = StorageProvider {
+            config: BackendConfig::parse_url("s3://my-bucket", false).unwrap(),
+            object_store: s3.clone(),
+            canonical_url: "https://s3.us-west-2.amazonaws.com/my-bucket".to_string(),
+            read_your_writes: None,
+            retry_classifier: None,
+            retry: None,
+            delete_batch_size: None,
+            signer: Some(s3),
+        };
+
+        let url = provider
+            .signed_url("path/to/object.parquet", Duration::from_secs(300))
+            .await
+            .unwrap();
+        let (base, query) = url.split_once('?').unwrap();
+        assert!(base.ends_with("/path/to/object.parquet"));
+        let params: Vec<_> = query.split('&').collect();
+        assert!(params.contains(&"X-Amz-Algorithm=AWS4-HMAC-SHA256"));
+        assert!(params.contains(&"X-Amz-Expires=300"));
+        assert!(params.contains(&"X-Amz-SignedHeaders=host"));
+        assert!(params
+            .iter()
+            .any(|param| param.starts_with("X-Amz-Credential=AKIDEXAMPLE%2F")
+                && param.ends_with("%2Fus-west-2%2Fs3%2Faws4_request")));
+        assert!(params
+            .iter()
+            .any(|param| param.starts_with("X-Amz-Signature=")));
+
+        assert!(matches!(
+            provider
+                .signed_url(
+                    "path/to/object.parquet",
+                    Duration::from_secs(8 * 24 * 60 * 60)
+                )
+                .await,
+            Err(StorageError::PresignedUrlExpiryTooLong { .. })
+        ));
+
+        assert!(provider.supports_signed_urls());
+        for url in [
+            "gs://my-bucket/key",
+            "https://my-account.blob.core.windows.net/container",
+        ] {
+            assert!(!BackendConfig::parse_url(url, true)
+                .unwrap()
+                .supports_signed_urls());
+        }
+
+        let local = StorageProvider::for_url("file:///tmp/arroyo-testing/presigned")
+            .await
+            .unwrap();
+        assert!(!local.supports_signed_urls());
+        assert!(matches!(
+            local.signed_url("key", Duration::from_secs(300)).await,
+            Err(StorageError::ObjectStore(
+                object_store::Error::NotImplemented
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_signed_url_custom_endpoint() {
+        let s3 = Arc::new(
+            AmazonS3Builder::new()
+                .with_bucket_name("my-bucket")
+                .with_region("auto")
+                .with_endpoint("https://0123abcd.r2.cloudflarestorage.com")
+                .with_virtual_hosted_style_request(false)
+                .with_access_key_id("AKIDEXAMPLE")
+                .with_secret_access_key("secret")
+                .build()
+                .unwrap(),
+        );
+        let provider = StorageProvider {
+            config: BackendConfig::parse_url("r2://0123abcd/my-bucket", false).unwrap(),
+            object_store: s3.clone(),
+            canonical_url: "s3::https://0123abcd.r2.cloudflarestorage.com:443/my-bucket"
+                .to_string(),
+            read_your_writes: None,
+            retry_classifier: None,
+            retry: None,
+            delete_batch_size: None,
+            signer: Some(s3),
+        };
+
+        let url = provider
+            .signed_url("output/part-0.json", Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let (base, query) = url.split_once('?').unwrap();
+        assert_eq!(
+            base,
+            "https://0123abcd.r2.cloudflarestorage.com/my-bucket/output/part-0.json"
+        );
+        let params: Vec<_> = query.split('&').collect();
+        assert!(params.contains(&"X-Amz-Expires=3600"));
+        assert!(params
+            .iter()
+            .any(|param| param.starts_with("X-Amz-Credential=AKIDEXAMPLE%2F")
+                && param.ends_with("%2Fauto%2Fs3%2Faws4_request")));
+    }
+
+    /// Accepts a single request and responds with an empty 200, returning the request's head
+    async fn mock_s3(listener: TcpListener) -> String {
+        mock_s3_requests(listener, 1).await.remove(0)
+    }
+
+    /// Answers `n` requests, one per connection, with empty successes (or the bodies rusoto
+    /// expects for multipart uploads and copies), returning the request lines and headers
+    async fn mock_s3_requests(listener: TcpListener, n: usize) -> Vec<String> {
+        let mut requests = vec![];
+        for _ in 0..n {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let content_length = headers
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if body.len() >= content_length {
+                        break;
+                    }
+                }
+            }
+
+            let request = String::from_utf8_lossy(&request).to_string();
+            let head = request.split_once("\r\n\r\n").unwrap().0.to_string();
+            let request_line = head.lines().next().unwrap();
+            let body = if request_line.starts_with("POST") && request_line.contains("?uploads") {
+                "<InitiateMultipartUploadResult><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>"
+            } else if request_line.starts_with("POST") {
+                "<CompleteMultipartUploadResult><ETag>\"1\"</ETag></CompleteMultipartUploadResult>"
+            } else if head.to_lowercase().contains("x-amz-copy-source:") {
+                "<CopyObjectResult><ETag>\"1\"</ETag></CopyObjectResult>"
+            } else {
+                ""
+            };
+
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\netag: \"1\"\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            requests.push(head);
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn test_s3_object_tags() {
+        let options = StorageOptions::default()
+            .with_s3_object_tag("job-id", "job_1")
+            .with_s3_object_tags(vec![("env".to_string(), "prod & staging".to_string())]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_s3(listener));
+
+        let s3 = AmazonS3Builder::new()
+            .with_bucket_name("my-bucket")
+            .with_region("us-east-1")
+            .with_endpoint(endpoint)
+            .with_allow_http(true)
+            .with_access_key_id("AKIDEXAMPLE")
+            .with_secret_access_key("secret")
+            .with_client_options(
+                ClientOptions::new().with_default_headers(s3_default_headers(&options).unwrap()),
+            )
+            .build()
+            .unwrap();
+
+        s3.put(&"checkpoints/metadata".into(), vec![1, 2, 3].into())
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("PUT /my-bucket/checkpoints/metadata "));
+        assert!(request
+            .lines()
+            .any(|line| line == "x-amz-tagging: job-id=job_1&env=prod%20%26%20staging"));
+
+        // no header without tags, and S3's limit on the number of tags is enforced
+        assert!(s3_default_headers(&StorageOptions::default())
+            .unwrap()
+            .is_empty());
+        let too_many = (0..11)
+            .map(|i| (format!("key-{}", i), "value".to_string()))
+            .collect();
+        assert!(matches!(
+            s3_default_headers(&StorageOptions::default().with_s3_object_tags(too_many)),
+            Err(StorageError::PathError(_))
+        ));
+    }
+
+    #[test]
+    fn test_s3_encryption_headers() {
+        assert_eq!("sse-s3".parse::<S3Encryption>().unwrap(), S3Encryption::Sse);
+        assert_eq!(
+            "sse-kms:arn:aws:kms:us-east-1:111122223333:key/my-key"
+                .parse::<S3Encryption>()
+                .unwrap(),
+            S3Encryption::SseKms {
+                key_id: "arn:aws:kms:us-east-1:111122223333:key/my-key".to_string()
+            }
+        );
+        for invalid in ["", "sse-kms", "sse-kms:", "aes256"] {
+            assert!(invalid.parse::<S3Encryption>().is_err(), "{}", invalid);
+        }
+
+        let headers = S3Encryption::Sse.headers().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["x-amz-server-side-encryption"], "AES256");
+
+        let sse_c: S3Encryption = "sse-c:BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc="
+            .parse()
+            .unwrap();
+        let headers = sse_c.headers().unwrap();
+        assert_eq!(
+            headers["x-amz-server-side-encryption-customer-algorithm"],
+            "AES256"
+        );
+        assert_eq!(
+            headers["x-amz-server-side-encryption-customer-key-md5"],
+            "y4HAEFCYWuvAXWFTtA1Qpg=="
+        );
+        // the key is never logged
+        assert!(!format!("{:?}", sse_c).contains("BwcH"));
+
+        // keys must be 256 bits
+        assert!(S3Encryption::SseC {
+            key: "BwcHBwcHBwcHBwcH".to_string()
+        }
+        .headers()
+        .is_err());
+    }
+
+    /// The store for an encrypted bucket served at `endpoint`
+    fn encrypted_store(endpoint: &str, encryption: &S3Encryption) -> Arc<dyn ObjectStore> {
+        let builder = AmazonS3Builder::new()
+            .with_bucket_name("my-bucket")
+            .with_region("us-east-1")
+            .with_endpoint(endpoint)
+            .with_allow_http(true)
+            .with_access_key_id("AKIDEXAMPLE")
+            .with_secret_access_key("secret");
+        let uploads = S3Client::new_with(
+            HttpClient::new().unwrap(),
+            StaticProvider::new_minimal("AKIDEXAMPLE".to_string(), "secret".to_string()),
+            Region::Custom {
+                name: "us-east-1".to_string(),
+                endpoint: endpoint.to_string(),
+            },
+        );
+        let options = StorageOptions::default().with_s3_encryption(encryption.clone());
+        let (object_store, _) = s3_stores(builder, &options, Some((encryption, uploads))).unwrap();
+        object_store
+    }
+
+    /// Makes a single write or read through the clients for `encryption`, returning the request
+    async fn encrypted_request(encryption: S3Encryption, write: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_s3(listener));
+        let object_store = encrypted_store(&endpoint, &encryption);
+
+        let path = "checkpoints/metadata".into();
+        if write {
+            object_store.put(&path, vec![1, 2, 3].into()).await.unwrap();
+        } else {
+            // the mock's response is missing the object's metadata, so this fails after the
+            // request is made
+            let _ = object_store.head(&path).await;
+        }
+
+        server.await.unwrap()
+    }
+
+    /// Makes a two-part multipart upload and then a copy through the clients for
+    /// `encryption`, returning the requests: create, two parts, complete, and copy
+    async fn encrypted_upload_requests(encryption: S3Encryption) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_s3_requests(listener, 5));
+        let object_store = encrypted_store(&endpoint, &encryption);
+
+        let path: Path = "output/part-0".into();
+        let (_, mut writer) = object_store.put_multipart(&path).await.unwrap();
+        writer
+            .write_all(&vec![0; super::ENCRYPTED_PART_SIZE + 1])
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+        object_store
+            .copy(&path, &"output/copy".into())
+            .await
+            .unwrap();
+
+        let mut requests = server.await.unwrap();
+        // the parts may be uploaded in either order
+        requests[1..3].sort();
+        requests
+    }
+
+    fn has_header(request: &str, header: &str) -> bool {
+        request
+            .lines()
+            .any(|line| line.to_lowercase().starts_with(&format!("{}:", header)))
+    }
+
+    #[tokio::test]
+    async fn test_s3_encrypted_writes() {
+        let kms = S3Encryption::SseKms {
+            key_id: "my-key".to_string(),
+        };
+
+        let request = encrypted_request(kms.clone(), true).await;
+        assert!(request.starts_with("PUT /my-bucket/checkpoints/metadata "));
+        assert!(request
+            .lines()
+            .any(|line| line == "x-amz-server-side-encryption: aws:kms"));
+        assert!(request
+            .lines()
+            .any(|line| line == "x-amz-server-side-encryption-aws-kms-key-id: my-key"));
+
+        // S3 rejects reads with the SSE-KMS headers
+        let request = encrypted_request(kms, false).await;
+        assert!(request.starts_with("HEAD /my-bucket/checkpoints/metadata "));
+        assert!(!request.contains("x-amz-server-side-encryption"));
+
+        // but requires the SSE-C key to read
+        let sse_c = S3Encryption::SseC {
+            key: "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=".to_string(),
+        };
+        let request = encrypted_request(sse_c, false).await;
+        assert!(request.starts_with("HEAD /my-bucket/checkpoints/metadata "));
+        assert!(request.lines().any(|line| line
+            == "x-amz-server-side-encryption-customer-key-md5: y4HAEFCYWuvAXWFTtA1Qpg=="));
+    }
+
+    #[tokio::test]
+    async fn test_s3_encrypted_multipart() {
+        let requests = encrypted_upload_requests(S3Encryption::SseKms {
+            key_id: "my-key".to_string(),
+        })
+        .await;
+        let [create, first, second, complete, copy] = &requests[..] else {
+            panic!("expected 5 requests, got {:?}", requests);
+        };
+
+        // SSE-KMS is requested when the upload is created, and S3 rejects it on the parts
+        // and completion
+        assert!(create.starts_with("POST /my-bucket/output/part-0?uploads"));
+        assert!(has_header(create, "x-amz-server-side-encryption"));
+        assert!(has_header(
+            create,
+            "x-amz-server-side-encryption-aws-kms-key-id"
+        ));
+        for request in [first, second, complete] {
+            assert!(
+                !request.contains("x-amz-server-side-encryption"),
+                "{}",
+                request
+            );
+        }
+        assert!(first.starts_with("PUT /my-bucket/output/part-0?"));
+        assert!(first.contains("partNumber=1"));
+        assert!(second.contains("partNumber=2"));
+        assert!(complete.starts_with("POST /my-bucket/output/part-0?uploadId=upload-1"));
+
+        // copies create objects, so they're encrypted too
+        assert!(copy.starts_with("PUT /my-bucket/output/copy "));
+        assert!(has_header(copy, "x-amz-server-side-encryption"));
+        assert!(!has_header(
+            copy,
+            "x-amz-copy-source-server-side-encryption-customer-key"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_s3_customer_key_multipart() {
+        let requests = encrypted_upload_requests(S3Encryption::SseC {
+            key: "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=".to_string(),
+        })
+        .await;
+        let [create, first, second, _, copy] = &requests[..] else {
+            panic!("expected 5 requests, got {:?}", requests);
+        };
+
+        // the SSE-C key is needed for every part
+        for request in [create, first, second] {
+            assert!(
+                has_header(request, "x-amz-server-side-encryption-customer-key-md5"),
+                "{}",
+                request
+            );
+        }
+
+        // and for both the source and destination of copies
+        for header in [
+            "x-amz-server-side-encryption-customer-algorithm",
+            "x-amz-server-side-encryption-customer-key",
+            "x-amz-server-side-encryption-customer-key-md5",
+            "x-amz-copy-source-server-side-encryption-customer-algorithm",
+            "x-amz-copy-source-server-side-encryption-customer-key",
+            "x-amz-copy-source-server-side-encryption-customer-key-md5",
+        ] {
+            assert!(has_header(copy, header), "{} missing from {}", header, copy);
+        }
+        assert!(copy
+            .lines()
+            .any(|line| line.to_lowercase() == "x-amz-copy-source: my-bucket/output/part-0"));
+    }
+
+    #[tokio::test]
+    async fn test_object_store_accessor() {
+        let storage = StorageProvider::for_url("file:///tmp/arroyo-testing/object-store")
+            .await
+            .unwrap();
+        let key = format!("key-{}", to_nanos(SystemTime::now()));
+        storage.put(&key, b"hello".to_vec()).await.unwrap();
+
+        let object_store = storage.object_store();
+        let bytes = object_store
+            .get(&key.as_str().into())
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], b"hello");
+
+        storage.delete_if_present(&key).await.unwrap();
+    }
 }
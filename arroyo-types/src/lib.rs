@@ -106,7 +106,20 @@ pub const API_METRICS_RATE_ENV: &str = "API_METRICS_RATE";
 // storage configuration
 pub const S3_ENDPOINT_ENV: &str = "S3_ENDPOINT";
 pub const S3_REGION_ENV: &str = "S3_REGION";
+// server-side encryption for objects written to S3: sse-s3, sse-kms:{key_id}, or
+// sse-c:{base64_key}
+pub const S3_SSE_ENV: &str = "ARROYO_S3_SSE";
 pub const CHECKPOINT_URL_ENV: &str = "CHECKPOINT_URL";
+// tags for checkpoint objects written to S3, as comma-separated key=value pairs
+pub const CHECKPOINT_OBJECT_TAGS_ENV: &str = "CHECKPOINT_OBJECT_TAGS";
+// maximum number of superseded checkpoint files removed by each bulk delete during cleanup
+pub const CHECKPOINT_DELETE_BATCH_SIZE_ENV: &str = "CHECKPOINT_DELETE_BATCH_SIZE";
+// how closely a checkpoint must match the job restoring from it: strict, same_version, warn
+// (the default), or any
+pub const RESTORE_COMPATIBILITY_ENV: &str = "RESTORE_COMPATIBILITY";
+// timeouts, in seconds, for requests to S3 and GCS
+pub const STORAGE_REQUEST_TIMEOUT_ENV: &str = "STORAGE_REQUEST_TIMEOUT";
+pub const STORAGE_CONNECT_TIMEOUT_ENV: &str = "STORAGE_CONNECT_TIMEOUT";
 
 // compiler service
 pub const ARTIFACT_URL_ENV: &str = "ARTIFACT_URL";
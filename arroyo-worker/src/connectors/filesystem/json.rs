@@ -1,13 +1,164 @@
 use std::{fs::File, io::Write, marker::PhantomData};
 
+use anyhow::anyhow;
 use arroyo_types::Data;
+use jsonschema::JSONSchema;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::warn;
 
 use super::{
+    checksum_sidecar_enabled,
     local::{CurrentFileRecovery, LocalWriter},
-    BatchBufferingWriter, BatchBuilder, FileSettings,
+    BatchBufferingWriter, BatchBuilder, FileSettings, FormatSettings,
 };
 
+/// Called with each record that fails schema validation, along with the validation errors.
+/// Records that can't be serialized at all are passed as a string of their `Debug`
+/// representation, with the serialization error.
+pub type DeadLetterCallback = Box<dyn FnMut(serde_json::Value, Vec<String>) + Send>;
+
+lazy_static! {
+    static ref SERIALIZATION_FAILURES_COUNTER: IntCounter = register_int_counter!(
+        "arroyo_worker_json_serialization_failures",
+        "Count of records the JSON writer could not serialize and sent to its dead-letter callback"
+    )
+    .unwrap();
+}
+
+/// Skips records whose serialized bytes are the same as the previous record's. Only the
+/// bytes of the last record are kept, and only records that were actually written update them,
+/// so the state always agrees with the bytes that end up in the file and its checkpoints.
+#[derive(Default)]
+struct ConsecutiveDeduper {
+    last_record: Option<Vec<u8>>,
+}
+
+impl ConsecutiveDeduper {
+    fn is_duplicate(&mut self, bytes: &[u8]) -> bool {
+        match &mut self.last_record {
+            Some(last_record) if last_record.as_slice() == bytes => true,
+            Some(last_record) => {
+                last_record.clear();
+                last_record.extend_from_slice(bytes);
+                false
+            }
+            None => {
+                self.last_record = Some(bytes.to_vec());
+                false
+            }
+        }
+    }
+}
+
+fn deduper_for(config: &super::FileSystemTable) -> Option<ConsecutiveDeduper> {
+    matches!(
+        config.format_settings,
+        Some(FormatSettings::Json {
+            dedupe_consecutive: Some(true),
+            ..
+        })
+    )
+    .then(ConsecutiveDeduper::default)
+}
+
+/// Writes the newline that ends each record. Without a trailing newline, a record's newline is
+/// deferred until the next record is written, so that the last one never gets one; this keeps
+/// the bytes that have been written (and checkpointed) a prefix of the finished file.
+struct RecordSeparator {
+    defer: bool,
+    pending: bool,
+}
+
+impl RecordSeparator {
+    fn new(config: &super::FileSystemTable) -> Self {
+        Self {
+            defer: matches!(
+                config.format_settings,
+                Some(FormatSettings::Json {
+                    trailing_newline: Some(false),
+                    ..
+                })
+            ),
+            pending: false,
+        }
+    }
+
+    /// The bytes to write before the next record
+    fn before_record(&mut self) -> &'static [u8] {
+        if std::mem::take(&mut self.pending) {
+            b"\n"
+        } else {
+            b""
+        }
+    }
+
+    /// The bytes to write after a record
+    fn after_record(&mut self) -> &'static [u8] {
+        if self.defer {
+            self.pending = true;
+            b""
+        } else {
+            b"\n"
+        }
+    }
+}
+
+/// Replaces the values of the table's `redact_fields` in each record before it's written. The
+/// record itself is untouched; only its serialized copy is redacted.
+struct Redactor {
+    // a JSON pointer to each field, with nested fields separated by '.' in the config
+    pointers: Vec<String>,
+    hash: bool,
+}
+
+impl Redactor {
+    fn for_table(config: &super::FileSystemTable) -> Option<Self> {
+        let Some(FormatSettings::Json {
+            redact_fields,
+            redact_with_hash,
+            ..
+        }) = &config.format_settings
+        else {
+            return None;
+        };
+        if redact_fields.is_empty() {
+            return None;
+        }
+        let pointers = redact_fields
+            .iter()
+            .map(|field| {
+                field
+                    .split('.')
+                    .map(|part| format!("/{}", part.replace('~', "~0").replace('/', "~1")))
+                    .collect()
+            })
+            .collect();
+        Some(Self {
+            pointers,
+            hash: *redact_with_hash == Some(true),
+        })
+    }
+
+    fn redact(&self, value: &mut serde_json::Value) {
+        for pointer in &self.pointers {
+            match value.pointer_mut(pointer) {
+                // nulls don't reveal anything, so they're left as they are
+                Some(serde_json::Value::Null) | None => {}
+                Some(field) => {
+                    *field = serde_json::Value::String(if self.hash {
+                        hex::encode(Sha256::digest(field.to_string().as_bytes()))
+                    } else {
+                        "[REDACTED]".to_string()
+                    });
+                }
+            }
+        }
+    }
+}
+
 pub struct PassThrough<D: Data> {
     _phantom: PhantomData<D>,
 }
@@ -36,16 +187,114 @@ impl<D: Data> BatchBuilder for PassThrough<D> {
     }
 }
 
+/// Turns records into the bytes of a JSON line, validating them against the table's schema and
+/// redacting their configured fields. Records that fail either step go to the dead-letter
+/// callback. Shared by [`JsonWriter`] and [`JsonLocalWriter`] so both treat records the same.
+struct RecordSerializer {
+    schema_validator: Option<JSONSchema>,
+    dead_letter_callback: DeadLetterCallback,
+    redactor: Option<Redactor>,
+}
+
+impl RecordSerializer {
+    fn new(config: &super::FileSystemTable) -> anyhow::Result<Self> {
+        let schema_validator = if let Some(FormatSettings::Json {
+            validate_against_schema: Some(true),
+            json_schema: Some(json_schema),
+            ..
+        }) = &config.format_settings
+        {
+            // also checked when the connection is created
+            let schema: serde_json::Value = serde_json::from_str(json_schema)
+                .map_err(|e| anyhow!("invalid JSON schema for JSON writer: {}", e))?;
+            Some(
+                JSONSchema::compile(&schema)
+                    .map_err(|e| anyhow!("invalid JSON schema for JSON writer: {}", e))?,
+            )
+        } else {
+            None
+        };
+        Ok(Self {
+            schema_validator,
+            dead_letter_callback: Box::new(|value, errors| {
+                warn!(
+                    "dropping record that could not be written as JSON ({}): {}",
+                    errors.join(", "),
+                    value
+                );
+            }),
+            redactor: Redactor::for_table(config),
+        })
+    }
+
+    /// Serializes a record, sending it to the dead-letter callback instead if it can't be
+    /// serialized or doesn't match the schema
+    fn serialize<D: Data + Serialize>(&mut self, data: &D) -> Option<Vec<u8>> {
+        if self.schema_validator.is_some() || self.redactor.is_some() {
+            let mut value = match serde_json::to_value(data) {
+                Ok(value) => value,
+                Err(err) => {
+                    self.serialization_failed(data, err);
+                    return None;
+                }
+            };
+            if let Some(schema_validator) = &self.schema_validator {
+                let errors: Vec<String> = match schema_validator.validate(&value) {
+                    Ok(()) => vec![],
+                    Err(errors) => errors.map(|error| error.to_string()).collect(),
+                };
+                if !errors.is_empty() {
+                    (self.dead_letter_callback)(value, errors);
+                    return None;
+                }
+            }
+            if let Some(redactor) = &self.redactor {
+                redactor.redact(&mut value);
+                return self.to_bytes(data, &value);
+            }
+        }
+        self.to_bytes(data, data)
+    }
+
+    fn to_bytes<D: Data>(&mut self, data: &D, value: &impl Serialize) -> Option<Vec<u8>> {
+        match serde_json::to_vec(value) {
+            Ok(bytes) => Some(bytes),
+            Err(err) => {
+                self.serialization_failed(data, err);
+                None
+            }
+        }
+    }
+
+    fn serialization_failed<D: Data>(&mut self, data: &D, err: serde_json::Error) {
+        SERIALIZATION_FAILURES_COUNTER.inc();
+        (self.dead_letter_callback)(
+            serde_json::Value::String(format!("{:?}", data)),
+            vec![format!("failed to serialize record: {}", err)],
+        );
+    }
+}
+
 pub struct JsonWriter<D: Data + Serialize> {
     current_buffer: Vec<u8>,
     target_part_size: usize,
+    serializer: RecordSerializer,
+    // set when consecutive duplicate records should be dropped; reset with each file
+    deduper: Option<ConsecutiveDeduper>,
+    separator: RecordSeparator,
     phantom: PhantomData<D>,
 }
 
+impl<D: Data + Serialize> JsonWriter<D> {
+    pub fn set_dead_letter_callback(&mut self, callback: DeadLetterCallback) {
+        self.serializer.dead_letter_callback = callback;
+    }
+}
+
 impl<D: Data + Serialize> BatchBufferingWriter for JsonWriter<D> {
     type BatchData = D;
 
-    fn new(config: &super::FileSystemTable) -> Self {
+    fn new(config: &super::FileSystemTable) -> anyhow::Result<Self> {
         let target_part_size = if let Some(FileSettings {
             target_part_size: Some(target_part_size),
             ..
@@ -55,20 +304,31 @@ impl<D: Data + Serialize> BatchBufferingWriter for JsonWriter<D> {
         } else {
             5 * 1024 * 1024
         };
-        Self {
+        Ok(Self {
             current_buffer: Vec::new(),
             target_part_size,
+            serializer: RecordSerializer::new(config)?,
+            deduper: deduper_for(config),
+            separator: RecordSeparator::new(config),
             phantom: PhantomData,
-        }
+        })
     }
     fn suffix() -> String {
         "json".to_string()
     }
 
     fn add_batch_data(&mut self, data: Self::BatchData) -> Option<Vec<u8>> {
+        let bytes = self.serializer.serialize(&data)?;
+        if let Some(deduper) = &mut self.deduper {
+            if deduper.is_duplicate(&bytes) {
+                return None;
+            }
+        }
+        self.current_buffer
+            .extend_from_slice(self.separator.before_record());
+        self.current_buffer.extend(bytes);
         self.current_buffer
-            .extend(serde_json::to_vec(&data).unwrap());
-        self.current_buffer.extend(b"\n");
+            .extend_from_slice(self.separator.after_record());
         if self.buffer_length() > self.target_part_size {
             Some(self.evict_current_buffer())
         } else {
@@ -108,23 +368,98 @@ impl<D: Data + Serialize> BatchBufferingWriter for JsonWriter<D> {
     }
 }
 
-pub struct JsonLocalWriter {
+/// The file handle used by [`JsonLocalWriter`], abstracted so that tests can observe syncs
+pub trait LocalFile: Write + Send + Sized + 'static {
+    fn create(path: &str) -> std::io::Result<Self>;
+    fn size(&self) -> std::io::Result<u64>;
+    fn sync_all(&self) -> std::io::Result<()>;
+}
+
+impl LocalFile for File {
+    fn create(path: &str) -> std::io::Result<Self> {
+        File::create(path)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// Applies the table's `file_mode` to a newly created file
+#[cfg(unix)]
+fn set_file_mode(path: &str, table_properties: &super::FileSystemTable) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match table_properties
+        .file_settings
+        .as_ref()
+        .and_then(|settings| settings.file_mode)
+    {
+        Some(mode) => std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+/// File permissions are only configurable on Unix
+#[cfg(not(unix))]
+fn set_file_mode(_path: &str, _table_properties: &super::FileSystemTable) -> std::io::Result<()> {
+    Ok(())
+}
+
+pub struct JsonLocalWriter<F: LocalFile = File> {
     tmp_path: String,
     final_path: String,
-    file: File,
+    file: F,
+    // fsyncing on every close and checkpoint makes committed files durable across machine
+    // crashes, but blocks the task on the disk and can substantially reduce throughput
+    fsync_on_close: bool,
+    deduper: Option<ConsecutiveDeduper>,
+    separator: RecordSeparator,
+    checksum: Option<Sha256>,
+    serializer: RecordSerializer,
 }
 
-impl<D: Data + Serialize> LocalWriter<D> for JsonLocalWriter {
+impl<F: LocalFile> JsonLocalWriter<F> {
+    pub fn set_dead_letter_callback(&mut self, callback: DeadLetterCallback) {
+        self.serializer.dead_letter_callback = callback;
+    }
+
+    fn flush(&mut self, durable: bool) -> anyhow::Result<usize> {
+        self.file.flush()?;
+        if durable && self.fsync_on_close {
+            self.file.sync_all()?;
+        }
+        Ok(self.file.size()? as usize)
+    }
+}
+
+impl<D: Data + Serialize, F: LocalFile> LocalWriter<D> for JsonLocalWriter<F> {
     fn new(
         tmp_path: String,
         final_path: String,
-        _table_properties: &super::FileSystemTable,
+        table_properties: &super::FileSystemTable,
     ) -> Self {
-        let file = File::create(&tmp_path).unwrap();
+        let file = F::create(&tmp_path).unwrap();
+        set_file_mode(&tmp_path, table_properties).unwrap();
+        let fsync_on_close = matches!(
+            table_properties.file_settings,
+            Some(FileSettings {
+                fsync_on_close: Some(true),
+                ..
+            })
+        );
         JsonLocalWriter {
             tmp_path,
             final_path,
             file,
+            fsync_on_close,
+            deduper: deduper_for(table_properties),
+            separator: RecordSeparator::new(table_properties),
+            checksum: checksum_sidecar_enabled(table_properties).then(Sha256::new),
+            serializer: RecordSerializer::new(table_properties).unwrap(),
         }
     }
 
@@ -133,28 +468,45 @@ impl<D: Data + Serialize> LocalWriter<D> for JsonLocalWriter {
     }
 
     fn write(&mut self, value: D) -> anyhow::Result<()> {
-        self.file
-            .write_all(serde_json::to_vec(&value)?.as_slice())?;
-        self.file.write_all(b"\n")?;
+        let Some(bytes) = self.serializer.serialize(&value) else {
+            return Ok(());
+        };
+        if let Some(deduper) = &mut self.deduper {
+            if deduper.is_duplicate(&bytes) {
+                return Ok(());
+            }
+        }
+        let (before, after) = (
+            self.separator.before_record(),
+            self.separator.after_record(),
+        );
+        for chunk in [before, bytes.as_slice(), after] {
+            self.file.write_all(chunk)?;
+            if let Some(hasher) = &mut self.checksum {
+                hasher.update(chunk);
+            }
+        }
         Ok(())
     }
 
     fn sync(&mut self) -> anyhow::Result<usize> {
-        self.file.flush()?;
-        let size = self.file.metadata()?.len() as usize;
-        Ok(size)
+        self.flush(false)
     }
 
     fn close(&mut self) -> anyhow::Result<super::local::FilePreCommit> {
-        LocalWriter::<D>::sync(self)?;
+        self.flush(true)?;
         Ok(super::local::FilePreCommit {
             tmp_file: self.tmp_path.clone(),
             destination: self.final_path.clone(),
+            checksum: self
+                .checksum
+                .take()
+                .map(|hasher| hex::encode(hasher.finalize())),
         })
     }
 
     fn checkpoint(&mut self) -> anyhow::Result<Option<super::local::CurrentFileRecovery>> {
-        let bytes_written = LocalWriter::<D>::sync(self)?;
+        let bytes_written = self.flush(true)?;
         if bytes_written > 0 {
             Ok(Some(CurrentFileRecovery {
                 tmp_file: self.tmp_path.clone(),
@@ -167,3 +519,419 @@ impl<D: Data + Serialize> LocalWriter<D> for JsonLocalWriter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde::{ser::Error, Serialize, Serializer};
+
+    use super::{JsonLocalWriter, JsonWriter, SERIALIZATION_FAILURES_COUNTER};
+    use crate::connectors::filesystem::{
+        local::LocalWriter, BatchBufferingWriter, FileSystemTable,
+    };
+
+    #[cfg(unix)]
+    mod fsync {
+        use std::{
+            fs::File,
+            io::Write,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        use super::Order;
+        use crate::connectors::filesystem::{
+            json::{JsonLocalWriter, LocalFile},
+            local::LocalWriter,
+            FileSystemTable,
+        };
+
+        static SYNC_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        /// Wraps a real file, counting calls to `sync_all`
+        struct RecordingFile(File);
+
+        impl Write for RecordingFile {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        impl LocalFile for RecordingFile {
+            fn create(path: &str) -> std::io::Result<Self> {
+                Ok(Self(File::create(path)?))
+            }
+
+            fn size(&self) -> std::io::Result<u64> {
+                self.0.size()
+            }
+
+            fn sync_all(&self) -> std::io::Result<()> {
+                SYNC_CALLS.fetch_add(1, Ordering::SeqCst);
+                self.0.sync_all()
+            }
+        }
+
+        fn new_writer(fsync_on_close: bool, name: &str) -> JsonLocalWriter<RecordingFile> {
+            std::fs::create_dir_all("/tmp/arroyo-testing/json-fsync").unwrap();
+            let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+                "write_target": {"Path": "file:///tmp/arroyo-testing/json-fsync"},
+                "file_settings": {"fsync_on_close": fsync_on_close},
+            }))
+            .unwrap();
+            <JsonLocalWriter<RecordingFile> as LocalWriter<Order>>::new(
+                format!("/tmp/arroyo-testing/json-fsync/{}.tmp", name),
+                format!("/tmp/arroyo-testing/json-fsync/{}.json", name),
+                &table,
+            )
+        }
+
+        #[test]
+        fn test_fsync_on_close() {
+            let order = Order {
+                id: 1,
+                customer: Some("alice".to_string()),
+            };
+
+            let mut writer = new_writer(false, "no-fsync");
+            writer.write(order.clone()).unwrap();
+            LocalWriter::<Order>::checkpoint(&mut writer).unwrap();
+            LocalWriter::<Order>::close(&mut writer).unwrap();
+            assert_eq!(SYNC_CALLS.load(Ordering::SeqCst), 0);
+
+            let mut writer = new_writer(true, "fsync");
+            writer.write(order).unwrap();
+            // rolling checks only flush
+            LocalWriter::<Order>::sync(&mut writer).unwrap();
+            assert_eq!(SYNC_CALLS.load(Ordering::SeqCst), 0);
+            LocalWriter::<Order>::checkpoint(&mut writer).unwrap();
+            assert_eq!(SYNC_CALLS.load(Ordering::SeqCst), 1);
+            LocalWriter::<Order>::close(&mut writer).unwrap();
+            assert_eq!(SYNC_CALLS.load(Ordering::SeqCst), 2);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::create_dir_all("/tmp/arroyo-testing/json-file-mode").unwrap();
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json-file-mode"},
+            "file_settings": {"file_mode": 0o640},
+        }))
+        .unwrap();
+        let tmp_path = "/tmp/arroyo-testing/json-file-mode/data.tmp";
+        let mut writer = <JsonLocalWriter as LocalWriter<Order>>::new(
+            tmp_path.to_string(),
+            "/tmp/arroyo-testing/json-file-mode/data.json".to_string(),
+            &table,
+        );
+        writer
+            .write(Order {
+                id: 1,
+                customer: None,
+            })
+            .unwrap();
+        LocalWriter::<Order>::close(&mut writer).unwrap();
+
+        let mode = std::fs::metadata(tmp_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[derive(Clone, Debug, bincode::Encode, bincode::Decode, PartialEq, Serialize)]
+    struct Order {
+        id: i64,
+        customer: Option<String>,
+    }
+
+    #[derive(Clone, Debug, bincode::Encode, bincode::Decode, PartialEq, Serialize)]
+    struct User {
+        id: i64,
+        email: String,
+        referrer: Option<String>,
+    }
+
+    #[test]
+    fn test_redact_fields() {
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json"},
+            "format_settings": {"redact_fields": ["email", "referrer", "missing"]},
+        }))
+        .unwrap();
+        let user = User {
+            id: 1,
+            email: "alice@example.com".to_string(),
+            referrer: None,
+        };
+
+        let mut writer = JsonWriter::<User>::new(&table).unwrap();
+        writer.add_batch_data(user.clone());
+        let written: serde_json::Value =
+            serde_json::from_slice(&writer.close(None).unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({"id": 1, "email": "[REDACTED]", "referrer": null})
+        );
+
+        // the record passed on to the rest of the pipeline keeps its value
+        let mut writer = JsonWriter::<User>::new(&table).unwrap();
+        let sent = user.clone();
+        writer.serializer.serialize(&sent).unwrap();
+        assert_eq!(sent, user);
+        assert_eq!(sent.email, "alice@example.com");
+
+        // hashed values are stable, so they can still be joined on
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json"},
+            "format_settings": {"redact_fields": ["email"], "redact_with_hash": true},
+        }))
+        .unwrap();
+        let mut writer = JsonWriter::<User>::new(&table).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_slice(&writer.serializer.serialize(&user).unwrap()).unwrap();
+        let second: serde_json::Value =
+            serde_json::from_slice(&writer.serializer.serialize(&user).unwrap()).unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first["email"], "alice@example.com");
+        assert_eq!(first["email"].as_str().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_validate_against_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer"},
+                "customer": {"type": "string"},
+            },
+            "required": ["id", "customer"],
+        });
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json"},
+            "format_settings": {
+                "validate_against_schema": true,
+                "json_schema": schema.to_string(),
+            },
+        }))
+        .unwrap();
+
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let mut writer = JsonWriter::<Order>::new(&table).unwrap();
+        let recorded = dead_letters.clone();
+        writer.set_dead_letter_callback(Box::new(move |value, _errors| {
+            recorded.lock().unwrap().push(value);
+        }));
+
+        writer.add_batch_data(Order {
+            id: 1,
+            customer: Some("alice".to_string()),
+        });
+        writer.add_batch_data(Order {
+            id: 2,
+            customer: None,
+        });
+
+        let written = writer.close(None).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "{\"id\":1,\"customer\":\"alice\"}\n"
+        );
+        assert_eq!(
+            *dead_letters.lock().unwrap(),
+            vec![serde_json::json!({"id": 2, "customer": null})]
+        );
+
+        // the local writer validates records the same way
+        std::fs::create_dir_all("/tmp/arroyo-testing/json-validate").unwrap();
+        let tmp_path = "/tmp/arroyo-testing/json-validate/data.tmp";
+        let mut writer = <JsonLocalWriter as LocalWriter<Order>>::new(
+            tmp_path.to_string(),
+            "/tmp/arroyo-testing/json-validate/data.json".to_string(),
+            &table,
+        );
+        let recorded = dead_letters.clone();
+        writer.set_dead_letter_callback(Box::new(move |value, _errors| {
+            recorded.lock().unwrap().push(value);
+        }));
+        writer
+            .write(Order {
+                id: 3,
+                customer: None,
+            })
+            .unwrap();
+        writer
+            .write(Order {
+                id: 4,
+                customer: Some("bob".to_string()),
+            })
+            .unwrap();
+        LocalWriter::<Order>::close(&mut writer).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp_path).unwrap(),
+            "{\"id\":4,\"customer\":\"bob\"}\n"
+        );
+        assert_eq!(
+            dead_letters.lock().unwrap()[1],
+            serde_json::json!({"id": 3, "customer": null})
+        );
+    }
+
+    /// Serializes as its id, unless it's poisoned
+    #[derive(Clone, Debug, bincode::Encode, bincode::Decode, PartialEq)]
+    struct Flaky {
+        id: i64,
+        poisoned: bool,
+    }
+
+    impl Serialize for Flaky {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if self.poisoned {
+                return Err(S::Error::custom("poisoned record"));
+            }
+            serializer.serialize_i64(self.id)
+        }
+    }
+
+    #[test]
+    fn test_unserializable_records_are_dead_lettered() {
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json"},
+        }))
+        .unwrap();
+
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let mut writer = JsonWriter::<Flaky>::new(&table).unwrap();
+        let recorded = dead_letters.clone();
+        writer.set_dead_letter_callback(Box::new(move |value, errors| {
+            recorded.lock().unwrap().push((value, errors));
+        }));
+
+        let failures = SERIALIZATION_FAILURES_COUNTER.get();
+        for (id, poisoned) in [(1, false), (2, true), (3, false)] {
+            writer.add_batch_data(Flaky { id, poisoned });
+        }
+
+        // the writer carries on with the records after the bad one
+        let written = writer.close(None).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), "1\n3\n");
+        assert_eq!(SERIALIZATION_FAILURES_COUNTER.get(), failures + 1);
+
+        let dead_letters = dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(
+            dead_letters[0].0,
+            serde_json::json!("Flaky { id: 2, poisoned: true }")
+        );
+        assert!(dead_letters[0].1[0].contains("poisoned record"));
+    }
+
+    fn dedupe_table() -> FileSystemTable {
+        serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json-dedupe"},
+            "format_settings": {"dedupe_consecutive": true},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_dedupe_consecutive() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+
+        let mut writer = JsonWriter::<String>::new(&dedupe_table()).unwrap();
+        for value in [&a, &a, &b, &a] {
+            writer.add_batch_data(value.clone());
+        }
+        assert_eq!(
+            String::from_utf8(writer.close(None).unwrap()).unwrap(),
+            "\"a\"\n\"b\"\n\"a\"\n"
+        );
+
+        std::fs::create_dir_all("/tmp/arroyo-testing/json-dedupe").unwrap();
+        let tmp_path = "/tmp/arroyo-testing/json-dedupe/dedupe.tmp".to_string();
+        let mut writer: JsonLocalWriter = LocalWriter::<String>::new(
+            tmp_path.clone(),
+            "/tmp/arroyo-testing/json-dedupe/dedupe.json".to_string(),
+            &dedupe_table(),
+        );
+        writer.write(a.clone()).unwrap();
+        writer.write(a.clone()).unwrap();
+        // the checkpointed file only covers the record that was written, so recovering from
+        // it can't bring back the skipped one
+        let recovery = LocalWriter::<String>::checkpoint(&mut writer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(recovery.bytes_written, 4);
+        writer.write(b).unwrap();
+        writer.write(a).unwrap();
+        LocalWriter::<String>::close(&mut writer).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp_path).unwrap(),
+            "\"a\"\n\"b\"\n\"a\"\n"
+        );
+    }
+
+    fn written_file(trailing_newline: bool, checkpoint_after: usize) -> (Vec<u8>, Vec<u8>) {
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/json"},
+            "file_settings": {"target_part_size": 8},
+            "format_settings": {"trailing_newline": trailing_newline},
+        }))
+        .unwrap();
+
+        let mut writer = JsonWriter::<String>::new(&table).unwrap();
+        let mut file = vec![];
+        let mut checkpointed = vec![];
+        for (i, value) in ["a", "bb", "ccc", "dddd"].into_iter().enumerate() {
+            if let Some(part) = writer.add_batch_data(value.to_string()) {
+                file.extend(part);
+            }
+            if i + 1 == checkpoint_after {
+                // what recovery would finish the file with: the parts so far plus trailing bytes
+                checkpointed = file.clone();
+                checkpointed.extend(
+                    writer
+                        .get_trailing_bytes_for_checkpoint()
+                        .unwrap_or_default(),
+                );
+            }
+        }
+        file.extend(writer.close(None).unwrap_or_default());
+        (file, checkpointed)
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        let (file, _) = written_file(true, 0);
+        assert_eq!(
+            String::from_utf8(file.clone()).unwrap(),
+            "\"a\"\n\"bb\"\n\"ccc\"\n\"dddd\"\n"
+        );
+        assert_eq!(file.last(), Some(&b'\n'));
+
+        let (file, _) = written_file(false, 0);
+        assert_eq!(
+            String::from_utf8(file.clone()).unwrap(),
+            "\"a\"\n\"bb\"\n\"ccc\"\n\"dddd\""
+        );
+        assert_eq!(file.last(), Some(&b'"'));
+
+        // a file recovered from a checkpoint ends the same way as a closed one
+        for checkpoint_after in 1..=4 {
+            for trailing_newline in [true, false] {
+                let (_, checkpointed) = written_file(trailing_newline, checkpoint_after);
+                assert_eq!(
+                    checkpointed.last() == Some(&b'\n'),
+                    trailing_newline,
+                    "checkpoint after {} records",
+                    checkpoint_after
+                );
+            }
+        }
+    }
+}
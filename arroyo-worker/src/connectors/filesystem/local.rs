@@ -1,5 +1,9 @@
 use std::{
-    collections::HashMap, fs::create_dir_all, marker::PhantomData, path::Path, time::Instant,
+    collections::HashMap,
+    fs::create_dir_all,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
 use arroyo_types::{Data, Key, Record, TaskInfo};
@@ -10,47 +14,85 @@ use tracing::info;
 
 use crate::connectors::two_phase_committer::TwoPhaseCommitter;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
-use super::{FileSystemTable, MultiPartWriterStats, RollingPolicy};
+use super::{
+    checksum_sidecar_enabled,
+    clock::{Clock, TokioClock},
+    host_name, indexed_file_name, key_prefix,
+    parquet::add_lineage_metadata,
+    roll_on_checkpoint_enabled, run_directory, sha256_hex, subtask_directory, with_host_name,
+    FileSystemTable, MultiPartWriterStats, RollingPolicy,
+};
 
 pub struct LocalFileSystemWriter<K: Key, D: Data + Sync, V: LocalWriter<D>> {
     // writer to a local tmp file
     writer: Option<V>,
     tmp_dir: String,
+    // where finished files are moved to, including the run subdirectory if there is one
     final_dir: String,
+    // appended to filenames, if configured
+    host_name: Option<String>,
     next_file_index: usize,
     subtask_id: usize,
+    parallelism: usize,
     finished_files: Vec<FilePreCommit>,
     first_write: Option<Instant>,
     last_write: Option<Instant>,
+    records_written: usize,
     rolling_policy: RollingPolicy,
+    clock: Box<dyn Clock>,
     table_properties: FileSystemTable,
     phantom: PhantomData<(K, D)>,
 }
 
 impl<K: Key, D: Data + Sync, V: LocalWriter<D>> LocalFileSystemWriter<K, D, V> {
-    pub fn new(final_dir: String, table_properties: FileSystemTable) -> Self {
-        // TODO: explore configuration options here
-        let tmp_dir = format!("{}/__in_progress", final_dir);
+    pub fn new(final_dir: String, table_properties: FileSystemTable) -> Result<Self> {
+        let tmp_dir = table_properties
+            .file_settings
+            .as_ref()
+            .unwrap()
+            .tmp_dir
+            .clone();
+        let final_dir = match key_prefix(table_properties.file_settings.as_ref().unwrap()) {
+            Some(key_prefix) => format!("{}/{}", final_dir, key_prefix),
+            None => final_dir,
+        };
+        let final_dir = match run_directory(table_properties.file_settings.as_ref().unwrap()) {
+            Some(run_directory) => format!("{}/{}", final_dir, run_directory),
+            None => final_dir,
+        };
+        let tmp_dir = match tmp_dir {
+            // a shared tmp_dir gets a subdirectory per destination, so that sinks writing the
+            // same file names to different places don't overwrite each other's files
+            Some(tmp_dir) => format!("{}/{}", tmp_dir, &sha256_hex(final_dir.as_bytes())[..16]),
+            None => format!("{}/__in_progress", final_dir),
+        };
         // make sure final_dir and tmp_dir exists
-        create_dir_all(&tmp_dir).unwrap();
+        create_dir_all(&tmp_dir)
+            .with_context(|| format!("failed to create temp directory {}", tmp_dir))?;
+        create_dir_all(&final_dir)
+            .with_context(|| format!("failed to create directory {}", final_dir))?;
 
-        Self {
+        Ok(Self {
             writer: None,
             tmp_dir,
             final_dir,
+            host_name: host_name(table_properties.file_settings.as_ref().unwrap()),
             next_file_index: 0,
             subtask_id: 0,
+            parallelism: 1,
             finished_files: Vec::new(),
             first_write: None,
             last_write: None,
+            records_written: 0,
             rolling_policy: RollingPolicy::from_file_settings(
                 table_properties.file_settings.as_ref().unwrap(),
             ),
+            clock: Box::new(TokioClock),
             table_properties,
             phantom: PhantomData,
-        }
+        })
     }
 
     fn should_roll(&mut self) -> bool {
@@ -62,10 +104,11 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D>> LocalFileSystemWriter<K, D, V> {
             let stats = MultiPartWriterStats {
                 bytes_written,
                 parts_written: 0,
+                records_written: self.records_written,
                 last_write_at: self.last_write.unwrap(),
                 first_write_at: self.first_write.unwrap(),
             };
-            self.rolling_policy.should_roll(&stats)
+            self.rolling_policy.should_roll(&stats, self.clock.as_ref())
         } else {
             false
         }
@@ -73,22 +116,63 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D>> LocalFileSystemWriter<K, D, V> {
 
     fn init_writer(&mut self) -> Result<()> {
         let file_name = format!(
-            "{:>05}-{:>03}.{}",
-            self.next_file_index,
-            self.subtask_id,
+            "{}.{}",
+            with_host_name(
+                indexed_file_name(
+                    self.table_properties.file_settings.as_ref().unwrap(),
+                    self.next_file_index,
+                    self.subtask_id,
+                    self.parallelism,
+                ),
+                &self.host_name,
+            ),
             V::file_suffix()
         );
+        let final_dir = match subtask_directory(
+            self.table_properties.file_settings.as_ref().unwrap(),
+            self.subtask_id,
+        ) {
+            Some(subtask_directory) => {
+                let final_dir = format!("{}/{}", self.final_dir, subtask_directory);
+                create_dir_all(&final_dir)?;
+                final_dir
+            }
+            None => self.final_dir.clone(),
+        };
         self.writer = Some(V::new(
             format!("{}/{}", self.tmp_dir, file_name),
-            format!("{}/{}", self.final_dir, file_name),
+            format!("{}/{}", final_dir, file_name),
             &self.table_properties,
         ));
         self.next_file_index += 1;
-        self.first_write = Some(Instant::now());
+        self.first_write = Some(self.clock.now());
+        self.records_written = 0;
         Ok(())
     }
 }
 
+/// Moves `from` to `to`, copying instead if they're on different filesystems, as they may be
+/// when `tmp_dir` is set
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match tokio::fs::rename(from, to).await {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_across_devices(from, to).await
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Copies `from` next to `to` and renames it into place before removing `from`, so that `to`
+/// never holds a partial file
+pub(super) async fn copy_across_devices(from: &Path, to: &Path) -> Result<()> {
+    let staging = PathBuf::from(format!("{}.copying", to.to_string_lossy()));
+    tokio::fs::copy(from, &staging).await?;
+    tokio::fs::File::open(&staging).await?.sync_all().await?;
+    tokio::fs::rename(&staging, to).await?;
+    tokio::fs::remove_file(from).await?;
+    Ok(())
+}
+
 pub trait LocalWriter<T: Data>: Send + 'static {
     fn new(tmp_path: String, final_path: String, table_properties: &FileSystemTable) -> Self;
     fn file_suffix() -> &'static str;
@@ -117,6 +201,8 @@ pub struct CurrentFileRecovery {
 pub struct FilePreCommit {
     pub tmp_file: String,
     pub destination: String,
+    // hex SHA-256 of the file, computed as it was written, if a checksum sidecar is enabled
+    pub checksum: Option<String>,
 }
 
 #[async_trait]
@@ -171,9 +257,16 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D> + Send + 'static> TwoPhaseCommitt
             recovered_files.push(FilePreCommit {
                 tmp_file,
                 destination,
+                checksum: None,
             })
         }
         self.subtask_id = task_info.task_index;
+        self.parallelism = task_info.parallelism;
+        add_lineage_metadata(
+            &mut self.table_properties,
+            &task_info.job_id,
+            &task_info.operator_id,
+        );
         self.finished_files = recovered_files;
         self.next_file_index = max_file_index;
         Ok(())
@@ -184,7 +277,8 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D> + Send + 'static> TwoPhaseCommitt
             self.init_writer()?;
         };
         self.writer.as_mut().unwrap().write(record.value.clone())?;
-        self.last_write = Some(Instant::now());
+        self.last_write = Some(self.clock.now());
+        self.records_written += 1;
         Ok(())
     }
 
@@ -193,9 +287,11 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D> + Send + 'static> TwoPhaseCommitt
         _task_info: &TaskInfo,
         pre_commit: Vec<Self::PreCommit>,
     ) -> Result<()> {
+        let checksum_sidecar = checksum_sidecar_enabled(&self.table_properties);
         for FilePreCommit {
             tmp_file,
             destination,
+            checksum,
         } in pre_commit
         {
             let (tmp_file, destination) = (Path::new(&tmp_file), Path::new(&destination));
@@ -210,7 +306,19 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D> + Send + 'static> TwoPhaseCommitt
                 tmp_file.to_string_lossy(),
                 destination.to_string_lossy()
             );
-            tokio::fs::rename(tmp_file, destination).await?;
+            // the sidecar goes first, so that a committed file always has one
+            if checksum_sidecar {
+                let checksum = match checksum {
+                    Some(checksum) => checksum,
+                    None => sha256_hex(&tokio::fs::read(tmp_file).await?),
+                };
+                tokio::fs::write(
+                    format!("{}.sha256", destination.to_string_lossy()),
+                    checksum,
+                )
+                .await?;
+            }
+            move_file(tmp_file, destination).await?;
         }
         Ok(())
     }
@@ -220,7 +328,9 @@ impl<K: Key, D: Data + Sync, V: LocalWriter<D> + Send + 'static> TwoPhaseCommitt
         _task_info: &TaskInfo,
         stopping: bool,
     ) -> Result<(Self::DataRecovery, HashMap<String, Self::PreCommit>)> {
-        if self.should_roll() || stopping {
+        let roll_on_checkpoint =
+            roll_on_checkpoint_enabled(&self.table_properties) && self.first_write.is_some();
+        if self.should_roll() || roll_on_checkpoint || stopping {
             let pre_commit = self.writer.take().unwrap().close()?;
             self.first_write = None;
             self.last_write = None;
@@ -1,42 +1,72 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::{Debug, Formatter},
     marker::PhantomData,
     pin::Pin,
-    sync::Arc,
-    time::{Duration, Instant, SystemTime},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use arroyo_rpc::OperatorConfig;
+use arroyo_storage::WebIdentityProvider;
 use async_trait::async_trait;
-use bincode::{Decode, Encode};
+use bincode::{
+    de::{read::Reader, Decoder},
+    enc::Encoder,
+    error::{AllowedEnumVariants, DecodeError, EncodeError},
+    Decode, Encode,
+};
 use futures::{stream::FuturesUnordered, Future};
 use futures::{stream::StreamExt, TryStreamExt};
+use lazy_static::lazy_static;
 use object_store::{
     aws::{AmazonS3Builder, AwsCredential},
+    gcp::GoogleCloudStorageBuilder,
     local::LocalFileSystem,
     path::Path,
     CredentialProvider, MultipartId, ObjectStore, UploadPart,
 };
-use rusoto_core::credential::{DefaultCredentialsProvider, ProvideAwsCredentials};
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use rusoto_core::{
+    credential::{
+        AutoRefreshingProvider, AwsCredentials, CredentialsError, DefaultCredentialsProvider,
+        ProvideAwsCredentials,
+    },
+    HttpClient, Region,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tracing::warn;
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    mpsc::{Receiver, Sender},
+    oneshot, Semaphore,
+};
+use tracing::{debug, info, warn};
 use typify::import_types;
+use uuid::Uuid;
 
 import_types!(schema = "../connector-schemas/filesystem/table.json");
 
 use arroyo_types::*;
+pub mod clock;
 pub mod json;
 pub mod local;
 pub mod parquet;
+pub mod registry;
 pub mod single_file;
+#[cfg(test)]
+mod test;
 
 use self::{
+    clock::{Clock, TokioClock},
     json::{JsonLocalWriter, JsonWriter, PassThrough},
     local::{LocalFileSystemWriter, LocalWriter},
-    parquet::{FixedSizeRecordBatchBuilder, ParquetLocalWriter, RecordBatchBufferingWriter},
+    parquet::{
+        add_lineage_metadata, FixedSizeRecordBatchBuilder, ParquetLocalWriter,
+        RecordBatchBufferingWriter,
+    },
+    registry::FormatWriter,
 };
 
 use super::two_phase_committer::{TwoPhaseCommitter, TwoPhaseCommitterOperator};
@@ -48,17 +78,27 @@ pub struct FileSystemSink<
 > {
     sender: Sender<FileSystemMessages<T>>,
     checkpoint_receiver: Receiver<CheckpointData<T>>,
+    // the most recently finished files, oldest first, so that a file committed again (e.g.,
+    // when a commit is retried after a restore) isn't finished twice
+    finished_files: VecDeque<String>,
+    // the same files as `finished_files`, for lookups
+    finished_file_set: HashSet<String>,
+    // the writer's run subdirectory, if configured; checkpointed so that a restored sink keeps
+    // writing to the same one
+    run_directory: Option<String>,
     _ts: PhantomData<(K, R)>,
 }
 
+// both write the table's `format` if it's set, from the formats registered with
+// [`registry::register_format`], and otherwise their built-in format
 pub type ParquetFileSystemSink<K, T, R> = FileSystemSink<
     K,
     T,
-    BatchMultipartWriter<FixedSizeRecordBatchBuilder<R>, RecordBatchBufferingWriter<R>>,
+    FormatWriter<FixedSizeRecordBatchBuilder<R>, RecordBatchBufferingWriter<R>>,
 >;
 
 pub type JsonFileSystemSink<K, T> =
-    FileSystemSink<K, T, BatchMultipartWriter<PassThrough<T>, JsonWriter<T>>>;
+    FileSystemSink<K, T, FormatWriter<PassThrough<T>, JsonWriter<T>>>;
 
 pub type LocalParquetFileSystemSink<K, T, R> = LocalFileSystemWriter<K, T, ParquetLocalWriter<R>>;
 
@@ -66,23 +106,30 @@ pub type LocalJsonFileSystemSink<K, T> = LocalFileSystemWriter<K, T, JsonLocalWr
 
 impl<K: Key, T: Data + Sync, V: LocalWriter<T>> LocalFileSystemWriter<K, T, V> {
     pub fn from_config(config_str: &str) -> TwoPhaseCommitterOperator<K, T, Self> {
+        Self::try_from_config(config_str)
+            .unwrap_or_else(|e| panic!("failed to create LocalFileSystemSink: {:#}", e))
+    }
+
+    pub fn try_from_config(config_str: &str) -> Result<TwoPhaseCommitterOperator<K, T, Self>> {
         let config: OperatorConfig =
-            serde_json::from_str(config_str).expect("Invalid config for FileSystemSink");
-        let table: FileSystemTable =
-            serde_json::from_value(config.table).expect("Invalid table config for FileSystemSink");
-        let (_object_store, path): (Box<dyn ObjectStore>, Path) = match table.write_target.clone() {
-            Destination::LocalFilesystem { local_directory } => {
-                (Box::new(LocalFileSystem::new()), local_directory.into())
-            }
+            serde_json::from_str(config_str).context("invalid config for LocalFileSystemSink")?;
+        let table: FileSystemTable = serde_json::from_value(config.table)
+            .context("invalid table config for LocalFileSystemSink")?;
+        let path: Path = match table.write_target.clone() {
+            Destination::LocalFilesystem { local_directory } => local_directory.into(),
             Destination::S3Bucket { .. } => {
-                unreachable!("shouldn't be using local writer for S3");
+                bail!("shouldn't be using local writer for S3");
             }
-            Destination::FolderUri { path } => {
-                object_store::parse_url(&url::Url::parse(&path).unwrap()).unwrap()
+            Destination::GcsBucket { .. } => {
+                bail!("shouldn't be using local writer for GCS");
             }
+            Destination::FolderUri {
+                path,
+                scheme_override,
+            } => parse_folder_uri(&path, scheme_override.as_deref())?.1,
         };
-        let writer = LocalFileSystemWriter::new(path.to_string(), table);
-        TwoPhaseCommitterOperator::new(writer)
+        let writer = LocalFileSystemWriter::new(path.to_string(), table)?;
+        Ok(TwoPhaseCommitterOperator::new(writer))
     }
 }
 
@@ -90,36 +137,17 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
     FileSystemSink<K, T, R>
 {
     pub fn from_config(config_str: &str) -> TwoPhaseCommitterOperator<K, T, Self> {
+        Self::try_from_config(config_str)
+            .unwrap_or_else(|e| panic!("failed to create FileSystemSink: {:#}", e))
+    }
+
+    pub fn try_from_config(config_str: &str) -> Result<TwoPhaseCommitterOperator<K, T, Self>> {
         let config: OperatorConfig =
-            serde_json::from_str(config_str).expect("Invalid config for FileSystemSink");
-        let table: FileSystemTable =
-            serde_json::from_value(config.table).expect("Invalid table config for FileSystemSink");
-        let (object_store, path): (Box<dyn ObjectStore>, Path) = match table.write_target.clone() {
-            Destination::LocalFilesystem { local_directory } => {
-                (Box::new(LocalFileSystem::new()), local_directory.into())
-            }
-            Destination::S3Bucket {
-                s3_bucket,
-                s3_directory,
-                aws_region,
-            } => {
-                (
-                    Box::new(
-                        // use default credentials
-                        AmazonS3Builder::from_env()
-                            .with_bucket_name(s3_bucket)
-                            .with_credentials(Arc::new(S3Credentialing::try_new().unwrap()))
-                            .with_region(aws_region)
-                            .build()
-                            .unwrap(),
-                    ),
-                    s3_directory.into(),
-                )
-            }
-            Destination::FolderUri { path } => {
-                object_store::parse_url(&url::Url::parse(&path).unwrap()).unwrap()
-            }
-        };
+            serde_json::from_str(config_str).context("invalid config for FileSystemSink")?;
+        let table: FileSystemTable = serde_json::from_value(config.table)
+            .context("invalid table config for FileSystemSink")?;
+        let (object_store, path) = object_store_for(table.write_target.clone())
+            .context("failed to create object store for FileSystemSink")?;
 
         let (sender, receiver) = tokio::sync::mpsc::channel(10000);
         let (checkpoint_sender, checkpoint_receiver) = tokio::sync::mpsc::channel(10000);
@@ -130,14 +158,255 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
             checkpoint_sender,
             table,
         );
+        let run_directory = writer.run_directory.clone();
         tokio::spawn(async move {
             writer.run().await.unwrap();
         });
-        TwoPhaseCommitterOperator::new(Self {
+        Ok(TwoPhaseCommitterOperator::new(Self::new(
+            sender,
+            checkpoint_receiver,
+            run_directory,
+        )))
+    }
+
+    fn new(
+        sender: Sender<FileSystemMessages<T>>,
+        checkpoint_receiver: Receiver<CheckpointData<T>>,
+        run_directory: Option<String>,
+    ) -> Self {
+        Self {
             sender,
             checkpoint_receiver,
+            finished_files: VecDeque::new(),
+            finished_file_set: HashSet::new(),
+            run_directory,
             _ts: PhantomData,
-        })
+        }
+    }
+
+    fn record_finished_file(&mut self, filename: String) {
+        if !self.finished_file_set.insert(filename.clone()) {
+            return;
+        }
+        if self.finished_files.len() == MAX_TRACKED_FINISHED_FILES {
+            if let Some(oldest) = self.finished_files.pop_front() {
+                self.finished_file_set.remove(&oldest);
+            }
+        }
+        self.finished_files.push_back(filename);
+    }
+
+    /// Discards everything written since the last checkpoint: open multipart uploads are
+    /// aborted and no pre-commits are produced, so the next checkpoint starts from a new file.
+    pub async fn abort(&mut self, task_info: &TaskInfo) -> Result<()> {
+        self.sender
+            .send(FileSystemMessages::Abort {
+                subtask_id: task_info.task_index,
+            })
+            .await?;
+        while let Some(checkpoint_message) = self.checkpoint_receiver.recv().await {
+            match checkpoint_message {
+                CheckpointData::Finished { max_file_index: _ } => return Ok(()),
+                _ => {
+                    bail!("unexpected checkpoint message")
+                }
+            }
+        }
+        bail!("checkpoint receiver closed unexpectedly")
+    }
+
+    /// The files the sink has open and not yet committed, with how much has been written to each
+    pub async fn in_progress_files(&self) -> Result<Vec<InProgressFile>> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.sender
+            .send(FileSystemMessages::ListInProgress(response_sender))
+            .await?;
+        Ok(response_receiver.await?)
+    }
+}
+
+/// The object store and base path for a destination. Nothing is looked up here (in
+/// particular, S3 credentials are only resolved when the store makes its first request), so
+/// this succeeds in environments without any AWS configuration.
+fn object_store_for(destination: Destination) -> Result<(Box<dyn ObjectStore>, Path)> {
+    Ok(match destination {
+        Destination::LocalFilesystem { local_directory } => {
+            (Box::new(LocalFileSystem::new()), local_directory.into())
+        }
+        Destination::S3Bucket {
+            s3_bucket,
+            s3_directory,
+            aws_region,
+            role_arn,
+            external_id,
+        } => {
+            // use default credentials, or assume the sink's role with them
+            let credentials = match role_arn {
+                Some(role_arn) => S3Credentialing::assume_role(
+                    S3Credentialing::new(),
+                    role_arn,
+                    external_id,
+                    aws_region
+                        .parse()
+                        .map_err(|_| anyhow!("invalid AWS region '{}'", aws_region))?,
+                )?,
+                None => S3Credentialing::new(),
+            };
+            (
+                Box::new(
+                    AmazonS3Builder::from_env()
+                        .with_bucket_name(s3_bucket)
+                        .with_credentials(Arc::new(credentials))
+                        .with_region(aws_region)
+                        .build()?,
+                ),
+                s3_directory.into(),
+            )
+        }
+        Destination::GcsBucket {
+            gcs_bucket,
+            gcs_directory,
+            endpoint,
+        } => (
+            Box::new(
+                gcs_builder(endpoint.as_deref())
+                    .with_bucket_name(gcs_bucket)
+                    .build()?,
+            ),
+            gcs_directory.into(),
+        ),
+        Destination::FolderUri {
+            path,
+            scheme_override,
+        } => parse_folder_uri(&path, scheme_override.as_deref())?,
+    })
+}
+
+fn gcs_builder(endpoint: Option<&str>) -> GoogleCloudStorageBuilder {
+    match endpoint {
+        // emulators like fake-gcs-server don't check credentials, and object_store only
+        // lets the base URL be set through the service account key
+        Some(endpoint) => GoogleCloudStorageBuilder::new().with_service_account_key(
+            serde_json::json!({
+                "private_key": "",
+                "client_email": "",
+                "gcs_base_url": endpoint.trim_end_matches('/'),
+                "disable_oauth": true,
+            })
+            .to_string(),
+        ),
+        None => GoogleCloudStorageBuilder::from_env(),
+    }
+}
+
+/// Schemes that a `FolderUri` destination may use: local files, S3 (s3a is the name Hadoop
+/// tools use for it), and GCS, the object_store backends the worker is built with
+const SUPPORTED_SCHEMES: &[&str] = &["file", "s3", "s3a", "gs"];
+
+/// A `FolderUri` destination that can't be turned into an object store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestinationError {
+    InvalidUrl { url: String, reason: String },
+    UnsupportedScheme { url: String, scheme: String },
+}
+
+impl std::fmt::Display for DestinationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DestinationError::InvalidUrl { url, reason } => {
+                write!(f, "invalid filesystem sink path '{}': {}", url, reason)
+            }
+            DestinationError::UnsupportedScheme { url, scheme } => write!(
+                f,
+                "unsupported scheme '{}' in filesystem sink path '{}'; supported schemes are {}",
+                scheme,
+                url,
+                SUPPORTED_SCHEMES.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DestinationError {}
+
+/// Creates the object store for a `FolderUri` destination. The backend is chosen by the URI's
+/// scheme unless `scheme_override` is set, in which case the URI is interpreted for that
+/// backend: for S3, `{scheme}://{host}/{bucket}/{directory}` is a bucket on an S3-compatible
+/// endpoint (over plain HTTP only if the scheme is http), for GCS, it's a bucket on a GCS
+/// emulator at that host, and for file, the URI's path is the local directory.
+fn parse_folder_uri(
+    path: &str,
+    scheme_override: Option<&str>,
+) -> Result<(Box<dyn ObjectStore>, Path), DestinationError> {
+    let invalid = |reason: String| DestinationError::InvalidUrl {
+        url: path.to_string(),
+        reason,
+    };
+    let url = url::Url::parse(path).map_err(|e| invalid(e.to_string()))?;
+
+    let scheme = scheme_override.unwrap_or(url.scheme());
+    if !SUPPORTED_SCHEMES.contains(&scheme) {
+        return Err(DestinationError::UnsupportedScheme {
+            url: path.to_string(),
+            scheme: scheme.to_string(),
+        });
+    }
+
+    match scheme_override {
+        // parse_url doesn't read GCS credentials from the environment
+        None if scheme == "gs" => {
+            let bucket = url
+                .host_str()
+                .ok_or_else(|| invalid("a GCS path requires a bucket".to_string()))?;
+            let store = gcs_builder(None)
+                .with_bucket_name(bucket)
+                .build()
+                .map_err(|e| invalid(e.to_string()))?;
+            Ok((Box::new(store), Path::from(url.path())))
+        }
+        None => object_store::parse_url(&url).map_err(|e| invalid(e.to_string())),
+        Some("file") => Ok((Box::new(LocalFileSystem::new()), Path::from(url.path()))),
+        Some(override_scheme) => {
+            let host = url
+                .host_str()
+                .ok_or_else(|| invalid("an endpoint requires a host".to_string()))?;
+            let endpoint = format!(
+                "{}://{}{}",
+                if url.scheme() == "http" {
+                    "http"
+                } else {
+                    "https"
+                },
+                host,
+                url.port()
+                    .map(|port| format!(":{}", port))
+                    .unwrap_or_default()
+            );
+            let mut segments = url.path().trim_matches('/').splitn(2, '/');
+            let bucket = segments
+                .next()
+                .filter(|bucket| !bucket.is_empty())
+                .ok_or_else(|| invalid("a path on an endpoint requires a bucket".to_string()))?;
+            let directory = segments.next().unwrap_or_default();
+
+            if override_scheme == "gs" {
+                let store = gcs_builder(Some(&endpoint))
+                    .with_bucket_name(bucket)
+                    .build()
+                    .map_err(|e| invalid(e.to_string()))?;
+                return Ok((Box::new(store), Path::from(directory)));
+            }
+
+            let store = AmazonS3Builder::from_env()
+                .with_endpoint(endpoint)
+                .with_bucket_name(bucket)
+                .with_virtual_hosted_style_request(false)
+                .with_allow_http(url.scheme() == "http")
+                .with_credentials(Arc::new(S3Credentialing::new()))
+                .build()
+                .map_err(|e| invalid(e.to_string()))?;
+            Ok((Box::new(store), Path::from(directory)))
+        }
     }
 }
 
@@ -147,9 +416,15 @@ enum FileSystemMessages<T: Data> {
         value: T,
         time: SystemTime,
     },
+    Watermark(SystemTime),
     Init {
         max_file_index: usize,
         subtask_id: usize,
+        parallelism: usize,
+        job_id: String,
+        operator_id: String,
+        // the run subdirectory to keep writing to, if one was checkpointed
+        run_directory: Option<String>,
         recovered_files: Vec<InProgressFileCheckpoint<T>>,
     },
     Checkpoint {
@@ -157,6 +432,10 @@ enum FileSystemMessages<T: Data> {
         then_stop: bool,
     },
     FilesToFinish(Vec<FileToFinish>),
+    Abort {
+        subtask_id: usize,
+    },
+    ListInProgress(oneshot::Sender<Vec<InProgressFile>>),
 }
 
 #[derive(Debug)]
@@ -172,7 +451,7 @@ struct InProgressFileCheckpoint<T: Data> {
     buffered_data: Vec<T>,
 }
 
-#[derive(Debug, Decode, Encode, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FileCheckpointData {
     Empty,
     MultiPartNotCreated {
@@ -191,9 +470,126 @@ pub enum FileCheckpointData {
     MultiPartWriterUploadCompleted {
         multi_part_upload_id: String,
         completed_parts: Vec<String>,
+        checksum: Option<String>,
+    },
+    /// Written with a single put to `staging_path`, from which it's moved into place on commit
+    SinglePutStaged {
+        staging_path: String,
+        checksum: Option<String>,
     },
 }
 
+// Checkpoints from before checksum sidecars were added encode `MultiPartWriterUploadCompleted`
+// without a checksum, and they're nested in `active_files` where a missing trailing field can't
+// be detected. So that layout is kept for uploads without a checksum, and uploads with one are
+// encoded as a variant that older checkpoints don't contain.
+const UPLOAD_COMPLETED_WITH_CHECKSUM_VARIANT: u32 = 5;
+const SINGLE_PUT_STAGED_VARIANT: u32 = 6;
+
+impl Encode for FileCheckpointData {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        match self {
+            FileCheckpointData::Empty => 0u32.encode(encoder),
+            FileCheckpointData::MultiPartNotCreated {
+                parts_to_add,
+                trailing_bytes,
+            } => {
+                1u32.encode(encoder)?;
+                parts_to_add.encode(encoder)?;
+                trailing_bytes.encode(encoder)
+            }
+            FileCheckpointData::MultiPartInFlight {
+                multi_part_upload_id,
+                in_flight_parts,
+                trailing_bytes,
+            } => {
+                2u32.encode(encoder)?;
+                multi_part_upload_id.encode(encoder)?;
+                in_flight_parts.encode(encoder)?;
+                trailing_bytes.encode(encoder)
+            }
+            FileCheckpointData::MultiPartWriterClosed {
+                multi_part_upload_id,
+                in_flight_parts,
+            } => {
+                3u32.encode(encoder)?;
+                multi_part_upload_id.encode(encoder)?;
+                in_flight_parts.encode(encoder)
+            }
+            FileCheckpointData::MultiPartWriterUploadCompleted {
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+            } => {
+                match checksum {
+                    Some(_) => UPLOAD_COMPLETED_WITH_CHECKSUM_VARIANT.encode(encoder)?,
+                    None => 4u32.encode(encoder)?,
+                }
+                multi_part_upload_id.encode(encoder)?;
+                completed_parts.encode(encoder)?;
+                match checksum {
+                    Some(checksum) => checksum.encode(encoder),
+                    None => Ok(()),
+                }
+            }
+            FileCheckpointData::SinglePutStaged {
+                staging_path,
+                checksum,
+            } => {
+                SINGLE_PUT_STAGED_VARIANT.encode(encoder)?;
+                staging_path.encode(encoder)?;
+                checksum.encode(encoder)
+            }
+        }
+    }
+}
+
+impl Decode for FileCheckpointData {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        match u32::decode(decoder)? {
+            0 => Ok(FileCheckpointData::Empty),
+            1 => Ok(FileCheckpointData::MultiPartNotCreated {
+                parts_to_add: Decode::decode(decoder)?,
+                trailing_bytes: Decode::decode(decoder)?,
+            }),
+            2 => Ok(FileCheckpointData::MultiPartInFlight {
+                multi_part_upload_id: Decode::decode(decoder)?,
+                in_flight_parts: Decode::decode(decoder)?,
+                trailing_bytes: Decode::decode(decoder)?,
+            }),
+            3 => Ok(FileCheckpointData::MultiPartWriterClosed {
+                multi_part_upload_id: Decode::decode(decoder)?,
+                in_flight_parts: Decode::decode(decoder)?,
+            }),
+            variant @ (4 | UPLOAD_COMPLETED_WITH_CHECKSUM_VARIANT) => {
+                Ok(FileCheckpointData::MultiPartWriterUploadCompleted {
+                    multi_part_upload_id: Decode::decode(decoder)?,
+                    completed_parts: Decode::decode(decoder)?,
+                    checksum: if variant == UPLOAD_COMPLETED_WITH_CHECKSUM_VARIANT {
+                        Some(Decode::decode(decoder)?)
+                    } else {
+                        None
+                    },
+                })
+            }
+            SINGLE_PUT_STAGED_VARIANT => Ok(FileCheckpointData::SinglePutStaged {
+                staging_path: Decode::decode(decoder)?,
+                checksum: Decode::decode(decoder)?,
+            }),
+            found => Err(DecodeError::UnexpectedVariant {
+                type_name: "FileCheckpointData",
+                allowed: &AllowedEnumVariants::Range {
+                    min: 0,
+                    max: SINGLE_PUT_STAGED_VARIANT,
+                },
+                found,
+            }),
+        }
+    }
+}
+
+bincode::impl_borrow_decode!(FileCheckpointData);
+
 #[derive(Debug, Decode, Encode, Clone, PartialEq, Eq)]
 pub enum InFlightPartCheckpoint {
     FinishedPart { part: usize, content_id: String },
@@ -201,7 +597,7 @@ pub enum InFlightPartCheckpoint {
 }
 
 struct S3Credentialing {
-    credentials_provider: DefaultCredentialsProvider,
+    credentials_provider: Box<dyn ProvideAwsCredentials + Send + Sync>,
 }
 
 impl Debug for S3Credentialing {
@@ -211,13 +607,81 @@ impl Debug for S3Credentialing {
 }
 
 impl S3Credentialing {
-    fn try_new() -> Result<Self> {
+    /// The default credentials, which aren't looked up until they're first needed
+    fn new() -> Self {
+        Self {
+            credentials_provider: Box::new(LazyDefaultCredentials::default()),
+        }
+    }
+
+    /// Credentials for `role_arn`, assumed through STS in `sts_region` with the `base`
+    /// credentials and refreshed before they expire. This is scoped to a single sink, so
+    /// that sinks can write to buckets in different accounts.
+    fn assume_role(
+        base: impl ProvideAwsCredentials + Send + Sync + 'static,
+        role_arn: String,
+        external_id: Option<String>,
+        sts_region: Region,
+    ) -> Result<Self> {
+        let sts = StsClient::new_with(HttpClient::new()?, base, sts_region);
+        let provider = StsAssumeRoleSessionCredentialsProvider::new(
+            sts,
+            role_arn,
+            format!("arroyo-{}", Uuid::new_v4()),
+            external_id,
+            None,
+            None,
+            None,
+        );
         Ok(Self {
-            credentials_provider: DefaultCredentialsProvider::new()?,
+            credentials_provider: Box::new(AutoRefreshingProvider::new(provider)?),
         })
     }
 }
 
+/// Builds the default credentials provider on first use, so that creating a sink doesn't
+/// depend on the environment's AWS configuration until the sink makes a request to S3
+#[derive(Default)]
+struct LazyDefaultCredentials {
+    provider: OnceLock<std::result::Result<Box<dyn ProvideAwsCredentials + Send + Sync>, String>>,
+}
+
+impl LazyDefaultCredentials {
+    fn provider(
+        &self,
+    ) -> std::result::Result<&(dyn ProvideAwsCredentials + Send + Sync), CredentialsError> {
+        self.provider
+            .get_or_init(|| {
+                // prefer assuming the IRSA role directly when running on EKS
+                let provider: Box<dyn ProvideAwsCredentials + Send + Sync> =
+                    match WebIdentityProvider::from_env() {
+                        Some(web_identity) => Box::new(
+                            AutoRefreshingProvider::new(web_identity).map_err(|e| e.message)?,
+                        ),
+                        None => Box::new(DefaultCredentialsProvider::new().map_err(|e| e.message)?),
+                    };
+                Ok(provider)
+            })
+            .as_ref()
+            .map(|provider| provider.as_ref())
+            .map_err(CredentialsError::new)
+    }
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for LazyDefaultCredentials {
+    async fn credentials(&self) -> std::result::Result<AwsCredentials, CredentialsError> {
+        self.provider()?.credentials().await
+    }
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for S3Credentialing {
+    async fn credentials(&self) -> std::result::Result<AwsCredentials, CredentialsError> {
+        self.credentials_provider.credentials().await
+    }
+}
+
 #[async_trait::async_trait]
 impl CredentialProvider for S3Credentialing {
     #[doc = " The type of credential returned by this provider"]
@@ -241,25 +705,106 @@ impl CredentialProvider for S3Credentialing {
     }
 }
 
+const PARTITION_STATS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+// manifest of small committed files written when `compaction_candidate_threshold` is set
+const COMPACTION_CANDIDATES_FILE: &str = "_compaction_candidates.json";
+// maximum number of files completed at once when committing a checkpoint
+const FINISH_FILE_CONCURRENCY: usize = 10;
+// number of finished filenames each subtask remembers to keep commits idempotent
+const MAX_TRACKED_FINISHED_FILES: usize = 10_000;
+
+lazy_static! {
+    static ref RECOVERED_FILES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "arroyo_worker_filesystem_recovered_files",
+        "Count of in-progress files recovered from checkpoints by the filesystem sink",
+        &["path", "subtask_idx"]
+    )
+    .unwrap();
+    static ref RECOVERED_RECORDS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "arroyo_worker_filesystem_recovered_records",
+        "Count of buffered records re-inserted from checkpoints by the filesystem sink",
+        &["path", "subtask_idx"]
+    )
+    .unwrap();
+    static ref RECOVERED_PARTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "arroyo_worker_filesystem_recovered_parts",
+        "Count of parts re-uploaded while recovering files from checkpoints by the filesystem sink",
+        &["path", "subtask_idx"]
+    )
+    .unwrap();
+}
+
+/// Totals for the files recovered by a single `Init`
+#[derive(Debug, Default)]
+struct RecoveryStats {
+    files: usize,
+    records: usize,
+    parts: usize,
+}
+
+/// The files listed in a compaction candidates manifest
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct CompactionCandidates {
+    files: Vec<CompactionCandidate>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct CompactionCandidate {
+    path: String,
+    bytes: usize,
+}
+
 struct AsyncMultipartFileSystemWriter<T: Data + Sync, R: MultiPartWriter> {
     path: Path,
     current_writer_name: String,
     max_file_index: usize,
     subtask_id: usize,
+    parallelism: usize,
     object_store: Arc<dyn ObjectStore>,
     writers: HashMap<String, R>,
     receiver: Receiver<FileSystemMessages<T>>,
     checkpoint_sender: Sender<CheckpointData<T>>,
     futures: FuturesUnordered<BoxedTryFuture<MultipartCallbackWithName>>,
+    // uploads waiting for room under `max_inflight_uploads`, in the order they were created
+    queued_futures: VecDeque<BoxedTryFuture<MultipartCallbackWithName>>,
+    max_inflight_uploads: Option<usize>,
     files_to_finish: Vec<FileToFinish>,
     properties: FileSystemTable,
     rolling_policy: RollingPolicy,
+    clock: Arc<dyn Clock>,
+    // totals for files that are no longer being written, by partition
+    finished_partition_stats: HashMap<String, PartitionStats>,
+    // subdirectory of `path` that this run's files are written to, if configured
+    run_directory: Option<String>,
+    // appended to filenames, if configured; files are recovered by their checkpointed names,
+    // so it doesn't need to match across restarts
+    host_name: Option<String>,
+    // how long the current file can go without writes before its buffer is uploaded as a part
+    idle_flush: Option<Duration>,
+    // files smaller than this are listed in the compaction candidates manifest on commit
+    compaction_candidate_threshold: Option<usize>,
+    // sizes of closed files waiting to be committed, if compaction candidates are tracked
+    finished_file_sizes: HashMap<String, usize>,
+    // width of the event-time windows files are aligned to, if `finalize_on_watermark_seconds`
+    // is set
+    finalize_on_watermark: Option<Duration>,
+    // the open writer for each event-time window with records, by the end of the window
+    window_writers: BTreeMap<SystemTime, String>,
+    max_parts: usize,
+    // what happens when the current file has `max_parts` parts and more data arrives
+    on_part_limit: OnPartLimit,
 }
 
 #[async_trait]
 pub trait MultiPartWriter {
     type InputType: Data;
-    fn new(object_store: Arc<dyn ObjectStore>, path: Path, config: &FileSystemTable) -> Self;
+    fn new(
+        object_store: Arc<dyn ObjectStore>,
+        path: Path,
+        config: &FileSystemTable,
+    ) -> Result<Self>
+    where
+        Self: Sized;
 
     fn name(&self) -> String;
 
@@ -274,6 +819,10 @@ pub trait MultiPartWriter {
         multipart_id: String,
     ) -> Result<Vec<BoxedTryFuture<MultipartCallbackWithName>>>;
 
+    fn handle_multipart_not_supported(
+        &mut self,
+    ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>>;
+
     fn handle_completed_part(
         &mut self,
         part_idx: usize,
@@ -286,29 +835,61 @@ pub trait MultiPartWriter {
 
     fn close(&mut self) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>>;
 
+    /// Uploads any buffered bytes as a part without closing the file
+    fn flush_buffer(&mut self) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>>;
+
     fn stats(&self) -> Option<MultiPartWriterStats>;
 
     fn get_finished_file(&mut self) -> FileToFinish;
+
+    async fn abort(&mut self) -> Result<()>;
 }
 
 async fn from_checkpoint(
     path: &Path,
     checkpoint_data: FileCheckpointData,
     object_store: Arc<dyn ObjectStore>,
-) -> Result<Option<FileToFinish>> {
+    checksum_sidecar: bool,
+    restart_expired_uploads: bool,
+) -> Result<(Option<FileToFinish>, usize)> {
     let mut parts = vec![];
+    let mut checksum = None;
+    // number of parts (or single puts) that had to be uploaded again
+    let mut parts_uploaded = 0;
     let multipart_id = match checkpoint_data {
         FileCheckpointData::Empty => {
-            return Ok(None);
+            return Ok((None, 0));
         }
         FileCheckpointData::MultiPartNotCreated {
             parts_to_add,
             trailing_bytes,
         } => {
-            let multipart_id = object_store
-                .start_multipart(path)
-                .await
-                .expect("failed to create multipart upload");
+            let multipart_id = match object_store.start_multipart(path).await {
+                Ok(multipart_id) => multipart_id,
+                Err(object_store::Error::NotImplemented) => {
+                    warn!(
+                        "object store does not support multipart uploads, writing {} with a single put",
+                        path
+                    );
+                    let bytes: Vec<u8> = parts_to_add
+                        .into_iter()
+                        .chain(trailing_bytes)
+                        .flatten()
+                        .collect();
+                    let checksum = checksum_sidecar.then(|| sha256_hex(&bytes));
+                    let staging_path = staging_path(path)?;
+                    object_store.put(&staging_path, bytes.into()).await?;
+                    return Ok((
+                        Some(FileToFinish::staged(
+                            path.to_string(),
+                            staging_path.to_string(),
+                            checksum,
+                        )),
+                        1,
+                    ));
+                }
+                Err(err) => bail!("failed to create multipart upload for {}: {}", path, err),
+            };
             let mut parts = vec![];
             for (part_index, data) in parts_to_add.into_iter().enumerate() {
                 let upload_part = object_store
@@ -316,12 +897,14 @@ async fn from_checkpoint(
                     .await
                     .unwrap();
                 parts.push(upload_part);
+                parts_uploaded += 1;
             }
             if let Some(trailing_bytes) = trailing_bytes {
                 let upload_part = object_store
                     .add_multipart(path, &multipart_id, parts.len(), trailing_bytes.into())
                     .await?;
                 parts.push(upload_part);
+                parts_uploaded += 1;
             }
             multipart_id
         }
@@ -342,6 +925,7 @@ async fn from_checkpoint(
                             .await
                             .unwrap();
                         parts.push(upload_part);
+                        parts_uploaded += 1;
                     }
                 }
             }
@@ -355,6 +939,7 @@ async fn from_checkpoint(
                     )
                     .await?;
                 parts.push(upload_part);
+                parts_uploaded += 1;
             }
             multi_part_upload_id
         }
@@ -362,129 +947,803 @@ async fn from_checkpoint(
             multi_part_upload_id,
             in_flight_parts,
         } => {
-            for (part_index, data) in in_flight_parts.into_iter().enumerate() {
-                match data {
-                    InFlightPartCheckpoint::FinishedPart {
-                        part: _,
-                        content_id,
-                    } => parts.push(UploadPart { content_id }),
-                    InFlightPartCheckpoint::InProgressPart { part: _, data } => {
-                        let upload_part = object_store
-                            .add_multipart(path, &multi_part_upload_id, part_index, data.into())
-                            .await
-                            .unwrap();
-                        parts.push(upload_part);
+            match upload_closed_parts(
+                path,
+                &multi_part_upload_id,
+                &in_flight_parts,
+                object_store.as_ref(),
+            )
+            .await
+            {
+                Ok(uploaded) => {
+                    parts_uploaded += uploaded.len() - finished_parts(&in_flight_parts);
+                    parts = uploaded;
+                    multi_part_upload_id
+                }
+                Err(err) if is_expired_upload(&err) => {
+                    if !restart_expired_uploads {
+                        bail!(
+                            "multipart upload {} for {} no longer exists: {}",
+                            multi_part_upload_id,
+                            path,
+                            err
+                        );
+                    }
+                    // parts uploaded before the checkpoint only have their content ids, so
+                    // their data was lost with the upload
+                    if finished_parts(&in_flight_parts) > 0 {
+                        bail!(
+                            "multipart upload {} for {} no longer exists, and its uploaded parts can't be recovered: {}",
+                            multi_part_upload_id,
+                            path,
+                            err
+                        );
                     }
+                    let multipart_id = object_store.start_multipart(path).await?;
+                    warn!(
+                        "multipart upload {} for {} no longer exists, restarting it as {}",
+                        multi_part_upload_id, path, multipart_id
+                    );
+                    parts = upload_closed_parts(
+                        path,
+                        &multipart_id,
+                        &in_flight_parts,
+                        object_store.as_ref(),
+                    )
+                    .await?;
+                    parts_uploaded += parts.len();
+                    multipart_id
                 }
+                Err(err) => bail!("failed to upload parts for {}: {}", path, err),
             }
-            multi_part_upload_id
         }
         FileCheckpointData::MultiPartWriterUploadCompleted {
             multi_part_upload_id,
             completed_parts,
+            checksum: completed_checksum,
         } => {
             for content_id in completed_parts {
                 parts.push(UploadPart { content_id })
             }
+            checksum = completed_checksum;
             multi_part_upload_id
         }
+        FileCheckpointData::SinglePutStaged {
+            staging_path,
+            checksum,
+        } => {
+            return Ok((
+                Some(FileToFinish::staged(
+                    path.to_string(),
+                    staging_path,
+                    checksum,
+                )),
+                0,
+            ));
+        }
     };
-    Ok(Some(FileToFinish {
-        filename: path.to_string(),
-        multi_part_upload_id: multipart_id,
-        completed_parts: parts.into_iter().map(|p| p.content_id).collect(),
-    }))
+    Ok((
+        Some(FileToFinish {
+            filename: path.to_string(),
+            multi_part_upload_id: multipart_id,
+            completed_parts: parts.into_iter().map(|p| p.content_id).collect(),
+            checksum,
+            staging_path: None,
+        }),
+        parts_uploaded,
+    ))
+}
+
+/// Uploads the in-progress parts of a closed writer to `multipart_id`, returning all of its
+/// parts in order
+async fn upload_closed_parts(
+    path: &Path,
+    multipart_id: &MultipartId,
+    in_flight_parts: &[InFlightPartCheckpoint],
+    object_store: &dyn ObjectStore,
+) -> object_store::Result<Vec<UploadPart>> {
+    let mut parts = vec![];
+    for (part_index, data) in in_flight_parts.iter().enumerate() {
+        match data {
+            InFlightPartCheckpoint::FinishedPart {
+                part: _,
+                content_id,
+            } => parts.push(UploadPart {
+                content_id: content_id.clone(),
+            }),
+            InFlightPartCheckpoint::InProgressPart { part: _, data } => {
+                parts.push(
+                    object_store
+                        .add_multipart(path, multipart_id, part_index, data.clone().into())
+                        .await?,
+                );
+            }
+        }
+    }
+    Ok(parts)
 }
 
-#[derive(Debug, Clone, Encode, Decode, PartialEq, Eq)]
+fn finished_parts(in_flight_parts: &[InFlightPartCheckpoint]) -> usize {
+    in_flight_parts
+        .iter()
+        .filter(|part| matches!(part, InFlightPartCheckpoint::FinishedPart { .. }))
+        .count()
+}
+
+/// Whether an error from adding a part means the multipart upload no longer exists, as when
+/// S3 aborts uploads left incomplete for longer than the bucket's lifecycle rules allow
+fn is_expired_upload(err: &object_store::Error) -> bool {
+    matches!(err, object_store::Error::NotFound { .. }) || err.to_string().contains("NoSuchUpload")
+}
+
+/// Completes the multipart upload for a file that was pre-committed in a checkpoint
+async fn finish_file(
+    object_store: Arc<dyn ObjectStore>,
+    file_to_finish: FileToFinish,
+    checksum_sidecar: bool,
+) -> Result<()> {
+    let FileToFinish {
+        filename,
+        multi_part_upload_id,
+        completed_parts,
+        checksum,
+        staging_path,
+    } = file_to_finish;
+    let location = Path::parse(&filename)?;
+    if let Some(staging_path) = staging_path {
+        match object_store
+            .rename(&Path::parse(&staging_path)?, &location)
+            .await
+        {
+            Ok(()) => {}
+            // a commit retried after the earlier attempt had already moved the file into place
+            Err(object_store::Error::NotFound { .. })
+                if object_store.head(&location).await.is_ok() =>
+            {
+                debug!("{} was already finished", filename);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    } else if completed_parts.len() == 0 {
+        // a multipart upload can't be completed without parts, so abort it rather than
+        // leaving it open
+        warn!(
+            "no parts to finish for file {}, aborting its upload",
+            filename
+        );
+        object_store
+            .abort_multipart(&location, &multi_part_upload_id)
+            .await?;
+        return Ok(());
+    } else {
+        let parts: Vec<_> = completed_parts
+            .into_iter()
+            .map(|content_id| UploadPart {
+                content_id: content_id.clone(),
+            })
+            .collect();
+        match object_store
+            .close_multipart(&location, &multi_part_upload_id, parts)
+            .await
+        {
+            Ok(()) => {}
+            // a commit retried after restoring from the checkpoint that pre-committed the file,
+            // when the earlier attempt had already completed the upload
+            Err(err) if is_expired_upload(&err) && object_store.head(&location).await.is_ok() => {
+                debug!("{} was already finished", filename);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if checksum_sidecar {
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => sha256_hex(&object_store.get(&location).await?.bytes().await?),
+        };
+        put_checksum_sidecar(object_store.as_ref(), &location, &checksum).await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Encode, PartialEq, Eq)]
 pub struct FileToFinish {
     filename: String,
     multi_part_upload_id: String,
     completed_parts: Vec<String>,
+    // hex SHA-256 of the file contents, if a checksum sidecar should be written; None for files
+    // recovered mid-upload, whose digest is computed from the finished object instead
+    checksum: Option<String>,
+    // where the file was written with a single put, for object stores without multipart
+    // uploads; it has no upload ID or parts, and is moved to `filename` when committed
+    staging_path: Option<String>,
+}
+
+// Files pre-committed before checksum sidecars and staged puts were added end after
+// `completed_parts`. Each pre-commit is decoded on its own from a slice, so a missing field
+// shows up as the end of the input.
+impl Decode for FileToFinish {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        fn has_more<D: Decoder>(decoder: &mut D) -> bool {
+            decoder.reader().peek_read(1).is_some()
+        }
+        let filename = Decode::decode(decoder)?;
+        let multi_part_upload_id = Decode::decode(decoder)?;
+        let completed_parts = Decode::decode(decoder)?;
+        let checksum = if has_more(decoder) {
+            Decode::decode(decoder)?
+        } else {
+            None
+        };
+        let staging_path = if has_more(decoder) {
+            Decode::decode(decoder)?
+        } else {
+            None
+        };
+        Ok(Self {
+            filename,
+            multi_part_upload_id,
+            completed_parts,
+            checksum,
+            staging_path,
+        })
+    }
+}
+
+impl FileToFinish {
+    fn staged(filename: String, staging_path: String, checksum: Option<String>) -> Self {
+        Self {
+            filename,
+            multi_part_upload_id: String::new(),
+            completed_parts: vec![],
+            checksum,
+            staging_path: Some(staging_path),
+        }
+    }
+
+    fn checkpoint_data(&self) -> FileCheckpointData {
+        match &self.staging_path {
+            Some(staging_path) => FileCheckpointData::SinglePutStaged {
+                staging_path: staging_path.clone(),
+                checksum: self.checksum.clone(),
+            },
+            None => FileCheckpointData::MultiPartWriterUploadCompleted {
+                multi_part_upload_id: self.multi_part_upload_id.clone(),
+                completed_parts: self.completed_parts.clone(),
+                checksum: self.checksum.clone(),
+            },
+        }
+    }
 }
 
 enum RollingPolicy {
     PartLimit(usize),
     SizeLimit(usize),
+    RecordLimit(usize),
     InactivityDuration(Duration),
     RolloverDuration(Duration),
     AnyPolicy(Vec<RollingPolicy>),
 }
 
 impl RollingPolicy {
-    fn should_roll(&self, stats: &MultiPartWriterStats) -> bool {
+    fn should_roll(&self, stats: &MultiPartWriterStats, clock: &dyn Clock) -> bool {
         match self {
             RollingPolicy::PartLimit(part_limit) => stats.parts_written >= *part_limit,
             RollingPolicy::SizeLimit(size_limit) => stats.bytes_written >= *size_limit,
-            RollingPolicy::InactivityDuration(duration) => {
-                stats.last_write_at.elapsed() >= *duration
-            }
-            RollingPolicy::RolloverDuration(duration) => {
-                stats.first_write_at.elapsed() >= *duration
-            }
-            RollingPolicy::AnyPolicy(policies) => {
-                policies.iter().any(|policy| policy.should_roll(stats))
-            }
+            RollingPolicy::RecordLimit(record_limit) => stats.records_written >= *record_limit,
+            RollingPolicy::InactivityDuration(duration) => stats.idle_for(clock) >= *duration,
+            RollingPolicy::RolloverDuration(duration) => stats.age(clock) >= *duration,
+            RollingPolicy::AnyPolicy(policies) => policies
+                .iter()
+                .any(|policy| policy.should_roll(stats, clock)),
         }
     }
 
     fn from_file_settings(file_settings: &FileSettings) -> RollingPolicy {
         let mut policies = vec![];
-        let part_size_limit = file_settings.max_parts.unwrap_or(1000) as usize;
-        // this is a hard limit, so will always be present.
-        policies.push(RollingPolicy::PartLimit(part_size_limit));
+        // this is a hard limit, so it's always present unless reaching it is an error instead
+        if file_settings.on_part_limit != Some(OnPartLimit::Error) {
+            policies.push(RollingPolicy::PartLimit(max_parts(file_settings)));
+        }
         if let Some(file_size_target) = file_settings.target_file_size {
             policies.push(RollingPolicy::SizeLimit(file_size_target as usize))
         }
+        if let Some(max_records) = file_settings.max_records {
+            policies.push(RollingPolicy::RecordLimit(max_records as usize))
+        }
         if let Some(inactivity_timeout) = file_settings
             .inactivity_rollover_seconds
             .map(|seconds| Duration::from_secs(seconds as u64))
         {
             policies.push(RollingPolicy::InactivityDuration(inactivity_timeout))
         }
-        let rollover_timeout =
-            Duration::from_secs(file_settings.rollover_seconds.unwrap_or(30) as u64);
-        policies.push(RollingPolicy::RolloverDuration(rollover_timeout));
+        // a rollover of 0 seconds turns off time-based rolling
+        match file_settings.rollover_seconds.unwrap_or(30) {
+            0 => {}
+            seconds => policies.push(RollingPolicy::RolloverDuration(Duration::from_secs(
+                seconds as u64,
+            ))),
+        }
         RollingPolicy::AnyPolicy(policies)
     }
-}
+}
+
+/// The most parts a file can be uploaded in, which defaults to 1000
+fn max_parts(file_settings: &FileSettings) -> usize {
+    file_settings.max_parts.unwrap_or(1000) as usize
+}
+
+/// The end of the event-time window of `width` (aligned to the Unix epoch) that `time` falls in
+fn window_end(time: SystemTime, width: Duration) -> SystemTime {
+    let width = width.as_nanos();
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    UNIX_EPOCH + Duration::from_nanos((since_epoch / width * width + width) as u64)
+}
+
+/// Formats the `{file_index}-{subtask_index}` part of a filename, zero-padding both indices
+/// so that lexical order matches numeric order. The subtask index is always padded wide
+/// enough to fit the largest subtask index for the parallelism.
+fn indexed_file_name(
+    file_settings: &FileSettings,
+    file_index: usize,
+    subtask_index: usize,
+    parallelism: usize,
+) -> String {
+    let file_index_width = file_settings.file_index_width.unwrap_or(5) as usize;
+    let subtask_index_width = (file_settings.subtask_index_width.unwrap_or(3) as usize)
+        .max(parallelism.saturating_sub(1).to_string().len());
+    format!(
+        "{:0>file_index_width$}-{:0>subtask_index_width$}",
+        file_index, subtask_index
+    )
+}
+
+/// A subdirectory unique to this run of the writer, if `run_subdirectory` is set, so that its
+/// files can't collide with those of earlier runs (e.g., at a different parallelism)
+fn run_directory(file_settings: &FileSettings) -> Option<String> {
+    match file_settings.run_subdirectory? {
+        RunSubdirectory::Uuid => Some(format!("run-{}", Uuid::new_v4())),
+        RunSubdirectory::Epoch => Some(format!(
+            "run-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        )),
+    }
+}
+
+/// The configured `key_prefix` without leading or trailing slashes, so that it can be joined
+/// to the base path without doubling them up
+fn key_prefix(file_settings: &FileSettings) -> Option<&str> {
+    file_settings
+        .key_prefix
+        .as_deref()
+        .map(|prefix| prefix.trim_matches('/'))
+        .filter(|prefix| !prefix.is_empty())
+}
+
+/// The name of this host from `$HOSTNAME` (the pod name, on Kubernetes), if
+/// `include_host_in_filename` is enabled
+fn host_name(file_settings: &FileSettings) -> Option<String> {
+    if !file_settings.include_host_in_filename.unwrap_or(false) {
+        return None;
+    }
+    sanitize_host_name(&std::env::var("HOSTNAME").ok()?)
+}
+
+/// Replaces everything but ASCII letters, digits, `-`, and `_` with `-`, so that the host name
+/// can't add path segments or an extension to a filename
+fn sanitize_host_name(host_name: &str) -> Option<String> {
+    let sanitized: String = host_name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    (!sanitized.is_empty()).then_some(sanitized)
+}
+
+/// Appends the host name to a filename, if there is one
+fn with_host_name(file_name: String, host_name: &Option<String>) -> String {
+    match host_name {
+        Some(host_name) => format!("{}-{}", file_name, host_name),
+        None => file_name,
+    }
+}
+
+/// The `subtask={id}` directory that a subtask's files are written to, if
+/// `subtask_subdirectory` is enabled
+fn subtask_directory(file_settings: &FileSettings, subtask_index: usize) -> Option<String> {
+    file_settings
+        .subtask_subdirectory
+        .unwrap_or(false)
+        .then(|| format!("subtask={}", subtask_index))
+}
+
+/// The largest part a multipart upload to `destination` accepts, or None if there's no limit
+fn max_part_size(destination: &Destination) -> Option<usize> {
+    const GIB: usize = 1024 * 1024 * 1024;
+    const MIB: usize = 1024 * 1024;
+    match destination {
+        Destination::LocalFilesystem { .. } => None,
+        Destination::S3Bucket { .. } | Destination::GcsBucket { .. } => Some(5 * GIB),
+        Destination::FolderUri {
+            path,
+            scheme_override,
+        } => match scheme_override
+            .clone()
+            .or_else(|| Some(url::Url::parse(path).ok()?.scheme().to_string()))?
+            .as_str()
+        {
+            "s3" | "s3a" | "gs" => Some(5 * GIB),
+            "az" | "adl" | "azure" | "abfs" | "abfss" => Some(4000 * MIB),
+            _ => None,
+        },
+    }
+}
+
+fn checksum_sidecar_enabled(config: &FileSystemTable) -> bool {
+    matches!(
+        config.file_settings,
+        Some(FileSettings {
+            write_checksum_sidecar: Some(true),
+            ..
+        })
+    )
+}
+
+/// Whether recovery restarts multipart uploads that expired while the job was down, which
+/// defaults to true
+fn restart_expired_uploads_enabled(config: &FileSystemTable) -> bool {
+    !matches!(
+        config.file_settings,
+        Some(FileSettings {
+            restart_expired_uploads: Some(false),
+            ..
+        })
+    )
+}
+
+fn roll_on_checkpoint_enabled(config: &FileSystemTable) -> bool {
+    matches!(
+        config.file_settings,
+        Some(FileSettings {
+            roll_on_checkpoint: Some(true),
+            ..
+        })
+    )
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Where a file written with a single put is uploaded until its checkpoint commits, so that it
+/// isn't visible at `path` before then
+fn staging_path(path: &Path) -> Result<Path> {
+    Ok(Path::parse(format!("{}.staging", path))?)
+}
+
+/// Writes `{filename}.sha256` next to `path`, containing the hex SHA-256 digest of its contents
+async fn put_checksum_sidecar(
+    object_store: &dyn ObjectStore,
+    path: &Path,
+    checksum: &str,
+) -> Result<()> {
+    let sidecar = Path::parse(format!("{}.sha256", path))?;
+    object_store
+        .put(&sidecar, checksum.as_bytes().to_vec().into())
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MultiPartWriterStats {
+    bytes_written: usize,
+    parts_written: usize,
+    records_written: usize,
+    #[serde(rename = "idle_for", serialize_with = "serialize_elapsed")]
+    last_write_at: Instant,
+    #[serde(rename = "age", serialize_with = "serialize_elapsed")]
+    first_write_at: Instant,
+}
+
+impl MultiPartWriterStats {
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    pub fn parts_written(&self) -> usize {
+        self.parts_written
+    }
+
+    pub fn records_written(&self) -> usize {
+        self.records_written
+    }
+
+    /// Time since the last value was written, according to `clock`
+    pub fn idle_for(&self, clock: &dyn Clock) -> Duration {
+        clock.now().saturating_duration_since(self.last_write_at)
+    }
+
+    /// Time since the first value was written, according to `clock`
+    pub fn age(&self, clock: &dyn Clock) -> Duration {
+        clock.now().saturating_duration_since(self.first_write_at)
+    }
+}
+
+/// A file that's being written but hasn't been committed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InProgressFile {
+    pub name: String,
+    pub bytes_written: usize,
+    pub parts_written: usize,
+    pub records_written: usize,
+}
+
+/// Totals for the files written to one partition (the directory under the sink's path)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PartitionStats {
+    pub bytes_written: usize,
+    pub parts_written: usize,
+    pub files: usize,
+}
+
+impl PartitionStats {
+    fn add(&mut self, stats: &MultiPartWriterStats) {
+        self.bytes_written += stats.bytes_written;
+        self.parts_written += stats.parts_written;
+        self.files += 1;
+    }
+}
+
+fn serialize_elapsed<S: serde::Serializer>(
+    instant: &Instant,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    instant.elapsed().serialize(serializer)
+}
+
+impl<T, R> AsyncMultipartFileSystemWriter<T, R>
+where
+    T: Data + std::marker::Sync,
+    R: MultiPartWriter<InputType = T>,
+{
+    fn new(
+        path: Path,
+        object_store: Arc<dyn ObjectStore>,
+        receiver: Receiver<FileSystemMessages<T>>,
+        checkpoint_sender: Sender<CheckpointData<T>>,
+        writer_properties: FileSystemTable,
+    ) -> Self {
+        Self {
+            path,
+            current_writer_name: "".to_string(),
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            object_store,
+            writers: HashMap::new(),
+            receiver,
+            checkpoint_sender,
+            futures: FuturesUnordered::new(),
+            queued_futures: VecDeque::new(),
+            max_inflight_uploads: writer_properties
+                .file_settings
+                .as_ref()
+                .and_then(|settings| settings.max_inflight_uploads)
+                .map(|max_inflight_uploads| (max_inflight_uploads as usize).max(1)),
+            files_to_finish: Vec::new(),
+            rolling_policy: RollingPolicy::from_file_settings(
+                writer_properties.file_settings.as_ref().unwrap(),
+            ),
+            run_directory: run_directory(writer_properties.file_settings.as_ref().unwrap()),
+            host_name: host_name(writer_properties.file_settings.as_ref().unwrap()),
+            idle_flush: writer_properties
+                .file_settings
+                .as_ref()
+                .and_then(|settings| settings.idle_flush_seconds)
+                .map(|seconds| Duration::from_secs(seconds as u64)),
+            compaction_candidate_threshold: writer_properties
+                .file_settings
+                .as_ref()
+                .and_then(|settings| settings.compaction_candidate_threshold)
+                .map(|threshold| threshold as usize),
+            finished_file_sizes: HashMap::new(),
+            finalize_on_watermark: writer_properties
+                .file_settings
+                .as_ref()
+                .and_then(|settings| settings.finalize_on_watermark_seconds)
+                .filter(|seconds| *seconds > 0)
+                .map(|seconds| Duration::from_secs(seconds as u64)),
+            window_writers: BTreeMap::new(),
+            max_parts: max_parts(writer_properties.file_settings.as_ref().unwrap()),
+            on_part_limit: writer_properties
+                .file_settings
+                .as_ref()
+                .and_then(|settings| settings.on_part_limit)
+                .unwrap_or(OnPartLimit::Roll),
+            properties: writer_properties,
+            clock: Arc::new(TokioClock),
+            finished_partition_stats: HashMap::new(),
+        }
+    }
+
+    /// The partition a writer belongs to: the directory of its file relative to the sink's path
+    fn partition_for(&self, writer_name: &str) -> String {
+        let relative = writer_name
+            .strip_prefix(self.path.as_ref())
+            .unwrap_or(writer_name)
+            .trim_start_matches('/');
+        match relative.rsplit_once('/') {
+            Some((partition, _)) => partition.to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Bytes, parts, and files written by this subtask for each partition, including the
+    /// files currently being written
+    pub fn partition_stats(&self) -> HashMap<String, PartitionStats> {
+        let mut partition_stats = self.finished_partition_stats.clone();
+        for (name, writer) in &self.writers {
+            if let Some(stats) = writer.stats() {
+                partition_stats
+                    .entry(self.partition_for(name))
+                    .or_default()
+                    .add(&stats);
+            }
+        }
+        partition_stats
+    }
+
+    /// Every file with an open writer, sorted by name. Writers that haven't received any data
+    /// yet are listed with zero bytes.
+    fn in_progress_files(&self) -> Vec<InProgressFile> {
+        let mut files: Vec<_> = self
+            .writers
+            .iter()
+            .map(|(name, writer)| {
+                let stats = writer.stats();
+                InProgressFile {
+                    name: name.clone(),
+                    bytes_written: stats.as_ref().map_or(0, |stats| stats.bytes_written),
+                    parts_written: stats.as_ref().map_or(0, |stats| stats.parts_written),
+                    records_written: stats.as_ref().map_or(0, |stats| stats.records_written),
+                }
+            })
+            .collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        files
+    }
+
+    fn log_partition_stats(&self) {
+        for (partition, stats) in self.partition_stats() {
+            debug!(
+                "{}/{}: {} bytes in {} parts across {} files",
+                self.path, partition, stats.bytes_written, stats.parts_written, stats.files
+            );
+        }
+    }
+
+    // removes a writer whose file is complete, keeping its stats for its partition
+    fn retire_writer(&mut self, name: &str) -> Option<MultiPartWriterStats> {
+        let stats = self
+            .writers
+            .remove(name)
+            .and_then(|writer| writer.stats())?;
+        let partition = self.partition_for(name);
+        self.finished_partition_stats
+            .entry(partition)
+            .or_default()
+            .add(&stats);
+        Some(stats)
+    }
+
+    /// Retires the writer of a file that's waiting to be committed, remembering its size if
+    /// compaction candidates are tracked
+    fn retire_writer_to_finish(&mut self, name: &str, filename: &str) {
+        let stats = self.retire_writer(name);
+        if let (Some(_), Some(stats)) = (self.compaction_candidate_threshold, stats) {
+            self.finished_file_sizes
+                .insert(filename.to_string(), stats.bytes_written);
+        }
+    }
+
+    /// Adds the committed files below `compaction_candidate_threshold` to the manifest of
+    /// compaction candidates, keeping the files listed by earlier commits. Files recovered from
+    /// a checkpoint have no stats, so their sizes are looked up in the object store.
+    async fn write_compaction_candidates(&mut self, committed: &[String]) -> Result<()> {
+        let Some(threshold) = self.compaction_candidate_threshold else {
+            return Ok(());
+        };
+        let mut candidates = vec![];
+        for filename in committed {
+            let bytes = match self.finished_file_sizes.remove(filename) {
+                Some(bytes) => bytes,
+                None => match self.object_store.head(&Path::parse(filename)?).await {
+                    Ok(meta) => meta.size,
+                    // files without any parts are aborted rather than committed
+                    Err(object_store::Error::NotFound { .. }) => continue,
+                    Err(err) => return Err(err.into()),
+                },
+            };
+            if bytes < threshold {
+                candidates.push(CompactionCandidate {
+                    path: filename.clone(),
+                    bytes,
+                });
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.compaction_candidates_path();
+        let mut files = match self.object_store.get(&path).await {
+            Ok(existing) => {
+                serde_json::from_slice::<CompactionCandidates>(&existing.bytes().await?)?.files
+            }
+            Err(object_store::Error::NotFound { .. }) => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        // a commit that's retried after a restore lists its files again
+        files.retain(|file| {
+            !candidates
+                .iter()
+                .any(|candidate| candidate.path == file.path)
+        });
+        files.extend(candidates);
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let manifest = serde_json::to_vec(&CompactionCandidates { files })?;
+        self.object_store.put(&path, manifest.into()).await?;
+        Ok(())
+    }
 
-#[derive(Debug, Clone)]
-pub struct MultiPartWriterStats {
-    bytes_written: usize,
-    parts_written: usize,
-    last_write_at: Instant,
-    first_write_at: Instant,
-}
+    /// Where this subtask's compaction candidates manifest is written: next to its files, with
+    /// the subtask in the name if other subtasks write to the same directory
+    fn compaction_candidates_path(&self) -> Path {
+        let file_settings = self.properties.file_settings.as_ref().unwrap();
+        let name = if self.parallelism > 1
+            && subtask_directory(file_settings, self.subtask_id).is_none()
+        {
+            format!("_compaction_candidates-{:05}.json", self.subtask_id)
+        } else {
+            COMPACTION_CANDIDATES_FILE.to_string()
+        };
+        format!("{}/{}", self.output_directory(), name).into()
+    }
 
-impl<T, R> AsyncMultipartFileSystemWriter<T, R>
-where
-    T: Data + std::marker::Sync,
-    R: MultiPartWriter<InputType = T>,
-{
-    fn new(
-        path: Path,
-        object_store: Arc<dyn ObjectStore>,
-        receiver: Receiver<FileSystemMessages<T>>,
-        checkpoint_sender: Sender<CheckpointData<T>>,
-        writer_properties: FileSystemTable,
-    ) -> Self {
-        Self {
-            path,
-            current_writer_name: "".to_string(),
-            max_file_index: 0,
-            subtask_id: 0,
-            object_store,
-            writers: HashMap::new(),
-            receiver,
-            checkpoint_sender,
-            futures: FuturesUnordered::new(),
-            files_to_finish: Vec::new(),
-            rolling_policy: RollingPolicy::from_file_settings(
-                writer_properties.file_settings.as_ref().unwrap(),
-            ),
-            properties: writer_properties,
+    fn record_recovery(&self, recovered: &RecoveryStats) {
+        if recovered.files == 0 {
+            return;
         }
+        info!(
+            path = %self.path,
+            subtask_id = self.subtask_id,
+            recovered_files = recovered.files,
+            buffered_records = recovered.records,
+            parts_uploaded = recovered.parts,
+            "recovered in-progress files from checkpoint"
+        );
+        let labels = [self.path.as_ref(), &self.subtask_id.to_string()];
+        RECOVERED_FILES_COUNTER
+            .with_label_values(&labels)
+            .inc_by(recovered.files as u64);
+        RECOVERED_RECORDS_COUNTER
+            .with_label_values(&labels)
+            .inc_by(recovered.records as u64);
+        RECOVERED_PARTS_COUNTER
+            .with_label_values(&labels)
+            .inc_by(recovered.parts as u64);
     }
 
     fn add_part_to_finish(&mut self, file_to_finish: FileToFinish) {
@@ -492,47 +1751,94 @@ where
     }
 
     async fn run(&mut self) -> Result<()> {
+        let result = self.process_messages().await;
+        if result.is_err() && self.checkpoint_sender.is_closed() {
+            // the sink was dropped, so there's no one to hand checkpoints to; nothing written
+            // since the last checkpoint can be committed
+            warn!(
+                path = %self.path,
+                subtask_id = self.subtask_id,
+                "filesystem sink is no longer receiving checkpoints; aborting in-progress files and shutting down the writer"
+            );
+            if let Err(err) = self.abort_in_progress_files().await {
+                warn!(
+                    path = %self.path,
+                    "failed to abort in-progress files while shutting down: {:?}", err
+                );
+            }
+            return Ok(());
+        }
+        result
+    }
+
+    async fn process_messages(&mut self) -> Result<()> {
         let mut next_policy_check = tokio::time::Instant::now();
+        let mut next_stats_log = tokio::time::Instant::now() + PARTITION_STATS_LOG_INTERVAL;
         loop {
             tokio::select! {
                 Some(message) = self.receiver.recv() => {
                     match message {
                         FileSystemMessages::Data{value, time} => {
+                            self.select_window_writer(time)?;
+                            self.enforce_part_limit()?;
                             let Some(writer) = self.writers.get_mut(&self.current_writer_name) else {
                                 bail!("expect the current writer to be initialized");
                             };
                             if let Some(future) = writer.insert_value(value, time).await? {
-                                self.futures.push(future);
+                                self.push_future(future);
                             }
                         },
-                        FileSystemMessages::Init {max_file_index, subtask_id, recovered_files } => {
+                        FileSystemMessages::Watermark(watermark) => {
+                            self.close_complete_windows(watermark)?;
+                        },
+                        FileSystemMessages::Init {max_file_index, subtask_id, parallelism, job_id, operator_id, run_directory, recovered_files } => {
                             if let Some(writer) = self.writers.get_mut(&self.current_writer_name) {
                                 if let Some(future) = writer.close()? {
-                                    self.futures.push(future);
+                                    self.push_future(future);
                                 }
                             }
                             self.max_file_index = max_file_index;
                             self.subtask_id = subtask_id;
-                            let new_writer = self.new_writer();
+                            self.parallelism = parallelism;
+                            if self.run_directory.is_some() && run_directory.is_some() {
+                                self.run_directory = run_directory;
+                            }
+                            add_lineage_metadata(&mut self.properties, &job_id, &operator_id);
+                            let new_writer = self.new_writer()?;
                             self.current_writer_name = new_writer.name();
                             self.writers.insert(new_writer.name(), new_writer);
+                            let mut recovered = RecoveryStats::default();
                             for recovered_file in recovered_files {
-                                if let Some(file_to_finish) = from_checkpoint(
-                                     &Path::parse(&recovered_file.filename)?, recovered_file.data, self.object_store.clone()).await? {
-                                        self.add_part_to_finish(file_to_finish);
-                                     }
+                                let (file_to_finish, parts_uploaded) = from_checkpoint(
+                                     &Path::parse(&recovered_file.filename)?, recovered_file.data, self.object_store.clone(),
+                                     checksum_sidecar_enabled(&self.properties),
+                                     restart_expired_uploads_enabled(&self.properties)).await?;
+                                if let Some(file_to_finish) = file_to_finish {
+                                    self.add_part_to_finish(file_to_finish);
+                                }
+                                recovered.files += 1;
+                                recovered.parts += parts_uploaded;
+                                recovered.records += recovered_file.buffered_data.len();
 
                                 for value in recovered_file.buffered_data {
                                     let Some(writer) = self.writers.get_mut(&self.current_writer_name) else {
                                         bail!("expect the current writer to be initialized");
                                     };
                                     if let Some(future) = writer.insert_value(value, SystemTime::now()).await? {
-                                        self.futures.push(future);
+                                        self.push_future(future);
                                     }
                                 }
                             }
+                            self.record_recovery(&recovered);
                         },
                         FileSystemMessages::Checkpoint { subtask_id, then_stop } => {
+                            if !then_stop && roll_on_checkpoint_enabled(&self.properties) {
+                                if let Some(writer) = self.writers.get(&self.current_writer_name) {
+                                    if writer.stats().is_some() {
+                                        self.roll_writer()?;
+                                    }
+                                }
+                            }
                             self.flush_futures().await?;
                             if then_stop {
                                 self.stop().await?;
@@ -541,32 +1847,47 @@ where
                             self.checkpoint_sender.send(CheckpointData::Finished {  max_file_index: self.max_file_index}).await?;
                         },
                         FileSystemMessages::FilesToFinish(files_to_finish) =>{
-                            for file_to_finish in files_to_finish {
-                                self.finish_file(file_to_finish).await?;
-                            }
+                            let object_store = self.object_store.clone();
+                            let checksum_sidecar = checksum_sidecar_enabled(&self.properties);
+                            let committed: Vec<_> = files_to_finish.iter().map(|file| file.filename.clone()).collect();
+                            futures::stream::iter(files_to_finish)
+                                .map(|file| finish_file(object_store.clone(), file, checksum_sidecar))
+                                .buffer_unordered(FINISH_FILE_CONCURRENCY)
+                                .try_collect::<Vec<_>>()
+                                .await?;
+                            self.write_compaction_candidates(&committed).await?;
+                            self.checkpoint_sender.send(CheckpointData::Finished {  max_file_index: self.max_file_index}).await?;
+                        }
+                        FileSystemMessages::Abort { subtask_id } => {
+                            self.abort(subtask_id).await?;
                             self.checkpoint_sender.send(CheckpointData::Finished {  max_file_index: self.max_file_index}).await?;
                         }
+                        FileSystemMessages::ListInProgress(response) => {
+                            // the caller may have stopped waiting, which is fine
+                            let _ = response.send(self.in_progress_files());
+                        }
                     }
                 }
                 Some(result) = self.futures.next() => {
                     let MultipartCallbackWithName { callback, name } = result?;
                     self.process_callback(name, callback)?;
+                    self.start_queued_futures();
                 }
                 _ = tokio::time::sleep_until(next_policy_check) => {
                     next_policy_check = tokio::time::Instant::now() + Duration::from_millis(100);
                     if let Some(writer) = self.writers.get_mut(&self.current_writer_name) {
                         if let Some(stats) = writer.stats() {
-                            if self.rolling_policy.should_roll(&stats){
-                            if let Some(future) = writer.close()? {
-                                self.futures.push(future);
+                            if self.rolling_policy.should_roll(&stats, self.clock.as_ref()) {
+                                self.roll_writer()?;
+                            } else {
+                                self.flush_if_idle(&stats)?;
                             }
-                            self.max_file_index += 1;
-                            let new_writer = self.new_writer();
-                            self.current_writer_name = new_writer.name();
-                            self.writers.insert(new_writer.name(), new_writer);
                         }
                     }
-                    }
+                }
+                _ = tokio::time::sleep_until(next_stats_log) => {
+                    next_stats_log = tokio::time::Instant::now() + PARTITION_STATS_LOG_INTERVAL;
+                    self.log_partition_stats();
                 }
                 else => {
                     break;
@@ -576,16 +1897,167 @@ where
         Ok(())
     }
 
-    fn new_writer(&mut self) -> R {
-        R::new(
-            self.object_store.clone(),
-            format!(
-                "{}/{:0>5}-{:0>3}",
-                self.path, self.max_file_index, self.subtask_id
-            )
-            .into(),
-            &self.properties,
-        )
+    /// Rolls the current file, or fails if `on_part_limit` is `error`, once it has `max_parts`
+    /// parts, since any more data would need another part. The rolling policy checks the limit
+    /// too, but only periodically, by which point a fast writer may have gone past it.
+    fn enforce_part_limit(&mut self) -> Result<()> {
+        let Some(stats) = self
+            .writers
+            .get(&self.current_writer_name)
+            .and_then(|writer| writer.stats())
+        else {
+            return Ok(());
+        };
+        if stats.parts_written < self.max_parts {
+            return Ok(());
+        }
+        match self.on_part_limit {
+            OnPartLimit::Roll => self.roll_writer(),
+            OnPartLimit::Error => bail!(
+                "{} has reached max_parts ({} parts) and on_part_limit is 'error'; raise max_parts (up to 10000) or target_part_size",
+                self.current_writer_name,
+                self.max_parts
+            ),
+        }
+    }
+
+    /// Uploads the current writer's buffered data as a part once it has gone `idle_flush`
+    /// without writes, so that a slow stream doesn't keep data buffered until the file rolls
+    fn flush_if_idle(&mut self, stats: &MultiPartWriterStats) -> Result<()> {
+        let Some(idle_flush) = self.idle_flush else {
+            return Ok(());
+        };
+        if stats.idle_for(self.clock.as_ref()) < idle_flush {
+            return Ok(());
+        }
+        let Some(writer) = self.writers.get_mut(&self.current_writer_name) else {
+            bail!("expect the current writer to be initialized");
+        };
+        if let Some(future) = writer.flush_buffer()? {
+            self.push_future(future);
+        }
+        Ok(())
+    }
+
+    /// Closes the current writer and starts a new file
+    fn roll_writer(&mut self) -> Result<()> {
+        let Some(writer) = self.writers.get_mut(&self.current_writer_name) else {
+            bail!("expect the current writer to be initialized");
+        };
+        if let Some(future) = writer.close()? {
+            self.push_future(future);
+        }
+        let rolled = self.current_writer_name.clone();
+        self.window_writers.retain(|_, name| *name != rolled);
+        self.open_writer()
+    }
+
+    /// Starts a new file and makes it the current one
+    fn open_writer(&mut self) -> Result<()> {
+        self.max_file_index += 1;
+        let new_writer = self.new_writer()?;
+        self.current_writer_name = new_writer.name();
+        self.writers.insert(new_writer.name(), new_writer);
+        Ok(())
+    }
+
+    /// With `finalize_on_watermark_seconds`, makes the writer of the event-time window `time`
+    /// falls in the current one, so that each file holds a single window. The current file is
+    /// used for the window if it isn't holding another one; otherwise a new file is started.
+    /// A record for a window that's already been finalized starts a new file for it, which the
+    /// next watermark finalizes.
+    fn select_window_writer(&mut self, time: SystemTime) -> Result<()> {
+        let Some(width) = self.finalize_on_watermark else {
+            return Ok(());
+        };
+        let end = window_end(time, width);
+        if let Some(name) = self.window_writers.get(&end) {
+            self.current_writer_name = name.clone();
+            return Ok(());
+        }
+        if self
+            .window_writers
+            .values()
+            .any(|name| *name == self.current_writer_name)
+        {
+            self.open_writer()?;
+        }
+        self.window_writers
+            .insert(end, self.current_writer_name.clone());
+        Ok(())
+    }
+
+    /// Closes the files of the windows that end at or before `watermark`, since no more
+    /// records can arrive for them. Files of later windows stay open.
+    fn close_complete_windows(&mut self, watermark: SystemTime) -> Result<()> {
+        let complete: Vec<_> = self
+            .window_writers
+            .range(..=watermark)
+            .map(|(end, _)| *end)
+            .collect();
+        for end in complete {
+            let Some(name) = self.window_writers.remove(&end) else {
+                continue;
+            };
+            if name == self.current_writer_name {
+                self.roll_writer()?;
+            } else if let Some(writer) = self.writers.get_mut(&name) {
+                if let Some(future) = writer.close()? {
+                    self.push_future(future);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn new_writer(&mut self) -> Result<R> {
+        let file_name = with_host_name(
+            indexed_file_name(
+                self.properties.file_settings.as_ref().unwrap(),
+                self.max_file_index,
+                self.subtask_id,
+                self.parallelism,
+            ),
+            &self.host_name,
+        );
+        let path = format!("{}/{}", self.output_directory(), file_name);
+        R::new(self.object_store.clone(), path.into(), &self.properties)
+    }
+
+    /// The directory this subtask writes its files to
+    fn output_directory(&self) -> String {
+        let mut directory = self.path.to_string();
+        if let Some(key_prefix) = key_prefix(self.properties.file_settings.as_ref().unwrap()) {
+            directory = format!("{}/{}", directory, key_prefix);
+        }
+        if let Some(run_directory) = &self.run_directory {
+            directory = format!("{}/{}", directory, run_directory);
+        }
+        if let Some(subtask_directory) = subtask_directory(
+            self.properties.file_settings.as_ref().unwrap(),
+            self.subtask_id,
+        ) {
+            directory = format!("{}/{}", directory, subtask_directory);
+        }
+        directory
+    }
+
+    /// Starts an upload, or queues it if `max_inflight_uploads` are already in flight
+    fn push_future(&mut self, future: BoxedTryFuture<MultipartCallbackWithName>) {
+        self.queued_futures.push_back(future);
+        self.start_queued_futures();
+    }
+
+    fn start_queued_futures(&mut self) {
+        while self
+            .max_inflight_uploads
+            .map_or(true, |max| self.futures.len() < max)
+        {
+            let Some(future) = self.queued_futures.pop_front() else {
+                break;
+            };
+            self.futures.push(future);
+        }
     }
 
     async fn flush_futures(&mut self) -> Result<()> {
@@ -593,6 +2065,7 @@ where
             self.futures.try_next().await?
         {
             self.process_callback(name, callback)?;
+            self.start_queued_futures();
         }
         Ok(())
     }
@@ -603,8 +2076,9 @@ where
         })?;
         match callback {
             MultipartCallback::InitializedMultipart { multipart_id } => {
-                self.futures
-                    .extend(writer.handle_initialization(multipart_id)?);
+                for future in writer.handle_initialization(multipart_id)? {
+                    self.push_future(future);
+                }
                 Ok(())
             }
             MultipartCallback::CompletedPart {
@@ -613,58 +2087,95 @@ where
             } => {
                 if let Some(file_to_write) = writer.handle_completed_part(part_idx, upload_part)? {
                     // need the file to finish to be checkpointed first.
+                    let filename = file_to_write.filename.clone();
                     self.add_part_to_finish(file_to_write);
-                    self.writers.remove(&name);
+                    self.retire_writer_to_finish(&name, &filename);
                 }
                 Ok(())
             }
             MultipartCallback::UploadsFinished => {
                 let file_to_write = writer.get_finished_file();
+                let filename = file_to_write.filename.clone();
                 self.add_part_to_finish(file_to_write);
-                self.writers.remove(&name);
+                self.retire_writer_to_finish(&name, &filename);
+                Ok(())
+            }
+            MultipartCallback::MultipartNotSupported => {
+                if let Some(future) = writer.handle_multipart_not_supported()? {
+                    self.push_future(future);
+                }
+                Ok(())
+            }
+            MultipartCallback::SinglePutFinished { file_to_finish } => {
+                let filename = file_to_finish.filename.clone();
+                self.add_part_to_finish(file_to_finish);
+                self.retire_writer_to_finish(&name, &filename);
                 Ok(())
             }
         }
     }
 
-    async fn finish_file(&mut self, file_to_finish: FileToFinish) -> Result<()> {
-        let FileToFinish {
-            filename,
-            multi_part_upload_id,
-            completed_parts,
-        } = file_to_finish;
-        if completed_parts.len() == 0 {
-            warn!("no parts to finish for file {}", filename);
-            return Ok(());
-        }
-        let parts: Vec<_> = completed_parts
-            .into_iter()
-            .map(|content_id| UploadPart {
-                content_id: content_id.clone(),
-            })
-            .collect();
-        let location = Path::parse(&filename)?;
-        self.object_store
-            .close_multipart(&location, &multi_part_upload_id, parts)
-            .await
-            .unwrap();
-        Ok(())
-    }
-
     async fn stop(&mut self) -> Result<()> {
         if let Some(writer) = self.writers.get_mut(&self.current_writer_name) {
             let close_future: Option<BoxedTryFuture<MultipartCallbackWithName>> = writer.close()?;
             if let Some(future) = close_future {
-                self.futures.push(future);
+                self.push_future(future);
             }
         }
-        while let Some(result) = self.futures.next().await {
-            let MultipartCallbackWithName { callback, name } = result?;
-            self.process_callback(name, callback)?;
+        // the files of windows that are still open are closed too
+        let window_writers = std::mem::take(&mut self.window_writers);
+        for name in window_writers.into_values() {
+            if name == self.current_writer_name {
+                continue;
+            }
+            if let Some(writer) = self.writers.get_mut(&name) {
+                if let Some(future) = writer.close()? {
+                    self.push_future(future);
+                }
+            }
+        }
+        self.flush_futures().await
+    }
+
+    /// Aborts the uploads of every file that hasn't been checkpointed, including those that
+    /// are closed but waiting for a checkpoint
+    async fn abort_in_progress_files(&mut self) -> Result<()> {
+        // let in-flight requests land so that every multipart upload that was started is known
+        self.flush_futures().await?;
+        for (_, mut writer) in self.writers.drain() {
+            writer.abort().await?;
+        }
+        for file_to_finish in self.files_to_finish.drain(..) {
+            match &file_to_finish.staging_path {
+                Some(staging_path) => {
+                    self.object_store
+                        .delete(&Path::parse(staging_path)?)
+                        .await?
+                }
+                None => {
+                    self.object_store
+                        .abort_multipart(
+                            &Path::parse(&file_to_finish.filename)?,
+                            &file_to_finish.multi_part_upload_id,
+                        )
+                        .await?
+                }
+            }
         }
         Ok(())
     }
 
+    async fn abort(&mut self, subtask_id: usize) -> Result<()> {
+        self.abort_in_progress_files().await?;
+        warn!(
+            "aborted in-progress files for subtask {} of {}",
+            subtask_id, self.path
+        );
+
+        self.window_writers.clear();
+        self.open_writer()
+    }
+
     async fn take_checkpoint(&mut self, _subtask_id: usize) -> Result<()> {
         for (filename, writer) in self.writers.iter_mut() {
             let buffered_data = writer.currently_buffered_data();
@@ -681,10 +2192,7 @@ where
                 .send(CheckpointData::InProgressFileCheckpoint(
                     InProgressFileCheckpoint {
                         filename: file_to_finish.filename.clone(),
-                        data: FileCheckpointData::MultiPartWriterUploadCompleted {
-                            multi_part_upload_id: file_to_finish.multi_part_upload_id.clone(),
-                            completed_parts: file_to_finish.completed_parts.clone(),
-                        },
+                        data: file_to_finish.checkpoint_data(),
                         buffered_data: vec![],
                     },
                 ))
@@ -706,10 +2214,23 @@ struct MultipartManager {
     pushed_size: usize,
     parts_to_add: Vec<PartToUpload>,
     closed: bool,
+    // set when the object store rejects multipart uploads; parts are then buffered
+    // in parts_to_add and written with a single put on close
+    multipart_not_supported: bool,
+    // limits the number of parts of this file being uploaded at once
+    part_upload_permits: Option<Arc<Semaphore>>,
+    // digest of every part written so far, when a checksum sidecar is enabled
+    checksum: Option<Sha256>,
+    // largest part the object store accepts, if it has a limit
+    max_part_size: Option<usize>,
 }
 
 impl MultipartManager {
-    fn new(object_store: Arc<dyn ObjectStore>, location: Path) -> Self {
+    fn new(
+        object_store: Arc<dyn ObjectStore>,
+        location: Path,
+        max_concurrent_parts: Option<usize>,
+    ) -> Self {
         Self {
             object_store,
             location,
@@ -719,6 +2240,11 @@ impl MultipartManager {
             pushed_size: 0,
             parts_to_add: vec![],
             closed: false,
+            multipart_not_supported: false,
+            part_upload_permits: max_concurrent_parts
+                .map(|max_concurrent_parts| Arc::new(Semaphore::new(max_concurrent_parts))),
+            checksum: None,
+            max_part_size: None,
         }
     }
 
@@ -726,10 +2252,38 @@ impl MultipartManager {
         self.location.to_string()
     }
 
+    fn checksum(&self) -> Option<String> {
+        self.checksum
+            .as_ref()
+            .map(|hasher| hex::encode(hasher.clone().finalize()))
+    }
+
     fn write_next_part(
         &mut self,
         data: Vec<u8>,
     ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        if let Some(max_part_size) = self.max_part_size {
+            // single puts are buffered here too, but aren't uploaded as parts
+            if data.len() > max_part_size && !self.multipart_not_supported {
+                let part_index = match self.multipart_id {
+                    Some(_) => self.pushed_parts.len(),
+                    None => self.parts_to_add.len(),
+                };
+                bail!(
+                    "part {} of {} is {} bytes, but the object store accepts parts of at most {} bytes; lower target_part_size",
+                    part_index,
+                    self.location,
+                    data.len(),
+                    max_part_size
+                );
+            }
+        }
+        if let Some(hasher) = &mut self.checksum {
+            hasher.update(&data);
+        }
+        // a closed file is only checkpointed as empty if nothing was ever written to it, so
+        // the residual buffer flushed on close isn't lost while its upload is starting
+        self.pushed_size += data.len();
         match &self.multipart_id {
             Some(_multipart_id) => Ok(Some(self.get_part_upload_future(PartToUpload {
                 part_index: self.pushed_parts.len(),
@@ -741,7 +2295,9 @@ impl MultipartManager {
                     byte_data: data,
                     part_index: self.parts_to_add.len(),
                 });
-                if is_first_part {
+                if self.multipart_not_supported {
+                    Ok(None)
+                } else if is_first_part {
                     // start a new multipart upload
                     Ok(Some(self.get_initialize_multipart_future()?))
                 } else {
@@ -767,7 +2323,12 @@ impl MultipartManager {
             .clone()
             .ok_or_else(|| anyhow::anyhow!("missing multipart id"))?;
         let object_store = self.object_store.clone();
+        let part_upload_permits = self.part_upload_permits.clone();
         Ok(Box::pin(async move {
+            let _permit = match part_upload_permits {
+                Some(permits) => Some(permits.acquire_owned().await?),
+                None => None,
+            };
             let upload_part = object_store
                 .add_multipart(
                     &location,
@@ -792,14 +2353,60 @@ impl MultipartManager {
         let object_store = self.object_store.clone();
         let location = self.location.clone();
         Ok(Box::pin(async move {
-            let multipart_id = object_store.start_multipart(&location).await?;
+            let callback = match object_store.start_multipart(&location).await {
+                Ok(multipart_id) => MultipartCallback::InitializedMultipart { multipart_id },
+                Err(object_store::Error::NotImplemented) => {
+                    MultipartCallback::MultipartNotSupported
+                }
+                Err(err) => return Err(err.into()),
+            };
             Ok(MultipartCallbackWithName {
                 name: location.to_string(),
-                callback: MultipartCallback::InitializedMultipart { multipart_id },
+                callback,
             })
         }))
     }
 
+    fn get_single_put_future(&mut self) -> BoxedTryFuture<MultipartCallbackWithName> {
+        let bytes: Vec<u8> = std::mem::take(&mut self.parts_to_add)
+            .into_iter()
+            .flat_map(|part| part.byte_data)
+            .collect();
+        let object_store = self.object_store.clone();
+        let location = self.location.clone();
+        let checksum = self.checksum();
+        Box::pin(async move {
+            // the file is only moved into place once the checkpoint that pre-commits it commits
+            let staging_path = staging_path(&location)?;
+            object_store.put(&staging_path, bytes.into()).await?;
+            Ok(MultipartCallbackWithName {
+                name: location.to_string(),
+                callback: MultipartCallback::SinglePutFinished {
+                    file_to_finish: FileToFinish::staged(
+                        location.to_string(),
+                        staging_path.to_string(),
+                        checksum,
+                    ),
+                },
+            })
+        })
+    }
+
+    fn handle_multipart_not_supported(
+        &mut self,
+    ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        warn!(
+            "object store does not support multipart uploads, {} will be written with a single put",
+            self.location
+        );
+        self.multipart_not_supported = true;
+        if self.closed {
+            Ok(Some(self.get_single_put_future()))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn handle_initialization(
         &mut self,
         multipart_id: String,
@@ -842,6 +2449,8 @@ impl MultipartManager {
                         }
                     })
                     .collect::<Result<Vec<_>>>()?,
+                checksum: self.checksum(),
+                staging_path: None,
             }))
         }
     }
@@ -882,6 +2491,7 @@ impl MultipartManager {
                         }
                     })
                     .collect(),
+                checksum: self.checksum(),
             };
         } else {
             let in_flight_parts = self
@@ -954,6 +2564,18 @@ impl MultipartManager {
         }
     }
 
+    async fn abort(&mut self) -> Result<()> {
+        self.parts_to_add.clear();
+        self.pushed_parts.clear();
+        self.closed = true;
+        if let Some(multipart_id) = self.multipart_id.take() {
+            self.object_store
+                .abort_multipart(&self.location, &multipart_id)
+                .await?;
+        }
+        Ok(())
+    }
+
     fn get_finished_file(&mut self) -> FileToFinish {
         if !self.closed {
             unreachable!("get_finished_file called on open file");
@@ -977,6 +2599,8 @@ impl MultipartManager {
                     }
                 })
                 .collect(),
+            checksum: self.checksum(),
+            staging_path: None,
         }
     }
 }
@@ -992,7 +2616,9 @@ pub trait BatchBuilder: Send {
 
 pub trait BatchBufferingWriter: Send {
     type BatchData;
-    fn new(config: &FileSystemTable) -> Self;
+    fn new(config: &FileSystemTable) -> Result<Self>
+    where
+        Self: Sized;
     fn suffix() -> String;
     fn add_batch_data(&mut self, data: Self::BatchData) -> Option<Vec<u8>>;
     fn buffer_length(&self) -> usize;
@@ -1009,6 +2635,7 @@ pub struct BatchMultipartWriter<
     batch_buffering_writer: BBW,
     multipart_manager: MultipartManager,
     stats: Option<MultiPartWriterStats>,
+    clock: Arc<dyn Clock>,
 }
 #[async_trait]
 impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> MultiPartWriter
@@ -1016,16 +2643,31 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> Mul
 {
     type InputType = BB::InputType;
 
-    fn new(object_store: Arc<dyn ObjectStore>, path: Path, config: &FileSystemTable) -> Self {
+    fn new(
+        object_store: Arc<dyn ObjectStore>,
+        path: Path,
+        config: &FileSystemTable,
+    ) -> Result<Self> {
         let batch_builder = BB::new(config);
-        let batch_buffering_writer = BBW::new(config);
+        let batch_buffering_writer = BBW::new(config)?;
         let path = format!("{}.{}", path, BBW::suffix()).into();
-        Self {
+        let max_concurrent_parts = config
+            .file_settings
+            .as_ref()
+            .and_then(|settings| settings.max_concurrent_parts)
+            .map(|max_concurrent_parts| max_concurrent_parts as usize);
+        let mut multipart_manager = MultipartManager::new(object_store, path, max_concurrent_parts);
+        if checksum_sidecar_enabled(config) {
+            multipart_manager.checksum = Some(Sha256::new());
+        }
+        multipart_manager.max_part_size = max_part_size(&config.write_target);
+        Ok(Self {
             batch_builder,
             batch_buffering_writer,
-            multipart_manager: MultipartManager::new(object_store, path),
+            multipart_manager,
             stats: None,
-        }
+            clock: Arc::new(TokioClock),
+        })
     }
 
     fn name(&self) -> String {
@@ -1037,16 +2679,16 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> Mul
         value: Self::InputType,
         _time: SystemTime,
     ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
-        if self.stats.is_none() {
-            self.stats = Some(MultiPartWriterStats {
-                bytes_written: 0,
-                parts_written: 0,
-                last_write_at: Instant::now(),
-                first_write_at: Instant::now(),
-            });
-        }
-        let stats = self.stats.as_mut().unwrap();
-        stats.last_write_at = Instant::now();
+        let now = self.clock.now();
+        let stats = self.stats.get_or_insert_with(|| MultiPartWriterStats {
+            bytes_written: 0,
+            parts_written: 0,
+            records_written: 0,
+            last_write_at: now,
+            first_write_at: now,
+        });
+        stats.last_write_at = now;
+        stats.records_written += 1;
 
         if let Some(batch) = self.batch_builder.insert(value.clone()) {
             let prev_size = self.batch_buffering_writer.buffer_length();
@@ -1070,6 +2712,12 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> Mul
         self.multipart_manager.handle_initialization(multipart_id)
     }
 
+    fn handle_multipart_not_supported(
+        &mut self,
+    ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.multipart_manager.handle_multipart_not_supported()
+    }
+
     fn handle_completed_part(
         &mut self,
         part_idx: usize,
@@ -1099,6 +2747,17 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> Mul
         self.write_closing_multipart()
     }
 
+    fn flush_buffer(&mut self) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        if self.batch_buffering_writer.buffer_length() == 0 {
+            return Ok(None);
+        }
+        let bytes = self.batch_buffering_writer.evict_current_buffer();
+        if let Some(stats) = self.stats.as_mut() {
+            stats.parts_written += 1;
+        }
+        self.multipart_manager.write_next_part(bytes)
+    }
+
     fn stats(&self) -> Option<MultiPartWriterStats> {
         self.stats.clone()
     }
@@ -1106,6 +2765,10 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>> Mul
     fn get_finished_file(&mut self) -> FileToFinish {
         self.multipart_manager.get_finished_file()
     }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.multipart_manager.abort().await
+    }
 }
 
 impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>>
@@ -1120,7 +2783,13 @@ impl<BB: BatchBuilder, BBW: BatchBufferingWriter<BatchData = BB::BatchData>>
         } else {
             None
         };
-        if let Some(bytes) = self.batch_buffering_writer.close(final_batch) {
+        let final_bytes = self.batch_buffering_writer.close(final_batch);
+        if self.multipart_manager.multipart_not_supported {
+            if let Some(bytes) = final_bytes {
+                self.multipart_manager.write_next_part(bytes)?;
+            }
+            Ok(Some(self.multipart_manager.get_single_put_future()))
+        } else if let Some(bytes) = final_bytes {
             self.multipart_manager.write_next_part(bytes)
         } else if self.multipart_manager.all_uploads_finished() {
             // Return a finished file future
@@ -1162,6 +2831,10 @@ pub enum MultipartCallback {
         upload_part: UploadPart,
     },
     UploadsFinished,
+    MultipartNotSupported,
+    SinglePutFinished {
+        file_to_finish: FileToFinish,
+    },
 }
 
 impl Debug for MultipartCallback {
@@ -1174,14 +2847,51 @@ impl Debug for MultipartCallback {
                 write!(f, "MultipartCallback::CompletedPart({})", part_idx)
             }
             MultipartCallback::UploadsFinished => write!(f, "MultipartCallback::UploadsFinished"),
+            MultipartCallback::MultipartNotSupported => {
+                write!(f, "MultipartCallback::MultipartNotSupported")
+            }
+            MultipartCallback::SinglePutFinished { .. } => {
+                write!(f, "MultipartCallback::SinglePutFinished")
+            }
         }
     }
 }
 
-#[derive(Debug, Decode, Encode, Clone, PartialEq, Eq)]
+#[derive(Debug, Encode, Clone, PartialEq, Eq)]
 pub struct FileSystemDataRecovery<T: Data> {
     next_file_index: usize,
     active_files: Vec<InProgressFileCheckpoint<T>>,
+    finished_files: Vec<String>,
+    run_directory: Option<String>,
+}
+
+// Checkpoints from before `finished_files` and `run_directory` were added end after
+// `active_files`, so each later field is only decoded if there's input left for it. State is
+// always decoded from a slice, whose reader can tell when it's exhausted.
+impl<T: Data> Decode for FileSystemDataRecovery<T> {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        fn has_more<D: Decoder>(decoder: &mut D) -> bool {
+            decoder.reader().peek_read(1).is_some()
+        }
+        let next_file_index = Decode::decode(decoder)?;
+        let active_files = Decode::decode(decoder)?;
+        let finished_files = if has_more(decoder) {
+            Decode::decode(decoder)?
+        } else {
+            Vec::new()
+        };
+        let run_directory = if has_more(decoder) {
+            Decode::decode(decoder)?
+        } else {
+            None
+        };
+        Ok(Self {
+            next_file_index,
+            active_files,
+            finished_files,
+            run_directory,
+        })
+    }
 }
 
 #[async_trait]
@@ -1203,8 +2913,23 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
     ) -> Result<()> {
         let mut max_file_index = 0;
         let mut recovered_files = Vec::new();
+        // subtasks may have used different run directories, so all of them pick the same one
+        if self.run_directory.is_some() {
+            if let Some(run_directory) = data_recovery
+                .iter()
+                .filter_map(|recovery| recovery.run_directory.clone())
+                .min()
+            {
+                self.run_directory = Some(run_directory);
+            }
+        }
         for file_system_data_recovery in data_recovery {
             max_file_index = max_file_index.max(file_system_data_recovery.next_file_index);
+            // any subtask may be asked to commit a file again, so each remembers every
+            // subtask's finished files
+            for filename in file_system_data_recovery.finished_files {
+                self.record_finished_file(filename);
+            }
             // task 0 is responsible for recovering all files.
             // This is because the number of subtasks may have changed.
             // Recovering should be reasonably fast since it is just finishing in-flight uploads.
@@ -1216,6 +2941,10 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
             .send(FileSystemMessages::Init {
                 max_file_index,
                 subtask_id: task_info.task_index,
+                parallelism: task_info.parallelism,
+                job_id: task_info.job_id.clone(),
+                operator_id: task_info.operator_id.clone(),
+                run_directory: self.run_directory.clone(),
                 recovered_files,
             })
             .await?;
@@ -1233,18 +2962,43 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
         Ok(())
     }
 
+    async fn handle_watermark(&mut self, watermark: Watermark) -> Result<()> {
+        if let Watermark::EventTime(watermark) = watermark {
+            self.sender
+                .send(FileSystemMessages::Watermark(watermark))
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn commit(
         &mut self,
         _task_info: &TaskInfo,
         pre_commit: Vec<Self::PreCommit>,
     ) -> Result<()> {
+        let (already_finished, pre_commit): (Vec<_>, Vec<_>) = pre_commit
+            .into_iter()
+            .partition(|file| self.finished_file_set.contains(&file.filename));
+        for file in already_finished {
+            debug!("skipping {}, which was already finished", file.filename);
+        }
+        let filenames: Vec<_> = pre_commit
+            .iter()
+            .map(|file| file.filename.clone())
+            .collect();
+
         self.sender
             .send(FileSystemMessages::FilesToFinish(pre_commit))
             .await?;
         // loop over checkpoint receiver until finished received
         while let Some(checkpoint_message) = self.checkpoint_receiver.recv().await {
             match checkpoint_message {
-                CheckpointData::Finished { max_file_index: _ } => return Ok(()),
+                CheckpointData::Finished { max_file_index: _ } => {
+                    for filename in filenames {
+                        self.record_finished_file(filename);
+                    }
+                    return Ok(());
+                }
                 _ => {
                     bail!("unexpected checkpoint message")
                 }
@@ -1273,6 +3027,8 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
                         FileSystemDataRecovery {
                             next_file_index: max_file_index + 1,
                             active_files,
+                            finished_files: self.finished_files.iter().cloned().collect(),
+                            run_directory: self.run_directory.clone(),
                         },
                         pre_commit_messages,
                     ))
@@ -1281,28 +3037,38 @@ impl<K: Key, T: Data + Sync, R: MultiPartWriter<InputType = T> + Send + 'static>
                     filename,
                     data,
                     buffered_data,
-                }) => {
-                    if let FileCheckpointData::MultiPartWriterUploadCompleted {
+                }) => match data {
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
                         multi_part_upload_id,
                         completed_parts,
-                    } = data
-                    {
+                        checksum,
+                    } => {
                         pre_commit_messages.insert(
                             filename.clone(),
                             FileToFinish {
                                 filename,
                                 multi_part_upload_id,
                                 completed_parts,
+                                checksum,
+                                staging_path: None,
                             },
                         );
-                    } else {
-                        active_files.push(InProgressFileCheckpoint {
-                            filename,
-                            data,
-                            buffered_data,
-                        })
                     }
-                }
+                    FileCheckpointData::SinglePutStaged {
+                        staging_path,
+                        checksum,
+                    } => {
+                        pre_commit_messages.insert(
+                            filename.clone(),
+                            FileToFinish::staged(filename, staging_path, checksum),
+                        );
+                    }
+                    data => active_files.push(InProgressFileCheckpoint {
+                        filename,
+                        data,
+                        buffered_data,
+                    }),
+                },
             }
         }
         bail!("checkpoint receiver closed unexpectedly")
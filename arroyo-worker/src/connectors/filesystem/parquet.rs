@@ -1,25 +1,40 @@
 use std::{fs::File, io::Write, marker::PhantomData, sync::Arc};
 
+use arrow::datatypes::SchemaRef;
 use arrow_array::RecordBatch;
 use arroyo_types::RecordBatchBuilder;
 use parquet::{
     arrow::ArrowWriter,
     basic::{GzipLevel, ZstdLevel},
-    file::properties::WriterProperties,
+    file::properties::{EnabledStatistics, WriterProperties},
+    format::KeyValue,
+    schema::types::ColumnPath,
 };
+use sha2::{Digest, Sha256};
 
 use super::{
+    checksum_sidecar_enabled,
     local::{CurrentFileRecovery, FilePreCommit, LocalWriter},
     BatchBufferingWriter, BatchBuilder, FileSettings, FileSystemTable,
 };
-use super::{Compression, FormatSettings};
+use super::{Compression, FormatSettings, ParquetStatistics};
+
+/// Max length of the min/max values stored in column indexes, so long strings don't bloat
+/// the footer
+const COLUMN_INDEX_TRUNCATE_LENGTH: usize = 64;
 
 fn writer_properties_from_table(table: &FileSystemTable) -> WriterProperties {
     let mut parquet_writer_options = WriterProperties::builder();
     if let Some(FormatSettings::Parquet {
         compression,
-        row_batch_size: _,
         row_group_size,
+        ref bloom_filter_columns,
+        statistics,
+        dictionary_enabled,
+        ref dictionary_column_overrides,
+        ref key_value_metadata,
+        write_page_index,
+        ..
     }) = table.format_settings
     {
         if let Some(compression) = compression {
@@ -36,10 +51,108 @@ fn writer_properties_from_table(table: &FileSystemTable) -> WriterProperties {
             parquet_writer_options =
                 parquet_writer_options.set_max_row_group_size(row_group_size as usize);
         }
+        for column in bloom_filter_columns {
+            parquet_writer_options = parquet_writer_options
+                .set_column_bloom_filter_enabled(ColumnPath::from(column.as_str()), true);
+        }
+        if let Some(statistics) = statistics {
+            let statistics = match statistics {
+                ParquetStatistics::None => EnabledStatistics::None,
+                ParquetStatistics::Chunk => EnabledStatistics::Chunk,
+                ParquetStatistics::Page => EnabledStatistics::Page,
+            };
+            parquet_writer_options = parquet_writer_options.set_statistics_enabled(statistics);
+        }
+        // column indexes are built from page statistics, and the offset index is written
+        // alongside them; an explicit statistics level always wins
+        match write_page_index {
+            Some(true) => {
+                parquet_writer_options = parquet_writer_options
+                    .set_column_index_truncate_length(Some(COLUMN_INDEX_TRUNCATE_LENGTH));
+                if statistics.is_none() {
+                    parquet_writer_options =
+                        parquet_writer_options.set_statistics_enabled(EnabledStatistics::Page);
+                }
+            }
+            Some(false) if statistics.is_none() => {
+                parquet_writer_options =
+                    parquet_writer_options.set_statistics_enabled(EnabledStatistics::Chunk);
+            }
+            _ => {}
+        }
+        if let Some(dictionary_enabled) = dictionary_enabled {
+            parquet_writer_options =
+                parquet_writer_options.set_dictionary_enabled(dictionary_enabled);
+        }
+        for (column, enabled) in dictionary_column_overrides {
+            parquet_writer_options = parquet_writer_options
+                .set_column_dictionary_enabled(ColumnPath::from(column.as_str()), *enabled);
+        }
+        if !key_value_metadata.is_empty() {
+            let mut key_value_metadata: Vec<_> = key_value_metadata
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+                .collect();
+            key_value_metadata.sort_by(|a, b| a.key.cmp(&b.key));
+            parquet_writer_options =
+                parquet_writer_options.set_key_value_metadata(Some(key_value_metadata));
+        }
     }
     parquet_writer_options.build()
 }
 
+/// Adds the job and operator ids to the key-value metadata written to Parquet footers, for
+/// lineage. Values the user configured for the same keys are kept.
+pub(super) fn add_lineage_metadata(table: &mut FileSystemTable, job_id: &str, operator_id: &str) {
+    if let Some(FormatSettings::Parquet {
+        key_value_metadata, ..
+    }) = &mut table.format_settings
+    {
+        key_value_metadata
+            .entry("arroyo.job_id".to_string())
+            .or_insert_with(|| job_id.to_string());
+        key_value_metadata
+            .entry("arroyo.operator_id".to_string())
+            .or_insert_with(|| operator_id.to_string());
+    }
+}
+
+/// Indices of the configured `columns` in `schema`, in the configured order, or None if every
+/// field should be written. Column names are validated against the table schema when the
+/// connection is created.
+fn column_projection(schema: &SchemaRef, table: &FileSystemTable) -> Option<Vec<usize>> {
+    let Some(FormatSettings::Parquet { columns, .. }) = &table.format_settings else {
+        return None;
+    };
+    if columns.is_empty() {
+        return None;
+    }
+    Some(
+        columns
+            .iter()
+            .map(|column| {
+                schema
+                    .index_of(column)
+                    .unwrap_or_else(|_| panic!("parquet column '{}' is not in the schema", column))
+            })
+            .collect(),
+    )
+}
+
+fn project_schema(schema: SchemaRef, projection: &Option<Vec<usize>>) -> SchemaRef {
+    match projection {
+        Some(projection) => Arc::new(schema.project(projection).unwrap()),
+        None => schema,
+    }
+}
+
+fn project_batch(batch: RecordBatch, projection: &Option<Vec<usize>>) -> RecordBatch {
+    match projection {
+        Some(projection) => batch.project(projection).unwrap(),
+        None => batch,
+    }
+}
+
 /// A buffer with interior mutability shared by the [`ArrowWriter`] and
 /// [`AsyncArrowWriter`]. From Arrow. This lets us write data from the buffer to S3.
 #[derive(Clone)]
@@ -74,7 +187,13 @@ impl Write for SharedBuffer {
 pub struct FixedSizeRecordBatchBuilder<B: RecordBatchBuilder> {
     builder: B,
     batch_size: usize,
+    max_buffer_bytes: Option<usize>,
+    buffered_bytes: usize,
+    /// Arrow memory per row in the last flushed batch, used to estimate how much the builder
+    /// is holding without measuring every record
+    estimated_row_bytes: usize,
     buffered_elements: Vec<B::Data>,
+    projection: Option<Vec<usize>>,
 }
 
 impl<B: RecordBatchBuilder> BatchBuilder for FixedSizeRecordBatchBuilder<B> {
@@ -83,28 +202,43 @@ impl<B: RecordBatchBuilder> BatchBuilder for FixedSizeRecordBatchBuilder<B> {
     type BatchData = RecordBatch;
     fn new(config: &FileSystemTable) -> Self {
         let batch_size = if let Some(FormatSettings::Parquet {
-            compression: _,
             row_batch_size: Some(batch_size),
-            row_group_size: _,
+            ..
         }) = config.format_settings
         {
             batch_size as usize
         } else {
             10_000
         };
+        let max_buffer_bytes = config
+            .file_settings
+            .as_ref()
+            .and_then(|settings| settings.parquet_max_buffer_bytes)
+            .map(|bytes| bytes as usize);
+        let builder = B::default();
+        let projection = column_projection(&builder.schema(), config);
         Self {
-            builder: B::default(),
+            builder,
             batch_size,
+            max_buffer_bytes,
+            buffered_bytes: 0,
+            // until a batch has been flushed, only the in-memory size of a record is known
+            estimated_row_bytes: std::mem::size_of::<B::Data>().max(1),
             buffered_elements: Vec::new(),
+            projection,
         }
     }
 
     fn insert(&mut self, value: Self::InputType) -> Option<Self::BatchData> {
+        self.buffered_bytes += self.estimated_row_bytes;
         self.builder.add_data(Some(value.clone()));
         self.buffered_elements.push(value);
-        if self.buffered_elements.len() == self.batch_size {
-            self.buffered_elements.clear();
-            Some(self.builder.flush())
+        let buffer_full = self
+            .max_buffer_bytes
+            .map(|max_buffer_bytes| self.buffered_bytes >= max_buffer_bytes)
+            .unwrap_or(false);
+        if self.buffered_elements.len() == self.batch_size || buffer_full {
+            Some(self.flush_buffer())
         } else {
             None
         }
@@ -116,7 +250,12 @@ impl<B: RecordBatchBuilder> BatchBuilder for FixedSizeRecordBatchBuilder<B> {
 
     fn flush_buffer(&mut self) -> Self::BatchData {
         self.buffered_elements.clear();
-        self.builder.flush()
+        self.buffered_bytes = 0;
+        let batch = self.builder.flush();
+        if batch.num_rows() > 0 {
+            self.estimated_row_bytes = (batch.get_array_memory_size() / batch.num_rows()).max(1);
+        }
+        project_batch(batch, &self.projection)
     }
 }
 
@@ -130,7 +269,7 @@ pub struct RecordBatchBufferingWriter<R: RecordBatchBuilder> {
 impl<R: RecordBatchBuilder> BatchBufferingWriter for RecordBatchBufferingWriter<R> {
     type BatchData = RecordBatch;
 
-    fn new(config: &FileSystemTable) -> Self {
+    fn new(config: &FileSystemTable) -> anyhow::Result<Self> {
         let target_part_size = if let Some(FileSettings {
             target_part_size: Some(target_part_size),
             ..
@@ -142,19 +281,20 @@ impl<R: RecordBatchBuilder> BatchBufferingWriter for RecordBatchBufferingWriter<
         };
         let shared_buffer = SharedBuffer::new(target_part_size);
         let writer_properties = writer_properties_from_table(config);
+        let schema = R::default().schema();
+        let projection = column_projection(&schema, config);
         let writer = ArrowWriter::try_new(
             shared_buffer.clone(),
-            R::default().schema(),
+            project_schema(schema, &projection),
             Some(writer_properties),
-        )
-        .unwrap();
+        )?;
 
-        Self {
+        Ok(Self {
             writer: Some(writer),
             shared_buffer,
             target_part_size,
             phantom: PhantomData,
-        }
+        })
     }
 
     fn suffix() -> String {
@@ -215,6 +355,8 @@ pub struct ParquetLocalWriter<V: RecordBatchBuilder> {
     file: File,
     destination_path: String,
     shared_buffer: SharedBuffer,
+    checksum: Option<Sha256>,
+    projection: Option<Vec<usize>>,
 }
 
 impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWriter<V> {
@@ -222,9 +364,10 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
         let shared_buffer = SharedBuffer::new(0);
         let writer_properties = writer_properties_from_table(table_properties);
         let builder = V::default();
+        let projection = column_projection(&builder.schema(), table_properties);
         let writer = ArrowWriter::try_new(
             shared_buffer.clone(),
-            V::default().schema(),
+            project_schema(builder.schema(), &projection),
             Some(writer_properties),
         )
         .unwrap();
@@ -236,6 +379,8 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
             file,
             destination_path: final_path,
             shared_buffer,
+            checksum: checksum_sidecar_enabled(table_properties).then(Sha256::new),
+            projection,
         }
     }
 
@@ -251,6 +396,9 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
     fn sync(&mut self) -> anyhow::Result<usize> {
         let mut buffer = self.shared_buffer.buffer.try_lock().unwrap();
         self.file.write_all(&buffer)?;
+        if let Some(hasher) = &mut self.checksum {
+            hasher.update(&*buffer);
+        }
         self.file.sync_all()?;
         // get size of the file
         let metadata = self.file.metadata()?;
@@ -260,7 +408,7 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
     }
 
     fn close(&mut self) -> anyhow::Result<FilePreCommit> {
-        let batch = self.builder.flush();
+        let batch = project_batch(self.builder.flush(), &self.projection);
         let writer = self.writer.take();
         let mut writer = writer.unwrap();
         writer.write(&batch)?;
@@ -269,12 +417,16 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
         Ok(FilePreCommit {
             tmp_file: self.tmp_path.clone(),
             destination: self.destination_path.clone(),
+            checksum: self
+                .checksum
+                .take()
+                .map(|hasher| hex::encode(hasher.finalize())),
         })
     }
 
     fn checkpoint(&mut self) -> anyhow::Result<Option<CurrentFileRecovery>> {
         let writer = self.writer.as_mut().unwrap();
-        let batch = self.builder.flush();
+        let batch = project_batch(self.builder.flush(), &self.projection);
         writer.write(&batch)?;
         writer.flush()?;
         let bytes_written = self.sync()?;
@@ -295,3 +447,344 @@ impl<V: RecordBatchBuilder + 'static> LocalWriter<V::Data> for ParquetLocalWrite
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+    use arrow_array::{
+        builder::{StringBuilder, UInt64Builder},
+        RecordBatch,
+    };
+    use arroyo_types::RecordBatchBuilder;
+    use bytes::Bytes;
+    use parquet::{
+        basic::Encoding,
+        file::{
+            metadata::ParquetMetaData,
+            page_index::index::Index,
+            reader::FileReader,
+            serialized_reader::{ReadOptionsBuilder, SerializedFileReader},
+        },
+    };
+
+    use super::{add_lineage_metadata, FixedSizeRecordBatchBuilder, RecordBatchBufferingWriter};
+    use crate::connectors::filesystem::{BatchBufferingWriter, BatchBuilder, FileSystemTable};
+
+    #[derive(Debug, Default)]
+    struct StringBatchBuilder {
+        builder: StringBuilder,
+    }
+
+    impl RecordBatchBuilder for StringBatchBuilder {
+        type Data = String;
+
+        fn add_data(&mut self, data: Option<String>) {
+            self.builder.append_option(data);
+        }
+
+        fn flush(&mut self) -> RecordBatch {
+            RecordBatch::try_new(self.schema(), vec![Arc::new(self.builder.finish())]).unwrap()
+        }
+
+        fn schema(&self) -> SchemaRef {
+            Arc::new(Schema::new(vec![Field::new("value", DataType::Utf8, true)]))
+        }
+    }
+
+    /// Writes each string as an `id`, `upper` and `len` column
+    #[derive(Debug, Default)]
+    struct WideBatchBuilder {
+        id: StringBuilder,
+        upper: StringBuilder,
+        len: UInt64Builder,
+    }
+
+    impl RecordBatchBuilder for WideBatchBuilder {
+        type Data = String;
+
+        fn add_data(&mut self, data: Option<String>) {
+            let data = data.unwrap();
+            self.upper.append_value(data.to_uppercase());
+            self.len.append_value(data.len() as u64);
+            self.id.append_value(data);
+        }
+
+        fn flush(&mut self) -> RecordBatch {
+            RecordBatch::try_new(
+                self.schema(),
+                vec![
+                    Arc::new(self.id.finish()),
+                    Arc::new(self.upper.finish()),
+                    Arc::new(self.len.finish()),
+                ],
+            )
+            .unwrap()
+        }
+
+        fn schema(&self) -> SchemaRef {
+            Arc::new(Schema::new(vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new("upper", DataType::Utf8, false),
+                Field::new("len", DataType::UInt64, false),
+            ]))
+        }
+    }
+
+    fn table(max_buffer_bytes: Option<usize>) -> FileSystemTable {
+        serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/parquet"},
+            "format_settings": {"row_batch_size": 1000},
+            "file_settings": {"parquet_max_buffer_bytes": max_buffer_bytes},
+        }))
+        .unwrap()
+    }
+
+    fn parquet_table(format_settings: serde_json::Value) -> FileSystemTable {
+        serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/parquet"},
+            "format_settings": format_settings,
+        }))
+        .unwrap()
+    }
+
+    fn write_parquet(format_settings: serde_json::Value, rows: &[&str]) -> ParquetMetaData {
+        write_parquet_table(&parquet_table(format_settings), rows)
+    }
+
+    fn write_parquet_table(table: &FileSystemTable, rows: &[&str]) -> ParquetMetaData {
+        let mut batch_builder = StringBatchBuilder::default();
+        for row in rows {
+            batch_builder.add_data(Some(row.to_string()));
+        }
+
+        let mut writer = RecordBatchBufferingWriter::<StringBatchBuilder>::new(table).unwrap();
+        let bytes = writer.close(Some(batch_builder.flush())).unwrap();
+
+        SerializedFileReader::new(Bytes::from(bytes))
+            .unwrap()
+            .metadata()
+            .clone()
+    }
+
+    #[test]
+    fn test_bloom_filter_columns() {
+        let metadata = write_parquet(
+            serde_json::json!({"bloom_filter_columns": ["value"]}),
+            &["a", "b"],
+        );
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .bloom_filter_offset()
+            .is_some());
+
+        let metadata = write_parquet(serde_json::json!({"compression": "none"}), &["a", "b"]);
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .bloom_filter_offset()
+            .is_none());
+    }
+
+    #[test]
+    fn test_statistics() {
+        // statistics are written by default
+        let metadata = write_parquet(serde_json::json!({}), &["a", "b"]);
+        let statistics = metadata.row_group(0).column(0).statistics().unwrap();
+        assert!(statistics.has_min_max_set());
+        assert_eq!(statistics.min_bytes(), b"a");
+        assert_eq!(statistics.max_bytes(), b"b");
+
+        let metadata = write_parquet(serde_json::json!({"statistics": "none"}), &["a", "b"]);
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .statistics()
+            .map_or(true, |statistics| !statistics.has_min_max_set()));
+    }
+
+    #[test]
+    fn test_write_page_index() {
+        fn read_with_page_index(format_settings: serde_json::Value) -> ParquetMetaData {
+            let mut batch_builder = StringBatchBuilder::default();
+            for row in ["a", "b"] {
+                batch_builder.add_data(Some(row.to_string()));
+            }
+            let mut writer = RecordBatchBufferingWriter::<StringBatchBuilder>::new(&parquet_table(
+                format_settings,
+            ))
+            .unwrap();
+            let bytes = writer.close(Some(batch_builder.flush())).unwrap();
+
+            let options = ReadOptionsBuilder::new().with_page_index().build();
+            SerializedFileReader::new_with_options(Bytes::from(bytes), options)
+                .unwrap()
+                .metadata()
+                .clone()
+        }
+
+        let metadata = read_with_page_index(serde_json::json!({"write_page_index": true}));
+        let column_index = metadata.column_index().unwrap();
+        assert!(!matches!(column_index[0][0], Index::NONE));
+        let offset_index = metadata.offset_index().unwrap();
+        assert!(!offset_index[0][0].is_empty());
+
+        let metadata = read_with_page_index(serde_json::json!({"write_page_index": false}));
+        assert!(metadata
+            .column_index()
+            .map_or(true, |column_index| column_index
+                .iter()
+                .flatten()
+                .all(|index| matches!(index, Index::NONE))));
+        // chunk statistics are still written
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .statistics()
+            .unwrap()
+            .has_min_max_set());
+
+        // an explicit statistics level isn't overridden, so there are no page statistics to
+        // build a column index from
+        let metadata = read_with_page_index(serde_json::json!({
+            "write_page_index": true,
+            "statistics": "chunk",
+        }));
+        assert!(metadata
+            .column_index()
+            .map_or(true, |column_index| column_index
+                .iter()
+                .flatten()
+                .all(|index| matches!(index, Index::NONE))));
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .statistics()
+            .unwrap()
+            .has_min_max_set());
+    }
+
+    #[test]
+    fn test_key_value_metadata() {
+        let mut table = parquet_table(serde_json::json!({
+            "key_value_metadata": {"schema_version": "2", "arroyo.operator_id": "renamed"},
+        }));
+        add_lineage_metadata(&mut table, "job-1", "sink-1");
+
+        let metadata = write_parquet_table(&table, &["a"]);
+        let key_value_metadata: HashMap<_, _> = metadata
+            .file_metadata()
+            .key_value_metadata()
+            .unwrap()
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.as_deref()))
+            .collect();
+        assert_eq!(key_value_metadata["schema_version"], Some("2"));
+        assert_eq!(key_value_metadata["arroyo.job_id"], Some("job-1"));
+        // configured values win over the injected ones
+        assert_eq!(key_value_metadata["arroyo.operator_id"], Some("renamed"));
+    }
+
+    #[test]
+    fn test_dictionary_enabled() {
+        fn is_dictionary_encoded(metadata: &ParquetMetaData) -> bool {
+            metadata
+                .row_group(0)
+                .column(0)
+                .encodings()
+                .iter()
+                .any(|encoding| {
+                    matches!(
+                        encoding,
+                        Encoding::RLE_DICTIONARY | Encoding::PLAIN_DICTIONARY
+                    )
+                })
+        }
+
+        // dictionary encoding is on by default
+        let metadata = write_parquet(serde_json::json!({}), &["a", "b", "a"]);
+        assert!(is_dictionary_encoded(&metadata));
+
+        let metadata = write_parquet(
+            serde_json::json!({"dictionary_enabled": false}),
+            &["a", "b", "a"],
+        );
+        assert!(!is_dictionary_encoded(&metadata));
+        assert!(metadata
+            .row_group(0)
+            .column(0)
+            .encodings()
+            .contains(&Encoding::PLAIN));
+
+        // per-column overrides win over the table-wide setting
+        let metadata = write_parquet(
+            serde_json::json!({
+                "dictionary_enabled": true,
+                "dictionary_column_overrides": {"value": false},
+            }),
+            &["a", "b", "a"],
+        );
+        assert!(!is_dictionary_encoded(&metadata));
+    }
+
+    #[test]
+    fn test_column_projection() {
+        let table: FileSystemTable = serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/parquet"},
+            "format_settings": {"columns": ["len", "id"]},
+        }))
+        .unwrap();
+
+        let mut builder: FixedSizeRecordBatchBuilder<WideBatchBuilder> =
+            FixedSizeRecordBatchBuilder::new(&table);
+        builder.insert("hello".to_string());
+        let batch = builder.flush_buffer();
+
+        let mut writer = RecordBatchBufferingWriter::<WideBatchBuilder>::new(&table).unwrap();
+        let bytes = writer.close(Some(batch)).unwrap();
+
+        let metadata = SerializedFileReader::new(Bytes::from(bytes))
+            .unwrap()
+            .metadata()
+            .clone();
+        let columns: Vec<_> = metadata
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|column| column.name().to_string())
+            .collect();
+        assert_eq!(columns, vec!["len", "id"]);
+        assert_eq!(metadata.file_metadata().num_rows(), 1);
+    }
+
+    #[test]
+    fn test_flush_on_buffer_bytes() {
+        let wide_row = "x".repeat(1024);
+
+        let mut builder: FixedSizeRecordBatchBuilder<StringBatchBuilder> =
+            FixedSizeRecordBatchBuilder::new(&table(Some(10 * 1024)));
+        // the first batch is sized from the in-memory size of a record, which doesn't count the
+        // string's heap allocation
+        let flushed_at = (1..=1000)
+            .find(|_| builder.insert(wide_row.clone()).is_some())
+            .unwrap();
+        assert!(flushed_at < 1000);
+        assert!(builder.buffered_inputs().is_empty());
+
+        // later batches are sized from the arrow memory used by the previous one
+        let flushed_at = (1..=1000)
+            .find(|_| builder.insert(wide_row.clone()).is_some())
+            .unwrap();
+        assert!((5..=10).contains(&flushed_at), "flushed at {}", flushed_at);
+
+        let mut builder: FixedSizeRecordBatchBuilder<StringBatchBuilder> =
+            FixedSizeRecordBatchBuilder::new(&table(None));
+        let flushed_at = (1..=1000)
+            .find(|_| builder.insert(wide_row.clone()).is_some())
+            .unwrap();
+        assert_eq!(flushed_at, 1000);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use arroyo_types::Data;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use object_store::{path::Path, ObjectStore, UploadPart};
+
+use super::{
+    BatchBufferingWriter, BatchBuilder, BatchMultipartWriter, BoxedTryFuture, FileCheckpointData,
+    FileSystemTable, FileToFinish, MultiPartWriter, MultiPartWriterStats,
+    MultipartCallbackWithName,
+};
+
+/// Creates the writer for one file of a named output format. Factories are registered with
+/// [`register_format`] and looked up by the `format` field of the table config. The built-in
+/// JSON and Parquet formats are registered as `json` and `parquet`.
+pub trait FormatWriterFactory<T: Data + Sync>: Send + Sync + 'static {
+    fn new_writer(
+        &self,
+        object_store: Arc<dyn ObjectStore>,
+        path: Path,
+        config: &FileSystemTable,
+    ) -> Result<Box<dyn MultiPartWriter<InputType = T> + Send>>;
+}
+
+/// A factory for formats built from a [`BatchBuilder`] and [`BatchBufferingWriter`] pair,
+/// like the built-in JSON and Parquet formats.
+pub struct BatchFormatFactory<BB, BBW> {
+    _phantom: PhantomData<fn() -> (BB, BBW)>,
+}
+
+impl<BB, BBW> Default for BatchFormatFactory<BB, BBW> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<BB, BBW> FormatWriterFactory<BB::InputType> for BatchFormatFactory<BB, BBW>
+where
+    BB: BatchBuilder + 'static,
+    BB::InputType: Sync,
+    BBW: BatchBufferingWriter<BatchData = BB::BatchData> + 'static,
+{
+    fn new_writer(
+        &self,
+        object_store: Arc<dyn ObjectStore>,
+        path: Path,
+        config: &FileSystemTable,
+    ) -> Result<Box<dyn MultiPartWriter<InputType = BB::InputType> + Send>> {
+        Ok(Box::new(BatchMultipartWriter::<BB, BBW>::new(
+            object_store,
+            path,
+            config,
+        )?))
+    }
+}
+
+lazy_static! {
+    // keyed by format name and the TypeId of the record type, holding an
+    // Arc<dyn FormatWriterFactory<T>> for that record type
+    static ref FORMATS: RwLock<HashMap<(String, TypeId), Arc<dyn Any + Send + Sync>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Registers a format for records of type `T`, replacing any existing format with that name
+pub fn register_format<T: Data + Sync>(name: &str, factory: impl FormatWriterFactory<T>) {
+    let factory: Arc<dyn FormatWriterFactory<T>> = Arc::new(factory);
+    FORMATS
+        .write()
+        .unwrap()
+        .insert((name.to_string(), TypeId::of::<T>()), Arc::new(factory));
+}
+
+/// Registers a format built from a [`BatchBuilder`] and [`BatchBufferingWriter`] pair
+pub fn register_batch_format<BB, BBW>(name: &str)
+where
+    BB: BatchBuilder + 'static,
+    BB::InputType: Sync,
+    BBW: BatchBufferingWriter<BatchData = BB::BatchData> + 'static,
+{
+    register_format(name, BatchFormatFactory::<BB, BBW>::default());
+}
+
+pub fn format_factory<T: Data + Sync>(name: &str) -> Option<Arc<dyn FormatWriterFactory<T>>> {
+    FORMATS
+        .read()
+        .unwrap()
+        .get(&(name.to_string(), TypeId::of::<T>()))
+        .and_then(|factory| factory.downcast_ref::<Arc<dyn FormatWriterFactory<T>>>())
+        .cloned()
+}
+
+/// A [`MultiPartWriter`] that delegates to the writer for the table's `format`. The sink's
+/// built-in format, the `BB`/`BBW` pair, is registered under the writer's suffix and is used
+/// when the table doesn't name a format.
+pub struct FormatWriter<BB: BatchBuilder, BBW> {
+    inner: Box<dyn MultiPartWriter<InputType = BB::InputType> + Send>,
+    _phantom: PhantomData<fn() -> BBW>,
+}
+#[async_trait]
+impl<BB, BBW> MultiPartWriter for FormatWriter<BB, BBW>
+where
+    BB: BatchBuilder + 'static,
+    BB::InputType: Sync,
+    BBW: BatchBufferingWriter<BatchData = BB::BatchData> + 'static,
+{
+    type InputType = BB::InputType;
+
+    fn new(
+        object_store: Arc<dyn ObjectStore>,
+        path: Path,
+        config: &FileSystemTable,
+    ) -> Result<Self> {
+        let builtin = BBW::suffix();
+        // registered formats take precedence, so the built-in is only added if it's missing
+        FORMATS
+            .write()
+            .unwrap()
+            .entry((builtin.clone(), TypeId::of::<BB::InputType>()))
+            .or_insert_with(|| {
+                let factory: Arc<dyn FormatWriterFactory<BB::InputType>> =
+                    Arc::new(BatchFormatFactory::<BB, BBW>::default());
+                Arc::new(factory)
+            });
+        let format = config.format.as_ref().unwrap_or(&builtin);
+        let factory = format_factory::<BB::InputType>(format).ok_or_else(|| {
+            anyhow!(
+                "no format '{}' registered for records of type {}",
+                format,
+                std::any::type_name::<BB::InputType>()
+            )
+        })?;
+        Ok(Self {
+            inner: factory.new_writer(object_store, path, config)?,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    async fn insert_value(
+        &mut self,
+        value: Self::InputType,
+        time: SystemTime,
+    ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.inner.insert_value(value, time).await
+    }
+
+    fn handle_initialization(
+        &mut self,
+        multipart_id: String,
+    ) -> Result<Vec<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.inner.handle_initialization(multipart_id)
+    }
+
+    fn handle_multipart_not_supported(
+        &mut self,
+    ) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.inner.handle_multipart_not_supported()
+    }
+
+    fn handle_completed_part(
+        &mut self,
+        part_idx: usize,
+        upload_part: UploadPart,
+    ) -> Result<Option<FileToFinish>> {
+        self.inner.handle_completed_part(part_idx, upload_part)
+    }
+
+    fn get_in_progress_checkpoint(&mut self) -> FileCheckpointData {
+        self.inner.get_in_progress_checkpoint()
+    }
+
+    fn currently_buffered_data(&mut self) -> Vec<Self::InputType> {
+        self.inner.currently_buffered_data()
+    }
+
+    fn close(&mut self) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.inner.close()
+    }
+
+    fn flush_buffer(&mut self) -> Result<Option<BoxedTryFuture<MultipartCallbackWithName>>> {
+        self.inner.flush_buffer()
+    }
+
+    fn stats(&self) -> Option<MultiPartWriterStats> {
+        self.inner.stats()
+    }
+
+    fn get_finished_file(&mut self) -> FileToFinish {
+        self.inner.get_finished_file()
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
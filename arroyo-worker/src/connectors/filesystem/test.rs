@@ -0,0 +1,2587 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arroyo_types::{get_test_task_info, Record};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, TryStreamExt};
+use object_store::{
+    memory::InMemory, path::Path, CredentialProvider, GetResult, ListResult, MultipartId,
+    ObjectMeta, ObjectStore, UploadPart,
+};
+use rusoto_core::{credential::StaticProvider, Region};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use super::{
+    clock::{Clock, MockClock, TokioClock},
+    finish_file, from_checkpoint, indexed_file_name,
+    json::{JsonLocalWriter, JsonWriter, PassThrough},
+    local::{copy_across_devices, LocalFileSystemWriter},
+    object_store_for,
+    registry::{format_factory, register_batch_format, FormatWriter},
+    sanitize_host_name, AsyncMultipartFileSystemWriter, BatchBufferingWriter, BatchMultipartWriter,
+    CheckpointData, CompactionCandidate, CompactionCandidates, Destination, DestinationError,
+    FileCheckpointData, FileSettings, FileSystemDataRecovery, FileSystemMessages, FileSystemSink,
+    FileSystemTable, FileToFinish, InFlightPartCheckpoint, InProgressFile,
+    InProgressFileCheckpoint, JsonFileSystemSink, LocalJsonFileSystemSink, MultiPartWriter,
+    MultiPartWriterStats, MultipartManager, PartToUpload, PartitionStats, RollingPolicy,
+    S3Credentialing, RECOVERED_FILES_COUNTER, RECOVERED_PARTS_COUNTER, RECOVERED_RECORDS_COUNTER,
+};
+
+use crate::connectors::two_phase_committer::TwoPhaseCommitter;
+
+type JsonMultipartWriter = BatchMultipartWriter<PassThrough<String>, JsonWriter<String>>;
+
+fn test_table(file_settings: serde_json::Value) -> FileSystemTable {
+    serde_json::from_value(serde_json::json!({
+        "write_target": {"Path": "file:///tmp/arroyo-testing/filesystem-sink"},
+        "file_settings": file_settings,
+    }))
+    .unwrap()
+}
+
+fn start_writer(
+    object_store: Arc<dyn ObjectStore>,
+    table: FileSystemTable,
+) -> (
+    Sender<FileSystemMessages<String>>,
+    Receiver<CheckpointData<String>>,
+) {
+    let (sender, receiver) = channel(100);
+    let (checkpoint_sender, checkpoint_receiver) = channel(100);
+    let mut writer = AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+        "output".into(),
+        object_store,
+        receiver,
+        checkpoint_sender,
+        table,
+    );
+    tokio::spawn(async move {
+        writer.run().await.unwrap();
+    });
+    (sender, checkpoint_receiver)
+}
+
+async fn checkpoint(
+    sender: &Sender<FileSystemMessages<String>>,
+    checkpoint_receiver: &mut Receiver<CheckpointData<String>>,
+    then_stop: bool,
+) -> Vec<CheckpointData<String>> {
+    sender
+        .send(FileSystemMessages::Checkpoint {
+            subtask_id: 0,
+            then_stop,
+        })
+        .await
+        .unwrap();
+    let mut messages = vec![];
+    while let Some(message) = checkpoint_receiver.recv().await {
+        if let CheckpointData::Finished { .. } = message {
+            return messages;
+        }
+        messages.push(message);
+    }
+    panic!("checkpoint receiver closed");
+}
+
+/// Takes a checkpoint and returns the files it lists as completed uploads
+async fn completed_files(
+    sender: &Sender<FileSystemMessages<String>>,
+    checkpoint_receiver: &mut Receiver<CheckpointData<String>>,
+) -> Vec<String> {
+    checkpoint(sender, checkpoint_receiver, false)
+        .await
+        .into_iter()
+        .filter_map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data: FileCheckpointData::MultiPartWriterUploadCompleted { .. },
+                ..
+            }) => Some(filename),
+            _ => None,
+        })
+        .collect()
+}
+
+/// An in-memory store that supports Arroyo's multipart API (unless `multipart` is false,
+/// like some S3-compatible stores) and records how it was used.
+#[derive(Debug)]
+struct MockStore {
+    inner: InMemory,
+    multipart: bool,
+    part_latency: Duration,
+    uploads: Mutex<HashMap<MultipartId, BTreeMap<usize, Bytes>>>,
+    next_multipart_id: AtomicUsize,
+    parts_in_flight: AtomicUsize,
+    max_parts_in_flight: AtomicUsize,
+    closes_in_flight: AtomicUsize,
+    max_closes_in_flight: AtomicUsize,
+}
+
+impl MockStore {
+    fn new() -> Self {
+        Self {
+            inner: InMemory::new(),
+            multipart: true,
+            part_latency: Duration::ZERO,
+            uploads: Mutex::new(HashMap::new()),
+            next_multipart_id: AtomicUsize::new(0),
+            parts_in_flight: AtomicUsize::new(0),
+            max_parts_in_flight: AtomicUsize::new(0),
+            closes_in_flight: AtomicUsize::new(0),
+            max_closes_in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    fn single_put() -> Self {
+        Self {
+            multipart: false,
+            ..Self::new()
+        }
+    }
+
+    async fn read(&self, location: &str) -> Bytes {
+        self.inner
+            .get(&location.into())
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap()
+    }
+}
+
+impl Display for MockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MockStore")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MockStore {
+    async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
+        self.inner.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        _location: &Path,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        Err(object_store::Error::NotImplemented)
+    }
+
+    async fn abort_multipart(
+        &self,
+        _location: &Path,
+        multipart_id: &MultipartId,
+    ) -> object_store::Result<()> {
+        self.uploads.lock().unwrap().remove(multipart_id);
+        Ok(())
+    }
+
+    async fn start_multipart(&self, _location: &Path) -> object_store::Result<MultipartId> {
+        if !self.multipart {
+            return Err(object_store::Error::NotImplemented);
+        }
+        let multipart_id = self
+            .next_multipart_id
+            .fetch_add(1, Ordering::SeqCst)
+            .to_string();
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(multipart_id.clone(), BTreeMap::new());
+        Ok(multipart_id)
+    }
+
+    async fn add_multipart(
+        &self,
+        _location: &Path,
+        multipart_id: &MultipartId,
+        part_number: usize,
+        bytes: Bytes,
+    ) -> object_store::Result<UploadPart> {
+        let in_flight = self.parts_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_parts_in_flight
+            .fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(self.part_latency).await;
+        self.parts_in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        self.uploads
+            .lock()
+            .unwrap()
+            .get_mut(multipart_id)
+            .ok_or_else(|| object_store::Error::NotFound {
+                path: multipart_id.clone(),
+                source: "no such upload".into(),
+            })?
+            .insert(part_number, bytes);
+        Ok(UploadPart {
+            content_id: part_number.to_string(),
+        })
+    }
+
+    async fn close_multipart(
+        &self,
+        location: &Path,
+        multipart_id: &MultipartId,
+        _parts: Vec<UploadPart>,
+    ) -> object_store::Result<()> {
+        let in_flight = self.closes_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_closes_in_flight
+            .fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(self.part_latency).await;
+        self.closes_in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        let parts = self
+            .uploads
+            .lock()
+            .unwrap()
+            .remove(multipart_id)
+            .ok_or_else(|| object_store::Error::NotFound {
+                path: multipart_id.clone(),
+                source: "no such upload".into(),
+            })?;
+        let bytes: Vec<u8> = parts.into_values().flatten().collect();
+        self.inner.put(location, bytes.into()).await
+    }
+
+    async fn get(&self, location: &Path) -> object_store::Result<GetResult> {
+        self.inner.get(location).await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<usize>) -> object_store::Result<Bytes> {
+        self.inner.get_range(location, range).await
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.inner.delete(location).await
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&Path>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
+
+#[tokio::test]
+async fn test_single_put_when_multipart_not_supported() {
+    let store = Arc::new(MockStore::single_put());
+    let (sender, mut checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+
+    // the file is staged, and isn't visible at its final path until it's committed
+    let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, true)
+        .await
+        .into_iter()
+        .map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::SinglePutStaged {
+                        staging_path,
+                        checksum,
+                    },
+                ..
+            }) => FileToFinish::staged(filename, staging_path, checksum),
+            other => panic!("unexpected checkpoint message {:?}", other),
+        })
+        .collect();
+    assert_eq!(files_to_finish.len(), 1);
+    assert!(store.head(&"output/00000-000.json".into()).await.is_err());
+    assert_eq!(
+        &store.read("output/00000-000.json.staging").await[..],
+        b"\"hello\"\n"
+    );
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert_eq!(
+        &store.read("output/00000-000.json").await[..],
+        b"\"hello\"\n"
+    );
+    assert!(store
+        .head(&"output/00000-000.json.staging".into())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_include_host_in_filename() {
+    // only read by writers with include_host_in_filename set
+    std::env::set_var("HOSTNAME", "worker-7.arroyo/svc");
+    let store = Arc::new(MockStore::single_put());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"include_host_in_filename": true})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+
+    let messages = checkpoint(&sender, &mut checkpoint_receiver, true).await;
+    assert_eq!(messages.len(), 1);
+
+    // the host name is sanitized so it can't add path segments or an extension; the file is
+    // staged next to its final path until it's committed
+    assert_eq!(
+        &store
+            .read("output/00000-000-worker-7-arroyo-svc.json.staging")
+            .await[..],
+        b"\"hello\"\n"
+    );
+    assert_eq!(sanitize_host_name("  "), None);
+}
+
+#[test]
+fn test_indexed_file_name_padding() {
+    let defaults: FileSettings = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert_eq!(indexed_file_name(&defaults, 12, 3, 8), "00012-003");
+    // the subtask index is widened to fit the parallelism
+    assert_eq!(indexed_file_name(&defaults, 12, 3, 2000), "00012-0003");
+
+    let wide: FileSettings =
+        serde_json::from_value(serde_json::json!({"file_index_width": 7})).unwrap();
+    let mut names: Vec<_> = [(123456, 1999), (99999, 5), (123456, 5), (7, 1999)]
+        .into_iter()
+        .map(|(file_index, subtask)| indexed_file_name(&wide, file_index, subtask, 2000))
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "0000007-1999",
+            "0099999-0005",
+            "0123456-0005",
+            "0123456-1999"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_max_concurrent_parts() {
+    let store = Arc::new(MockStore {
+        part_latency: Duration::from_millis(20),
+        ..MockStore::new()
+    });
+    let mut manager = MultipartManager::new(store.clone(), "output/file.json".into(), Some(2));
+    manager.parts_to_add = (0..6)
+        .map(|part_index| PartToUpload {
+            part_index,
+            byte_data: vec![part_index as u8],
+        })
+        .collect();
+
+    let multipart_id = store.start_multipart(&manager.location).await.unwrap();
+    let futures = manager.handle_initialization(multipart_id).unwrap();
+    assert_eq!(futures.len(), 6);
+    for result in futures::future::join_all(futures).await {
+        result.unwrap();
+    }
+
+    assert_eq!(store.max_parts_in_flight.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_max_inflight_uploads() {
+    let store = Arc::new(MockStore {
+        part_latency: Duration::from_millis(20),
+        ..MockStore::new()
+    });
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"target_part_size": 1, "max_inflight_uploads": 2})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    // every record fills a part, so the burst produces more uploads than the cap allows
+    for i in 0..10 {
+        sender
+            .send(FileSystemMessages::Data {
+                value: format!("record-{}", i),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let messages = checkpoint(&sender, &mut checkpoint_receiver, false).await;
+    assert!(!messages.is_empty());
+    assert!(store.max_parts_in_flight.load(Ordering::SeqCst) >= 1);
+    assert!(store.max_parts_in_flight.load(Ordering::SeqCst) <= 2);
+    assert_eq!(store.parts_in_flight.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_abort_discards_in_progress_files() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"target_part_size": 1})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+
+    sender
+        .send(FileSystemMessages::Abort { subtask_id: 0 })
+        .await
+        .unwrap();
+    assert!(matches!(
+        checkpoint_receiver.recv().await.unwrap(),
+        CheckpointData::Finished { .. }
+    ));
+    assert!(store.uploads.lock().unwrap().is_empty());
+
+    sender
+        .send(FileSystemMessages::Data {
+            value: "world".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+    let messages = checkpoint(&sender, &mut checkpoint_receiver, true).await;
+    let files_to_finish: Vec<_> = messages
+        .into_iter()
+        .map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                    },
+                ..
+            }) => FileToFinish {
+                filename,
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+                staging_path: None,
+            },
+            other => panic!("unexpected checkpoint message {:?}", other),
+        })
+        .collect();
+    assert_eq!(files_to_finish.len(), 1);
+    assert_eq!(files_to_finish[0].filename, "output/00001-000.json");
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert!(store
+        .inner
+        .head(&"output/00000-000.json".into())
+        .await
+        .is_err());
+    assert_eq!(
+        &store.read("output/00001-000.json").await[..],
+        b"\"world\"\n"
+    );
+}
+
+#[tokio::test]
+async fn test_writer_stats() {
+    let table = test_table(serde_json::json!({"target_part_size": 10}));
+    let mut writer = JsonMultipartWriter::new(
+        Arc::new(MockStore::new()),
+        "output/00000-000".into(),
+        &table,
+    )
+    .unwrap();
+    assert!(writer.stats().is_none());
+
+    // "hello"\n is 8 bytes, so the second value fills the first part
+    writer
+        .insert_value("hello".to_string(), SystemTime::now())
+        .await
+        .unwrap();
+    writer
+        .insert_value("world".to_string(), SystemTime::now())
+        .await
+        .unwrap();
+
+    let stats = writer.stats().unwrap();
+    assert_eq!(stats.bytes_written(), 16);
+    assert_eq!(stats.parts_written(), 1);
+    assert!(stats.age(&TokioClock) >= stats.idle_for(&TokioClock));
+
+    let serialized = serde_json::to_value(&stats).unwrap();
+    assert_eq!(serialized["bytes_written"], 16);
+    assert_eq!(serialized["parts_written"], 1);
+    assert!(serialized.get("idle_for").is_some());
+    assert!(serialized.get("age").is_some());
+}
+
+#[tokio::test]
+async fn test_record_limit_rolling_policy() {
+    let table = test_table(serde_json::json!({"max_records": 100}));
+    let policy = RollingPolicy::from_file_settings(table.file_settings.as_ref().unwrap());
+    let mut writer = JsonMultipartWriter::new(
+        Arc::new(MockStore::new()),
+        "output/00000-000".into(),
+        &table,
+    )
+    .unwrap();
+
+    for i in 0..99 {
+        writer
+            .insert_value(i.to_string(), SystemTime::now())
+            .await
+            .unwrap();
+    }
+    let stats = writer.stats().unwrap();
+    assert_eq!(stats.records_written(), 99);
+    assert!(!policy.should_roll(&stats, &TokioClock));
+
+    writer
+        .insert_value("99".to_string(), SystemTime::now())
+        .await
+        .unwrap();
+    let stats = writer.stats().unwrap();
+    assert_eq!(stats.records_written(), 100);
+    assert!(policy.should_roll(&stats, &TokioClock));
+}
+
+/// A format that drops every record, writing empty files
+struct NoopWriter;
+
+impl BatchBufferingWriter for NoopWriter {
+    type BatchData = String;
+
+    fn new(_config: &FileSystemTable) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    fn suffix() -> String {
+        "noop".to_string()
+    }
+
+    fn add_batch_data(&mut self, _data: Self::BatchData) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn buffer_length(&self) -> usize {
+        0
+    }
+
+    fn evict_current_buffer(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn get_trailing_bytes_for_checkpoint(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn close(&mut self, _final_batch: Option<Self::BatchData>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[tokio::test]
+async fn test_registered_format() {
+    assert!(format_factory::<String>("noop").is_none());
+    register_batch_format::<PassThrough<String>, NoopWriter>("noop");
+    assert!(format_factory::<String>("noop").is_some());
+    // formats are registered per record type
+    assert!(format_factory::<Vec<u8>>("noop").is_none());
+
+    let table_with_format = |format: Option<&str>| -> FileSystemTable {
+        serde_json::from_value(serde_json::json!({
+            "write_target": {"Path": "file:///tmp/arroyo-testing/filesystem-sink"},
+            "file_settings": {},
+            "format": format,
+        }))
+        .unwrap()
+    };
+    let new_writer = |table: &FileSystemTable| {
+        FormatWriter::<PassThrough<String>, JsonWriter<String>>::new(
+            Arc::new(MockStore::new()),
+            "output/00000-000".into(),
+            table,
+        )
+    };
+
+    // without a format, the sink's built-in format is registered and used
+    let writer = new_writer(&table_with_format(None)).unwrap();
+    assert_eq!(writer.name(), "output/00000-000.json");
+    assert!(format_factory::<String>("json").is_some());
+    let writer = new_writer(&table_with_format(Some("json"))).unwrap();
+    assert_eq!(writer.name(), "output/00000-000.json");
+
+    let err = new_writer(&table_with_format(Some("missing")))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("no format 'missing' registered"), "{}", err);
+
+    let mut writer = new_writer(&table_with_format(Some("noop"))).unwrap();
+    assert_eq!(writer.name(), "output/00000-000.noop");
+
+    writer
+        .insert_value("hello".to_string(), SystemTime::now())
+        .await
+        .unwrap();
+    let stats = writer.stats().unwrap();
+    assert_eq!(stats.records_written(), 1);
+    assert_eq!(stats.bytes_written(), 0);
+}
+
+#[tokio::test]
+async fn test_partition_stats() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MockStore::new());
+    let table = test_table(serde_json::json!({"target_part_size": 10}));
+    let (_sender, receiver) = channel(100);
+    let (checkpoint_sender, _checkpoint_receiver) = channel(100);
+    let mut writer = AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+        "output".into(),
+        store.clone(),
+        receiver,
+        checkpoint_sender,
+        table.clone(),
+    );
+
+    // "hot" gets most of the data, over two files
+    for (name, values) in [
+        ("output/date=hot/00000-000", 8),
+        ("output/date=hot/00001-000", 4),
+        ("output/date=cold/00000-000", 1),
+    ] {
+        let mut partition_writer =
+            JsonMultipartWriter::new(store.clone(), name.into(), &table).unwrap();
+        for i in 0..values {
+            partition_writer
+                .insert_value(format!("value{}", i), SystemTime::now())
+                .await
+                .unwrap();
+        }
+        writer
+            .writers
+            .insert(partition_writer.name(), partition_writer);
+    }
+    // a finished file still counts towards its partition
+    writer.retire_writer("output/date=hot/00000-000.json");
+
+    let stats = writer.partition_stats();
+    assert_eq!(stats.len(), 2);
+    // each value is 9 bytes ("valueN" plus quotes and a newline), so a part is every 2 values
+    assert_eq!(
+        stats["date=hot"],
+        PartitionStats {
+            bytes_written: 108,
+            parts_written: 6,
+            files: 2,
+        }
+    );
+    assert_eq!(
+        stats["date=cold"],
+        PartitionStats {
+            bytes_written: 9,
+            parts_written: 0,
+            files: 1,
+        }
+    );
+}
+
+#[test]
+fn test_time_rolling_policies_with_mock_clock() {
+    let clock = MockClock::new();
+    let table = test_table(serde_json::json!({
+        "rollover_seconds": 60,
+        "inactivity_rollover_seconds": 10,
+    }));
+    let policy = RollingPolicy::from_file_settings(table.file_settings.as_ref().unwrap());
+    let mut stats = MultiPartWriterStats {
+        bytes_written: 0,
+        parts_written: 0,
+        records_written: 1,
+        last_write_at: clock.now(),
+        first_write_at: clock.now(),
+    };
+    assert!(!policy.should_roll(&stats, &clock));
+
+    // keep writing every few seconds, so the file never goes inactive
+    for _ in 0..11 {
+        clock.advance(Duration::from_secs(5));
+        stats.last_write_at = clock.now();
+        assert!(!policy.should_roll(&stats, &clock));
+    }
+
+    // past the rollover duration since the first write
+    clock.advance(Duration::from_secs(5));
+    stats.last_write_at = clock.now();
+    assert_eq!(stats.age(&clock), Duration::from_secs(60));
+    assert_eq!(stats.idle_for(&clock), Duration::ZERO);
+    assert!(policy.should_roll(&stats, &clock));
+
+    // a new file that stops receiving writes rolls once it's been inactive long enough
+    stats.first_write_at = clock.now();
+    clock.advance(Duration::from_secs(9));
+    assert!(!policy.should_roll(&stats, &clock));
+    clock.advance(Duration::from_secs(1));
+    assert!(policy.should_roll(&stats, &clock));
+}
+
+#[test]
+fn test_rollover_disabled() {
+    let clock = MockClock::new();
+    let table = test_table(serde_json::json!({
+        "rollover_seconds": 0,
+        "max_records": 100,
+    }));
+    let policy = RollingPolicy::from_file_settings(table.file_settings.as_ref().unwrap());
+    let mut stats = MultiPartWriterStats {
+        bytes_written: 0,
+        parts_written: 0,
+        records_written: 1,
+        last_write_at: clock.now(),
+        first_write_at: clock.now(),
+    };
+    assert!(!policy.should_roll(&stats, &clock));
+
+    // however long the file has been open, only the other policies roll it
+    for _ in 0..10 {
+        clock.advance(Duration::from_secs(3_600));
+        stats.last_write_at = clock.now();
+        assert!(!policy.should_roll(&stats, &clock));
+    }
+    stats.records_written = 100;
+    assert!(policy.should_roll(&stats, &clock));
+
+    // without the setting, files still roll after 30 seconds
+    let policy = RollingPolicy::from_file_settings(
+        test_table(serde_json::json!({}))
+            .file_settings
+            .as_ref()
+            .unwrap(),
+    );
+    stats.records_written = 1;
+    stats.first_write_at = clock.now();
+    clock.advance(Duration::from_secs(30));
+    assert!(policy.should_roll(&stats, &clock));
+}
+
+#[tokio::test]
+async fn test_checksum_sidecar() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"target_part_size": 1, "write_checksum_sidecar": true})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    for value in ["hello", "world"] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, true)
+        .await
+        .into_iter()
+        .map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                    },
+                ..
+            }) => FileToFinish {
+                filename,
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+                staging_path: None,
+            },
+            other => panic!("unexpected checkpoint message {:?}", other),
+        })
+        .collect();
+    assert_eq!(files_to_finish.len(), 1);
+    assert!(files_to_finish[0].checksum.is_some());
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    let contents = store.read("output/00000-000.json").await;
+    assert_eq!(&contents[..], b"\"hello\"\n\"world\"\n");
+    assert_eq!(
+        &store.read("output/00000-000.json.sha256").await[..],
+        hex::encode(Sha256::digest(&contents)).as_bytes()
+    );
+}
+
+#[test]
+fn test_run_subdirectories_do_not_collide() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MockStore::new());
+    let table = test_table(serde_json::json!({"run_subdirectory": "uuid"}));
+    let new_sink_writer = || {
+        let (_sender, receiver) = channel(100);
+        let (checkpoint_sender, _checkpoint_receiver) = channel(100);
+        AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+            "output".into(),
+            store.clone(),
+            receiver,
+            checkpoint_sender,
+            table.clone(),
+        )
+    };
+
+    let mut first_run = new_sink_writer();
+    let mut second_run = new_sink_writer();
+    let first_run_directory = first_run.run_directory.clone().unwrap();
+    let second_run_directory = second_run.run_directory.clone().unwrap();
+    assert_ne!(first_run_directory, second_run_directory);
+
+    // the same file index and subtask, which would collide without run subdirectories
+    let first = first_run.new_writer().unwrap().name();
+    let second = second_run.new_writer().unwrap().name();
+    assert_eq!(
+        first,
+        format!("output/{}/00000-000.json", first_run_directory)
+    );
+    assert_eq!(
+        second,
+        format!("output/{}/00000-000.json", second_run_directory)
+    );
+
+    // later files of a run stay in its directory
+    first_run.max_file_index += 1;
+    assert_eq!(
+        first_run.new_writer().unwrap().name(),
+        format!("output/{}/00001-000.json", first_run_directory)
+    );
+}
+
+#[tokio::test]
+async fn test_restored_sink_keeps_run_subdirectory() {
+    let store: Arc<dyn ObjectStore> = Arc::new(MockStore::new());
+    let table = test_table(serde_json::json!({"run_subdirectory": "uuid"}));
+    let start_sink = || {
+        let (sender, receiver) = channel(100);
+        let (checkpoint_sender, checkpoint_receiver) = channel(100);
+        let mut writer = AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+            "output".into(),
+            store.clone(),
+            receiver,
+            checkpoint_sender,
+            table.clone(),
+        );
+        let run_directory = writer.run_directory.clone();
+        tokio::spawn(async move {
+            writer.run().await.unwrap();
+        });
+        FileSystemSink::<(), String, JsonMultipartWriter>::new(
+            sender,
+            checkpoint_receiver,
+            run_directory,
+        )
+    };
+    let task_info = get_test_task_info();
+
+    let mut sink = start_sink();
+    let run_directory = sink.run_directory.clone().unwrap();
+    sink.init(&task_info, vec![]).await.unwrap();
+    let (recovery, _) = sink.checkpoint(&task_info, false).await.unwrap();
+    assert_eq!(recovery.run_directory.as_ref(), Some(&run_directory));
+
+    // a new sink generates a different directory, but a restored one keeps the checkpointed one
+    let mut restored = start_sink();
+    assert_ne!(restored.run_directory.as_ref(), Some(&run_directory));
+    restored.init(&task_info, vec![recovery]).await.unwrap();
+    assert_eq!(restored.run_directory.as_ref(), Some(&run_directory));
+    assert_eq!(
+        restored.in_progress_files().await.unwrap(),
+        vec![InProgressFile {
+            name: format!("output/{}/00001-000.json", run_directory),
+            bytes_written: 0,
+            parts_written: 0,
+            records_written: 0,
+        }]
+    );
+}
+
+const ASSUME_ROLE_RESPONSE: &str = r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleResult>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::210987654321:assumed-role/sink-writer/arroyo</Arn>
+      <AssumedRoleId>AROAEXAMPLE:arroyo</AssumedRoleId>
+    </AssumedRoleUser>
+    <Credentials>
+      <AccessKeyId>ASIASINKWRITER</AccessKeyId>
+      <SecretAccessKey>sink-secret-key</SecretAccessKey>
+      <SessionToken>sink-session-token</SessionToken>
+      <Expiration>2099-01-01T00:00:00Z</Expiration>
+    </Credentials>
+  </AssumeRoleResult>
+  <ResponseMetadata>
+    <RequestId>c6104cbe-af31-11e0-8154-cbc7ccf896c7</RequestId>
+  </ResponseMetadata>
+</AssumeRoleResponse>"#;
+
+/// Serves a single HTTP request with a 200 response, returning the request that was received
+async fn serve_once(listener: TcpListener, content_type: &str, response: &str) -> String {
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        let n = socket.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+            let content_length = headers
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+                .unwrap_or(0);
+            if body.len() >= content_length {
+                break;
+            }
+        }
+    }
+
+    socket
+        .write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                content_type,
+                response.len(),
+                response
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    String::from_utf8(request).unwrap()
+}
+
+/// Serves a single STS request, returning the request that was received
+async fn mock_sts(listener: TcpListener) -> String {
+    serve_once(listener, "text/xml", ASSUME_ROLE_RESPONSE).await
+}
+
+#[tokio::test]
+async fn test_assume_role_for_sink() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sts_region = Region::Custom {
+        name: "us-east-1".to_string(),
+        endpoint: format!("http://{}", listener.local_addr().unwrap()),
+    };
+    let sts = tokio::spawn(mock_sts(listener));
+
+    let credentials = S3Credentialing::assume_role(
+        StaticProvider::new_minimal("AKIAGLOBAL".to_string(), "global-secret".to_string()),
+        "arn:aws:iam::210987654321:role/sink-writer".to_string(),
+        Some("tenant-1234".to_string()),
+        sts_region,
+    )
+    .unwrap();
+
+    let credential = credentials.get_credential().await.unwrap();
+    assert_eq!(credential.key_id, "ASIASINKWRITER");
+    assert_eq!(credential.secret_key, "sink-secret-key");
+    assert_eq!(credential.token.as_deref(), Some("sink-session-token"));
+
+    let request = sts.await.unwrap();
+    assert!(request.contains("Action=AssumeRole"));
+    assert!(request.contains("RoleArn=arn%3Aaws%3Aiam%3A%3A210987654321%3Arole%2Fsink-writer"));
+    assert!(request.contains("ExternalId=tenant-1234"));
+    // the request is signed with the global credentials
+    assert!(request.contains("Credential=AKIAGLOBAL/"));
+}
+
+#[tokio::test]
+async fn test_recovery_metrics() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+
+    // a subtask id no other test uses, so the counters are only incremented here
+    let labels = ["output", "7"];
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 1,
+            subtask_id: 7,
+            parallelism: 8,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![
+                InProgressFileCheckpoint {
+                    filename: "output/00000-000.json".to_string(),
+                    data: FileCheckpointData::MultiPartNotCreated {
+                        parts_to_add: vec![b"\"a\"\n".to_vec()],
+                        trailing_bytes: Some(b"\"b\"\n".to_vec()),
+                    },
+                    buffered_data: vec!["c".to_string(), "d".to_string()],
+                },
+                InProgressFileCheckpoint {
+                    filename: "output/00000-001.json".to_string(),
+                    data: FileCheckpointData::MultiPartNotCreated {
+                        parts_to_add: vec![b"\"e\"\n".to_vec()],
+                        trailing_bytes: None,
+                    },
+                    buffered_data: vec!["f".to_string()],
+                },
+            ],
+        })
+        .await
+        .unwrap();
+
+    // the checkpoint is only handled once the init has been processed
+    checkpoint(&sender, &mut checkpoint_receiver, false).await;
+
+    assert_eq!(RECOVERED_FILES_COUNTER.with_label_values(&labels).get(), 2);
+    assert_eq!(
+        RECOVERED_RECORDS_COUNTER.with_label_values(&labels).get(),
+        3
+    );
+    assert_eq!(RECOVERED_PARTS_COUNTER.with_label_values(&labels).get(), 3);
+}
+
+#[tokio::test]
+async fn test_roll_on_checkpoint() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"roll_on_checkpoint": true})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+
+    for value in ["a", "b"] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+
+        // far below any size or time limit, but the checkpoint still closes the file
+        let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, false)
+            .await
+            .into_iter()
+            .filter_map(|message| match message {
+                CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                    filename,
+                    data:
+                        FileCheckpointData::MultiPartWriterUploadCompleted {
+                            multi_part_upload_id,
+                            completed_parts,
+                            checksum,
+                        },
+                    ..
+                }) => Some(FileToFinish {
+                    filename,
+                    multi_part_upload_id,
+                    completed_parts,
+                    checksum,
+                    staging_path: None,
+                }),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files_to_finish.len(), 1);
+
+        sender
+            .send(FileSystemMessages::FilesToFinish(files_to_finish))
+            .await
+            .unwrap();
+        checkpoint_receiver.recv().await.unwrap();
+    }
+
+    let files: Vec<_> = store
+        .list(Some(&"output".into()))
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(&store.read("output/00000-000.json").await[..], b"\"a\"\n");
+    assert_eq!(&store.read("output/00001-000.json").await[..], b"\"b\"\n");
+}
+
+#[tokio::test]
+async fn test_finalize_on_watermark() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"finalize_on_watermark_seconds": 60})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+
+    let at = |seconds| UNIX_EPOCH + Duration::from_secs(seconds);
+    for (value, seconds) in [("a", 10), ("b", 50)] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: at(seconds),
+            })
+            .await
+            .unwrap();
+    }
+    // still within the file's window, [0s, 60s)
+    sender
+        .send(FileSystemMessages::Watermark(at(59)))
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Watermark(at(60)))
+        .await
+        .unwrap();
+    // a record from a later window starts a new file without finalizing the open one
+    for (value, seconds) in [("c", 70), ("d", 130)] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: at(seconds),
+            })
+            .await
+            .unwrap();
+    }
+
+    let completed = completed_files(&sender, &mut checkpoint_receiver).await;
+    assert_eq!(completed, vec!["output/00000-000.json".to_string()]);
+
+    // only the watermark finalizes the [60s, 120s) window
+    sender
+        .send(FileSystemMessages::Watermark(at(120)))
+        .await
+        .unwrap();
+    let completed = completed_files(&sender, &mut checkpoint_receiver).await;
+    assert_eq!(completed, vec!["output/00001-000.json".to_string()]);
+}
+
+#[tokio::test]
+async fn test_finalize_on_watermark_out_of_order() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"finalize_on_watermark_seconds": 60})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+
+    let at = |seconds| UNIX_EPOCH + Duration::from_secs(seconds);
+    // "c" arrives after a record from the next window, but before the watermark passes its own
+    for (value, seconds) in [("a", 10), ("b", 70), ("c", 20)] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: at(seconds),
+            })
+            .await
+            .unwrap();
+    }
+    sender
+        .send(FileSystemMessages::Watermark(at(60)))
+        .await
+        .unwrap();
+
+    let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, false)
+        .await
+        .into_iter()
+        .filter_map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                    },
+                ..
+            }) => Some(FileToFinish {
+                filename,
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+                staging_path: None,
+            }),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(files_to_finish.len(), 1);
+    assert_eq!(files_to_finish[0].filename, "output/00000-000.json");
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert_eq!(
+        &store.read("output/00000-000.json").await[..],
+        b"\"a\"\n\"c\"\n"
+    );
+}
+
+#[tokio::test]
+async fn test_part_limit_rolls() {
+    let store = Arc::new(MockStore::new());
+    // each record is its own part
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"target_part_size": 1, "max_parts": 2})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    for value in ["a", "b", "c"] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let completed: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, false)
+        .await
+        .into_iter()
+        .filter_map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        completed_parts, ..
+                    },
+                ..
+            }) => Some((filename, completed_parts.len())),
+            _ => None,
+        })
+        .collect();
+    // the first file is full at exactly max_parts, so the third record starts a new one
+    assert_eq!(completed, vec![("output/00000-000.json".to_string(), 2)]);
+}
+
+#[tokio::test]
+async fn test_part_limit_error() {
+    let store = Arc::new(MockStore::new());
+    let (sender, receiver) = channel(100);
+    let (checkpoint_sender, mut checkpoint_receiver) = channel(100);
+    let mut writer = AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+        "output".into(),
+        store.clone(),
+        receiver,
+        checkpoint_sender,
+        test_table(serde_json::json!({
+            "target_part_size": 1,
+            "max_parts": 2,
+            "on_part_limit": "error",
+        })),
+    );
+    let writer = tokio::spawn(async move { writer.run().await });
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    for value in ["a", "b"] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    // a file with exactly max_parts parts is fine, and isn't rolled
+    let messages = checkpoint(&sender, &mut checkpoint_receiver, false).await;
+    assert!(messages.iter().all(|message| !matches!(
+        message,
+        CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+            data: FileCheckpointData::MultiPartWriterUploadCompleted { .. },
+            ..
+        })
+    )));
+
+    // but it has no room for another record
+    sender
+        .send(FileSystemMessages::Data {
+            value: "c".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+    let err = writer.await.unwrap().unwrap_err();
+    assert!(
+        err.to_string().contains("max_parts (2 parts)"),
+        "unexpected error: {}",
+        err
+    );
+}
+
+/// Writes each value to its own file, returning the files to finish
+async fn write_files(
+    sender: &Sender<FileSystemMessages<String>>,
+    checkpoint_receiver: &mut Receiver<CheckpointData<String>>,
+    values: Vec<String>,
+) -> Vec<FileToFinish> {
+    let mut files_to_finish = vec![];
+    for value in values {
+        sender
+            .send(FileSystemMessages::Data {
+                value,
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+
+        files_to_finish.extend(
+            checkpoint(sender, checkpoint_receiver, false)
+                .await
+                .into_iter()
+                .filter_map(|message| match message {
+                    CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                        filename,
+                        data:
+                            FileCheckpointData::MultiPartWriterUploadCompleted {
+                                multi_part_upload_id,
+                                completed_parts,
+                                checksum,
+                            },
+                        ..
+                    }) => Some(FileToFinish {
+                        filename,
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                        staging_path: None,
+                    }),
+                    _ => None,
+                }),
+        );
+    }
+    files_to_finish
+}
+
+async fn read_compaction_candidates(store: &MockStore) -> CompactionCandidates {
+    serde_json::from_slice(&store.read("output/_compaction_candidates.json").await).unwrap()
+}
+
+#[tokio::test]
+async fn test_compaction_candidates() {
+    let store = Arc::new(MockStore::new());
+    let table = test_table(serde_json::json!({
+        "roll_on_checkpoint": true,
+        "compaction_candidate_threshold": 100,
+    }));
+    let init = |max_file_index| FileSystemMessages::Init {
+        max_file_index,
+        subtask_id: 0,
+        parallelism: 1,
+        job_id: "job".to_string(),
+        operator_id: "sink".to_string(),
+        run_directory: None,
+        recovered_files: vec![],
+    };
+    let (sender, mut checkpoint_receiver) = start_writer(store.clone(), table.clone());
+    sender.send(init(0)).await.unwrap();
+
+    // a small file, a large one, and another small one, all committed together
+    let files_to_finish = write_files(
+        &sender,
+        &mut checkpoint_receiver,
+        vec!["a".to_string(), "b".repeat(200), "c".to_string()],
+    )
+    .await;
+    assert_eq!(files_to_finish.len(), 3);
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    let small_files = vec![
+        CompactionCandidate {
+            path: "output/00000-000.json".to_string(),
+            bytes: 4,
+        },
+        CompactionCandidate {
+            path: "output/00002-000.json".to_string(),
+            bytes: 4,
+        },
+    ];
+    assert_eq!(
+        read_compaction_candidates(&store).await,
+        CompactionCandidates { files: small_files }
+    );
+    assert_eq!(store.read("output/00001-000.json").await.len(), 203);
+
+    // a commit without small files leaves the manifest as it is
+    let files_to_finish =
+        write_files(&sender, &mut checkpoint_receiver, vec!["d".repeat(200)]).await;
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+    assert_eq!(read_compaction_candidates(&store).await.files.len(), 2);
+
+    // a file committed after a restart has no stats, but is still added to the manifest
+    let files_to_finish =
+        write_files(&sender, &mut checkpoint_receiver, vec!["e".to_string()]).await;
+    let (restored_sender, mut restored_receiver) = start_writer(store.clone(), table);
+    restored_sender.send(init(5)).await.unwrap();
+    restored_sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    restored_receiver.recv().await.unwrap();
+    let paths: Vec<_> = read_manifest()
+        .await
+        .files
+        .into_iter()
+        .map(|file| file.path)
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "output/00000-000.json",
+            "output/00002-000.json",
+            "output/00004-000.json",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_files_finished_concurrently() {
+    let store = Arc::new(MockStore {
+        part_latency: Duration::from_millis(20),
+        ..MockStore::new()
+    });
+    let (sender, mut checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+
+    let mut files_to_finish = vec![];
+    for i in 0..10 {
+        let location = Path::from(format!("output/{:05}-000.json", i));
+        let multi_part_upload_id = store.start_multipart(&location).await.unwrap();
+        let part = store
+            .add_multipart(
+                &location,
+                &multi_part_upload_id,
+                0,
+                format!("\"{}\"\n", i).into(),
+            )
+            .await
+            .unwrap();
+        files_to_finish.push(FileToFinish {
+            filename: location.to_string(),
+            multi_part_upload_id,
+            completed_parts: vec![part.content_id],
+            checksum: None,
+            staging_path: None,
+        });
+    }
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    for i in 0..10 {
+        assert_eq!(
+            store.read(&format!("output/{:05}-000.json", i)).await,
+            format!("\"{}\"\n", i).as_bytes()
+        );
+    }
+    assert!(store.max_closes_in_flight.load(Ordering::SeqCst) > 1);
+}
+
+#[tokio::test]
+async fn test_idle_flush() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) = start_writer(
+        store.clone(),
+        test_table(serde_json::json!({"idle_flush_seconds": 1})),
+    );
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+
+    // well below the target part size, so only the idle flush uploads it
+    tokio::time::sleep(Duration::from_millis(1300)).await;
+
+    let messages = checkpoint(&sender, &mut checkpoint_receiver, false).await;
+    assert_eq!(messages.len(), 1);
+    let CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+        data:
+            FileCheckpointData::MultiPartInFlight {
+                in_flight_parts,
+                trailing_bytes,
+                ..
+            },
+        ..
+    }) = &messages[0]
+    else {
+        panic!("unexpected checkpoint message {:?}", messages[0]);
+    };
+    assert_eq!(in_flight_parts.len(), 1);
+    assert_eq!(trailing_bytes, &None);
+}
+
+#[test]
+fn test_oversized_part_rejected() {
+    let store = Arc::new(MockStore::new());
+    let mut manager = MultipartManager::new(store, "output/file.json".into(), None);
+    manager.max_part_size = Some(10);
+
+    let error = manager.write_next_part(vec![0; 11]).err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        "part 0 of output/file.json is 11 bytes, but the object store accepts parts of at most 10 bytes; lower target_part_size"
+    );
+    // nothing was queued for upload
+    assert!(manager.parts_to_add.is_empty());
+
+    assert!(manager.write_next_part(vec![0; 10]).unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_residual_buffer_flushed_on_stop() {
+    // the residual buffer is written on close even if the upload hasn't started yet
+    let store = Arc::new(MockStore::new());
+    let mut writer = JsonMultipartWriter::new(
+        store.clone(),
+        "output/file".into(),
+        &test_table(serde_json::json!({})),
+    )
+    .unwrap();
+    assert!(writer
+        .insert_value("a".to_string(), SystemTime::now())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(writer.close().unwrap().is_some());
+    let FileCheckpointData::MultiPartNotCreated { parts_to_add, .. } =
+        writer.get_in_progress_checkpoint()
+    else {
+        panic!("closed file with buffered data should not be checkpointed as empty");
+    };
+    assert_eq!(parts_to_add, vec![b"\"a\"\n".to_vec()]);
+
+    // records below the part size appear in the committed file after a stopping checkpoint
+    let (sender, mut checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    for value in ["a", "b", "c"] {
+        sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, true)
+        .await
+        .into_iter()
+        .filter_map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                    },
+                ..
+            }) => Some(FileToFinish {
+                filename,
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+                staging_path: None,
+            }),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(files_to_finish.len(), 1);
+
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert_eq!(
+        store.read("output/00000-000.json").await,
+        b"\"a\"\n\"b\"\n\"c\"\n".to_vec()
+    );
+}
+
+#[tokio::test]
+async fn test_subtask_subdirectories() {
+    let store = Arc::new(MockStore::new());
+
+    for subtask_id in 0..2 {
+        let (sender, mut checkpoint_receiver) = start_writer(
+            store.clone(),
+            test_table(serde_json::json!({"subtask_subdirectory": true})),
+        );
+        sender
+            .send(FileSystemMessages::Init {
+                max_file_index: 0,
+                subtask_id,
+                parallelism: 2,
+                job_id: "job".to_string(),
+                operator_id: "sink".to_string(),
+                run_directory: None,
+                recovered_files: vec![],
+            })
+            .await
+            .unwrap();
+        sender
+            .send(FileSystemMessages::Data {
+                value: format!("subtask {}", subtask_id),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+
+        let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, true)
+            .await
+            .into_iter()
+            .map(|message| match message {
+                CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                    filename,
+                    data:
+                        FileCheckpointData::MultiPartWriterUploadCompleted {
+                            multi_part_upload_id,
+                            completed_parts,
+                            checksum,
+                        },
+                    ..
+                }) => FileToFinish {
+                    filename,
+                    multi_part_upload_id,
+                    completed_parts,
+                    checksum,
+                    staging_path: None,
+                },
+                other => panic!("unexpected checkpoint message {:?}", other),
+            })
+            .collect();
+        // recovery finishes files by their checkpointed name, so it keeps the same layout
+        assert_eq!(files_to_finish.len(), 1);
+        assert_eq!(
+            files_to_finish[0].filename,
+            format!("output/subtask={}/00000-00{}.json", subtask_id, subtask_id)
+        );
+
+        sender
+            .send(FileSystemMessages::FilesToFinish(files_to_finish))
+            .await
+            .unwrap();
+        checkpoint_receiver.recv().await.unwrap();
+    }
+
+    assert_eq!(
+        &store.read("output/subtask=0/00000-000.json").await[..],
+        b"\"subtask 0\"\n"
+    );
+    assert_eq!(
+        &store.read("output/subtask=1/00000-001.json").await[..],
+        b"\"subtask 1\"\n"
+    );
+}
+
+#[tokio::test]
+async fn test_file_without_parts_aborts_upload() {
+    let store = Arc::new(MockStore::new());
+    let (sender, mut checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+
+    let location = Path::from("output/00000-000.json");
+    let multi_part_upload_id = store.start_multipart(&location).await.unwrap();
+    sender
+        .send(FileSystemMessages::FilesToFinish(vec![FileToFinish {
+            filename: location.to_string(),
+            multi_part_upload_id: multi_part_upload_id.clone(),
+            completed_parts: vec![],
+            checksum: None,
+            staging_path: None,
+        }]))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert!(!store
+        .uploads
+        .lock()
+        .unwrap()
+        .contains_key(&multi_part_upload_id));
+    assert!(store.inner.head(&location).await.is_err());
+}
+
+#[tokio::test]
+async fn test_recovery_restarts_expired_upload() {
+    let store = Arc::new(MockStore::new());
+    let location = Path::from("output/00000-000.json");
+    let closed = || FileCheckpointData::MultiPartWriterClosed {
+        // never started on this store, as if it had been aborted by a lifecycle rule
+        multi_part_upload_id: "expired".to_string(),
+        in_flight_parts: vec![
+            InFlightPartCheckpoint::InProgressPart {
+                part: 0,
+                data: b"\"a\"\n".to_vec(),
+            },
+            InFlightPartCheckpoint::InProgressPart {
+                part: 1,
+                data: b"\"b\"\n".to_vec(),
+            },
+        ],
+    };
+
+    assert!(
+        from_checkpoint(&location, closed(), store.clone(), false, false)
+            .await
+            .is_err()
+    );
+
+    let (file_to_finish, parts_uploaded) =
+        from_checkpoint(&location, closed(), store.clone(), false, true)
+            .await
+            .unwrap();
+    let file_to_finish = file_to_finish.unwrap();
+    assert_eq!(parts_uploaded, 2);
+    assert_ne!(file_to_finish.multi_part_upload_id, "expired");
+    assert!(store
+        .uploads
+        .lock()
+        .unwrap()
+        .contains_key(&file_to_finish.multi_part_upload_id));
+
+    finish_file(store.clone(), file_to_finish, false)
+        .await
+        .unwrap();
+    assert_eq!(&store.read(location.as_ref()).await[..], b"\"a\"\n\"b\"\n");
+
+    // parts that were uploaded to the expired upload can't be restored
+    let partially_uploaded = FileCheckpointData::MultiPartWriterClosed {
+        multi_part_upload_id: "expired".to_string(),
+        in_flight_parts: vec![
+            InFlightPartCheckpoint::FinishedPart {
+                part: 0,
+                content_id: "0".to_string(),
+            },
+            InFlightPartCheckpoint::InProgressPart {
+                part: 1,
+                data: b"\"b\"\n".to_vec(),
+            },
+        ],
+    };
+    assert!(
+        from_checkpoint(&location, partially_uploaded, store.clone(), false, true)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_commit_is_idempotent() {
+    let store = Arc::new(MockStore::new());
+    let (sender, checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+    let mut sink =
+        FileSystemSink::<(), String, JsonMultipartWriter>::new(sender, checkpoint_receiver, None);
+    let task_info = get_test_task_info();
+
+    let location = Path::from("output/00000-000.json");
+    let multi_part_upload_id = store.start_multipart(&location).await.unwrap();
+    let part = store
+        .add_multipart(&location, &multi_part_upload_id, 0, "\"a\"\n".into())
+        .await
+        .unwrap();
+    let file = FileToFinish {
+        filename: location.to_string(),
+        multi_part_upload_id,
+        completed_parts: vec![part.content_id],
+        checksum: None,
+        staging_path: None,
+    };
+
+    // the checkpoint that pre-commits the file doesn't know it will be finished
+    let (pre_commit_recovery, _) = sink.checkpoint(&task_info, false).await.unwrap();
+    assert!(pre_commit_recovery.finished_files.is_empty());
+
+    sink.commit(&task_info, vec![file.clone()]).await.unwrap();
+    assert_eq!(&store.read("output/00000-000.json").await[..], b"\"a\"\n");
+
+    // the upload is gone, so finishing it again would fail
+    sink.commit(&task_info, vec![file.clone()]).await.unwrap();
+
+    // the finished files are checkpointed, so a restored sink skips them too
+    let (recovery, _) = sink.checkpoint(&task_info, false).await.unwrap();
+    let (sender, checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+    let mut restored =
+        FileSystemSink::<(), String, JsonMultipartWriter>::new(sender, checkpoint_receiver, None);
+    restored.init(&task_info, vec![recovery]).await.unwrap();
+    restored
+        .commit(&task_info, vec![file.clone()])
+        .await
+        .unwrap();
+    assert_eq!(&store.read("output/00000-000.json").await[..], b"\"a\"\n");
+
+    // a sink restored from the pre-commit checkpoint retries the commit, and finds that the
+    // upload is gone because the file is already in place
+    let (sender, checkpoint_receiver) =
+        start_writer(store.clone(), test_table(serde_json::json!({})));
+    let mut restored =
+        FileSystemSink::<(), String, JsonMultipartWriter>::new(sender, checkpoint_receiver, None);
+    restored
+        .init(&task_info, vec![pre_commit_recovery])
+        .await
+        .unwrap();
+    restored
+        .commit(&task_info, vec![file.clone()])
+        .await
+        .unwrap();
+    assert_eq!(&store.read("output/00000-000.json").await[..], b"\"a\"\n");
+
+    // but an upload that's gone without its file having been written is still an error
+    let missing = FileToFinish {
+        filename: "output/00001-000.json".to_string(),
+        ..file
+    };
+    assert!(restored.commit(&task_info, vec![missing]).await.is_err());
+}
+
+#[test]
+fn test_decode_data_recovery_without_later_fields() {
+    let config = bincode::config::standard();
+    let recovery = FileSystemDataRecovery::<String> {
+        next_file_index: 3,
+        active_files: vec![],
+        finished_files: vec!["output/00000-000.json".to_string()],
+        run_directory: Some("run-1".to_string()),
+    };
+    let encoded = bincode::encode_to_vec(&recovery, config).unwrap();
+    let (decoded, _): (FileSystemDataRecovery<String>, _) =
+        bincode::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(decoded, recovery);
+
+    // as written before finished files and run directories were checkpointed
+    let encoded = bincode::encode_to_vec(
+        (3usize, Vec::<InProgressFileCheckpoint<String>>::new()),
+        config,
+    )
+    .unwrap();
+    let (decoded, _): (FileSystemDataRecovery<String>, _) =
+        bincode::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(
+        decoded,
+        FileSystemDataRecovery {
+            next_file_index: 3,
+            active_files: vec![],
+            finished_files: vec![],
+            run_directory: None,
+        }
+    );
+}
+
+#[test]
+fn test_decode_state_from_before_checksums() {
+    let config = bincode::config::standard();
+    let file = FileToFinish {
+        filename: "output/00000-000.json".to_string(),
+        multi_part_upload_id: "upload-1".to_string(),
+        completed_parts: vec!["part-1".to_string()],
+        checksum: None,
+        staging_path: None,
+    };
+
+    // a pre-commit as written before checksums were added
+    let old_file = ("output/00000-000.json", "upload-1", vec!["part-1"]);
+    let encoded = bincode::encode_to_vec(&old_file, config).unwrap();
+    let (decoded, _): (FileToFinish, _) = bincode::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(decoded, file);
+
+    let file = FileToFinish {
+        checksum: Some("0123abcd".to_string()),
+        ..file
+    };
+    let encoded = bincode::encode_to_vec(&file, config).unwrap();
+    let (decoded, _): (FileToFinish, _) = bincode::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(decoded, file);
+
+    // a stopped job's recovery with an upload that completed before it was checkpointed, as
+    // written before checksums were added: `MultiPartWriterUploadCompleted` is variant 4
+    let old_active_file = (
+        "output/00000-000.json",
+        4u32,
+        "upload-1",
+        vec!["part-1"],
+        Vec::<String>::new(),
+    );
+    let encoded = bincode::encode_to_vec((3usize, vec![old_active_file.clone()]), config).unwrap();
+    let (decoded, _): (FileSystemDataRecovery<String>, _) =
+        bincode::decode_from_slice(&encoded, config).unwrap();
+    let completed = InProgressFileCheckpoint {
+        filename: "output/00000-000.json".to_string(),
+        data: FileCheckpointData::MultiPartWriterUploadCompleted {
+            multi_part_upload_id: "upload-1".to_string(),
+            completed_parts: vec!["part-1".to_string()],
+            checksum: None,
+        },
+        buffered_data: vec![],
+    };
+    assert_eq!(decoded.active_files, vec![completed.clone()]);
+
+    // uploads without a checksum keep the old layout, and those with one round trip
+    assert_eq!(
+        bincode::encode_to_vec(&completed, config).unwrap(),
+        bincode::encode_to_vec(old_active_file, config).unwrap()
+    );
+    let completed = InProgressFileCheckpoint {
+        data: FileCheckpointData::MultiPartWriterUploadCompleted {
+            multi_part_upload_id: "upload-1".to_string(),
+            completed_parts: vec!["part-1".to_string()],
+            checksum: Some("0123abcd".to_string()),
+        },
+        ..completed
+    };
+    let encoded = bincode::encode_to_vec(&completed, config).unwrap();
+    let (decoded, _): (InProgressFileCheckpoint<String>, _) =
+        bincode::decode_from_slice(&encoded, config).unwrap();
+    assert_eq!(decoded, completed);
+}
+
+#[tokio::test]
+async fn test_list_in_progress_files() {
+    let (sender, checkpoint_receiver) = start_writer(
+        Arc::new(MockStore::new()),
+        test_table(serde_json::json!({"target_part_size": 10})),
+    );
+    let sink =
+        FileSystemSink::<(), String, JsonMultipartWriter>::new(sender, checkpoint_receiver, None);
+    assert!(sink.in_progress_files().await.unwrap().is_empty());
+
+    sink.sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    for value in ["hello", "world"] {
+        sink.sender
+            .send(FileSystemMessages::Data {
+                value: value.to_string(),
+                time: SystemTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    // "hello"\n is 8 bytes, so the second value fills the first part
+    assert_eq!(
+        sink.in_progress_files().await.unwrap(),
+        vec![InProgressFile {
+            name: "output/00000-000.json".to_string(),
+            bytes_written: 16,
+            parts_written: 1,
+            records_written: 2,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_local_writer_tmp_dir() {
+    let root = "/tmp/arroyo-testing/local-tmp-dir";
+    let _ = std::fs::remove_dir_all(root);
+    let (final_dir, tmp_dir) = (format!("{}/final", root), format!("{}/tmp", root));
+
+    let mut writer = LocalFileSystemWriter::<(), String, JsonLocalWriter>::new(
+        final_dir.clone(),
+        test_table(serde_json::json!({"tmp_dir": tmp_dir})),
+    )
+    .unwrap();
+    let task_info = get_test_task_info();
+    writer.init(&task_info, vec![]).await.unwrap();
+    writer
+        .insert_record(&Record {
+            timestamp: SystemTime::now(),
+            key: None,
+            value: "hello".to_string(),
+        })
+        .await
+        .unwrap();
+    let (_, pre_commits) = writer.checkpoint(&task_info, true).await.unwrap();
+    let pre_commits: Vec<_> = pre_commits.into_values().collect();
+    // each destination gets its own subdirectory of the shared tmp_dir
+    let tmp_file = std::path::PathBuf::from(&pre_commits[0].tmp_file);
+    assert_eq!(tmp_file.file_name().unwrap(), "00000-000.json");
+    let sink_tmp_dir = tmp_file.parent().unwrap();
+    assert_eq!(
+        sink_tmp_dir.parent().unwrap(),
+        std::path::Path::new(&tmp_dir)
+    );
+    assert!(!std::path::Path::new(&format!("{}/__in_progress", final_dir)).exists());
+    LocalFileSystemWriter::<(), String, JsonLocalWriter>::new(
+        format!("{}/other", root),
+        test_table(serde_json::json!({"tmp_dir": tmp_dir})),
+    )
+    .unwrap();
+    assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 2);
+
+    writer.commit(&task_info, pre_commits).await.unwrap();
+    assert_eq!(
+        std::fs::read_to_string(format!("{}/00000-000.json", final_dir)).unwrap(),
+        "\"hello\"\n"
+    );
+    assert!(!tmp_file.exists());
+
+    // tmp and final are usually on the same filesystem in tests, so exercise the fallback for
+    // renames across devices directly
+    let (from, to) = (
+        format!("{}/cross-device.json", tmp_dir),
+        format!("{}/cross-device.json", final_dir),
+    );
+    std::fs::write(&from, "\"moved\"\n").unwrap();
+    copy_across_devices(std::path::Path::new(&from), std::path::Path::new(&to))
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "\"moved\"\n");
+    assert!(!std::path::Path::new(&from).exists());
+    assert!(!std::path::Path::new(&format!("{}.copying", to)).exists());
+}
+
+#[test]
+fn test_object_store_without_aws_credentials() {
+    // creating a sink's store doesn't look up credentials, so it works without any AWS
+    // configuration; S3 credentials are only resolved by the store's first request
+    let (_, path) = object_store_for(Destination::LocalFilesystem {
+        local_directory: "/tmp/arroyo-testing/no-credentials".to_string(),
+    })
+    .unwrap();
+    assert_eq!(path.as_ref(), "tmp/arroyo-testing/no-credentials");
+
+    let (_, path) = object_store_for(Destination::S3Bucket {
+        s3_bucket: "my-bucket".to_string(),
+        s3_directory: "output".to_string(),
+        aws_region: "us-east-1".to_string(),
+        role_arn: None,
+        external_id: None,
+    })
+    .unwrap();
+    assert_eq!(path.as_ref(), "output");
+}
+
+#[tokio::test]
+async fn test_gcs_bucket_endpoint() {
+    // stands in for fake-gcs-server, which accepts uploads without credentials
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        serve_once(
+            listener,
+            "application/json",
+            r#"{"bucket": "my-bucket", "name": "output/00000-000.json"}"#,
+        )
+        .await
+    });
+
+    let (store, path) = object_store_for(Destination::GcsBucket {
+        gcs_bucket: "my-bucket".to_string(),
+        gcs_directory: "output".to_string(),
+        endpoint: Some(format!("{}/", endpoint)),
+    })
+    .unwrap();
+    assert_eq!(path.as_ref(), "output");
+
+    store
+        .put(
+            &path.child("00000-000.json"),
+            Bytes::from_static(b"\"hello\"\n"),
+        )
+        .await
+        .unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.contains("/upload/storage/v1/b/my-bucket/o"));
+    assert!(
+        request.contains("output%2F00000-000.json") || request.contains("output/00000-000.json")
+    );
+    assert!(request.ends_with("\"hello\"\n"));
+}
+
+#[test]
+fn test_folder_uri_errors() {
+    let err = object_store_for(Destination::FolderUri {
+        path: "not a url".to_string(),
+        scheme_override: None,
+    })
+    .err()
+    .expect("malformed url should fail");
+    let err = err
+        .downcast_ref::<DestinationError>()
+        .expect("typed destination error");
+    assert!(matches!(err, DestinationError::InvalidUrl { url, .. } if url == "not a url"));
+
+    let err = object_store_for(Destination::FolderUri {
+        path: "ftp://example.com/output".to_string(),
+        scheme_override: None,
+    })
+    .err()
+    .expect("ftp should be unsupported");
+    assert_eq!(
+        err.downcast_ref::<DestinationError>(),
+        Some(&DestinationError::UnsupportedScheme {
+            url: "ftp://example.com/output".to_string(),
+            scheme: "ftp".to_string(),
+        })
+    );
+    assert!(err.to_string().contains("unsupported scheme 'ftp'"));
+
+    // the sinks report the same errors instead of panicking
+    let config = serde_json::json!({
+        "connection": {},
+        "table": {"write_target": {"Path": "ftp://example.com/output"}},
+        "format": null,
+        "rate_limit": null,
+    })
+    .to_string();
+    let err = format!(
+        "{:#}",
+        JsonFileSystemSink::<(), String>::try_from_config(&config)
+            .err()
+            .unwrap()
+    );
+    assert!(err.contains("failed to create object store for FileSystemSink"));
+    assert!(err.contains("unsupported scheme 'ftp'"));
+    let err = LocalJsonFileSystemSink::<(), String>::try_from_config(&config)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("unsupported scheme 'ftp'"));
+
+    let (_, path) = object_store_for(Destination::FolderUri {
+        path: "file:///tmp/arroyo-testing/folder-uri".to_string(),
+        scheme_override: None,
+    })
+    .unwrap();
+    assert_eq!(path.as_ref(), "tmp/arroyo-testing/folder-uri");
+
+    let (store, path) = object_store_for(Destination::FolderUri {
+        path: "gs://my-bucket/output".to_string(),
+        scheme_override: None,
+    })
+    .unwrap();
+    assert!(store.to_string().contains("my-bucket"));
+    assert_eq!(path.as_ref(), "output");
+}
+
+#[test]
+fn test_folder_uri_scheme_override() {
+    // an S3-compatible endpoint addressed over https
+    let (store, path) = object_store_for(Destination::FolderUri {
+        path: "https://minio.internal:9000/my-bucket/output/events".to_string(),
+        scheme_override: Some("s3".to_string()),
+    })
+    .unwrap();
+    assert!(store.to_string().contains("AmazonS3"));
+    assert!(store.to_string().contains("my-bucket"));
+    assert_eq!(path.as_ref(), "output/events");
+
+    // a bucket on a GCS emulator
+    let (store, path) = object_store_for(Destination::FolderUri {
+        path: "http://localhost:4443/my-bucket/output/events".to_string(),
+        scheme_override: Some("gs".to_string()),
+    })
+    .unwrap();
+    assert!(store.to_string().contains("GoogleCloudStorage"));
+    assert!(store.to_string().contains("my-bucket"));
+    assert_eq!(path.as_ref(), "output/events");
+
+    let (store, path) = object_store_for(Destination::FolderUri {
+        path: "custom://host/tmp/arroyo-testing/folder-uri".to_string(),
+        scheme_override: Some("file".to_string()),
+    })
+    .unwrap();
+    assert!(store.to_string().contains("LocalFileSystem"));
+    assert_eq!(path.as_ref(), "tmp/arroyo-testing/folder-uri");
+
+    let err = object_store_for(Destination::FolderUri {
+        path: "https://minio.internal:9000/my-bucket".to_string(),
+        scheme_override: Some("ftp".to_string()),
+    })
+    .err()
+    .expect("unsupported override should fail");
+    assert!(matches!(
+        err.downcast_ref::<DestinationError>(),
+        Some(DestinationError::UnsupportedScheme { scheme, .. }) if scheme == "ftp"
+    ));
+
+    let err = object_store_for(Destination::FolderUri {
+        path: "https://minio.internal:9000/".to_string(),
+        scheme_override: Some("s3".to_string()),
+    })
+    .err()
+    .expect("missing bucket should fail");
+    assert!(matches!(
+        err.downcast_ref::<DestinationError>(),
+        Some(DestinationError::InvalidUrl { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_shutdown_when_checkpoint_receiver_dropped() {
+    let store = Arc::new(MockStore::new());
+    let (sender, receiver) = channel(100);
+    let (checkpoint_sender, checkpoint_receiver) = channel(100);
+    let mut writer = AsyncMultipartFileSystemWriter::<String, JsonMultipartWriter>::new(
+        "output".into(),
+        store.clone(),
+        receiver,
+        checkpoint_sender,
+        test_table(serde_json::json!({"target_part_size": 1})),
+    );
+    let writer = tokio::spawn(async move { writer.run().await });
+
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+
+    // the sink goes away before the checkpoint can be handed to it
+    drop(checkpoint_receiver);
+    sender
+        .send(FileSystemMessages::Checkpoint {
+            subtask_id: 0,
+            then_stop: false,
+        })
+        .await
+        .unwrap();
+
+    writer.await.unwrap().unwrap();
+    assert!(store.uploads.lock().unwrap().is_empty());
+    assert!(store
+        .inner
+        .head(&"output/00000-000.json".into())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_key_prefix() {
+    let store = Arc::new(MockStore::new());
+    // slashes around the prefix are ignored, so keys never have empty segments
+    let table = test_table(serde_json::json!({"key_prefix": "/v2/"}));
+
+    let (sender, mut checkpoint_receiver) = start_writer(store.clone(), table.clone());
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 0,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files: vec![],
+        })
+        .await
+        .unwrap();
+    sender
+        .send(FileSystemMessages::Data {
+            value: "hello".to_string(),
+            time: SystemTime::now(),
+        })
+        .await
+        .unwrap();
+    let recovered_files: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, false)
+        .await
+        .into_iter()
+        .map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(checkpoint) => checkpoint,
+            other => panic!("unexpected checkpoint message {:?}", other),
+        })
+        .collect();
+    assert_eq!(recovered_files.len(), 1);
+    assert_eq!(recovered_files[0].filename, "output/v2/00000-000.json");
+
+    // a restarted writer finishes the recovered file under the same prefixed key
+    let (sender, mut checkpoint_receiver) = start_writer(store.clone(), table);
+    sender
+        .send(FileSystemMessages::Init {
+            max_file_index: 1,
+            subtask_id: 0,
+            parallelism: 1,
+            job_id: "job".to_string(),
+            operator_id: "sink".to_string(),
+            run_directory: None,
+            recovered_files,
+        })
+        .await
+        .unwrap();
+    let files_to_finish: Vec<_> = checkpoint(&sender, &mut checkpoint_receiver, true)
+        .await
+        .into_iter()
+        .filter_map(|message| match message {
+            CheckpointData::InProgressFileCheckpoint(InProgressFileCheckpoint {
+                filename,
+                data:
+                    FileCheckpointData::MultiPartWriterUploadCompleted {
+                        multi_part_upload_id,
+                        completed_parts,
+                        checksum,
+                    },
+                ..
+            }) => Some(FileToFinish {
+                filename,
+                multi_part_upload_id,
+                completed_parts,
+                checksum,
+                staging_path: None,
+            }),
+            _ => None,
+        })
+        .collect();
+    assert!(files_to_finish
+        .iter()
+        .all(|file| file.filename.starts_with("output/v2/")));
+    sender
+        .send(FileSystemMessages::FilesToFinish(files_to_finish))
+        .await
+        .unwrap();
+    checkpoint_receiver.recv().await.unwrap();
+
+    assert_eq!(
+        &store.read("output/v2/00000-000.json").await[..],
+        b"\"hello\"\n"
+    );
+}
@@ -79,6 +79,8 @@ impl KafkaTopicTester {
             start_time: to_micros(SystemTime::now()),
             finish_time: to_micros(SystemTime::now()),
             operator_ids: vec![task_info.operator_id.clone()],
+            arroyo_version: String::new(),
+            program_hash: String::new(),
         });
 
         let mut ctx: Context<(), TestData> = Context::new(
@@ -242,6 +244,8 @@ async fn test_kafka() {
         start_time: 0,
         finish_time: 0,
         operator_ids: vec![task_info.operator_id.clone()],
+        arroyo_version: String::new(),
+        program_hash: String::new(),
     })
     .await;
 
@@ -8,7 +8,7 @@ use arroyo_rpc::{
     CheckpointEvent, ControlMessage,
 };
 use arroyo_state::tables::global_keyed_map::GlobalKeyedState;
-use arroyo_types::{Data, Key, Record, TaskInfo};
+use arroyo_types::{Data, Key, Message, Record, TaskInfo, Watermark};
 use async_trait::async_trait;
 use tracing::warn;
 
@@ -58,6 +58,11 @@ pub trait TwoPhaseCommitter<K: Key, T: Data + Sync>: Send + 'static {
         task_info: &TaskInfo,
         stopping: bool,
     ) -> Result<(Self::DataRecovery, HashMap<String, Self::PreCommit>)>;
+    /// Called with each watermark the sink receives, for committers that close their output
+    /// based on event time
+    async fn handle_watermark(&mut self, _watermark: Watermark) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[process_fn(in_k = K, in_t = T)]
@@ -127,6 +132,14 @@ impl<K: Key, T: Data + Sync, TPC: TwoPhaseCommitter<K, T>> TwoPhaseCommitterOper
             .expect("record inserted");
     }
 
+    async fn handle_watermark(&mut self, watermark: Watermark, ctx: &mut Context<(), ()>) {
+        self.committer
+            .handle_watermark(watermark)
+            .await
+            .expect("watermark handled");
+        ctx.broadcast(Message::Watermark(watermark)).await;
+    }
+
     async fn on_close(&mut self, ctx: &mut crate::engine::Context<(), ()>) {
         if let Some(ControlMessage::Commit { epoch }) = ctx.control_rx.recv().await {
             self.handle_commit(epoch, ctx).await;